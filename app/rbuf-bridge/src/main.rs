@@ -0,0 +1,436 @@
+// main.rs
+//
+// Forwards the messages of a byte ring over a socket, so a ring on one host
+// can be mirrored to another, or into a container that can't share memory
+// with it. One bridge runs at each end:
+//
+//     rbuf-bridge out <ring> (--listen | --connect) <addr> [--capacity BYTES]
+//     rbuf-bridge in <ring> (--listen | --connect) <addr>
+//
+//     rbuf-bridge out <ring> --multicast <group:port> [--ttl N] [--interface IP]
+//     rbuf-bridge in <ring> --multicast <group:port> [--interface IP]
+//
+// `out` creates the ring, as its consumer, and sends every message local
+// producers push into it. `in` opens a ring a local consumer created and
+// pushes every message it receives into it. Addresses are `host:port` (or
+// `tcp:host:port`) or, on Unix, `unix:<path>`. Either end may listen; the
+// connecting end retries until it gets through, and again after the
+// connection drops.
+//
+// Each message goes over the socket as a frame:
+//
+//     [ len: u32, little-endian | payload ]
+//
+// While no peer is connected `out` leaves messages in the ring, so local
+// producers see it fill up rather than their messages vanish. A message
+// whose send failed is sent again on the next connection, so the far ring
+// may see it twice, but one the socket took just before the connection
+// dropped is lost: there are no acknowledgements.
+//
+// With `--multicast` there are no connections: `out` sends every message as
+// one UDP datagram to an IPv4 multicast group, for any number of `in`
+// bridges on the LAN to push into their own rings. Datagrams get lost and
+// nothing notices, so this is for monitoring, not for anything that must
+// see every message. Messages too big for a datagram are skipped. Only one
+// `in` bridge per host can join a given group and port.
+use rbuf::bytes::{Reader, Writer};
+use rbuf::RbufError;
+use std::env;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// How long a connecting bridge waits before trying again
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Largest payload of a UDP datagram over IPv4
+const MAX_DATAGRAM: usize = 65507;
+
+// --- Sockets ---
+
+enum Addr {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Addr {
+    fn parse(addr: &str) -> Result<Self, String> {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(Addr::Unix(path.into()));
+            #[cfg(not(unix))]
+            return Err(format!("no Unix sockets on this platform for {}", path));
+        }
+        Ok(Addr::Tcp(addr.strip_prefix("tcp:").unwrap_or(addr).to_string()))
+    }
+
+    fn connect(&self) -> io::Result<Stream> {
+        match self {
+            Addr::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_nodelay(true)?;
+                Ok(Stream::Tcp(stream))
+            }
+            #[cfg(unix)]
+            Addr::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+        }
+    }
+
+    fn bind(&self) -> io::Result<Listener> {
+        match self {
+            Addr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            Addr::Unix(path) => {
+                // A socket left behind by a bridge that didn't exit cleanly
+                use std::os::unix::fs::FileTypeExt;
+                if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+        }
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                Ok(Stream::Tcp(stream))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(Stream::Unix(listener.accept()?.0)),
+        }
+    }
+}
+
+// Where the peer bridge is: waiting for it to connect, or to connect to
+enum Peer {
+    Listen(Listener),
+    Connect(Addr),
+}
+
+impl Peer {
+    // The next connection to the peer, waiting as long as it takes
+    fn next(&self) -> Stream {
+        loop {
+            let stream = match self {
+                Peer::Listen(listener) => listener.accept(),
+                Peer::Connect(addr) => addr.connect(),
+            };
+            match stream {
+                Ok(stream) => return stream,
+                Err(e) => {
+                    eprintln!("rbuf-bridge: no connection: {}", e);
+                    thread::sleep(RETRY_INTERVAL);
+                }
+            }
+        }
+    }
+}
+
+// --- Framing ---
+
+// Frame `payload` into `frame` and send it in one write, so that a failure
+// belongs to this message and not one buffered before it
+fn write_frame(stream: &mut impl Write, frame: &mut Vec<u8>, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "message too large for a frame"))?;
+    frame.clear();
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(payload);
+    stream.write_all(frame)
+}
+
+// Read the next frame's payload into `buf`. Returns false if the peer
+// closed the connection between frames.
+fn read_frame(stream: &mut impl Read, buf: &mut Vec<u8>, max: usize) -> io::Result<bool> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > max {
+        let reason = format!("{} byte frame is more than the ring's {} byte maximum", len, max);
+        return Err(io::Error::new(ErrorKind::InvalidData, reason));
+    }
+    buf.resize(len, 0);
+    stream.read_exact(buf)?;
+    Ok(true)
+}
+
+// --- Ring to socket ---
+
+fn send(ring: &str, capacity: usize, peer: Peer) -> Result<(), String> {
+    let mut reader = Reader::create(ring, capacity).map_err(|e| e.to_string())?;
+    let mut message = Vec::new();
+    // Whether `message` holds one popped but not yet sent
+    let mut pending = false;
+    loop {
+        let mut stream = peer.next();
+        eprintln!("rbuf-bridge: connected, sending {}", ring);
+        if let Err(e) = forward(&mut reader, &mut stream, &mut message, &mut pending) {
+            eprintln!("rbuf-bridge: connection lost: {}", e);
+        }
+    }
+}
+
+fn forward(
+    reader: &mut Reader,
+    stream: &mut impl Write,
+    message: &mut Vec<u8>,
+    pending: &mut bool,
+) -> io::Result<()> {
+    let mut frame = Vec::new();
+    loop {
+        if !*pending {
+            match reader.pop_bytes(message) {
+                Ok(_) => {}
+                Err(RbufError::Empty) => {
+                    reader.pop_bytes_blocking(message);
+                }
+                // Consumed; nothing to forward
+                Err(e) => {
+                    eprintln!("rbuf-bridge: dropped a message: {}", e);
+                    continue;
+                }
+            }
+            *pending = true;
+        }
+        write_frame(stream, &mut frame, message)?;
+        *pending = false;
+    }
+}
+
+// --- Multicast ---
+
+struct Multicast {
+    group: SocketAddrV4,
+    // Which interface to send from and join the group on; the OS picks if
+    // unspecified
+    interface: Ipv4Addr,
+    // How many routers a datagram may cross; 1 keeps it on the LAN
+    ttl: u32,
+}
+
+fn publish(ring: &str, capacity: usize, multicast: &Multicast) -> Result<(), String> {
+    let socket = UdpSocket::bind((multicast.interface, 0)).map_err(|e| e.to_string())?;
+    socket.set_multicast_ttl_v4(multicast.ttl).map_err(|e| e.to_string())?;
+    let mut reader = Reader::create(ring, capacity).map_err(|e| e.to_string())?;
+    eprintln!("rbuf-bridge: sending {} to {}", ring, multicast.group);
+    let mut message = Vec::new();
+    loop {
+        match reader.pop_bytes(&mut message) {
+            Ok(_) => {}
+            Err(RbufError::Empty) => {
+                reader.pop_bytes_blocking(&mut message);
+            }
+            Err(e) => {
+                eprintln!("rbuf-bridge: dropped a message: {}", e);
+                continue;
+            }
+        }
+        if message.len() > MAX_DATAGRAM {
+            eprintln!("rbuf-bridge: skipped a {} byte message", message.len());
+            continue;
+        }
+        // Lost like any other datagram; the next may get through
+        if let Err(e) = socket.send_to(&message, multicast.group) {
+            eprintln!("rbuf-bridge: send failed: {}", e);
+        }
+    }
+}
+
+fn subscribe(ring: &str, multicast: &Multicast) -> Result<(), String> {
+    let writer = Writer::open(ring).map_err(|e| e.to_string())?;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, multicast.group.port()))
+        .map_err(|e| e.to_string())?;
+    socket
+        .join_multicast_v4(multicast.group.ip(), &multicast.interface)
+        .map_err(|e| format!("can't join {}: {}", multicast.group, e))?;
+    eprintln!("rbuf-bridge: receiving {} into {}", multicast.group, ring);
+    let mut datagram = vec![0; MAX_DATAGRAM];
+    loop {
+        let len = socket.recv(&mut datagram).map_err(|e| e.to_string())?;
+        if let Err(e) = writer.push_bytes_blocking(&datagram[..len]) {
+            eprintln!("rbuf-bridge: dropped a datagram: {}", e);
+        }
+    }
+}
+
+// --- Socket to ring ---
+
+fn receive(ring: &str, peer: Peer) -> Result<(), String> {
+    let writer = Arc::new(Writer::open(ring).map_err(|e| e.to_string())?);
+    loop {
+        let stream = peer.next();
+        eprintln!("rbuf-bridge: connected, receiving into {}", ring);
+        match peer {
+            // Every peer that connects gets a thread of its own
+            Peer::Listen(_) => {
+                let writer = Arc::clone(&writer);
+                thread::spawn(move || serve(&writer, stream));
+            }
+            Peer::Connect(_) => serve(&writer, stream),
+        }
+    }
+}
+
+fn serve(writer: &Writer, mut stream: Stream) {
+    match inject(writer, &mut stream) {
+        Ok(()) => eprintln!("rbuf-bridge: peer disconnected"),
+        Err(e) => eprintln!("rbuf-bridge: connection lost: {}", e),
+    }
+}
+
+fn inject(writer: &Writer, stream: &mut impl Read) -> io::Result<()> {
+    let mut message = Vec::new();
+    while read_frame(stream, &mut message, writer.max_message_size())? {
+        writer.push_bytes_blocking(&message).map_err(io::Error::other)?;
+    }
+    Ok(())
+}
+
+// --- Command line ---
+
+enum Direction {
+    Out,
+    In,
+}
+
+enum Transport {
+    Stream(Peer),
+    Multicast(Multicast),
+}
+
+struct Options {
+    direction: Direction,
+    ring: String,
+    transport: Transport,
+    capacity: usize,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let usage = "usage: rbuf-bridge (out | in) <ring> \
+                     (--listen <addr> | --connect <addr> | --multicast <group:port>) \
+                     [--capacity BYTES] [--ttl N] [--interface IP]";
+        let (direction, ring, flags) = match args {
+            [direction, ring, flags @ ..] => (direction, ring, flags),
+            _ => return Err(usage.to_string()),
+        };
+        let direction = match direction.as_str() {
+            "out" => Direction::Out,
+            "in" => Direction::In,
+            _ => return Err(usage.to_string()),
+        };
+        let mut peer = None;
+        let mut group = None;
+        let mut interface = Ipv4Addr::UNSPECIFIED;
+        let mut ttl = 1;
+        let mut capacity = 1 << 20;
+        let mut flags = flags.iter();
+        while let Some(flag) = flags.next() {
+            let value = flags.next().ok_or_else(|| format!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--listen" => {
+                    let addr = Addr::parse(value)?;
+                    let listener = addr.bind().map_err(|e| format!("can't listen: {}", e))?;
+                    peer = Some(Peer::Listen(listener));
+                }
+                "--connect" => peer = Some(Peer::Connect(Addr::parse(value)?)),
+                "--multicast" => {
+                    let addr: SocketAddrV4 =
+                        value.parse().map_err(|_| format!("bad value for {}", flag))?;
+                    if !addr.ip().is_multicast() {
+                        return Err(format!("{} isn't a multicast group", addr.ip()));
+                    }
+                    group = Some(addr);
+                }
+                "--interface" => {
+                    interface = value.parse().map_err(|_| format!("bad value for {}", flag))?
+                }
+                "--ttl" => ttl = value.parse().map_err(|_| format!("bad value for {}", flag))?,
+                "--capacity" => {
+                    capacity = value.parse().map_err(|_| format!("bad value for {}", flag))?
+                }
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        let transport = match (peer, group) {
+            (Some(peer), None) => Transport::Stream(peer),
+            (None, Some(group)) => Transport::Multicast(Multicast { group, interface, ttl }),
+            _ => return Err(usage.to_string()),
+        };
+        Ok(Options { direction, ring: ring.clone(), transport, capacity })
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let result = Options::parse(&args[1..]).and_then(|options| {
+        let ring = &options.ring;
+        match (options.direction, options.transport) {
+            (Direction::Out, Transport::Stream(peer)) => send(ring, options.capacity, peer),
+            (Direction::In, Transport::Stream(peer)) => receive(ring, peer),
+            (Direction::Out, Transport::Multicast(m)) => publish(ring, options.capacity, &m),
+            (Direction::In, Transport::Multicast(m)) => subscribe(ring, &m),
+        }
+    });
+    if let Err(e) = result {
+        eprintln!("rbuf-bridge: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,346 @@
+// archive.rs
+//
+// A capture split across a directory of fixed-size segment files, each an
+// ordinary capture, with the oldest deleted as the archive outgrows its
+// retention. This is how a recorder keeps "the last 15 minutes" of a ring
+// running for weeks:
+//
+//     <dir>/00000001.cap, 00000002.cap, ...   segments, oldest first
+//     <dir>/index                             where to start reading
+//
+//     index: [ magic: "RBUFIDX1" | entry ... ]
+//     entry: [ time: u64 | segment: u64 | offset: u64 ]
+//
+// An index entry says the record at `offset` in `segment` was recorded at
+// `time`. There is one for the first record of every segment and then one
+// every `INDEX_INTERVAL` bytes, so seeking to a time reads at most that far
+// past it. Entries for deleted segments are dropped when the index is
+// rewritten; a missing or torn index only makes seeking slower, never
+// wrong, since readers scan forward from wherever it points.
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{Player, Recorder, MAGIC};
+
+const INDEX_MAGIC: &[u8; 8] = b"RBUFIDX1";
+const INDEX_NAME: &str = "index";
+const INDEX_INTERVAL: u64 = 1 << 20;
+
+// Bytes a record takes besides its payload
+const RECORD_HEADER: u64 = 12;
+
+// When to start a new segment and when to delete old ones
+#[derive(Debug, Clone)]
+pub struct Rotation {
+    segment_size: u64,
+    max_age: Option<Duration>,
+    max_bytes: Option<u64>,
+}
+
+impl Rotation {
+    // Start a new segment before one would grow past `segment_size` bytes.
+    // A record bigger than that gets a segment to itself. Keeps everything
+    // until told otherwise.
+    pub fn new(segment_size: u64) -> Self {
+        Self { segment_size, max_age: None, max_bytes: None }
+    }
+
+    // Delete segments once their last record is older than `age`
+    pub fn retain_for(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    // Delete the oldest segments while the archive holds more than `bytes`
+    pub fn retain_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+}
+
+// --- Recording ---
+
+// A segment the recorder has finished with
+struct Closed {
+    number: u64,
+    size: u64,
+    // When its last record was recorded
+    last: SystemTime,
+}
+
+pub struct RotatingRecorder {
+    dir: PathBuf,
+    rotation: Rotation,
+    closed: VecDeque<Closed>,
+    // Bytes in the closed segments
+    closed_size: u64,
+    number: u64,
+    segment: Recorder,
+    size: u64,
+    last: SystemTime,
+    index: Index,
+    // Where in the segment the last index entry points
+    indexed: Option<u64>,
+}
+
+impl RotatingRecorder {
+    // Record into the archive in `dir`, creating it if need be. Carries on
+    // after whatever segments are already there, in a new one.
+    pub fn open(dir: &Path, rotation: Rotation) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut closed = VecDeque::new();
+        for number in segments(dir)? {
+            let meta = fs::metadata(segment_path(dir, number))?;
+            closed.push_back(Closed { number, size: meta.len(), last: meta.modified()? });
+        }
+        let closed_size = closed.iter().map(|c| c.size).sum();
+        let number = closed.back().map_or(1, |c| c.number + 1);
+        let mut index = Index::open(dir)?;
+        index.entries.retain(|e| closed.iter().any(|c| c.number == e.segment));
+        index.rewrite()?;
+        let mut recorder = Self {
+            dir: dir.to_path_buf(),
+            rotation,
+            closed,
+            closed_size,
+            number,
+            segment: Recorder::open(&segment_path(dir, number))?,
+            size: MAGIC.len() as u64,
+            last: SystemTime::now(),
+            index,
+            indexed: None,
+        };
+        recorder.retain(SystemTime::now())?;
+        Ok(recorder)
+    }
+
+    pub fn record(&mut self, time: SystemTime, message: &[u8]) -> io::Result<()> {
+        let len = RECORD_HEADER + message.len() as u64;
+        if self.size > MAGIC.len() as u64 && self.size + len > self.rotation.segment_size {
+            self.rotate()?;
+        }
+        if self.indexed.is_none_or(|offset| self.size - offset >= INDEX_INTERVAL) {
+            self.index.push(Entry { time: nanos(time), segment: self.number, offset: self.size })?;
+            self.indexed = Some(self.size);
+        }
+        self.segment.record(time, message)?;
+        self.size += len;
+        self.last = time;
+        self.retain(time)
+    }
+
+    // Get everything recorded so far, and the index, to the OS
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.segment.flush()?;
+        self.index.file.flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.segment.flush()?;
+        self.closed.push_back(Closed { number: self.number, size: self.size, last: self.last });
+        self.closed_size += self.size;
+        self.number += 1;
+        self.segment = Recorder::open(&segment_path(&self.dir, self.number))?;
+        self.size = MAGIC.len() as u64;
+        self.indexed = None;
+        Ok(())
+    }
+
+    // Delete whichever finished segments the rotation no longer keeps. The
+    // one being recorded into always stays.
+    fn retain(&mut self, now: SystemTime) -> io::Result<()> {
+        let mut deleted = false;
+        while let Some(oldest) = self.closed.front() {
+            let total = self.closed_size + self.size;
+            let too_old = self.rotation.max_age.is_some_and(|age| {
+                now.duration_since(oldest.last).is_ok_and(|elapsed| elapsed > age)
+            });
+            let too_big = self.rotation.max_bytes.is_some_and(|bytes| total > bytes);
+            if !too_old && !too_big {
+                break;
+            }
+            match fs::remove_file(segment_path(&self.dir, oldest.number)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            let number = oldest.number;
+            self.index.entries.retain(|e| e.segment != number);
+            self.closed_size -= oldest.size;
+            self.closed.pop_front();
+            deleted = true;
+        }
+        if deleted {
+            self.index.rewrite()?;
+        }
+        Ok(())
+    }
+}
+
+// --- Playback ---
+
+// Plays an archive back from its oldest retained record on, or from the
+// first one recorded at or after some time. Reading an archive a recorder
+// is still writing is fine: segments it deletes meanwhile are skipped.
+pub struct ArchivePlayer {
+    dir: PathBuf,
+    // Segments still to play, with where to start in each
+    segments: VecDeque<(u64, u64)>,
+    player: Option<Player>,
+    from: u64,
+}
+
+impl ArchivePlayer {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        Self::open_from(dir, UNIX_EPOCH)
+    }
+
+    pub fn open_from(dir: &Path, from: SystemTime) -> io::Result<Self> {
+        let numbers = segments(dir)?;
+        let from = nanos(from);
+        // The last index entry no later than `from` that's still there to read
+        let start = Index::read(dir)?
+            .into_iter()
+            .rfind(|e| e.time <= from && numbers.contains(&e.segment))
+            .map_or((0, 0), |e| (e.segment, e.offset));
+        let segments = numbers
+            .into_iter()
+            .filter(|&number| number >= start.0)
+            .map(|number| (number, if number == start.0 { start.1 } else { 0 }))
+            .collect();
+        Ok(Self { dir: dir.to_path_buf(), segments, player: None, from })
+    }
+
+    // Read the next message into `message` and return the time it was
+    // recorded, or None at the end of the archive
+    pub fn next(&mut self, message: &mut Vec<u8>) -> io::Result<Option<Duration>> {
+        loop {
+            let player = match &mut self.player {
+                Some(player) => player,
+                None => {
+                    let Some((number, offset)) = self.segments.pop_front() else {
+                        return Ok(None);
+                    };
+                    match Player::open_at(&segment_path(&self.dir, number), offset) {
+                        Ok(player) => self.player.insert(player),
+                        Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+            };
+            match player.next(message)? {
+                Some(time) if time.as_nanos() as u64 >= self.from => return Ok(Some(time)),
+                Some(_) => {}
+                None => self.player = None,
+            }
+        }
+    }
+}
+
+// --- Index ---
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    time: u64,
+    segment: u64,
+    offset: u64,
+}
+
+struct Index {
+    path: PathBuf,
+    file: BufWriter<File>,
+    entries: Vec<Entry>,
+}
+
+impl Index {
+    // Open the index of the archive in `dir` for appending, keeping the
+    // entries it already has
+    fn open(dir: &Path) -> io::Result<Self> {
+        let entries = Self::read(dir)?;
+        let path = dir.join(INDEX_NAME);
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        Ok(Self { path, file: BufWriter::new(file), entries })
+    }
+
+    // The entries of the index in `dir`, or none if it has no index
+    fn read(dir: &Path) -> io::Result<Vec<Entry>> {
+        let mut file = match File::open(dir.join(INDEX_NAME)) {
+            Ok(file) => BufReader::new(file),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut magic = [0; 8];
+        match file.read_exact(&mut magic) {
+            Ok(()) if &magic == INDEX_MAGIC => {}
+            Ok(()) => return Err(io::Error::new(ErrorKind::InvalidData, "not an rbuf index")),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        }
+        let mut entries = Vec::new();
+        let mut entry = [0; 24];
+        loop {
+            match file.read_exact(&mut entry) {
+                Ok(()) => {}
+                // A torn last entry counts as the end
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(entries),
+                Err(e) => return Err(e),
+            }
+            let field = |i: usize| u64::from_le_bytes(entry[i * 8..][..8].try_into().unwrap());
+            entries.push(Entry { time: field(0), segment: field(1), offset: field(2) });
+        }
+    }
+
+    fn push(&mut self, entry: Entry) -> io::Result<()> {
+        write_entry(&mut self.file, &entry)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    // Replace the file with `entries`, so it stops growing along with the
+    // segments it points into
+    fn rewrite(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let temp = self.path.with_extension("new");
+        let mut file = BufWriter::new(File::create(&temp)?);
+        file.write_all(INDEX_MAGIC)?;
+        for entry in &self.entries {
+            write_entry(&mut file, entry)?;
+        }
+        file.flush()?;
+        fs::rename(&temp, &self.path)?;
+        self.file = BufWriter::new(OpenOptions::new().append(true).open(&self.path)?);
+        Ok(())
+    }
+}
+
+fn write_entry(file: &mut impl Write, entry: &Entry) -> io::Result<()> {
+    for field in [entry.time, entry.segment, entry.offset] {
+        file.write_all(&field.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+// --- Files ---
+
+fn segment_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:08}.cap", number))
+}
+
+// The numbers of the segments in `dir`, oldest first
+fn segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut numbers = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let number = name.to_str().and_then(|n| n.strip_suffix(".cap")?.parse::<u64>().ok());
+        numbers.extend(number);
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+fn nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
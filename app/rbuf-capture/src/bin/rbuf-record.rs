@@ -0,0 +1,188 @@
+// rbuf-record.rs
+//
+// Capture every message of a byte ring, with the time it arrived, to a
+// file `rbuf-replay` can play back:
+//
+//     rbuf-record <ring> <file> [--capacity BYTES]
+//     rbuf-record <ring> <dir> --segment-size BYTES
+//                 [--retain-for SECS] [--retain-bytes BYTES] [--capacity BYTES]
+//
+// The recorder creates the ring and pops from it, so it takes the place of
+// the ring's consumer: start it where the consumer would run, and replay
+// the capture into the real one later. Appends to `file` if it's already a
+// capture. Runs until interrupted or terminated, then flushes the file and
+// removes the ring, so a recording can carry on from the same ring.
+//
+// With `--segment-size` it records into an archive in `dir` instead: files
+// of up to that many bytes, the oldest deleted once the archive holds more
+// than `--retain-bytes` or its last message is more than `--retain-for`
+// seconds old, to keep a window of recent traffic for as long as it runs.
+// `rbuf-replay` plays the directory back like a file.
+//
+// So it can notice a signal, it polls an empty ring rather than sleeping
+// on it, which puts up to `POLL_INTERVAL` into the time of a message that
+// ends a quiet spell.
+use rbuf::bytes::Reader;
+use rbuf::RbufError;
+use rbuf_capture::{Recorder, RotatingRecorder, Rotation};
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+// Set by SIGINT and SIGTERM
+static STOP: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn stop_on_signals() {
+    extern "C" fn stop(_: libc::c_int) {
+        STOP.store(true, Ordering::Relaxed);
+    }
+    let stop: extern "C" fn(libc::c_int) = stop;
+    unsafe {
+        libc::signal(libc::SIGINT, stop as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, stop as libc::sighandler_t);
+    }
+}
+
+// Ctrl-C ends the process on the spot; the ring is left behind
+#[cfg(not(unix))]
+fn stop_on_signals() {}
+
+// Where the messages go: one file, or an archive in a directory
+enum Sink {
+    File(Recorder),
+    Archive(Box<RotatingRecorder>),
+}
+
+impl Sink {
+    fn open(path: &Path, rotation: Option<Rotation>) -> io::Result<Self> {
+        match rotation {
+            Some(rotation) => {
+                RotatingRecorder::open(path, rotation).map(|r| Sink::Archive(Box::new(r)))
+            }
+            None => Recorder::open(path).map(Sink::File),
+        }
+    }
+
+    fn record(&mut self, time: SystemTime, message: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::File(recorder) => recorder.record(time, message),
+            Sink::Archive(recorder) => recorder.record(time, message),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::File(recorder) => recorder.flush(),
+            Sink::Archive(recorder) => recorder.flush(),
+        }
+    }
+}
+
+fn record(options: Options) -> Result<(), String> {
+    let path = &options.path;
+    let mut recorder = Sink::open(path, options.rotation)
+        .map_err(|e| format!("can't open {}: {}", path.display(), e))?;
+    let mut reader =
+        Reader::create(&options.ring, options.capacity).map_err(|e| e.to_string())?;
+    stop_on_signals();
+    let mut message = Vec::new();
+    let mut recorded = 0u64;
+    // Whether the last pop found the ring empty
+    let mut idle = false;
+    while !STOP.load(Ordering::Relaxed) {
+        match reader.pop_bytes(&mut message) {
+            Ok(_) => idle = false,
+            Err(RbufError::Empty) => {
+                // Make sure a kill while it's quiet loses nothing
+                if !idle {
+                    recorder.flush().map_err(|e| e.to_string())?;
+                    idle = true;
+                }
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("rbuf-record: dropped a message: {}", e);
+                continue;
+            }
+        }
+        recorder.record(SystemTime::now(), &message).map_err(|e| e.to_string())?;
+        recorded += 1;
+        if recorded.is_power_of_two() {
+            eprintln!("rbuf-record: {} messages recorded", recorded);
+        }
+    }
+    recorder.flush().map_err(|e| e.to_string())?;
+    eprintln!("rbuf-record: stopped after {} messages", recorded);
+    Ok(())
+}
+
+struct Options {
+    ring: String,
+    path: PathBuf,
+    rotation: Option<Rotation>,
+    capacity: usize,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let usage = "usage: rbuf-record <ring> <file> [--capacity BYTES] \
+                     [--segment-size BYTES [--retain-for SECS] [--retain-bytes BYTES]]";
+        let (ring, path, flags) = match args {
+            [ring, path, flags @ ..] => (ring, path, flags),
+            _ => return Err(usage.to_string()),
+        };
+        let mut capacity = 1 << 20;
+        let mut segment_size = None;
+        let mut retain_for = None;
+        let mut retain_bytes = None;
+        let mut flags = flags.iter();
+        while let Some(flag) = flags.next() {
+            let value = flags.next().ok_or_else(|| format!("{} needs a value", flag))?;
+            let bad = || format!("bad value for {}", flag);
+            match flag.as_str() {
+                "--capacity" => capacity = value.parse().map_err(|_| bad())?,
+                "--segment-size" => segment_size = Some(value.parse().map_err(|_| bad())?),
+                "--retain-for" => {
+                    let secs: f64 = value.parse().map_err(|_| bad())?;
+                    retain_for = Some(Duration::try_from_secs_f64(secs).map_err(|_| bad())?);
+                }
+                "--retain-bytes" => retain_bytes = Some(value.parse().map_err(|_| bad())?),
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        let rotation = match segment_size {
+            Some(segment_size) => {
+                let mut rotation = Rotation::new(segment_size);
+                if let Some(age) = retain_for {
+                    rotation = rotation.retain_for(age);
+                }
+                if let Some(bytes) = retain_bytes {
+                    rotation = rotation.retain_bytes(bytes);
+                }
+                Some(rotation)
+            }
+            None if retain_for.is_some() || retain_bytes.is_some() => {
+                return Err("retention needs --segment-size".to_string())
+            }
+            None => None,
+        };
+        Ok(Options { ring: ring.clone(), path: PathBuf::from(path), rotation, capacity })
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let result = Options::parse(&args[1..]).and_then(record);
+    if let Err(e) = result {
+        eprintln!("rbuf-record: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,129 @@
+// rbuf-replay.rs
+//
+// Push the messages of a capture from `rbuf-record` into a byte ring, with
+// the gaps between them they were recorded with:
+//
+//     rbuf-replay <file> <ring> [--speed FACTOR | --fast] [--since UNIX_SECS]
+//
+// The ring's consumer creates it as usual. `--speed 10` plays ten times
+// faster than recorded, `--speed 0.5` at half speed, and `--fast` without
+// waiting at all. Pushes wait for room rather than drop anything, so a slow
+// consumer stretches the replay out.
+//
+// `file` may also be the directory of an archive `rbuf-record` kept with
+// `--segment-size`. `--since` skips whatever was recorded before the given
+// time, in seconds since the Unix epoch; in an archive it seeks there
+// through the index rather than reading everything before it.
+use rbuf::bytes::Writer;
+use rbuf_capture::{ArchivePlayer, Player};
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// How to space the messages out: by their recorded gaps divided by the
+// factor, or not at all
+enum Pace {
+    Speed(f64),
+    Fast,
+}
+
+// Where the messages come from: one file, or an archive in a directory
+enum Source {
+    File(Player),
+    Archive(ArchivePlayer),
+}
+
+impl Source {
+    fn open(path: &Path, since: SystemTime) -> io::Result<Self> {
+        if path.is_dir() {
+            ArchivePlayer::open_from(path, since).map(Source::Archive)
+        } else {
+            Player::open(path).map(Source::File)
+        }
+    }
+
+    fn next(&mut self, message: &mut Vec<u8>) -> io::Result<Option<Duration>> {
+        match self {
+            Source::File(player) => player.next(message),
+            Source::Archive(player) => player.next(message),
+        }
+    }
+}
+
+fn replay(options: Options) -> Result<(), String> {
+    let path = &options.path;
+    let mut player = Source::open(path, options.since)
+        .map_err(|e| format!("can't open {}: {}", path.display(), e))?;
+    let writer = Writer::open(&options.ring).map_err(|e| e.to_string())?;
+    let since = options.since.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut message = Vec::new();
+    // When the first message was recorded, and when we replayed it
+    let mut start: Option<(Duration, Instant)> = None;
+    let mut replayed = 0u64;
+    while let Some(time) = player.next(&mut message).map_err(|e| e.to_string())? {
+        if time < since {
+            continue;
+        }
+        if let Pace::Speed(speed) = options.pace {
+            let (recorded, replayed) = *start.get_or_insert((time, Instant::now()));
+            let due = replayed + time.saturating_sub(recorded).div_f64(speed);
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+        writer.push_bytes_blocking(&message).map_err(|e| e.to_string())?;
+        replayed += 1;
+    }
+    eprintln!("rbuf-replay: {} messages replayed", replayed);
+    Ok(())
+}
+
+struct Options {
+    path: PathBuf,
+    ring: String,
+    pace: Pace,
+    since: SystemTime,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let usage = "usage: rbuf-replay <file> <ring> [--speed FACTOR | --fast] \
+                     [--since UNIX_SECS]";
+        let (path, ring, flags) = match args {
+            [path, ring, flags @ ..] => (path, ring, flags),
+            _ => return Err(usage.to_string()),
+        };
+        let mut pace = Pace::Speed(1.0);
+        let mut since = UNIX_EPOCH;
+        let mut flags = flags.iter();
+        while let Some(flag) = flags.next() {
+            if flag == "--fast" {
+                pace = Pace::Fast;
+                continue;
+            }
+            let value = flags.next().ok_or_else(|| format!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--speed" => match value.parse() {
+                    Ok(speed) if speed > 0.0 && f64::is_finite(speed) => pace = Pace::Speed(speed),
+                    _ => return Err("--speed must be a positive factor".to_string()),
+                },
+                "--since" => {
+                    let secs = value.parse().ok().and_then(|s| Duration::try_from_secs_f64(s).ok());
+                    since = UNIX_EPOCH + secs.ok_or_else(|| format!("bad value for {}", flag))?;
+                }
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        Ok(Options { path: PathBuf::from(path), ring: ring.clone(), pace, since })
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let result = Options::parse(&args[1..]).and_then(replay);
+    if let Err(e) = result {
+        eprintln!("rbuf-replay: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,113 @@
+// lib.rs
+//
+// The capture file `rbuf-record` writes and `rbuf-replay` reads: the
+// messages of a byte ring, each with the time it was popped.
+//
+//     [ magic: "RBUFCAP1" | record ... ]
+//     record: [ time: u64 | len: u32 | payload ]
+//
+// Integers are little-endian; `time` is nanoseconds since the Unix epoch.
+// The file is only ever appended to, so a recording can be stopped and
+// carried on later, and a recorder killed mid-write leaves at worst a torn
+// last record, which readers stop at.
+//
+// `archive` keeps a long-running recording to a bounded window of recent
+// traffic, across a directory of these files.
+mod archive;
+
+pub use archive::{ArchivePlayer, Rotation, RotatingRecorder};
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 8] = b"RBUFCAP1";
+
+pub struct Recorder {
+    file: BufWriter<File>,
+}
+
+impl Recorder {
+    // Open `path` for appending, starting it if it's new or empty. Fails
+    // with `InvalidData` if it holds something other than a capture.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        if file.seek(SeekFrom::End(0))? == 0 {
+            file.write_all(MAGIC)?;
+        } else {
+            file.seek(SeekFrom::Start(0))?;
+            check_magic(&mut file)?;
+        }
+        Ok(Self { file: BufWriter::new(file) })
+    }
+
+    pub fn record(&mut self, time: SystemTime, message: &[u8]) -> io::Result<()> {
+        let nanos = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        let len = u32::try_from(message.len())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "message too large"))?;
+        self.file.write_all(&nanos.to_le_bytes())?;
+        self.file.write_all(&len.to_le_bytes())?;
+        self.file.write_all(message)
+    }
+
+    // Get everything recorded so far to the OS
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn check_magic(file: &mut impl Read) -> io::Result<()> {
+    let mut magic = [0; 8];
+    match file.read_exact(&mut magic) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {}
+        Err(e) => return Err(e),
+    }
+    if &magic != MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not an rbuf capture"));
+    }
+    Ok(())
+}
+
+pub struct Player {
+    file: BufReader<File>,
+}
+
+impl Player {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::open_at(path, MAGIC.len() as u64)
+    }
+
+    // Start at the record at byte `offset` of the file, or the first one if
+    // `offset` points into the magic
+    fn open_at(path: &Path, offset: u64) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        check_magic(&mut file)?;
+        if offset > MAGIC.len() as u64 {
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        Ok(Self { file })
+    }
+
+    // Read the next message into `message` and return the time it was
+    // recorded, or None at the end of the capture. A torn last record
+    // counts as the end.
+    pub fn next(&mut self, message: &mut Vec<u8>) -> io::Result<Option<Duration>> {
+        let mut header = [0; 12];
+        match self.file.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let (time, len) = header.split_at(8);
+        let time = u64::from_le_bytes(time.try_into().unwrap());
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        message.resize(len, 0);
+        match self.file.read_exact(message) {
+            Ok(()) => Ok(Some(Duration::from_nanos(time))),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
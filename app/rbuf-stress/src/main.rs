@@ -0,0 +1,391 @@
+// main.rs
+//
+// Soak test for rbuf across processes: producers and a consumer run as
+// copies of this binary while the supervisor SIGKILLs one of them at random
+// every so often and starts a replacement. The consumer checks every item
+// as it arrives and exits non-zero on the first one that breaks sequence,
+// so a run that ends cleanly has shown that nothing committed was lost,
+// reordered or delivered twice (other than redelivery after the consumer
+// itself died holding an item) across every crash.
+//
+//     rbuf-stress [--duration SECS] [--producers N] [--kill-every MS]
+//                 [--capacity N] [--stall SECS] [--seed N]
+//
+// Each producer life pushes `(producer, life, 0)`, `(producer, life, 1)`, ...
+// A killed producer's replacement is its next life, starting over at 0; the
+// old life may end with an item it never committed, which recovery gives
+// up. The consumer pops with `begin_pop` and records where each producer is
+// in a shared arena before acking, so its replacement carries on checking
+// where it left off.
+//
+// A producer killed between claiming a slot and committing it stalls the
+// ring until a consumer attaches while no producer is alive, since only
+// then can recovery tell the slot will never be filled. When nothing moves
+// for `--stall` seconds the supervisor does what an operator would: it stops
+// every producer and brings a consumer back before them. A stall that
+// outlasts that fails the run.
+use rbuf::arena::ShmArena;
+use rbuf::{Consumer, Producer, RbufError, RingBuffer, ShmPtr, ShmSafe};
+use std::env;
+use std::process::{self, Child, Command};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MAX_PRODUCERS: usize = 16;
+// `Progress::expected` keeps the life above these bits and the sequence
+// number in them
+const SEQ_BITS: u32 = 40;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Item {
+    producer: u32,
+    life: u32,
+    seq: u64,
+}
+
+unsafe impl ShmSafe for Item {}
+
+// What the consumer has checked so far, kept where its replacement finds it
+#[repr(C)]
+struct Progress {
+    // Per producer, the life and sequence number of the next item due
+    expected: [AtomicU64; MAX_PRODUCERS],
+    // Ring position of the last item recorded, to tell an item redelivered
+    // after a crash from one delivered twice
+    last_pos: AtomicU64,
+    // Process ID of the last consumer to attach, set once its recovery ran
+    consumer: AtomicU64,
+    verified: AtomicU64,
+    redelivered: AtomicU64,
+}
+
+unsafe impl ShmSafe for Progress {}
+
+fn pack(life: u32, seq: u64) -> u64 {
+    (life as u64) << SEQ_BITS | seq
+}
+
+fn unpack(expected: u64) -> (u32, u64) {
+    ((expected >> SEQ_BITS) as u32, expected & ((1 << SEQ_BITS) - 1))
+}
+
+fn open_progress(arena: &ShmArena) -> &Progress {
+    let root: ShmPtr<Progress> = arena.root();
+    // Only ever accessed through its atomics
+    unsafe { root.as_ref(arena.segment()) }.expect("the arena has no progress record")
+}
+
+// --- Producer side ---
+
+fn produce(ring: &str, capacity: usize, producer: u32, life: u32) {
+    let mut handle = Producer::<Item>::open_or_create(ring, capacity).expect("failed to open ring");
+    // Killed processes never unlink it either; the supervisor does at the end
+    handle.set_unlink_on_drop(false);
+    let mut seq = 0;
+    loop {
+        match handle.push_blocking(Item { producer, life, seq }) {
+            Ok(()) => seq += 1,
+            // A replacement consumer is on its way
+            Err(e) if matches!(e.error(), RbufError::PeerDead { .. }) => {
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => panic!("push failed: {}", e.error()),
+        }
+    }
+}
+
+// --- Consumer side ---
+
+fn consume(ring: &str, capacity: usize) -> Result<(), String> {
+    let arena = ShmArena::open(&format!("{}.progress", ring)).map_err(|e| e.to_string())?;
+    let progress = open_progress(&arena);
+    let mut consumer = Consumer::<Item>::open_or_create(ring, capacity).map_err(|e| e.to_string())?;
+    consumer.set_unlink_on_drop(false);
+    let recovery = consumer.recovery();
+    if recovery.rolled_back + recovery.aborted > 0 {
+        eprintln!("consumer: recovered {:?}", recovery);
+    }
+    progress.consumer.store(process::id() as u64, Ordering::Relaxed);
+
+    loop {
+        let guard = match consumer.begin_pop() {
+            Ok(guard) => guard,
+            Err(RbufError::Empty) => {
+                thread::sleep(Duration::from_micros(100));
+                continue;
+            }
+            Err(e) => return Err(e.to_string()),
+        };
+        let item = *guard;
+        let expected = progress
+            .expected
+            .get(item.producer as usize)
+            .ok_or_else(|| format!("item from unknown producer {}", item.producer))?;
+        let (life, next) = unpack(expected.load(Ordering::Relaxed));
+
+        let in_sequence =
+            (item.life == life && item.seq == next) || (item.life > life && item.seq == 0);
+        if in_sequence {
+            // The position first: dying between the two leaves `expected`
+            // behind, and the redelivered item then simply matches it
+            progress.last_pos.store(guard.seq(), Ordering::Relaxed);
+            expected.store(pack(item.life, item.seq + 1), Ordering::Relaxed);
+            progress.verified.fetch_add(1, Ordering::Relaxed);
+        } else if guard.seq() == progress.last_pos.load(Ordering::Relaxed) {
+            // Recorded by the consumer before us, which died before acking
+            progress.redelivered.fetch_add(1, Ordering::Relaxed);
+        } else {
+            return Err(format!(
+                "producer {} sent life {} item {} where life {} item {} was due",
+                item.producer, item.life, item.seq, life, next
+            ));
+        }
+        guard.ack();
+    }
+}
+
+// --- Supervisor ---
+
+struct Options {
+    duration: Duration,
+    producers: usize,
+    kill_every: Duration,
+    capacity: usize,
+    stall: Duration,
+    seed: u64,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options {
+            duration: Duration::from_secs(60),
+            producers: 3,
+            kill_every: Duration::from_millis(200),
+            capacity: 1024,
+            stall: Duration::from_secs(10),
+            seed: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+                as u64,
+        };
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| format!("{} needs a value", flag))?;
+            let number: u64 = value.parse().map_err(|_| format!("bad value for {}", flag))?;
+            match flag.as_str() {
+                "--duration" => options.duration = Duration::from_secs(number),
+                "--producers" => options.producers = number as usize,
+                "--kill-every" => options.kill_every = Duration::from_millis(number),
+                "--capacity" => options.capacity = number as usize,
+                "--stall" => options.stall = Duration::from_secs(number),
+                "--seed" => options.seed = number,
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        if options.producers == 0 || options.producers > MAX_PRODUCERS {
+            return Err(format!("--producers must be 1 to {}", MAX_PRODUCERS));
+        }
+        Ok(options)
+    }
+}
+
+// xorshift64*, so runs repeat from their seed without another dependency
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as usize % n
+    }
+}
+
+struct Supervisor {
+    ring: String,
+    options: Options,
+    // The consumer, then one per producer
+    children: Vec<Child>,
+    lives: Vec<u32>,
+    kills: u64,
+    stalls: u64,
+}
+
+impl Supervisor {
+    fn spawn(&self, index: usize) -> Result<Child, String> {
+        let exe = env::current_exe().map_err(|e| e.to_string())?;
+        let capacity = self.options.capacity.to_string();
+        let mut command = Command::new(exe);
+        if index == 0 {
+            command.args(["consumer", &self.ring, &capacity]);
+        } else {
+            let producer = (index - 1).to_string();
+            let life = self.lives[index - 1].to_string();
+            command.args(["producer", &self.ring, &capacity, &producer, &life]);
+        }
+        command.spawn().map_err(|e| e.to_string())
+    }
+
+    // SIGKILL the child, or TerminateProcess it
+    fn kill(&mut self, index: usize) -> Result<(), String> {
+        let child = &mut self.children[index];
+        child.kill().map_err(|e| e.to_string())?;
+        child.wait().map_err(|e| e.to_string())?;
+        if index > 0 {
+            self.lives[index - 1] += 1;
+        }
+        self.kills += 1;
+        Ok(())
+    }
+
+    fn restart(&mut self, index: usize) -> Result<(), String> {
+        self.kill(index)?;
+        self.children[index] = self.spawn(index)?;
+        Ok(())
+    }
+
+    // Stop every producer and start a consumer, which recovers whatever
+    // they left claimed, before starting them again
+    fn recover(&mut self, progress: &Progress) -> Result<(), String> {
+        for index in 1..self.children.len() {
+            self.kill(index)?;
+        }
+        self.restart(0)?;
+        let pid = self.children[0].id() as u64;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while progress.consumer.load(Ordering::Relaxed) != pid {
+            if Instant::now() >= deadline {
+                return Err("the replacement consumer never attached".to_string());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        for index in 1..self.children.len() {
+            self.children[index] = self.spawn(index)?;
+        }
+        self.stalls += 1;
+        Ok(())
+    }
+
+    // A child that exits by itself has failed; the consumer says why
+    fn check_children(&mut self) -> Result<(), String> {
+        for (index, child) in self.children.iter_mut().enumerate() {
+            if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+                let role = if index == 0 { "consumer" } else { "producer" };
+                return Err(format!("{} exited with {}", role, status));
+            }
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, progress: &Progress) -> Result<(), String> {
+        let mut rng = Rng(self.options.seed | 1);
+        let children = (0..=self.options.producers).map(|i| self.spawn(i));
+        self.children = children.collect::<Result<_, _>>()?;
+
+        let start = Instant::now();
+        let mut next_kill = start + self.options.kill_every;
+        let mut next_report = start + Duration::from_secs(10);
+        let (mut last_verified, mut last_progress) = (0, start);
+        let mut recovered = false;
+        while start.elapsed() < self.options.duration {
+            thread::sleep(Duration::from_millis(10));
+            self.check_children()?;
+            let now = Instant::now();
+
+            if now >= next_kill {
+                let victim = rng.below(self.children.len());
+                self.restart(victim)?;
+                next_kill = now + self.options.kill_every;
+            }
+
+            let verified = progress.verified.load(Ordering::Relaxed);
+            if verified != last_verified {
+                (last_verified, last_progress) = (verified, now);
+                recovered = false;
+            } else if now - last_progress >= self.options.stall {
+                // If recovery doesn't get things moving, nothing will
+                if recovered {
+                    let info = RingBuffer::inspect(&self.ring).map_err(|e| e.to_string())?;
+                    return Err(format!("no progress for {:?}: {:#?}", self.options.stall, info));
+                }
+                self.recover(progress)?;
+                recovered = true;
+                last_progress = Instant::now();
+            }
+
+            if now >= next_report {
+                self.report(start, progress);
+                next_report = now + Duration::from_secs(10);
+            }
+        }
+        self.report(start, progress);
+        Ok(())
+    }
+
+    fn report(&self, start: Instant, progress: &Progress) {
+        println!(
+            "{:>6.0?}: {} items verified, {} redelivered, {} kills, {} stalls recovered",
+            start.elapsed(),
+            progress.verified.load(Ordering::Relaxed),
+            progress.redelivered.load(Ordering::Relaxed),
+            self.kills,
+            self.stalls
+        );
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        // Nobody unlinked the ring on the way out; take it with us
+        if let Ok(mut consumer) = Consumer::<Item>::open(&self.ring) {
+            consumer.set_unlink_on_drop(true);
+        }
+    }
+}
+
+fn supervise(options: Options) -> Result<(), String> {
+    let ring = format!("rbuf_stress_{}", process::id());
+    println!(
+        "{}: {} producers, a kill every {:?} for {:?}, seed {}",
+        ring, options.producers, options.kill_every, options.duration, options.seed
+    );
+
+    let arena = ShmArena::create(&format!("{}.progress", ring), 4096).map_err(|e| e.to_string())?;
+    let root = arena
+        .alloc(Progress {
+            expected: Default::default(),
+            last_pos: AtomicU64::new(u64::MAX),
+            consumer: AtomicU64::new(0),
+            verified: AtomicU64::new(0),
+            redelivered: AtomicU64::new(0),
+        })
+        .map_err(|e| e.to_string())?;
+    arena.set_root(root);
+
+    let mut supervisor =
+        Supervisor { ring, options, children: Vec::new(), lives: Vec::new(), kills: 0, stalls: 0 };
+    supervisor.lives = vec![0; supervisor.options.producers];
+    supervisor.run(open_progress(&arena))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let arg = |i: usize| args.get(i).map(String::as_str).unwrap_or_default();
+    let number = |i: usize| arg(i).parse().expect("bad argument from the supervisor");
+    let result = match arg(1) {
+        "producer" => {
+            produce(arg(2), number(3), number(4) as u32, number(5) as u32);
+            Ok(())
+        }
+        "consumer" => consume(arg(2), number(3)),
+        _ => Options::parse(&args[1..]).and_then(supervise),
+    };
+    if let Err(e) = result {
+        eprintln!("rbuf-stress: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,267 @@
+// lib.rs
+//
+// The index and slot management of a single-producer, single-consumer ring
+// of fixed-size slots, over any region of memory both sides can see: a
+// shared memory segment between two processes, or DDR shared between a
+// Linux core and a bare-metal one. Nothing here allocates or needs `std`;
+// `rbuf::region` puts this same ring in a segment.
+//
+// `head` and `tail` are free-running 32-bit counters, since not every core
+// has 64-bit atomics: `tail` counts every slot ever pushed and `head` every
+// slot ever popped, so the ring holds `tail - head` items, across a wrap. The
+// slot for position `pos` is `pos & mask`. The producer only ever writes
+// `tail` and the consumer only ever writes `head`, so neither needs a CAS.
+//
+// Both sides must see the region coherently: on a core whose caches don't
+// snoop the other's, map the region uncached.
+//
+// [ control | slots ]
+#![cfg_attr(not(loom), no_std)]
+
+mod sync;
+
+use core::fmt;
+use core::mem;
+use core::ptr;
+
+use sync::{AtomicU32, Ordering, SlotCells};
+
+// "RBCR" as a little-endian integer, written last by `init`
+const MAGIC: u32 = u32::from_le_bytes(*b"RBCR");
+// Bump whenever the layout of the region changes
+const VERSION: u32 = 1;
+
+// Largest capacity the 32-bit counters can tell apart from an empty ring
+#[cfg(not(loom))]
+pub const MAX_CAPACITY: usize = 1 << 31;
+// Loom models keep to a few slots
+#[cfg(loom)]
+pub const MAX_CAPACITY: usize = sync::LOOM_MAX_SLOTS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    // The region can't hold the ring; it needs `needed` bytes
+    TooSmall { needed: usize },
+    // The region doesn't start on a 64-byte boundary
+    Misaligned,
+    // Nobody has initialized a ring in the region yet
+    NotInitialized,
+    // The region holds a ring laid out by another version of this crate
+    VersionMismatch { found: u32 },
+    // The capacity isn't a power of two up to `MAX_CAPACITY`, or the slots
+    // are empty
+    InvalidLayout,
+    // An item isn't the ring's `elem_size` bytes
+    WrongSize { size: usize, expected: usize },
+    // The ring has no free slot
+    Full,
+    // The ring has nothing to pop
+    Empty,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TooSmall { needed } => write!(f, "region is too small, {} bytes needed", needed),
+            Error::Misaligned => write!(f, "region isn't aligned to 64 bytes"),
+            Error::NotInitialized => write!(f, "region holds no ring"),
+            Error::VersionMismatch { found } => {
+                write!(f, "region holds a version {} ring, not version {}", found, VERSION)
+            }
+            Error::InvalidLayout => write!(f, "capacity isn't a power of two or slots are empty"),
+            Error::WrongSize { size, expected } => {
+                write!(f, "item is {} bytes, slots are {}", size, expected)
+            }
+            Error::Full => write!(f, "ring buffer is full"),
+            Error::Empty => write!(f, "ring buffer is empty"),
+        }
+    }
+}
+
+// Gives a counter a cache line to itself, so that the producer writing
+// `tail` doesn't keep invalidating the consumer's `head` and the other way
+// round
+#[repr(C, align(64))]
+struct Padded(AtomicU32);
+
+#[repr(C)]
+struct Control {
+    magic: AtomicU32,
+    version: u32,
+    elem_size: u32,
+    capacity: u32,
+    head: Padded,
+    tail: Padded,
+    // Nothing outside loom models
+    cells: SlotCells,
+}
+
+// Bytes a region needs for `capacity` slots of `elem_size` bytes, or
+// `usize::MAX` if no region could be that large
+pub const fn region_size(elem_size: usize, capacity: usize) -> usize {
+    match elem_size.checked_mul(capacity) {
+        Some(slots) => slots.saturating_add(mem::size_of::<Control>()),
+        None => usize::MAX,
+    }
+}
+
+// Where the region's alignment has to be a multiple of
+pub const REGION_ALIGN: usize = mem::align_of::<Control>();
+
+// One side's view of a ring in a region. Either side can push and pop, but
+// only one view may push and only one may pop at a time.
+pub struct RegionRing<'a> {
+    control: &'a Control,
+    slots: *mut u8,
+    elem_size: usize,
+    mask: u32,
+}
+
+unsafe impl Send for RegionRing<'_> {}
+
+impl<'a> RegionRing<'a> {
+    // Lay out an empty ring in `region`, for the other side to `attach` to.
+    // Whatever the region held before is lost.
+    pub fn init(region: &'a mut [u8], elem_size: usize, capacity: usize) -> Result<Self, Error> {
+        if elem_size == 0
+            || elem_size > u32::MAX as usize
+            || !capacity.is_power_of_two()
+            || capacity > MAX_CAPACITY
+        {
+            return Err(Error::InvalidLayout);
+        }
+        check_region(region, region_size(elem_size, capacity))?;
+
+        let control = region.as_mut_ptr() as *mut Control;
+        unsafe {
+            // Field by field rather than through a `&mut Control`: the other
+            // side may already be polling `magic`
+            sync::init(ptr::addr_of_mut!((*control).magic), 0);
+            ptr::addr_of_mut!((*control).version).write(VERSION);
+            ptr::addr_of_mut!((*control).elem_size).write(elem_size as u32);
+            ptr::addr_of_mut!((*control).capacity).write(capacity as u32);
+            sync::init(ptr::addr_of_mut!((*control).head.0), 0);
+            sync::init(ptr::addr_of_mut!((*control).tail.0), 0);
+            ptr::addr_of_mut!((*control).cells).write(SlotCells::new());
+            (*control).magic.store(MAGIC, Ordering::Release);
+        }
+        Self::attach(region)
+    }
+
+    // View the ring that the other side laid out in `region`
+    pub fn attach(region: &'a mut [u8]) -> Result<Self, Error> {
+        check_region(region, mem::size_of::<Control>())?;
+        let base = region.as_mut_ptr();
+        let control = unsafe { &*(base as *const Control) };
+        if control.magic.load(Ordering::Acquire) != MAGIC {
+            return Err(Error::NotInitialized);
+        }
+        if control.version != VERSION {
+            return Err(Error::VersionMismatch { found: control.version });
+        }
+        let elem_size = control.elem_size as usize;
+        let capacity = control.capacity as usize;
+        if elem_size == 0 || !capacity.is_power_of_two() || capacity > MAX_CAPACITY {
+            return Err(Error::InvalidLayout);
+        }
+        check_region(region, region_size(elem_size, capacity))?;
+
+        let slots = unsafe { base.add(mem::size_of::<Control>()) };
+        Ok(Self { control, slots, elem_size, mask: capacity as u32 - 1 })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.mask as usize + 1
+    }
+
+    pub fn elem_size(&self) -> usize {
+        self.elem_size
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.control.head.0.load(Ordering::Acquire);
+        let tail = self.control.tail.0.load(Ordering::Acquire);
+        // The two loads aren't one snapshot; keep the answer in range
+        (tail.wrapping_sub(head) as usize).min(self.capacity())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Position of the next item to pop, counting every item ever popped
+    pub fn head(&self) -> u32 {
+        self.control.head.0.load(Ordering::Acquire)
+    }
+
+    // Position of the next item to push, counting every item ever pushed
+    pub fn tail(&self) -> u32 {
+        self.control.tail.0.load(Ordering::Acquire)
+    }
+
+    fn slot(&self, pos: u32) -> *mut u8 {
+        unsafe { self.slots.add(self.index(pos) * self.elem_size) }
+    }
+
+    fn index(&self, pos: u32) -> usize {
+        (pos & self.mask) as usize
+    }
+
+    // Copy `item` into the next free slot
+    pub fn push(&mut self, item: &[u8]) -> Result<(), Error> {
+        if item.len() != self.elem_size {
+            return Err(Error::WrongSize { size: item.len(), expected: self.elem_size });
+        }
+        unsafe { self.push_raw(item.as_ptr()) }
+    }
+
+    // Copy the oldest item into `out` and free its slot
+    pub fn pop(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        if out.len() != self.elem_size {
+            return Err(Error::WrongSize { size: out.len(), expected: self.elem_size });
+        }
+        unsafe { self.pop_raw(out.as_mut_ptr()) }
+    }
+
+    /// # Safety
+    ///
+    /// `item` must be valid for reading `elem_size` bytes. They may include
+    /// uninitialized padding, e.g. when `item` points at a struct.
+    pub unsafe fn push_raw(&mut self, item: *const u8) -> Result<(), Error> {
+        // Nobody else moves `tail`
+        let tail = self.control.tail.0.load(Ordering::Relaxed);
+        let head = self.control.head.0.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) as usize >= self.capacity() {
+            return Err(Error::Full);
+        }
+        ptr::copy_nonoverlapping(item, self.slot(tail), self.elem_size);
+        self.control.cells.written(self.index(tail));
+        self.control.tail.0.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// `out` must be valid for writing `elem_size` bytes
+    pub unsafe fn pop_raw(&mut self, out: *mut u8) -> Result<(), Error> {
+        // Nobody else moves `head`
+        let head = self.control.head.0.load(Ordering::Relaxed);
+        if self.control.tail.0.load(Ordering::Acquire) == head {
+            return Err(Error::Empty);
+        }
+        self.control.cells.read(self.index(head));
+        ptr::copy_nonoverlapping(self.slot(head), out, self.elem_size);
+        self.control.head.0.store(head.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+fn check_region(region: &[u8], needed: usize) -> Result<(), Error> {
+    if !(region.as_ptr() as usize).is_multiple_of(REGION_ALIGN) {
+        return Err(Error::Misaligned);
+    }
+    if region.len() < needed {
+        return Err(Error::TooSmall { needed });
+    }
+    Ok(())
+}
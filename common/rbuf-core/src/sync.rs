@@ -0,0 +1,56 @@
+// sync.rs
+//
+// What the ring synchronizes with: core's atomics, or loom's under
+// `--cfg loom`, so that tests/loom.rs runs the real push and pop through
+// every interleaving loom can find.
+#[cfg(not(loom))]
+pub(crate) use core::sync::atomic::{AtomicU32, Ordering};
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicU32, Ordering};
+
+// Largest ring loom models are run with; each slot gets a cell
+#[cfg(loom)]
+pub(crate) const LOOM_MAX_SLOTS: usize = 4;
+
+// Set an atomic in memory that may not hold an initialized one yet. Loom's
+// atomics carry state of their own, so they have to be constructed there.
+pub(crate) unsafe fn init(atomic: *mut AtomicU32, value: u32) {
+    #[cfg(not(loom))]
+    (*atomic).store(value, Ordering::Relaxed);
+    #[cfg(loom)]
+    atomic.write(AtomicU32::new(value));
+}
+
+// Loom can't see the plain copies in and out of the slots, so under loom
+// every slot also has a cell that is written by the push and read by the
+// pop. A push and a pop of the same slot that aren't ordered by `tail` and
+// `head` then show up as a race. Without loom these are empty.
+#[cfg(loom)]
+pub(crate) struct SlotCells([loom::cell::UnsafeCell<()>; LOOM_MAX_SLOTS]);
+
+#[cfg(not(loom))]
+pub(crate) struct SlotCells;
+
+impl SlotCells {
+    #[cfg(loom)]
+    pub(crate) fn new() -> Self {
+        Self(core::array::from_fn(|_| loom::cell::UnsafeCell::new(())))
+    }
+
+    #[cfg(not(loom))]
+    pub(crate) fn new() -> Self {
+        Self
+    }
+
+    #[inline]
+    pub(crate) fn written(&self, _slot: usize) {
+        #[cfg(loom)]
+        self.0[_slot].with_mut(|_| ());
+    }
+
+    #[inline]
+    pub(crate) fn read(&self, _slot: usize) {
+        #[cfg(loom)]
+        self.0[_slot].with(|_| ());
+    }
+}
@@ -0,0 +1,131 @@
+// loom.rs
+//
+// The ring's push and pop under loom, which runs each model through the
+// interleavings and memory orderings its threads can produce and fails on
+// any that lose an item, deliver one twice or read a slot before the push
+// that filled it is visible. Only built with `--cfg loom`; see Cargo.toml.
+#![cfg(loom)]
+
+use loom::model::Builder;
+use loom::thread;
+use rbuf_core::{region_size, Error, RegionRing};
+
+const ELEM_SIZE: usize = 4;
+
+// One cache line, so that a `Vec` of them is aligned like a segment
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct Line([u8; 64]);
+
+// The memory both sides map, like a segment does
+struct Region {
+    base: *mut u8,
+    len: usize,
+    lines: usize,
+}
+
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
+impl Region {
+    fn new(capacity: usize) -> Self {
+        let len = region_size(ELEM_SIZE, capacity);
+        let lines = len.div_ceil(64);
+        let base = Box::into_raw(vec![Line([0; 64]); lines].into_boxed_slice()) as *mut u8;
+        Self { base, len, lines }
+    }
+
+    // One side's view. Each side borrows the memory on its own, the way two
+    // processes each map a segment.
+    #[allow(clippy::mut_from_ref)]
+    fn view(&self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.base, self.len) }
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        let lines = std::ptr::slice_from_raw_parts_mut(self.base as *mut Line, self.lines);
+        drop(unsafe { Box::from_raw(lines) });
+    }
+}
+
+fn model(f: impl Fn() + Sync + Send + 'static) {
+    let mut builder = Builder::new();
+    // Enough to reorder every step of a push against every step of a pop
+    builder.preemption_bound = Some(3);
+    builder.check(f);
+}
+
+fn push(ring: &mut RegionRing<'_>, value: u32) {
+    loop {
+        match ring.push(&value.to_le_bytes()) {
+            Ok(()) => return,
+            Err(Error::Full) => thread::yield_now(),
+            Err(e) => panic!("push failed: {}", e),
+        }
+    }
+}
+
+fn pop(ring: &mut RegionRing<'_>) -> u32 {
+    let mut item = [0; ELEM_SIZE];
+    loop {
+        match ring.pop(&mut item) {
+            Ok(()) => return u32::from_le_bytes(item),
+            Err(Error::Empty) => thread::yield_now(),
+            Err(e) => panic!("pop failed: {}", e),
+        }
+    }
+}
+
+#[test]
+fn items_arrive_once_and_in_order() {
+    model(|| {
+        let region = loom::sync::Arc::new(Region::new(2));
+        RegionRing::init(region.view(), ELEM_SIZE, 2).unwrap();
+
+        let producer = {
+            let region = region.clone();
+            thread::spawn(move || {
+                let mut ring = RegionRing::attach(region.view()).unwrap();
+                // One more than fits, so the producer waits for a free slot
+                for value in 1..=3 {
+                    push(&mut ring, value);
+                }
+            })
+        };
+
+        let mut ring = RegionRing::attach(region.view()).unwrap();
+        for expected in 1..=3 {
+            assert_eq!(pop(&mut ring), expected);
+        }
+        producer.join().unwrap();
+        assert!(ring.is_empty());
+    });
+}
+
+#[test]
+fn full_ring_rejects_until_popped() {
+    model(|| {
+        let region = loom::sync::Arc::new(Region::new(1));
+        let mut ring = RegionRing::init(region.view(), ELEM_SIZE, 1).unwrap();
+        push(&mut ring, 7);
+
+        let consumer = {
+            let region = region.clone();
+            thread::spawn(move || {
+                let mut ring = RegionRing::attach(region.view()).unwrap();
+                assert_eq!(pop(&mut ring), 7);
+            })
+        };
+
+        // Either the pop has freed the slot or the ring is still full
+        match ring.push(&8u32.to_le_bytes()) {
+            Ok(()) => {}
+            Err(Error::Full) => push(&mut ring, 8),
+            Err(e) => panic!("push failed: {}", e),
+        }
+        consumer.join().unwrap();
+        assert!(ring.len() <= 1);
+    });
+}
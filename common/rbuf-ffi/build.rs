@@ -0,0 +1,18 @@
+// build.rs
+//
+// Regenerate include/rbuf.h from the exported functions and constants, so
+// the header C code includes can't drift from the library it links.
+use std::env;
+use std::path::PathBuf;
+
+fn main() {
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml"))
+        .expect("cbindgen.toml is malformed");
+    cbindgen::generate_with_config(&crate_dir, config)
+        .expect("failed to generate the C header")
+        .write_to_file(crate_dir.join("include/rbuf.h"));
+
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+}
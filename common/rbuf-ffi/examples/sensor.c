@@ -0,0 +1,61 @@
+/*
+ * sensor.c
+ *
+ * A consumer and a producer in one process, through the C API: the shape a
+ * C or C++ driver publishing into a ring takes. Build the library, then
+ *
+ *     cc -I include examples/sensor.c ../../target/debug/librbuf_ffi.a \
+ *         -lpthread -ldl -lm -o sensor && ./sensor
+ */
+#include <stdio.h>
+#include <string.h>
+#include <unistd.h>
+
+#include "rbuf.h"
+
+static int fail(const char *what) {
+    char reason[256];
+    rbuf_last_error(reason, sizeof reason);
+    fprintf(stderr, "%s: %s\n", what, reason);
+    return 1;
+}
+
+int main(void) {
+    char name[64];
+    snprintf(name, sizeof name, "rbuf_sensor_%d", (int)getpid());
+
+    rbuf_consumer_t *consumer = rbuf_create(name, 1 << 16);
+    if (!consumer) {
+        return fail("rbuf_create");
+    }
+    rbuf_producer_t *producer = rbuf_open_producer(name);
+    if (!producer) {
+        return fail("rbuf_open_producer");
+    }
+
+    for (int i = 0; i < 10; i++) {
+        char frame[32];
+        int len = snprintf(frame, sizeof frame, "reading %d", i);
+        if (rbuf_push_bytes(producer, (const uint8_t *)frame, (size_t)len) != RBUF_OK) {
+            return fail("rbuf_push_bytes");
+        }
+    }
+
+    /* Too small on purpose: the message waits for a bigger buffer */
+    uint8_t small[4];
+    size_t len = 0;
+    if (rbuf_pop_bytes(consumer, small, sizeof small, &len) != RBUF_BUFFER_TOO_SMALL) {
+        return fail("rbuf_pop_bytes");
+    }
+
+    uint8_t buf[64];
+    int received = 0;
+    while (rbuf_pop_bytes(consumer, buf, sizeof buf, &len) == RBUF_OK) {
+        printf("%.*s\n", (int)len, (const char *)buf);
+        received++;
+    }
+
+    rbuf_producer_free(producer);
+    rbuf_consumer_free(consumer);
+    return received == 10 ? 0 : 1;
+}
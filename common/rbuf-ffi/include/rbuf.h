@@ -0,0 +1,198 @@
+/*
+ * rbuf.h
+ *
+ * Byte rings in shared memory. The consumer creates a ring and any number
+ * of producers, in any number of processes, open it by name and push
+ * messages of any length into it:
+ *
+ *     rbuf_producer_t *producer = rbuf_open_producer("sensors");
+ *     if (!producer) {
+ *         char reason[256];
+ *         rbuf_last_error(reason, sizeof reason);
+ *         ...
+ *     }
+ *     if (rbuf_push_bytes(producer, frame, frame_len) == RBUF_FULL) {
+ *         ...
+ *     }
+ *     rbuf_producer_free(producer);
+ *
+ * Every function is safe to call from any thread, but a handle must only be
+ * used by one thread at a time.
+ */
+
+#ifndef RBUF_H
+#define RBUF_H
+
+/* Generated by cbindgen from rbuf-ffi/src/lib.rs; don't edit it by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ Success
+ */
+#define RBUF_OK 0
+
+/*
+ The ring has no room for the message right now
+ */
+#define RBUF_FULL 1
+
+/*
+ The ring has no message to pop right now
+ */
+#define RBUF_EMPTY 2
+
+/*
+ The message can never fit in the ring; see `rbuf_max_message_size`
+ */
+#define RBUF_MESSAGE_TOO_LARGE 3
+
+/*
+ The next message is bigger than the buffer given to pop it into. It
+ stays queued; `*len` says how big a buffer it needs.
+ */
+#define RBUF_BUFFER_TOO_SMALL 4
+
+/*
+ The process on the other side exited without detaching
+ */
+#define RBUF_PEER_DEAD 5
+
+/*
+ The other side is done with the ring
+ */
+#define RBUF_DISCONNECTED 6
+
+/*
+ A pointer was NULL or a name wasn't UTF-8
+ */
+#define RBUF_INVALID_ARGUMENT 7
+
+/*
+ Anything else; `rbuf_last_error` says what
+ */
+#define RBUF_ERROR 8
+
+typedef struct rbuf_consumer_t rbuf_consumer_t;
+
+typedef struct rbuf_producer_t rbuf_producer_t;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/*
+ Open the ring a consumer created under `name`, to push into it. Returns
+ NULL on failure; `rbuf_last_error` says why.
+
+ # Safety
+
+ `name` must be NULL or a NUL-terminated string.
+ */
+struct rbuf_producer_t *rbuf_open_producer(const char *name);
+
+/*
+ Push the `len` bytes at `data` as one message. Fails with `RBUF_FULL`
+ straight away if there's no room for it.
+
+ # Safety
+
+ `producer` must be NULL or from `rbuf_open_producer` and not yet freed,
+ and `data` must be valid for reading `len` bytes.
+ */
+int rbuf_push_bytes(struct rbuf_producer_t *producer, const uint8_t *data, size_t len);
+
+/*
+ `rbuf_push_bytes`, sleeping until the consumer makes room if the ring is
+ full
+
+ # Safety
+
+ As for `rbuf_push_bytes`.
+ */
+int rbuf_push_bytes_blocking(struct rbuf_producer_t *producer, const uint8_t *data, size_t len);
+
+/*
+ The largest message the ring takes, or 0 if `producer` is NULL
+
+ # Safety
+
+ `producer` must be NULL or from `rbuf_open_producer` and not yet freed.
+ */
+size_t rbuf_max_message_size(const struct rbuf_producer_t *producer);
+
+/*
+ Detach from the ring and free the handle. NULL is ignored.
+
+ # Safety
+
+ `producer` must be NULL or from `rbuf_open_producer` and not yet freed.
+ */
+void rbuf_producer_free(struct rbuf_producer_t *producer);
+
+/*
+ Create a ring under `name` with room for `capacity` bytes of messages,
+ rounded up to a power of two, and attach to it as its consumer. Returns
+ NULL on failure; `rbuf_last_error` says why.
+
+ # Safety
+
+ `name` must be NULL or a NUL-terminated string.
+ */
+struct rbuf_consumer_t *rbuf_create(const char *name, size_t capacity);
+
+/*
+ Copy the next message into the `cap` bytes at `buf` and its length into
+ `*len`. Fails with `RBUF_EMPTY` straight away if there's none, and with
+ `RBUF_BUFFER_TOO_SMALL` if it doesn't fit, leaving it to the next pop.
+
+ # Safety
+
+ `consumer` must be NULL or from `rbuf_create` and not yet freed, `buf`
+ must be valid for writing `cap` bytes and `len` must be NULL or valid
+ for writing.
+ */
+int rbuf_pop_bytes(struct rbuf_consumer_t *consumer, uint8_t *buf, size_t cap, size_t *len);
+
+/*
+ `rbuf_pop_bytes`, sleeping until a producer publishes if the ring is
+ empty. Messages that fail to decode are skipped.
+
+ # Safety
+
+ As for `rbuf_pop_bytes`.
+ */
+int rbuf_pop_bytes_blocking(struct rbuf_consumer_t *consumer,
+                            uint8_t *buf,
+                            size_t cap,
+                            size_t *len);
+
+/*
+ Detach from the ring, unlink it and free the handle. NULL is ignored.
+
+ # Safety
+
+ `consumer` must be NULL or from `rbuf_create` and not yet freed.
+ */
+void rbuf_consumer_free(struct rbuf_consumer_t *consumer);
+
+/*
+ Copy why the last call on this thread failed into the `cap` bytes at
+ `buf`, truncated and NUL-terminated. Returns the length of the whole
+ reason, not counting the NUL, like `snprintf`.
+
+ # Safety
+
+ `buf` must be NULL or valid for writing `cap` bytes.
+ */
+size_t rbuf_last_error(char *buf, size_t cap);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* RBUF_H */
@@ -0,0 +1,346 @@
+// lib.rs
+//
+// A C API over the byte ring (`rbuf::bytes`), so producers written in C or
+// C++ can publish into the same rings as Rust ones. Handles are opaque
+// pointers; every call returns one of the `RBUF_*` statuses and leaves the
+// reason for an `RBUF_ERROR` in a per-thread buffer for `rbuf_last_error`.
+// Doc comments here end up in include/rbuf.h, which build.rs regenerates
+// with cbindgen.
+//
+// No panic unwinds into C: one is caught and reported as `RBUF_ERROR`.
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use rbuf::bytes::{Reader, Writer};
+use rbuf::RbufError;
+
+// --- Statuses ---
+
+/// Success
+pub const RBUF_OK: c_int = 0;
+/// The ring has no room for the message right now
+pub const RBUF_FULL: c_int = 1;
+/// The ring has no message to pop right now
+pub const RBUF_EMPTY: c_int = 2;
+/// The message can never fit in the ring; see `rbuf_max_message_size`
+pub const RBUF_MESSAGE_TOO_LARGE: c_int = 3;
+/// The next message is bigger than the buffer given to pop it into. It
+/// stays queued; `*len` says how big a buffer it needs.
+pub const RBUF_BUFFER_TOO_SMALL: c_int = 4;
+/// The process on the other side exited without detaching
+pub const RBUF_PEER_DEAD: c_int = 5;
+/// The other side is done with the ring
+pub const RBUF_DISCONNECTED: c_int = 6;
+/// A pointer was NULL or a name wasn't UTF-8
+pub const RBUF_INVALID_ARGUMENT: c_int = 7;
+/// Anything else; `rbuf_last_error` says what
+pub const RBUF_ERROR: c_int = 8;
+
+thread_local! {
+    static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+fn set_last_error(reason: impl ToString) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = reason.to_string());
+}
+
+fn status(e: RbufError) -> c_int {
+    let status = match e {
+        RbufError::Full => RBUF_FULL,
+        RbufError::Empty => RBUF_EMPTY,
+        RbufError::MessageTooLarge { .. } => RBUF_MESSAGE_TOO_LARGE,
+        RbufError::PeerDead { .. } => RBUF_PEER_DEAD,
+        RbufError::Disconnected => RBUF_DISCONNECTED,
+        _ => RBUF_ERROR,
+    };
+    set_last_error(e);
+    status
+}
+
+// Run `f`, turning a panic into `failed`
+fn guard<R>(failed: R, f: impl FnOnce() -> R) -> R {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let reason = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "panicked".to_string());
+        set_last_error(format!("rbuf panicked: {}", reason));
+        failed
+    })
+}
+
+unsafe fn name<'a>(name: *const c_char) -> Result<&'a str, c_int> {
+    if name.is_null() {
+        set_last_error("name is NULL");
+        return Err(RBUF_INVALID_ARGUMENT);
+    }
+    CStr::from_ptr(name).to_str().map_err(|_| {
+        set_last_error("name isn't UTF-8");
+        RBUF_INVALID_ARGUMENT
+    })
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Result<&'a [u8], c_int> {
+    match (data.is_null(), len) {
+        (_, 0) => Ok(&[]),
+        (true, _) => {
+            set_last_error("data is NULL");
+            Err(RBUF_INVALID_ARGUMENT)
+        }
+        (false, _) => Ok(slice::from_raw_parts(data, len)),
+    }
+}
+
+unsafe fn handle<'a, H>(handle: *mut H) -> Result<&'a mut H, c_int> {
+    handle.as_mut().ok_or_else(|| {
+        set_last_error("handle is NULL");
+        RBUF_INVALID_ARGUMENT
+    })
+}
+
+// --- Producer ---
+
+pub struct Producer {
+    writer: Writer,
+}
+
+/// Open the ring a consumer created under `name`, to push into it. Returns
+/// NULL on failure; `rbuf_last_error` says why.
+///
+/// # Safety
+///
+/// `name` must be NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rbuf_open_producer(name: *const c_char) -> *mut Producer {
+    guard(ptr::null_mut(), || {
+        let Ok(name) = self::name(name) else {
+            return ptr::null_mut();
+        };
+        match Writer::open(name) {
+            Ok(writer) => Box::into_raw(Box::new(Producer { writer })),
+            Err(e) => {
+                status(e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Push the `len` bytes at `data` as one message. Fails with `RBUF_FULL`
+/// straight away if there's no room for it.
+///
+/// # Safety
+///
+/// `producer` must be NULL or from `rbuf_open_producer` and not yet freed,
+/// and `data` must be valid for reading `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rbuf_push_bytes(
+    producer: *mut Producer,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    guard(RBUF_ERROR, || {
+        let push = || -> Result<(), c_int> {
+            let data = bytes(data, len)?;
+            handle(producer)?.writer.push_bytes(data).map_err(status)
+        };
+        push().err().unwrap_or(RBUF_OK)
+    })
+}
+
+/// `rbuf_push_bytes`, sleeping until the consumer makes room if the ring is
+/// full
+///
+/// # Safety
+///
+/// As for `rbuf_push_bytes`.
+#[no_mangle]
+pub unsafe extern "C" fn rbuf_push_bytes_blocking(
+    producer: *mut Producer,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    guard(RBUF_ERROR, || {
+        let push = || -> Result<(), c_int> {
+            let data = bytes(data, len)?;
+            handle(producer)?.writer.push_bytes_blocking(data).map_err(status)
+        };
+        push().err().unwrap_or(RBUF_OK)
+    })
+}
+
+/// The largest message the ring takes, or 0 if `producer` is NULL
+///
+/// # Safety
+///
+/// `producer` must be NULL or from `rbuf_open_producer` and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn rbuf_max_message_size(producer: *const Producer) -> usize {
+    producer.as_ref().map_or(0, |producer| producer.writer.max_message_size())
+}
+
+/// Detach from the ring and free the handle. NULL is ignored.
+///
+/// # Safety
+///
+/// `producer` must be NULL or from `rbuf_open_producer` and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn rbuf_producer_free(producer: *mut Producer) {
+    if !producer.is_null() {
+        guard((), || drop(Box::from_raw(producer)));
+    }
+}
+
+// --- Consumer ---
+
+pub struct Consumer {
+    reader: Reader,
+    // A message popped from the ring that didn't fit the caller's buffer,
+    // handed out by the next pop
+    pending: Option<Vec<u8>>,
+}
+
+impl Consumer {
+    // Copy the pending message into `buf`, or say how big `buf` must be
+    unsafe fn deliver(&mut self, buf: *mut u8, cap: usize, len: *mut usize) -> c_int {
+        let Some(message) = self.pending.take() else {
+            return RBUF_EMPTY;
+        };
+        if !len.is_null() {
+            *len = message.len();
+        }
+        if message.len() > cap {
+            self.pending = Some(message);
+            return RBUF_BUFFER_TOO_SMALL;
+        }
+        if !message.is_empty() {
+            if buf.is_null() {
+                set_last_error("buf is NULL");
+                self.pending = Some(message);
+                return RBUF_INVALID_ARGUMENT;
+            }
+            ptr::copy_nonoverlapping(message.as_ptr(), buf, message.len());
+        }
+        RBUF_OK
+    }
+}
+
+/// Create a ring under `name` with room for `capacity` bytes of messages,
+/// rounded up to a power of two, and attach to it as its consumer. Returns
+/// NULL on failure; `rbuf_last_error` says why.
+///
+/// # Safety
+///
+/// `name` must be NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rbuf_create(name: *const c_char, capacity: usize) -> *mut Consumer {
+    guard(ptr::null_mut(), || {
+        let Ok(name) = self::name(name) else {
+            return ptr::null_mut();
+        };
+        match Reader::create(name, capacity) {
+            Ok(reader) => Box::into_raw(Box::new(Consumer { reader, pending: None })),
+            Err(e) => {
+                status(e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Copy the next message into the `cap` bytes at `buf` and its length into
+/// `*len`. Fails with `RBUF_EMPTY` straight away if there's none, and with
+/// `RBUF_BUFFER_TOO_SMALL` if it doesn't fit, leaving it to the next pop.
+///
+/// # Safety
+///
+/// `consumer` must be NULL or from `rbuf_create` and not yet freed, `buf`
+/// must be valid for writing `cap` bytes and `len` must be NULL or valid
+/// for writing.
+#[no_mangle]
+pub unsafe extern "C" fn rbuf_pop_bytes(
+    consumer: *mut Consumer,
+    buf: *mut u8,
+    cap: usize,
+    len: *mut usize,
+) -> c_int {
+    guard(RBUF_ERROR, || {
+        let consumer = match handle(consumer) {
+            Ok(consumer) => consumer,
+            Err(status) => return status,
+        };
+        if consumer.pending.is_none() {
+            let mut message = Vec::new();
+            if let Err(e) = consumer.reader.pop_bytes(&mut message) {
+                return status(e);
+            }
+            consumer.pending = Some(message);
+        }
+        consumer.deliver(buf, cap, len)
+    })
+}
+
+/// `rbuf_pop_bytes`, sleeping until a producer publishes if the ring is
+/// empty. Messages that fail to decode are skipped.
+///
+/// # Safety
+///
+/// As for `rbuf_pop_bytes`.
+#[no_mangle]
+pub unsafe extern "C" fn rbuf_pop_bytes_blocking(
+    consumer: *mut Consumer,
+    buf: *mut u8,
+    cap: usize,
+    len: *mut usize,
+) -> c_int {
+    guard(RBUF_ERROR, || {
+        let consumer = match handle(consumer) {
+            Ok(consumer) => consumer,
+            Err(status) => return status,
+        };
+        if consumer.pending.is_none() {
+            let mut message = Vec::new();
+            consumer.reader.pop_bytes_blocking(&mut message);
+            consumer.pending = Some(message);
+        }
+        consumer.deliver(buf, cap, len)
+    })
+}
+
+/// Detach from the ring, unlink it and free the handle. NULL is ignored.
+///
+/// # Safety
+///
+/// `consumer` must be NULL or from `rbuf_create` and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn rbuf_consumer_free(consumer: *mut Consumer) {
+    if !consumer.is_null() {
+        guard((), || drop(Box::from_raw(consumer)));
+    }
+}
+
+// --- Errors ---
+
+/// Copy why the last call on this thread failed into the `cap` bytes at
+/// `buf`, truncated and NUL-terminated. Returns the length of the whole
+/// reason, not counting the NUL, like `snprintf`.
+///
+/// # Safety
+///
+/// `buf` must be NULL or valid for writing `cap` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rbuf_last_error(buf: *mut c_char, cap: usize) -> usize {
+    LAST_ERROR.with(|last| {
+        let last = last.borrow();
+        if !buf.is_null() && cap > 0 {
+            let n = last.len().min(cap - 1);
+            ptr::copy_nonoverlapping(last.as_ptr() as *const c_char, buf, n);
+            *buf.add(n) = 0;
+        }
+        last.len()
+    })
+}
@@ -0,0 +1,326 @@
+// lib.rs
+//
+// Python bindings for the byte ring (`rbuf::bytes`), for notebooks and
+// scripts that want to tap a live ring or feed one:
+//
+//     import numpy as np, rbuf
+//
+//     consumer = rbuf.Consumer("sensors", 1 << 20)
+//     frame = consumer.pop(timeout=1.0)          # bytes, or None
+//
+//     reading = np.dtype([("id", "<u4"), ("value", "<f8")])
+//     rbuf.Producer("readings").push_array(np.zeros(16, reading))
+//     values = rbuf.Consumer("readings", 1 << 16).pop_array(reading)
+//
+// Fixed-size records travel one per message, so a Rust peer reads them
+// with `bytes::Reader` like any other message. `inspect` and `drain` look
+// at rings of any kind by name, typed rings included.
+//
+// Python can't interrupt a thread blocked in Rust, so the waiting calls
+// poll, letting go of the GIL and checking for Ctrl-C in between.
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pyo3::buffer::PyBuffer;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyMemoryView};
+
+use rbuf::bytes::{Reader, Writer};
+use rbuf::RingBuffer;
+
+// How long the waiting calls sleep between polls
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// How many records `pop_array` takes unless told otherwise
+const DEFAULT_MAX_RECORDS: usize = 1024;
+
+create_exception!(rbuf, RbufError, PyException, "A ring operation failed");
+create_exception!(rbuf, Full, RbufError, "The ring has no room for the message");
+create_exception!(rbuf, Disconnected, RbufError, "The other side is done with the ring");
+create_exception!(rbuf, PeerDead, RbufError, "The other side exited without detaching");
+
+fn error(e: rbuf::RbufError) -> PyErr {
+    match e {
+        rbuf::RbufError::Full => Full::new_err(e.to_string()),
+        rbuf::RbufError::Disconnected => Disconnected::new_err(e.to_string()),
+        rbuf::RbufError::PeerDead { .. } => PeerDead::new_err(e.to_string()),
+        e => RbufError::new_err(e.to_string()),
+    }
+}
+
+// The bytes of any C-contiguous buffer (bytes, bytearray, a numpy array,
+// ...) and the size of its items
+fn buffer(data: &Bound<'_, PyAny>) -> PyResult<(PyBuffer<u8>, usize)> {
+    let view = PyMemoryView::from(data)?;
+    let itemsize = view.getattr("itemsize")?.extract()?;
+    let bytes = PyBuffer::get(&view.call_method1("cast", ("B",))?)?;
+    Ok((bytes, itemsize))
+}
+
+fn contents(buffer: &PyBuffer<u8>) -> &[u8] {
+    // The buffer keeps the memory alive and `cast("B")` made it contiguous
+    unsafe { std::slice::from_raw_parts(buffer.buf_ptr() as *const u8, buffer.len_bytes()) }
+}
+
+// When to stop waiting: `None` waits forever and 0 not at all
+fn deadline(timeout: Option<f64>) -> PyResult<Option<Instant>> {
+    timeout
+        .map(|secs| {
+            let timeout = Duration::try_from_secs_f64(secs)
+                .map_err(|e| RbufError::new_err(format!("bad timeout: {}", e)))?;
+            Ok(Instant::now() + timeout)
+        })
+        .transpose()
+}
+
+// Wait one poll interval, or fail if `deadline` has passed or the user
+// pressed Ctrl-C. Returns false once it's time to give up.
+fn poll(py: Python<'_>, deadline: Option<Instant>) -> PyResult<bool> {
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        return Ok(false);
+    }
+    py.check_signals()?;
+    py.detach(|| thread::sleep(POLL_INTERVAL));
+    Ok(true)
+}
+
+fn itemsize<'py>(dtype: &Bound<'py, PyAny>) -> PyResult<(Bound<'py, PyAny>, usize)> {
+    let dtype = dtype.py().import("numpy")?.call_method1("dtype", (dtype,))?;
+    let itemsize = dtype.getattr("itemsize")?.extract()?;
+    Ok((dtype, itemsize))
+}
+
+fn frombuffer<'py>(dtype: &Bound<'py, PyAny>, records: &[u8]) -> PyResult<Bound<'py, PyAny>> {
+    let py = dtype.py();
+    let records = PyBytes::new(py, records);
+    py.import("numpy")?.call_method1("frombuffer", (records, dtype))
+}
+
+// --- Producer ---
+
+// Opens a ring a consumer created, to push messages into it
+#[pyclass(module = "rbuf")]
+struct Producer {
+    writer: Writer,
+}
+
+#[pymethods]
+impl Producer {
+    #[new]
+    fn new(name: &str) -> PyResult<Self> {
+        Ok(Self { writer: Writer::open(name).map_err(error)? })
+    }
+
+    #[getter]
+    fn name(&self) -> &str {
+        self.writer.name()
+    }
+
+    #[getter]
+    fn max_message_size(&self) -> usize {
+        self.writer.max_message_size()
+    }
+
+    // Push `data`, anything with the buffer protocol, as one message.
+    // Raises `Full` if there's no room, unless `block` is set, in which
+    // case it waits for the consumer to make some.
+    #[pyo3(signature = (data, block = false))]
+    fn push(&self, py: Python<'_>, data: &Bound<'_, PyAny>, block: bool) -> PyResult<()> {
+        let (data, _) = buffer(data)?;
+        self.push_message(py, contents(&data), block)
+    }
+
+    // Push every record of a numpy array (or any buffer) as a message of its
+    // own. Without `block`, stops at the first that doesn't fit. Returns how
+    // many were pushed.
+    #[pyo3(signature = (array, block = false))]
+    fn push_array(&self, py: Python<'_>, array: &Bound<'_, PyAny>, block: bool) -> PyResult<usize> {
+        let (data, itemsize) = buffer(array)?;
+        let mut pushed = 0;
+        for record in contents(&data).chunks_exact(itemsize.max(1)) {
+            match self.push_message(py, record, block) {
+                Ok(()) => pushed += 1,
+                Err(e) if e.is_instance_of::<Full>(py) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(pushed)
+    }
+}
+
+impl Producer {
+    fn push_message(&self, py: Python<'_>, message: &[u8], block: bool) -> PyResult<()> {
+        loop {
+            match self.writer.push_bytes(message) {
+                Err(rbuf::RbufError::Full) if block => {
+                    poll(py, None)?;
+                }
+                result => return result.map_err(error),
+            }
+        }
+    }
+}
+
+// --- Consumer ---
+
+// Creates a ring with room for `capacity` bytes of messages and pops from
+// it. The ring is removed when the consumer is garbage collected.
+#[pyclass(module = "rbuf")]
+struct Consumer {
+    reader: Reader,
+    message: Vec<u8>,
+}
+
+#[pymethods]
+impl Consumer {
+    #[new]
+    fn new(name: &str, capacity: usize) -> PyResult<Self> {
+        let reader = Reader::create(name, capacity).map_err(error)?;
+        Ok(Self { reader, message: Vec::new() })
+    }
+
+    #[getter]
+    fn name(&self) -> &str {
+        self.reader.name()
+    }
+
+    // The next message as bytes, waiting up to `timeout` seconds for one:
+    // None (the default) waits forever and 0 not at all. Returns None if
+    // nothing came.
+    #[pyo3(signature = (timeout = None))]
+    fn pop<'py>(
+        &mut self,
+        py: Python<'py>,
+        timeout: Option<f64>,
+    ) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        let deadline = deadline(timeout)?;
+        Ok(self.pop_message(py, deadline)?.then(|| PyBytes::new(py, &self.message)))
+    }
+
+    // Up to `max` records of numpy type `dtype`, one per message, as an
+    // array. Waits up to `timeout` seconds for the first, like `pop`, then
+    // takes whatever else is already there. Raises `RbufError` on a message
+    // that isn't one record long; it's consumed.
+    #[pyo3(signature = (dtype, max = DEFAULT_MAX_RECORDS, timeout = None))]
+    fn pop_array<'py>(
+        &mut self,
+        dtype: &Bound<'py, PyAny>,
+        max: usize,
+        timeout: Option<f64>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let py = dtype.py();
+        let (dtype, itemsize) = itemsize(dtype)?;
+        let mut records = Vec::new();
+        let mut deadline = deadline(timeout)?;
+        while records.len() < max * itemsize && self.pop_message(py, deadline)? {
+            if self.message.len() != itemsize {
+                return Err(RbufError::new_err(format!(
+                    "message of {} bytes isn't a {} byte record",
+                    self.message.len(),
+                    itemsize
+                )));
+            }
+            records.extend_from_slice(&self.message);
+            // Only wait for the first
+            deadline = Some(Instant::now());
+        }
+        frombuffer(&dtype, &records)
+    }
+}
+
+impl Consumer {
+    // Pop into `message`. Returns false if nothing came by `deadline`.
+    fn pop_message(&mut self, py: Python<'_>, deadline: Option<Instant>) -> PyResult<bool> {
+        loop {
+            match self.reader.pop_bytes(&mut self.message) {
+                Ok(_) => return Ok(true),
+                Err(rbuf::RbufError::Empty) => {
+                    if !poll(py, deadline)? {
+                        return Ok(false);
+                    }
+                }
+                Err(e) => return Err(error(e)),
+            }
+        }
+    }
+}
+
+// --- Rings by name ---
+
+// What the header of the named ring says about it, of any kind
+#[pyfunction]
+fn inspect<'py>(py: Python<'py>, name: &str) -> PyResult<Bound<'py, PyDict>> {
+    let info = RingBuffer::inspect(name).map_err(error)?;
+    let dict = PyDict::new(py);
+    dict.set_item("name", &info.name)?;
+    dict.set_item("kind", format!("{:?}", info.kind))?;
+    dict.set_item("elem_size", info.elem_size)?;
+    dict.set_item("capacity", info.capacity)?;
+    dict.set_item("len", info.len)?;
+    dict.set_item("attached", info.attached)?;
+    dict.set_item("consumers", info.consumers)?;
+    dict.set_item("producers", info.producers)?;
+    dict.set_item("creator_pid", info.creator_pid)?;
+    dict.set_item("closed", info.closed)?;
+    dict.set_item("head", info.head)?;
+    dict.set_item("tail", info.tail)?;
+    dict.set_item("pushes", info.stats.pushes)?;
+    dict.set_item("pops", info.stats.pops)?;
+    dict.set_item("full", info.stats.full)?;
+    dict.set_item("overwritten", info.stats.overwritten)?;
+    dict.set_item("corrupt", info.stats.corrupt)?;
+    dict.set_item("high_watermark", info.stats.high_watermark)?;
+    Ok(dict)
+}
+
+// Pop every item (message, for byte rings) waiting in the named ring as
+// bytes, without knowing its type. Stop the ring's own consumer first: it
+// assumes nobody else pops.
+#[pyfunction]
+fn drain<'py>(py: Python<'py>, name: &str) -> PyResult<Vec<Bound<'py, PyBytes>>> {
+    let mut items = Vec::new();
+    RingBuffer::drain(name, |item| items.push(PyBytes::new(py, item))).map_err(error)?;
+    Ok(items)
+}
+
+// `drain`, as one array of numpy type `dtype`; a typed ring's items must
+// be `dtype.itemsize` bytes each
+#[pyfunction]
+fn drain_array<'py>(dtype: &Bound<'py, PyAny>, name: &str) -> PyResult<Bound<'py, PyAny>> {
+    let (dtype, itemsize) = itemsize(dtype)?;
+    let mut records = Vec::new();
+    let mut mismatched = None;
+    RingBuffer::drain(name, |item| {
+        if item.len() == itemsize {
+            records.extend_from_slice(item);
+        } else {
+            mismatched = Some(item.len());
+        }
+    })
+    .map_err(error)?;
+    if let Some(len) = mismatched {
+        return Err(RbufError::new_err(format!(
+            "ring holds {} byte items, not {} byte records",
+            len, itemsize
+        )));
+    }
+    frombuffer(&dtype, &records)
+}
+
+#[pymodule]
+#[pyo3(name = "rbuf")]
+fn rbuf_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add_class::<Producer>()?;
+    m.add_class::<Consumer>()?;
+    m.add_function(wrap_pyfunction!(inspect, m)?)?;
+    m.add_function(wrap_pyfunction!(drain, m)?)?;
+    m.add_function(wrap_pyfunction!(drain_array, m)?)?;
+    m.add("RbufError", py.get_type::<RbufError>())?;
+    m.add("Full", py.get_type::<Full>())?;
+    m.add("Disconnected", py.get_type::<Disconnected>())?;
+    m.add("PeerDead", py.get_type::<PeerDead>())?;
+    Ok(())
+}
@@ -0,0 +1,96 @@
+// padding.rs
+//
+// Shows why `head` and `tail` live on separate cache lines in the header.
+// Run with `cargo bench --bench padding`.
+//
+// The first half hammers two counters from two threads, once with the
+// counters adjacent and once padded like the header. The second half pushes
+// items through a real ring between two threads.
+use rbuf::{Consumer, Producer};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const COUNTER_ITERS: usize = 20_000_000;
+const RING_ITEMS: u64 = 2_000_000;
+
+#[repr(C)]
+struct Adjacent {
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+#[repr(C)]
+struct Padded {
+    head: AtomicUsize,
+    _pad: [u8; 64 - std::mem::size_of::<AtomicUsize>()],
+    tail: AtomicUsize,
+}
+
+fn hammer(head: &AtomicUsize, tail: &AtomicUsize) -> Duration {
+    let start = Instant::now();
+    thread::scope(|s| {
+        s.spawn(|| {
+            for i in 0..COUNTER_ITERS {
+                head.store(i, Ordering::Release);
+                black_box(tail.load(Ordering::Acquire));
+            }
+        });
+        s.spawn(|| {
+            for i in 0..COUNTER_ITERS {
+                tail.store(i, Ordering::Release);
+                black_box(head.load(Ordering::Acquire));
+            }
+        });
+    });
+    start.elapsed()
+}
+
+fn ring_throughput() -> Duration {
+    let name = format!("rbuf_bench_padding_{}", std::process::id());
+    let mut consumer = Consumer::<u64>::create(&name, 4096).expect("create ring");
+    let producer = Producer::<u64>::open(&name).expect("open ring");
+
+    let start = Instant::now();
+    thread::scope(|s| {
+        s.spawn(|| {
+            for i in 0..RING_ITEMS {
+                let mut item = i;
+                while let Err(e) = producer.push(item) {
+                    item = e.into_inner();
+                    std::hint::spin_loop();
+                }
+            }
+        });
+        s.spawn(|| {
+            let mut received = 0;
+            while received < RING_ITEMS {
+                if let Ok(v) = consumer.pop() {
+                    black_box(v);
+                    received += 1;
+                }
+            }
+        });
+    });
+    start.elapsed()
+}
+
+fn report(label: &str, ops: u64, elapsed: Duration) {
+    println!(
+        "{:<28} {:>10.2} Mops/s  ({:?})",
+        label,
+        ops as f64 / elapsed.as_secs_f64() / 1e6,
+        elapsed
+    );
+}
+
+fn main() {
+    let adjacent = Adjacent { head: AtomicUsize::new(0), tail: AtomicUsize::new(0) };
+    report("adjacent head/tail", COUNTER_ITERS as u64, hammer(&adjacent.head, &adjacent.tail));
+
+    let padded = Padded { head: AtomicUsize::new(0), _pad: [0; 56], tail: AtomicUsize::new(0) };
+    report("padded head/tail", COUNTER_ITERS as u64, hammer(&padded.head, &padded.tail));
+
+    report("ring spsc u64", RING_ITEMS, ring_throughput());
+}
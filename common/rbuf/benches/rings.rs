@@ -0,0 +1,323 @@
+// rings.rs
+//
+// Throughput and latency of items through a ring between two threads, with
+// crossbeam's and std's bounded channels alongside as a sanity baseline: a
+// ring that can't keep up with an in-process channel has a problem. Run with
+// `cargo bench --bench rings`. Most of the ring benchmarks spin, so give
+// each thread a core of its own: with fewer cores they measure the scheduler.
+//
+// To judge a change, save a baseline before it and compare against it after:
+//
+//     cargo bench --bench rings -- --save-baseline before
+//     cargo bench --bench rings -- --baseline before
+use std::hint::black_box;
+use std::mem::MaybeUninit;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rbuf::{
+    Blocking, BusySpin, Consumer, Producer, ShmSafe, SpinThenYield, StaticRing, WaitStrategy,
+};
+
+const CAPACITY: usize = 4096;
+
+fn ring_name(label: &str) -> String {
+    format!("rbuf_bench_{}_{}", label, std::process::id())
+}
+
+// How long it takes `pop` on this thread to receive the `iters` items that
+// `push` sends from another
+fn transfer(iters: u64, push: impl FnOnce(u64) + Send, pop: impl FnOnce(u64)) -> Duration {
+    let start = Instant::now();
+    thread::scope(|s| {
+        s.spawn(|| push(iters));
+        pop(iters);
+    });
+    start.elapsed()
+}
+
+fn push_spinning<T: ShmSafe>(producer: &Producer<T>, mut item: T) {
+    while let Err(e) = producer.push(item) {
+        item = e.into_inner();
+        std::hint::spin_loop();
+    }
+}
+
+// Keep the optimizer from skipping whatever produced `item`
+fn consume<T>(item: T) {
+    black_box(item);
+}
+
+fn pop_spinning<T: ShmSafe>(consumer: &mut Consumer<T>) -> T {
+    loop {
+        if let Ok(item) = consumer.pop() {
+            return item;
+        }
+        std::hint::spin_loop();
+    }
+}
+
+// --- SPSC ---
+
+// One producer and one consumer for items of `W` words, through a ring, a
+// `StaticRing` and the two channels
+fn spsc_sized<const W: usize>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("spsc/{}B", W * 8));
+    group.throughput(Throughput::Elements(1));
+
+    let name = ring_name("spsc");
+    let mut consumer = Consumer::<[u64; W]>::create(&name, CAPACITY).expect("create ring");
+    let producer = Producer::<[u64; W]>::open(&name).expect("open ring");
+    group.bench_function("rbuf", |b| {
+        b.iter_custom(|iters| {
+            transfer(
+                iters,
+                |n| (0..n).for_each(|i| push_spinning(&producer, [i; W])),
+                |n| (0..n).for_each(|_| consume(pop_spinning(&mut consumer))),
+            )
+        })
+    });
+    drop((producer, consumer));
+
+    let name = ring_name("spsc_static");
+    let mut consumer = StaticRing::<[u64; W], CAPACITY>::consumer(&name).expect("create ring");
+    let mut producer = StaticRing::<[u64; W], CAPACITY>::producer(&name).expect("open ring");
+    group.bench_function("rbuf_static", |b| {
+        b.iter_custom(|iters| {
+            transfer(
+                iters,
+                |n| {
+                    for i in 0..n {
+                        let mut item = [i; W];
+                        while let Err(e) = producer.push(item) {
+                            item = e.into_inner();
+                            std::hint::spin_loop();
+                        }
+                    }
+                },
+                |n| {
+                    for _ in 0..n {
+                        while consumer.pop().map(black_box).is_err() {
+                            std::hint::spin_loop();
+                        }
+                    }
+                },
+            )
+        })
+    });
+    drop((producer, consumer));
+
+    let (tx, rx) = crossbeam_channel::bounded::<[u64; W]>(CAPACITY);
+    group.bench_function("crossbeam", |b| {
+        b.iter_custom(|iters| {
+            transfer(
+                iters,
+                |n| (0..n).for_each(|i| tx.send([i; W]).unwrap()),
+                |n| (0..n).for_each(|_| consume(rx.recv().unwrap())),
+            )
+        })
+    });
+
+    let (tx, rx) = mpsc::sync_channel::<[u64; W]>(CAPACITY);
+    group.bench_function("std", |b| {
+        b.iter_custom(|iters| {
+            transfer(
+                iters,
+                |n| (0..n).for_each(|i| tx.send([i; W]).unwrap()),
+                |n| (0..n).for_each(|_| consume(rx.recv().unwrap())),
+            )
+        })
+    });
+    group.finish();
+}
+
+fn spsc(c: &mut Criterion) {
+    spsc_sized::<1>(c);
+    spsc_sized::<8>(c);
+    spsc_sized::<32>(c);
+}
+
+// --- Wait strategies ---
+
+// `push_blocking` and `pop_blocking` under each strategy, on both sides
+fn wait_strategies(c: &mut Criterion) {
+    let mut group = c.benchmark_group("wait");
+    group.throughput(Throughput::Elements(1));
+
+    let strategies: [(&str, Arc<dyn WaitStrategy>); 3] = [
+        ("busy_spin", Arc::new(BusySpin)),
+        ("spin_then_yield", Arc::new(SpinThenYield::default())),
+        ("blocking", Arc::new(Blocking)),
+    ];
+    for (label, strategy) in strategies {
+        let name = ring_name("wait");
+        let mut consumer = Consumer::<u64>::create(&name, CAPACITY).expect("create ring");
+        let mut producer = Producer::<u64>::open(&name).expect("open ring");
+        consumer.set_wait_strategy(strategy.clone());
+        producer.set_wait_strategy(strategy);
+        group.bench_function(label, |b| {
+            b.iter_custom(|iters| {
+                transfer(
+                    iters,
+                    |n| (0..n).for_each(|i| producer.push_blocking(i).unwrap()),
+                    |n| (0..n).for_each(|_| consume(consumer.pop_blocking().unwrap())),
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+// --- MPSC ---
+
+// Items from several producer threads, split evenly, to one consumer
+fn mpsc_producers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mpsc");
+    group.throughput(Throughput::Elements(1));
+
+    for producers in [2, 4] {
+        // Each producer's share of `iters`, the first taking the remainder
+        let share =
+            |iters: u64, p: u64| iters / producers + u64::from(p == 0) * (iters % producers);
+
+        let name = ring_name("mpsc");
+        let mut consumer = Consumer::<u64>::create(&name, CAPACITY).expect("create ring");
+        let handles: Vec<_> =
+            (0..producers).map(|_| Producer::<u64>::open(&name).expect("open ring")).collect();
+        group.bench_with_input(BenchmarkId::new("rbuf", producers), &producers, |b, _| {
+            b.iter_custom(|iters| {
+                let start = Instant::now();
+                thread::scope(|s| {
+                    for (p, producer) in handles.iter().enumerate() {
+                        s.spawn(move || {
+                            (0..share(iters, p as u64)).for_each(|i| push_spinning(producer, i))
+                        });
+                    }
+                    (0..iters).for_each(|_| consume(pop_spinning(&mut consumer)));
+                });
+                start.elapsed()
+            })
+        });
+        drop((handles, consumer));
+
+        let (tx, rx) = crossbeam_channel::bounded::<u64>(CAPACITY);
+        group.bench_with_input(BenchmarkId::new("crossbeam", producers), &producers, |b, _| {
+            b.iter_custom(|iters| {
+                let start = Instant::now();
+                thread::scope(|s| {
+                    for p in 0..producers {
+                        let tx = tx.clone();
+                        s.spawn(move || (0..share(iters, p)).for_each(|i| tx.send(i).unwrap()));
+                    }
+                    (0..iters).for_each(|_| consume(rx.recv().unwrap()));
+                });
+                start.elapsed()
+            })
+        });
+
+        let (tx, rx) = mpsc::sync_channel::<u64>(CAPACITY);
+        group.bench_with_input(BenchmarkId::new("std", producers), &producers, |b, _| {
+            b.iter_custom(|iters| {
+                let start = Instant::now();
+                thread::scope(|s| {
+                    for p in 0..producers {
+                        let tx = tx.clone();
+                        s.spawn(move || (0..share(iters, p)).for_each(|i| tx.send(i).unwrap()));
+                    }
+                    (0..iters).for_each(|_| consume(rx.recv().unwrap()));
+                });
+                start.elapsed()
+            })
+        });
+    }
+    group.finish();
+}
+
+// --- Batching ---
+
+// `push_slice` and `pop_slice` moving up to `batch` items per call
+fn batching(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batch");
+    group.throughput(Throughput::Elements(1));
+
+    let name = ring_name("batch");
+    let mut consumer = Consumer::<u64>::create(&name, CAPACITY).expect("create ring");
+    let producer = Producer::<u64>::open(&name).expect("open ring");
+    for batch in [1, 16, 64, 256] {
+        group.bench_with_input(BenchmarkId::from_parameter(batch), &batch, |b, &batch| {
+            b.iter_custom(|iters| {
+                transfer(
+                    iters,
+                    |n| {
+                        let items: Vec<u64> = (0..batch as u64).collect();
+                        let mut sent = 0;
+                        while sent < n {
+                            let len = batch.min((n - sent) as usize);
+                            sent += producer.push_slice(&items[..len]) as u64;
+                        }
+                    },
+                    |n| {
+                        let mut out = vec![MaybeUninit::<u64>::uninit(); batch];
+                        let mut received = 0;
+                        while received < n {
+                            let len = batch.min((n - received) as usize);
+                            received += black_box(consumer.pop_slice(&mut out[..len])) as u64;
+                        }
+                    },
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+// --- Latency ---
+
+// Round trips of one item out to an echoing thread and back
+fn round_trip(c: &mut Criterion) {
+    let mut group = c.benchmark_group("round_trip");
+
+    let (ping, pong) = (ring_name("ping"), ring_name("pong"));
+    let mut ping_rx = Consumer::<u64>::create(&ping, CAPACITY).expect("create ring");
+    let ping_tx = Producer::<u64>::open(&ping).expect("open ring");
+    let mut pong_rx = Consumer::<u64>::create(&pong, CAPACITY).expect("create ring");
+    let pong_tx = Producer::<u64>::open(&pong).expect("open ring");
+    group.bench_function("rbuf", |b| {
+        b.iter_custom(|iters| {
+            transfer(
+                iters,
+                |n| (0..n).for_each(|_| push_spinning(&pong_tx, pop_spinning(&mut ping_rx))),
+                |n| {
+                    for i in 0..n {
+                        push_spinning(&ping_tx, i);
+                        black_box(pop_spinning(&mut pong_rx));
+                    }
+                },
+            )
+        })
+    });
+    drop((ping_rx, ping_tx, pong_rx, pong_tx));
+
+    let (ping_tx, ping_rx) = crossbeam_channel::bounded::<u64>(CAPACITY);
+    let (pong_tx, pong_rx) = crossbeam_channel::bounded::<u64>(CAPACITY);
+    group.bench_function("crossbeam", |b| {
+        b.iter_custom(|iters| {
+            transfer(
+                iters,
+                |n| (0..n).for_each(|_| pong_tx.send(ping_rx.recv().unwrap()).unwrap()),
+                |n| {
+                    for i in 0..n {
+                        ping_tx.send(i).unwrap();
+                        black_box(pong_rx.recv().unwrap());
+                    }
+                },
+            )
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, spsc, wait_strategies, mpsc_producers, batching, round_trip);
+criterion_main!(benches);
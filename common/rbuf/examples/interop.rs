@@ -0,0 +1,96 @@
+// interop.rs
+//
+// A consumer that starts producer processes (copies of itself) and checks
+// that everything they push arrives, in order per producer, through both a
+// typed ring and a byte ring. Exits non-zero on any mismatch; CI runs it on
+// every platform with `cargo run --example interop`.
+use rbuf::bytes::{Reader, Writer};
+use rbuf::{Consumer, Producer};
+use std::env;
+use std::process::{self, Command};
+use std::time::Duration;
+
+const PRODUCERS: u64 = 3;
+const ITEMS: u64 = 50_000;
+const MESSAGES: u64 = 2_000;
+
+// --- Producer side ---
+
+fn produce(ring: &str, id: u64) {
+    let producer = Producer::<u64>::open(ring).expect("failed to open the typed ring");
+    for seq in 0..ITEMS {
+        producer.push_blocking(id << 32 | seq).expect("consumer went away");
+    }
+
+    let writer = Writer::open(&format!("{}.bytes", ring)).expect("failed to open the byte ring");
+    for seq in 0..MESSAGES {
+        let message = format!("{}:{}:{}", id, seq, "x".repeat((seq % 97) as usize));
+        writer.push_bytes_blocking(message.as_bytes()).expect("reader went away");
+    }
+}
+
+// --- Consumer side ---
+
+fn consume(ring: &str) -> Result<(), String> {
+    let mut consumer = Consumer::<u64>::create(ring, 64).map_err(|e| e.to_string())?;
+    let mut reader = Reader::create(&format!("{}.bytes", ring), 4096).map_err(|e| e.to_string())?;
+
+    let exe = env::current_exe().map_err(|e| e.to_string())?;
+    let mut children = Vec::new();
+    for id in 0..PRODUCERS {
+        let child = Command::new(&exe).args(["producer", ring, &id.to_string()]).spawn();
+        children.push(child.map_err(|e| e.to_string())?);
+    }
+
+    let mut next = vec![0; PRODUCERS as usize];
+    for _ in 0..PRODUCERS * ITEMS {
+        let item = consumer.pop_timeout(Duration::from_secs(30)).map_err(|e| e.to_string())?;
+        let (id, seq) = ((item >> 32) as usize, item & 0xffff_ffff);
+        if next.get(id) != Some(&seq) {
+            return Err(format!("item {} from producer {} out of order", seq, id));
+        }
+        next[id] += 1;
+    }
+
+    let mut next = vec![0; PRODUCERS as usize];
+    let mut buf = Vec::new();
+    for _ in 0..PRODUCERS * MESSAGES {
+        reader.pop_bytes_blocking(&mut buf);
+        let message = std::str::from_utf8(&buf).map_err(|e| e.to_string())?;
+        let mut parts = message.splitn(3, ':');
+        let id: usize = parts.next().and_then(|p| p.parse().ok()).ok_or("bad message")?;
+        let seq: u64 = parts.next().and_then(|p| p.parse().ok()).ok_or("bad message")?;
+        if next.get(id) != Some(&seq) || parts.next().map(str::len) != Some((seq % 97) as usize) {
+            return Err(format!("message {} from producer {} garbled or out of order", seq, id));
+        }
+        next[id] += 1;
+    }
+
+    for mut child in children {
+        let status = child.wait().map_err(|e| e.to_string())?;
+        if !status.success() {
+            return Err(format!("producer exited with {}", status));
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("producer") => produce(&args[2], args[3].parse().expect("producer id")),
+        _ => {
+            let ring = format!("rbuf_interop_{}", process::id());
+            match consume(&ring) {
+                Ok(()) => println!(
+                    "{} producers, {} items and {} messages each: ok",
+                    PRODUCERS, ITEMS, MESSAGES
+                ),
+                Err(e) => {
+                    eprintln!("interop failed: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+// mpsc_demo.rs
+//
+// Run `cargo run --example mpsc_demo -- creator` in one terminal and
+// `cargo run --example mpsc_demo -- producer` in another.
+use rbuf::{Consumer, Producer};
+use std::thread;
+use std::time::Duration;
+
+// --- Main execution logic ---
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        println!("Usage: program <creator|producer>");
+        return;
+    }
+
+    const SHMEM_ID: &str = "my_mpsc_ring_buffer";
+
+    match args[1].as_str() {
+        "creator" => {
+            println!("[Creator/Consumer] Starting...");
+            let mut consumer = Consumer::<u32>::open_or_create(SHMEM_ID, 10)
+                .expect("Failed to create consumer");
+            println!("[Creator/Consumer] Shared memory mapped. Waiting for producers.");
+            
+            let mut received_count = 0;
+            loop {
+                let val = consumer.pop_blocking().expect("Producers closed the ring");
+                println!("[Consumer] Popped: {}", val);
+                received_count += 1;
+                if received_count == 20 { // Exit after 20 messages
+                    break;
+                }
+            }
+            println!("[Creator/Consumer] Done.");
+        }
+        "producer" => {
+            println!("[Producer] Starting...");
+            // Whichever side starts first creates the ring
+            let producer =
+                Producer::<u32>::open_or_create(SHMEM_ID, 10).expect("Failed to open producer");
+            println!("[Producer] Attached to shared memory.");
+            
+            for i in 0..10 {
+                println!("[Producer] Pushing {}", i);
+                producer.push_blocking(i).expect("Consumer went away");
+                thread::sleep(Duration::from_millis(200));
+            }
+            println!("[Producer] Done.");
+        }
+        _ => {
+            println!("Invalid argument. Use 'creator' or 'producer'.");
+        }
+    }
+}
\ No newline at end of file
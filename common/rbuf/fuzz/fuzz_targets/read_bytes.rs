@@ -0,0 +1,9 @@
+// read_bytes.rs
+//
+// Arbitrary record headers and payloads in a byte ring, drained by a
+// `Reader`. Run with `cargo fuzz run read_bytes`.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| rbuf::fuzzing::read_bytes(data));
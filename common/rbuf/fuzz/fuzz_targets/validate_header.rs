@@ -0,0 +1,9 @@
+// validate_header.rs
+//
+// Arbitrary bytes as a segment someone else created, attached to every way
+// a process would attach to it. Run with `cargo fuzz run validate_header`.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| rbuf::fuzzing::attach(data));
@@ -0,0 +1,179 @@
+// archive.rs
+//
+// rkyv archives over a byte ring, read in place. The sender serializes into
+// the ring's record format once; the receiver validates the archive where it
+// lies in shared memory and hands out a reference into the record, so big
+// messages are never copied or allocated on the way out.
+//
+//     let mut snapshots: Receiver<Snapshot> = ArchivedChannel::create("book", 1 << 24)?;
+//     let snapshot = snapshots.pop_blocking()?;
+//     println!("{} levels", snapshot.levels.len());
+//
+// Records start on 8-byte boundaries, so archived types needing more
+// alignment than that fail validation on every pop.
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use rkyv::api::high::{HighSerializer, HighValidator};
+use rkyv::bytecheck::CheckBytes;
+use rkyv::rancor::Error;
+use rkyv::ser::allocator::ArenaHandle;
+use rkyv::util::AlignedVec;
+use rkyv::{Archive, Serialize};
+
+use crate::bytes::{BytesRef, Reader, Writer};
+use crate::error::RbufError;
+
+// Whoever receives creates the channel, like with the byte ring underneath
+pub struct ArchivedChannel<T> {
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Archive> ArchivedChannel<T>
+where
+    T::Archived: for<'a> CheckBytes<HighValidator<'a, Error>>,
+{
+    // Create the segment with room for `capacity` bytes of archives
+    pub fn create(name: &str, capacity: usize) -> Result<Receiver<T>, RbufError> {
+        Receiver::create(name, capacity)
+    }
+}
+
+impl<T> ArchivedChannel<T>
+where
+    T: for<'a> Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, Error>>,
+{
+    pub fn open(name: &str) -> Result<Sender<T>, RbufError> {
+        Sender::open(name)
+    }
+}
+
+// --- Sender ---
+
+pub struct Sender<T> {
+    writer: Writer,
+    // Only ever handed a `&T`, so `T` doesn't decide whether this is Send
+    _phantom: PhantomData<fn(&T)>,
+}
+
+impl<T> Sender<T>
+where
+    T: for<'a> Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, Error>>,
+{
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        Ok(Self { writer: Writer::open(name)?, _phantom: PhantomData })
+    }
+
+    pub fn name(&self) -> &str {
+        self.writer.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.writer.attached()
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.writer.set_unlink_on_drop(unlink);
+    }
+
+    // The largest archive that can ever be pushed
+    pub fn max_message_size(&self) -> usize {
+        self.writer.max_message_size()
+    }
+
+    // Fails with `Encode` if `value` can't be archived, and with
+    // `MessageTooLarge` if its archive can never fit
+    pub fn push(&self, value: &T) -> Result<(), RbufError> {
+        self.writer.push_bytes(&archive(value)?)
+    }
+
+    // Push, sleeping until the receiver frees enough space if the ring is full
+    pub fn push_blocking(&self, value: &T) -> Result<(), RbufError> {
+        self.writer.push_bytes_blocking(&archive(value)?)
+    }
+}
+
+fn archive<T>(value: &T) -> Result<AlignedVec, RbufError>
+where
+    T: for<'a> Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, Error>>,
+{
+    rkyv::to_bytes::<Error>(value).map_err(|e| RbufError::Encode(e.to_string()))
+}
+
+// --- Receiver ---
+
+pub struct Receiver<T> {
+    reader: Reader,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T: Archive> Receiver<T>
+where
+    T::Archived: for<'a> CheckBytes<HighValidator<'a, Error>>,
+{
+    // Create the segment with room for `capacity` bytes of archives, rounded
+    // up to a power of two
+    pub fn create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        Ok(Self { reader: Reader::create(name, capacity)?, _phantom: PhantomData })
+    }
+
+    pub fn name(&self) -> &str {
+        self.reader.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.reader.attached()
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.reader.set_unlink_on_drop(unlink);
+    }
+
+    // The next archive, validated and borrowed in place. Writers can't reuse
+    // its space until the returned guard is dropped, so don't hold on to it
+    // longer than needed. Fails with `Decode` if validation fails; the
+    // message is consumed either way.
+    pub fn pop(&mut self) -> Result<ArchivedRef<'_, T>, RbufError> {
+        let bytes = self.reader.pop_ref()?;
+        ArchivedRef::new(bytes)
+    }
+
+    // Pop, sleeping until a sender pushes if the ring is empty
+    pub fn pop_blocking(&mut self) -> Result<ArchivedRef<'_, T>, RbufError> {
+        let bytes = self.reader.pop_ref_blocking()?;
+        ArchivedRef::new(bytes)
+    }
+}
+
+// A validated archive inside the ring. Dropping it frees the record.
+pub struct ArchivedRef<'a, T: Archive> {
+    bytes: BytesRef<'a>,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<'a, T: Archive> ArchivedRef<'a, T>
+where
+    T::Archived: for<'b> CheckBytes<HighValidator<'b, Error>>,
+{
+    fn new(bytes: BytesRef<'a>) -> Result<Self, RbufError> {
+        // The guard releases the record if validation fails
+        rkyv::access::<T::Archived, Error>(&bytes)
+            .map_err(|e| RbufError::Decode(e.to_string()))?;
+        Ok(Self { bytes, _phantom: PhantomData })
+    }
+}
+
+impl<T: Archive> Deref for ArchivedRef<'_, T> {
+    type Target = T::Archived;
+
+    fn deref(&self) -> &T::Archived {
+        // Checked in `new`, and nobody writes to the record until we drop it
+        unsafe { rkyv::access_unchecked::<T::Archived>(&self.bytes) }
+    }
+}
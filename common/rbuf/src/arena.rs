@@ -0,0 +1,218 @@
+// arena.rs
+//
+// A bump allocator over a shared segment, for structures other than rings
+// that several processes need to share. Allocations hand out `ShmPtr`s and
+// `ShmSlice`s, so they resolve in every process whatever address it mapped
+// the arena at. Nothing is freed on its own: the whole arena is reset at
+// once. A `BufferPool` can live in an arena too, next to whatever refers to
+// its blocks; see `BufferPool::create_in`.
+//
+// Processes find what the creator allocated through the root pointer, which
+// typically points at a struct of further pointers.
+//
+// [ header | top, root | allocations ]
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::directory;
+use crate::error::RbufError;
+use crate::header::{RingBufferHeader, RingKind, CACHE_LINE};
+use crate::ptr::{ShmPtr, ShmSlice};
+use crate::segment::{Backing, Segment};
+use crate::shm_safe::ShmSafe;
+
+#[repr(C)]
+struct ArenaControl {
+    // Offset from the start of the segment of the first unallocated byte
+    top: AtomicU64,
+    // Offset of whatever the creator wants openers to find first, or 0
+    root: AtomicU64,
+}
+
+fn data_offset() -> usize {
+    let end = mem::size_of::<RingBufferHeader>() + mem::size_of::<ArenaControl>();
+    (end + CACHE_LINE - 1) & !(CACHE_LINE - 1)
+}
+
+// Bytes allocated in an arena, for tools that don't hold one
+pub(crate) fn used_raw(base: *const u8, len: usize) -> Option<usize> {
+    if len < data_offset() {
+        return None;
+    }
+    let control = base.wrapping_add(mem::size_of::<RingBufferHeader>()) as *const ArenaControl;
+    let control = unsafe { &*control };
+    Some((control.top.load(Ordering::Acquire) as usize).saturating_sub(data_offset()))
+}
+
+struct ArenaShared {
+    segment: Box<dyn Segment>,
+    header: *const RingBufferHeader,
+    control: *const ArenaControl,
+    // Handed to the segment when the last clone goes, like `BufferPool`'s
+    unlink: AtomicBool,
+}
+
+unsafe impl Send for ArenaShared {}
+unsafe impl Sync for ArenaShared {}
+
+impl Drop for ArenaShared {
+    fn drop(&mut self) {
+        unsafe { (*self.header).detach() };
+        let unlink = self.unlink.load(Ordering::Relaxed);
+        self.segment.set_owner(unlink);
+        event!(debug, ring = self.segment.name(), kind = "arena", "detached from arena");
+    }
+}
+
+// A handle on the arena; clones share one mapping
+#[derive(Clone)]
+pub struct ShmArena {
+    shared: Arc<ArenaShared>,
+}
+
+impl ShmArena {
+    // Create an arena with room for `capacity` bytes of allocations
+    pub fn create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        let segment = Backing::Shm.create(name, data_offset() + capacity)?;
+        unsafe {
+            let base = segment.as_ptr();
+            let header = RingBufferHeader::initialize(
+                base,
+                RingBufferHeader::new(RingKind::Arena, 1, 1, capacity),
+            );
+            let control = base.add(mem::size_of::<RingBufferHeader>()) as *mut ArenaControl;
+            control.write(ArenaControl {
+                top: AtomicU64::new(data_offset() as u64),
+                root: AtomicU64::new(0),
+            });
+            header.publish();
+            directory::register(name, header);
+        }
+        Ok(Self::from_segment(segment, true))
+    }
+
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        let segment = Backing::Shm.open(name)?;
+        let header =
+            RingBufferHeader::validate(segment.as_ptr(), segment.len(), RingKind::Arena, 1, 1)?;
+        let expected = data_offset() + header.capacity();
+        if segment.len() < expected {
+            return Err(RbufError::SizeMismatch { expected, actual: segment.len() });
+        }
+        Ok(Self::from_segment(segment, false))
+    }
+
+    fn from_segment(segment: Box<dyn Segment>, owner: bool) -> Self {
+        let header = segment.as_ptr() as *const RingBufferHeader;
+        let control =
+            unsafe { segment.as_ptr().add(mem::size_of::<RingBufferHeader>()) } as *const _;
+        unsafe { (*header).attached.fetch_add(1, Ordering::AcqRel) };
+        event!(debug, ring = segment.name(), kind = "arena", "mapped arena");
+        let unlink = AtomicBool::new(owner);
+        Self { shared: Arc::new(ArenaShared { segment, header, control, unlink }) }
+    }
+
+    fn control(&self) -> &ArenaControl {
+        unsafe { &*self.shared.control }
+    }
+
+    pub fn name(&self) -> &str {
+        self.shared.segment.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        unsafe { (*self.shared.header).attached.load(Ordering::Acquire) as usize }
+    }
+
+    // Whether dropping the last clone of this handle removes the segment
+    // from the system. Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.shared.unlink.store(unlink, Ordering::Relaxed);
+    }
+
+    // The mapping, to resolve the arena's pointers against
+    pub fn segment(&self) -> &dyn Segment {
+        &*self.shared.segment
+    }
+
+    // Bytes of allocations the arena has room for
+    pub fn capacity(&self) -> usize {
+        self.shared.segment.len() - data_offset()
+    }
+
+    // Bytes allocated so far, alignment padding included; only a snapshot
+    pub fn used(&self) -> usize {
+        self.control().top.load(Ordering::Acquire) as usize - data_offset()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.capacity() - self.used()
+    }
+
+    // Claim `size` bytes aligned to `align` (a power of two) and return their
+    // offset into the segment. Fails with `Full` if they don't fit.
+    pub(crate) fn alloc_raw(&self, size: usize, align: usize) -> Result<u64, RbufError> {
+        let limit = self.shared.segment.len() as u64;
+        let align = align as u64;
+        let top = &self.control().top;
+        let mut current = top.load(Ordering::Relaxed);
+        loop {
+            let start = (current + align - 1) & !(align - 1);
+            let end = start.checked_add(size as u64).filter(|&end| end <= limit);
+            let Some(end) = end else {
+                return Err(RbufError::Full);
+            };
+            match top.compare_exchange_weak(current, end, Ordering::AcqRel, Ordering::Relaxed) {
+                Ok(_) => return Ok(start),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    // Move `value` into the arena. Fails with `Full` if it doesn't fit.
+    pub fn alloc<T: ShmSafe>(&self, value: T) -> Result<ShmPtr<T>, RbufError> {
+        let offset = self.alloc_raw(mem::size_of::<T>(), mem::align_of::<T>())?;
+        let ptr = ShmPtr::from_offset(offset);
+        // Freshly claimed, so nobody else can see it yet
+        unsafe { ptr.resolve(self.segment())?.write(value) };
+        Ok(ptr)
+    }
+
+    // Allocate `len` copies of `value`
+    pub fn alloc_slice<T: ShmSafe + Copy>(
+        &self,
+        len: usize,
+        value: T,
+    ) -> Result<ShmSlice<T>, RbufError> {
+        let size = len.checked_mul(mem::size_of::<T>()).ok_or(RbufError::Full)?;
+        let offset = self.alloc_raw(size, mem::align_of::<T>())?;
+        let slice = ShmSlice::from_raw(offset, len);
+        let start = slice.resolve(self.segment())?.cast::<T>();
+        for index in 0..len {
+            unsafe { start.add(index).write(value) };
+        }
+        Ok(slice)
+    }
+
+    // Record where openers should start looking
+    pub fn set_root<T>(&self, root: ShmPtr<T>) {
+        self.control().root.store(root.offset(), Ordering::Release);
+    }
+
+    // Whatever `set_root` recorded, or null. Nothing checks that `T` is the
+    // type it was recorded as.
+    pub fn root<T>(&self) -> ShmPtr<T> {
+        ShmPtr::from_offset(self.control().root.load(Ordering::Acquire))
+    }
+
+    // Throw away every allocation, and the root. Pointers handed out before
+    // still resolve, to whatever gets allocated in their place, so every
+    // process has to be done with them, and with any pool in the arena.
+    pub fn reset(&self) {
+        self.control().root.store(0, Ordering::Release);
+        self.control().top.store(data_offset() as u64, Ordering::Release);
+        event!(debug, ring = self.name(), "arena reset");
+    }
+}
@@ -0,0 +1,323 @@
+// async_ring.rs
+//
+// Async adapters over the blocking handles. The wait queues live in shared
+// memory and are signalled with a futex, which an executor can't poll, so
+// each adapter owns a small notifier thread: when a poll comes up empty the
+// task hands its waker to the thread, the thread sleeps on the wait queue
+// and wakes the task once the other side signals it.
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::consumer::Consumer;
+use crate::error::RbufError;
+use crate::notify::WaitQueue;
+use crate::producer::Producer;
+use crate::shm_safe::ShmSafe;
+
+// --- Notifier ---
+
+struct QueuePtr(*const WaitQueue);
+
+// The queue lives in shared memory that outlives the notifier thread
+unsafe impl Send for QueuePtr {}
+
+#[derive(Default)]
+struct NotifierState {
+    // Sequence number registered with `prepare_wait`, the task to wake and
+    // how long to wait at most
+    armed: Option<(u32, Waker, Option<Duration>)>,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct NotifierShared {
+    state: Mutex<NotifierState>,
+    armed: Condvar,
+}
+
+// Wakes a task when a wait queue is signalled. Must be dropped before the
+// mapping holding the queue.
+struct Notifier {
+    queue: *const WaitQueue,
+    shared: Arc<NotifierShared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Notifier {
+    fn new(queue: &WaitQueue) -> Self {
+        let shared = Arc::new(NotifierShared::default());
+        let thread = {
+            let shared = shared.clone();
+            let queue = QueuePtr(queue);
+            thread::Builder::new()
+                .name("rbuf-notifier".into())
+                .spawn(move || Self::run(&shared, queue))
+                .expect("failed to spawn rbuf notifier thread")
+        };
+        Self { queue, shared, thread: Some(thread) }
+    }
+
+    fn run(shared: &NotifierShared, queue: QueuePtr) {
+        let queue = unsafe { &*queue.0 };
+        loop {
+            let (seq, waker, timeout) = {
+                let mut state = shared.state.lock().unwrap();
+                loop {
+                    if state.closed {
+                        return;
+                    }
+                    if let Some(armed) = state.armed.take() {
+                        break armed;
+                    }
+                    state = shared.armed.wait(state).unwrap();
+                }
+            };
+            match timeout {
+                Some(timeout) => queue.wait_timeout(seq, timeout),
+                None => queue.wait(seq),
+            }
+            waker.wake();
+        }
+    }
+
+    // Wake `waker` once the queue moves past `seq`, or once `timeout` is up
+    // if there is one. The caller must already have registered with
+    // `prepare_wait`; the notifier takes over that registration.
+    fn arm(&self, seq: u32, waker: Waker, timeout: Option<Duration>) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.armed.replace((seq, waker, timeout)).is_some() {
+            // The thread never picked up the previous registration
+            self.queue().cancel_wait();
+        }
+        self.shared.armed.notify_one();
+    }
+
+    fn queue(&self) -> &WaitQueue {
+        unsafe { &*self.queue }
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.closed = true;
+            if state.armed.take().is_some() {
+                self.queue().cancel_wait();
+            }
+        }
+        self.shared.armed.notify_one();
+        // Kick the thread out of the wait queue if it's sleeping there. Anyone
+        // else waiting on it sees a spurious wakeup and re-checks.
+        self.queue().notify();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// --- AsyncConsumer ---
+
+// A `Stream` of items popped from the ring. The stream ends once a producer
+// closes the ring and everything pushed before has been popped; drop it to
+// detach sooner.
+pub struct AsyncConsumer<T> {
+    // Declared first so the thread is gone before the mapping is unmapped
+    notifier: Notifier,
+    consumer: Consumer<T>,
+}
+
+// Nothing is ever pinned in place
+impl<T> Unpin for AsyncConsumer<T> {}
+
+unsafe impl<T: Send> Send for AsyncConsumer<T> {}
+
+impl<T: ShmSafe> AsyncConsumer<T> {
+    pub fn new(consumer: Consumer<T>) -> Self {
+        let notifier = Notifier::new(&consumer.header().data_ready);
+        Self { notifier, consumer }
+    }
+
+    pub fn get_ref(&self) -> &Consumer<T> {
+        &self.consumer
+    }
+
+    pub fn get_mut(&mut self) -> &mut Consumer<T> {
+        &mut self.consumer
+    }
+
+    pub fn into_inner(self) -> Consumer<T> {
+        let Self { notifier, consumer } = self;
+        drop(notifier);
+        consumer
+    }
+}
+
+impl<T: ShmSafe> From<Consumer<T>> for AsyncConsumer<T> {
+    fn from(consumer: Consumer<T>) -> Self {
+        Self::new(consumer)
+    }
+}
+
+impl<T: ShmSafe> Stream for AsyncConsumer<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        match this.consumer.pop() {
+            Ok(item) => return Poll::Ready(Some(item)),
+            Err(RbufError::Disconnected) => return Poll::Ready(None),
+            Err(_) => {}
+        }
+
+        // Same dance as `Consumer::pop_blocking`, except the notifier thread
+        // does the sleeping, on the segment `Consumer::grow` last moved to
+        let queue: &WaitQueue = &this.consumer.header().data_ready;
+        if !ptr::eq(this.notifier.queue(), queue) {
+            this.notifier = Notifier::new(queue);
+        }
+        let data_ready = this.notifier.queue();
+        let seq = data_ready.prepare_wait();
+        match this.consumer.pop() {
+            Ok(item) => {
+                data_ready.cancel_wait();
+                return Poll::Ready(Some(item));
+            }
+            Err(RbufError::Disconnected) => {
+                data_ready.cancel_wait();
+                return Poll::Ready(None);
+            }
+            Err(_) => {}
+        }
+        this.notifier.arm(seq, cx.waker().clone(), None);
+        Poll::Pending
+    }
+}
+
+// --- AsyncProducer ---
+
+// A `Sink` that pushes into the ring. While the ring is full the sink
+// holds on to one item and reports `Pending` until the consumer frees a slot.
+// It waits out everything else `push_blocking` waits out the same way: no
+// credit, a pause and the rate limit.
+pub struct AsyncProducer<T> {
+    // Declared first so the thread is gone before the mapping is unmapped
+    notifier: Notifier,
+    producer: Producer<T>,
+    pending: Option<T>,
+}
+
+impl<T> Unpin for AsyncProducer<T> {}
+
+unsafe impl<T: Send> Send for AsyncProducer<T> {}
+
+impl<T: ShmSafe> AsyncProducer<T> {
+    pub fn new(producer: Producer<T>) -> Self {
+        let notifier = Notifier::new(&producer.header().space_ready);
+        Self { notifier, producer, pending: None }
+    }
+
+    pub fn get_ref(&self) -> &Producer<T> {
+        &self.producer
+    }
+
+    pub fn get_mut(&mut self) -> &mut Producer<T> {
+        &mut self.producer
+    }
+
+    // Any item still waiting for space is dropped; flush first to keep it
+    pub fn into_inner(self) -> Producer<T> {
+        let Self { notifier, producer, .. } = self;
+        drop(notifier);
+        producer
+    }
+
+    // Push the held item, if any, parking the task while the ring is full
+    fn poll_push(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), RbufError>> {
+        let Some(item) = self.pending.take() else {
+            return Poll::Ready(Ok(()));
+        };
+        let item = match self.producer.push(item) {
+            Ok(()) => return Poll::Ready(Ok(())),
+            Err(e) if waits(e.error()) => e.into_inner(),
+            // The item goes with the error; the ring isn't coming back
+            Err(e) => return Poll::Ready(Err(e.into_error())),
+        };
+
+        // Sleep on the segment the producer last followed the ring into
+        let queue: &WaitQueue = &self.producer.header().space_ready;
+        if !ptr::eq(self.notifier.queue(), queue) {
+            self.notifier = Notifier::new(queue);
+        }
+        let space_ready = self.notifier.queue();
+        let seq = space_ready.prepare_wait();
+        match self.producer.push(item) {
+            Ok(()) => {
+                space_ready.cancel_wait();
+                Poll::Ready(Ok(()))
+            }
+            Err(e) if waits(e.error()) => {
+                // Space, credit and resuming are all signalled on
+                // `space_ready`, but the rate limit only passes with time
+                let timeout = matches!(e.error(), RbufError::RateLimited)
+                    .then(|| self.producer.rate_delay());
+                self.pending = Some(e.into_inner());
+                self.notifier.arm(seq, cx.waker().clone(), timeout);
+                Poll::Pending
+            }
+            Err(e) => {
+                space_ready.cancel_wait();
+                Poll::Ready(Err(e.into_error()))
+            }
+        }
+    }
+}
+
+// Errors a push only has to wait out
+fn waits(error: &RbufError) -> bool {
+    matches!(
+        error,
+        RbufError::Full | RbufError::NoCredit | RbufError::Paused | RbufError::RateLimited
+    )
+}
+
+impl<T: ShmSafe> From<Producer<T>> for AsyncProducer<T> {
+    fn from(producer: Producer<T>) -> Self {
+        Self::new(producer)
+    }
+}
+
+impl<T: ShmSafe> Sink<T> for AsyncProducer<T> {
+    type Error = RbufError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), RbufError>> {
+        self.get_mut().poll_push(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), RbufError> {
+        let this = self.get_mut();
+        debug_assert!(this.pending.is_none(), "start_send without poll_ready");
+        // Try right away; if the ring filled up since `poll_ready` the item is
+        // pushed by the next poll instead
+        match this.producer.push(item) {
+            Err(e) if waits(e.error()) => this.pending = Some(e.into_inner()),
+            Err(e) => return Err(e.into_error()),
+            Ok(()) => {}
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), RbufError>> {
+        self.get_mut().poll_push(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), RbufError>> {
+        self.get_mut().poll_push(cx)
+    }
+}
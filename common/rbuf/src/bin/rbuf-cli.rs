@@ -0,0 +1,301 @@
+// rbuf-cli.rs
+//
+// Poke at rings from the shell:
+//
+//   rbuf-cli inspect <name>   dump the header, occupancy and stats
+//   rbuf-cli drain <name>     pop everything pending and hexdump it
+//   rbuf-cli unlink <name>    remove the segment from the system
+//   rbuf-cli list <prefix>    list the rings in a namespace
+//   rbuf-cli gc <prefix>      unlink the rings in a namespace nobody uses
+//   rbuf-cli rings <prefix>   list the rings in the directory whose name
+//                             starts with the prefix ("" for all of them)
+//   rbuf-cli limit <name> <messages/s> <bytes/s>
+//                             hold a typed ring's producers to a rate, 0
+//                             meaning no limit
+//   rbuf-cli pause <name>     stop a typed ring's producers publishing
+//   rbuf-cli resume <name>    let them carry on
+//   rbuf-cli reset-stats <name>
+//                             zero the ring's counters
+//   rbuf-cli log-level <name> <error|warn|info|debug|trace>
+//                             ask the ring's processes to log more or less
+use rbuf::{Codec, Command, Directory, LogLevel, Peer, RateLimit, RbufError, RingBuffer, RingInfo};
+use std::process::ExitCode;
+use std::time::SystemTime;
+
+const USAGE: &str = "usage: rbuf-cli <inspect|drain|unlink|list|gc|rings> <name>\n       \
+                     rbuf-cli <pause|resume|reset-stats> <name>\n       \
+                     rbuf-cli limit <name> <messages/s> <bytes/s>\n       \
+                     rbuf-cli log-level <name> <error|warn|info|debug|trace>";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (command, name, rest) = match args.as_slice() {
+        [command, name, rest @ ..] => (command.as_str(), name.as_str(), rest),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let result = match (command, rest) {
+        ("inspect", []) => inspect(name),
+        ("drain", []) => drain(name),
+        ("unlink", []) => RingBuffer::unlink(name).map(|()| println!("unlinked {}", name)),
+        #[cfg(target_os = "linux")]
+        ("list", []) => list(name),
+        #[cfg(target_os = "linux")]
+        ("gc", []) => gc(name),
+        ("rings", []) => rings(name),
+        ("limit", [messages, bytes]) => match (messages.parse(), bytes.parse()) {
+            (Ok(messages_per_sec), Ok(bytes_per_sec)) => {
+                let limit = RateLimit { messages_per_sec, bytes_per_sec };
+                RingBuffer::set_rate_limit(name, limit)
+                    .map(|()| println!("limited {} to {}", name, rate(&limit)))
+            }
+            _ => {
+                eprintln!("{}", USAGE);
+                return ExitCode::from(2);
+            }
+        },
+        ("pause", []) => send(name, Command::Pause),
+        ("resume", []) => send(name, Command::Resume),
+        ("reset-stats", []) => send(name, Command::ResetStats),
+        ("log-level", [level]) => match log_level(level) {
+            Some(level) => send(name, Command::SetLogLevel(level)),
+            None => {
+                eprintln!("{}", USAGE);
+                return ExitCode::from(2);
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("rbuf-cli: {}: {}", name, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// --- control ---
+
+fn send(name: &str, command: Command) -> Result<(), RbufError> {
+    RingBuffer::send_command(name, command)?;
+    println!("sent {:?} to {}", command, name);
+    Ok(())
+}
+
+fn log_level(level: &str) -> Option<LogLevel> {
+    match level {
+        "error" => Some(LogLevel::Error),
+        "warn" => Some(LogLevel::Warn),
+        "info" => Some(LogLevel::Info),
+        "debug" => Some(LogLevel::Debug),
+        "trace" => Some(LogLevel::Trace),
+        _ => None,
+    }
+}
+
+// --- inspect ---
+
+fn rate(limit: &RateLimit) -> String {
+    let per_sec = |rate: u64, unit: &str| match rate {
+        0 => format!("unlimited {}", unit),
+        rate => format!("{} {}/s", rate, unit),
+    };
+    let messages = per_sec(limit.messages_per_sec, "messages");
+    format!("{}, {}", messages, per_sec(limit.bytes_per_sec, "bytes"))
+}
+
+fn print_peer(label: &str, peer: Option<&Peer>) {
+    if let Some(peer) = peer {
+        let since = peer.last_heartbeat.elapsed().unwrap_or_default();
+        let state = if peer.alive { "alive" } else { "dead" };
+        println!(
+            "{:<16}{} ({}), heartbeat {:.1}s ago",
+            label,
+            peer.pid,
+            state,
+            since.as_secs_f64()
+        );
+    }
+}
+
+fn inspect(name: &str) -> Result<(), RbufError> {
+    let info = RingBuffer::inspect(name)?;
+    print_info(&info);
+    Ok(())
+}
+
+fn print_info(info: &RingInfo) {
+    println!("name            {}", info.name);
+    println!("kind            {:?}", info.kind);
+    println!("version         {}", info.version);
+    println!("generation      {:#018x}", info.generation);
+    println!("segment size    {} bytes", info.segment_size);
+    println!("element         {} bytes, align {}", info.elem_size, info.elem_align);
+    println!("capacity        {}", info.capacity);
+    if info.max_consumers > 0 {
+        println!("max consumers   {}", info.max_consumers);
+    }
+    println!("overwrite       {}", info.overwrite);
+    match info.codec {
+        Some(Codec::None) => {}
+        Some(codec) => println!("codec           {:?}", codec),
+        None => println!("codec           unknown"),
+    }
+    if info.encrypted {
+        println!("encrypted       yes");
+    }
+    if info.mirrored {
+        println!("mirrored        yes");
+    }
+    if info.max_message > 0 {
+        println!("max message     {} bytes (fragmented)", info.max_message);
+    }
+    if info.schema != 0 {
+        println!("schema          {:#018x}", info.schema);
+    }
+    println!("notifier        {:?}", info.notifier);
+    println!("attached        {}", info.attached);
+    if let Some(pid) = info.creator_pid {
+        println!("creator pid     {}", pid);
+    }
+    if let Some(creator) = info.creator {
+        println!("created by      {:?}", creator);
+        println!("consumers       {}", info.consumers);
+        println!("producers       {}", info.producers);
+        if info.closed {
+            println!("closed          yes");
+        }
+        if info.paused {
+            println!("paused          yes");
+        }
+        print_peer("consumer pid", info.consumer_peer.as_ref());
+        print_peer("producer pid", info.producer_peer.as_ref());
+    }
+    println!("head / tail     {} / {}", info.head, info.tail);
+    match info.len {
+        Some(len) => println!("pending         {} of {}", len, info.capacity),
+        None => println!("pending         n/a (per subscriber)"),
+    }
+    for reg in &info.registrations {
+        let since = reg.last_heartbeat.elapsed().unwrap_or_default();
+        let state = match (reg.alive, reg.evicted) {
+            (_, true) => ", evicted",
+            (true, false) => "",
+            (false, false) => ", dead",
+        };
+        println!(
+            "consumer        pid {} at {} (lag {}{}), heartbeat {:.1}s ago",
+            reg.pid,
+            reg.cursor,
+            reg.lag,
+            state,
+            since.as_secs_f64()
+        );
+    }
+
+    let stats = &info.stats;
+    println!("pushes          {}", stats.pushes);
+    println!("pops            {}", stats.pops);
+    println!("full            {}", stats.full);
+    println!("overwritten     {}", stats.overwritten);
+    if stats.corrupt > 0 {
+        println!("corrupt         {}", stats.corrupt);
+    }
+    if let Some(credits) = info.credits {
+        println!("credits         {}", credits);
+    }
+    if info.rate_limit != RateLimit::default() {
+        println!("rate limit      {}", rate(&info.rate_limit));
+    }
+    println!("high watermark  {}", stats.high_watermark);
+    println!("last push       {}", ago(stats.last_push));
+    println!("last pop        {}", ago(stats.last_pop));
+    if let Some(latency) = &stats.latency {
+        println!(
+            "latency         p50 {:?}, p99 {:?}, p99.9 {:?}, max {:?} ({} items)",
+            latency.percentile(0.5),
+            latency.percentile(0.99),
+            latency.percentile(0.999),
+            latency.max(),
+            latency.count()
+        );
+    }
+}
+
+fn ago(time: Option<SystemTime>) -> String {
+    match time.map(|t| t.elapsed()) {
+        None => "never".to_string(),
+        Some(Ok(elapsed)) => format!("{:.3}s ago", elapsed.as_secs_f64()),
+        // Clocks differ between processes; don't pretend otherwise
+        Some(Err(_)) => "in the future".to_string(),
+    }
+}
+
+// --- drain ---
+
+fn drain(name: &str) -> Result<(), RbufError> {
+    let mut index = 0;
+    let drained = RingBuffer::drain(name, |message| {
+        println!("#{} ({} bytes)", index, message.len());
+        hexdump(message);
+        index += 1;
+    })?;
+    println!("drained {} messages", drained);
+    Ok(())
+}
+
+fn hexdump(bytes: &[u8]) {
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        println!("  {:08x}  {:<47}  |{}|", line * 16, hex.join(" "), ascii);
+    }
+}
+
+// --- namespaces ---
+
+#[cfg(target_os = "linux")]
+fn list(prefix: &str) -> Result<(), RbufError> {
+    for ring in rbuf::Namespace::new(prefix).list()? {
+        println!("{}", ring);
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn gc(prefix: &str) -> Result<(), RbufError> {
+    let collected = rbuf::gc::collect(&rbuf::Namespace::new(prefix))?;
+    for ring in &collected {
+        println!("unlinked {}", ring);
+    }
+    println!("collected {} stale rings", collected.len());
+    Ok(())
+}
+
+// --- directory ---
+
+fn rings(prefix: &str) -> Result<(), RbufError> {
+    for entry in Directory::list()?.iter().filter(|entry| entry.name.starts_with(prefix)) {
+        println!(
+            "{:<32} {:<9?} capacity {:<10} type {:#018x}  pid {}, created {}",
+            entry.name,
+            entry.kind,
+            entry.capacity,
+            entry.type_hash,
+            entry.creator_pid,
+            ago(Some(entry.created))
+        );
+    }
+    Ok(())
+}
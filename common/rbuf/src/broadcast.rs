@@ -0,0 +1,610 @@
+// broadcast.rs
+//
+// One ring, many readers. Every subscriber owns an entry in the consumer
+// table that follows the header and keeps its own cursor there; every subscriber sees
+// every message. `tail` is a free-running sequence number and each slot is
+// stamped with `sequence + 1` once its contents are published, so readers
+// can tell a fresh slot from a stale one without looking at `tail`.
+//
+// What a publisher does about subscribers a whole ring behind is up to its
+// `SlowSubscriberPolicy`. By default it never overwrites a slot that the
+// slowest active subscriber still has to read, so one slow subscriber stalls
+// every publisher. Otherwise it overwrites the slot anyway, and the
+// subscriber either skips ahead past what it lost or finds its table entry
+// marked evicted.
+//
+// Slots are written like a seqlock: the stamp is cleared before the value
+// is, so a subscriber copying out a slot that's being overwritten sees the
+// stamp change and throws the copy away. Each slot also carries the wall
+// clock time it was published at, so a subscriber can `seek` to a time.
+//
+// [ header | subscriber table | slots ]
+//
+// A ring made with `Publisher::create_protected` keeps its slots in a second
+// segment, `<name>.data`, that only the publishing user can write and that
+// subscribers map read-only. A misbehaving subscriber can then still stall
+// the ring through its entry in the table, but it can't change what the
+// other subscribers read.
+//
+// [ header | subscriber table ]  [ slots ]
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::directory;
+use crate::error::{PushError, RbufError};
+use crate::header::{RingBufferHeader, RingKind, OPTION_SPLIT_DATA};
+use crate::registry::{self, ConsumerEntry, ConsumerTable};
+#[cfg(unix)]
+use crate::segment::{self, Permissions};
+use crate::segment::{Backing, Segment};
+use crate::shm_safe::ShmSafe;
+
+#[repr(C)]
+struct Slot<T> {
+    // Sequence number of the message in this slot, plus one (0 = never written)
+    stamp: AtomicU64,
+    // When it was published, in nanoseconds since the Unix epoch
+    time: AtomicU64,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+struct BroadcastLayout {
+    table_offset: usize,
+    // In the data segment when the ring is split
+    slots_offset: usize,
+    size: usize,
+    // Size of the data segment, or 0 if the slots follow the table
+    data_size: usize,
+}
+
+impl BroadcastLayout {
+    fn new<T>(capacity: usize, max_subscribers: usize, split: bool) -> Self {
+        let table_offset = registry::table_offset();
+        let table_end = table_offset + registry::table_size(max_subscribers);
+        let slots_size = capacity * mem::size_of::<Slot<T>>();
+        if split {
+            return Self { table_offset, slots_offset: 0, size: table_end, data_size: slots_size };
+        }
+        let align = mem::align_of::<Slot<T>>();
+        let slots_offset = (table_end + align - 1) & !(align - 1);
+        let size = slots_offset + slots_size;
+        Self { table_offset, slots_offset, size, data_size: 0 }
+    }
+}
+
+// The segment holding the slots of a split ring
+pub(crate) fn data_name(name: &str) -> String {
+    format!("{}.data", name)
+}
+
+struct BroadcastRing<T> {
+    segment: Box<dyn Segment>,
+    // The slots, if they have a segment of their own
+    data: Option<Box<dyn Segment>>,
+    header: *const RingBufferHeader,
+    subscribers: ConsumerTable,
+    slots: *const Slot<T>,
+    mask: u64,
+    _phantom: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for BroadcastRing<T> {}
+unsafe impl<T: Send> Sync for BroadcastRing<T> {}
+
+impl<T: ShmSafe + Copy> BroadcastRing<T> {
+    // With `mode`, split the slots out into a data segment that only this
+    // user can write, and give the control segment `mode`
+    fn create(
+        name: &str,
+        capacity: usize,
+        max_subscribers: usize,
+        mode: Option<u32>,
+    ) -> Result<Self, RbufError> {
+        let capacity = capacity.max(1).next_power_of_two();
+        let layout = BroadcastLayout::new::<T>(capacity, max_subscribers, mode.is_some());
+        let segment = Backing::Shm.create(name, layout.size)?;
+        // Nobody attaches before the header is published, so the data
+        // segment can come second
+        let data = match mode {
+            Some(mode) => Some(Self::create_data(&*segment, name, mode, layout.data_size)?),
+            None => None,
+        };
+
+        unsafe {
+            let header = RingBufferHeader::new(
+                RingKind::Broadcast,
+                mem::size_of::<T>(),
+                mem::align_of::<T>(),
+                capacity,
+            )
+            .with_max_consumers(max_subscribers)
+            .with_option(OPTION_SPLIT_DATA, data.is_some())
+            .with_schema(directory::type_hash::<T>());
+            let header = RingBufferHeader::initialize(segment.as_ptr(), header);
+            // Free subscriber entries and never-written slot stamps are all zeroes
+            std::ptr::write_bytes(
+                segment.as_ptr().add(layout.table_offset),
+                0,
+                layout.size - layout.table_offset,
+            );
+            if let Some(data) = &data {
+                std::ptr::write_bytes(data.as_ptr(), 0, layout.data_size);
+            }
+            header.publish();
+            directory::register(name, header);
+        }
+
+        Ok(Self::from_segment(segment, data))
+    }
+
+    #[cfg(unix)]
+    fn create_data(
+        control: &dyn Segment,
+        name: &str,
+        mode: u32,
+        size: usize,
+    ) -> Result<Box<dyn Segment>, RbufError> {
+        let data = Backing::Shm.create(&data_name(name), size)?;
+        let control_permissions = Permissions { mode: Some(mode), ..Default::default() };
+        // Whoever may read the ring may read the slots, but only we write them
+        let data_mode = (mode & !0o222) | 0o200;
+        let data_permissions = Permissions { mode: Some(data_mode), ..control_permissions };
+        control
+            .set_permissions(&control_permissions)
+            .and_then(|()| data.set_permissions(&data_permissions))
+            .map_err(RbufError::Permissions)?;
+        Ok(data)
+    }
+
+    #[cfg(not(unix))]
+    fn create_data(
+        _control: &dyn Segment,
+        _name: &str,
+        _mode: u32,
+        _size: usize,
+    ) -> Result<Box<dyn Segment>, RbufError> {
+        unreachable!("split rings are only created on unix")
+    }
+
+    // Subscribers of a split ring map its slots with `read_only`
+    fn open(name: &str, read_only: bool) -> Result<Self, RbufError> {
+        let segment = Backing::Shm.open(name)?;
+
+        let header = RingBufferHeader::validate(
+            segment.as_ptr(),
+            segment.len(),
+            RingKind::Broadcast,
+            mem::size_of::<T>(),
+            mem::align_of::<T>(),
+        )?;
+        header.check_schema(directory::type_hash::<T>())?;
+        let split = header.options.has(OPTION_SPLIT_DATA);
+        let layout = BroadcastLayout::new::<T>(header.capacity(), header.max_consumers(), split);
+        if segment.len() < layout.size {
+            return Err(RbufError::SizeMismatch { expected: layout.size, actual: segment.len() });
+        }
+
+        let data = if split { Some(Self::open_data(name, read_only)?) } else { None };
+        if let Some(data) = &data {
+            if data.len() < layout.data_size {
+                let (expected, actual) = (layout.data_size, data.len());
+                return Err(RbufError::SizeMismatch { expected, actual });
+            }
+        }
+
+        Ok(Self::from_segment(segment, data))
+    }
+
+    fn open_data(name: &str, read_only: bool) -> Result<Box<dyn Segment>, RbufError> {
+        let name = data_name(name);
+        #[cfg(unix)]
+        if read_only {
+            return segment::open_shm_read_only(&name);
+        }
+        // Split rings only exist on unix
+        #[cfg(not(unix))]
+        let _ = read_only;
+        Backing::Shm.open(&name)
+    }
+
+    fn from_segment(segment: Box<dyn Segment>, data: Option<Box<dyn Segment>>) -> Self {
+        let header = segment.as_ptr() as *const RingBufferHeader;
+        let (capacity, max_subscribers) =
+            unsafe { ((*header).capacity(), (*header).max_consumers()) };
+        let layout = BroadcastLayout::new::<T>(capacity, max_subscribers, data.is_some());
+        let subscribers = unsafe { ConsumerTable::new(segment.as_ptr(), max_subscribers) };
+        let base = data.as_ref().unwrap_or(&segment).as_ptr();
+        let slots = unsafe { base.add(layout.slots_offset) } as *const Slot<T>;
+        unsafe { (*header).attached.fetch_add(1, Ordering::AcqRel) };
+        event!(debug, ring = segment.name(), kind = "broadcast", capacity, "mapped ring");
+        let mask = capacity as u64 - 1;
+        Self { segment, data, header, subscribers, slots, mask, _phantom: PhantomData }
+    }
+
+    fn set_owner(&mut self, owner: bool) {
+        self.segment.set_owner(owner);
+        if let Some(data) = &mut self.data {
+            data.set_owner(owner);
+        }
+    }
+
+    fn header(&self) -> &RingBufferHeader {
+        unsafe { &*self.header }
+    }
+
+    fn slot(&self, seq: u64) -> &Slot<T> {
+        unsafe { &*self.slots.add((seq & self.mask) as usize) }
+    }
+
+    // How far the slowest active subscriber is behind `tail`
+    fn max_lag(&self, tail: u64) -> u64 {
+        self.subscribers
+            .entries()
+            .iter()
+            .filter(|entry| entry.is_active())
+            .map(|entry| tail.wrapping_sub(entry.cursor.load(Ordering::Acquire)))
+            // A cursor ahead of our snapshot of `tail` just registered
+            .filter(|lag| *lag <= i64::MAX as u64)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl<T> Drop for BroadcastRing<T> {
+    fn drop(&mut self) {
+        unsafe { (*self.header).detach() };
+        event!(debug, ring = self.segment.name(), kind = "broadcast", "detached from ring");
+    }
+}
+
+// --- Publisher ---
+
+// What `push` does when the slowest subscriber is a whole ring behind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlowSubscriberPolicy {
+    // Fail with `RbufError::Full` until it catches up
+    #[default]
+    Block,
+    // Overwrite its oldest unread message; its next pop fails with `Lagged`
+    // and skips to the oldest message still in the ring
+    DropOldest,
+    // Overwrite it and mark it evicted, after which its pops fail with
+    // `Evicted` and publishers ignore it
+    Evict,
+}
+
+pub struct Publisher<T: ShmSafe + Copy> {
+    ring: BroadcastRing<T>,
+    policy: SlowSubscriberPolicy,
+}
+
+impl<T: ShmSafe + Copy> Publisher<T> {
+    // Create the segment with `capacity` slots (rounded up to a power of two)
+    // and room for `max_subscribers`
+    pub fn create(name: &str, capacity: usize, max_subscribers: usize) -> Result<Self, RbufError> {
+        Ok(Self::from_ring(BroadcastRing::create(name, capacity, max_subscribers, None)?))
+    }
+
+    // `create`, with the slots in a segment of their own that subscribers
+    // can only map read-only. The ring gets `mode` (e.g. 0o660 to let a
+    // group subscribe); the slots get it without write access for anyone
+    // but this user, so only publishers running as this user can `open`.
+    #[cfg(unix)]
+    pub fn create_protected(
+        name: &str,
+        capacity: usize,
+        max_subscribers: usize,
+        mode: u32,
+    ) -> Result<Self, RbufError> {
+        let ring = BroadcastRing::create(name, capacity, max_subscribers, Some(mode))?;
+        Ok(Self::from_ring(ring))
+    }
+
+    // Attach an additional publisher to an existing broadcast ring
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        Ok(Self::from_ring(BroadcastRing::open(name, false)?))
+    }
+
+    fn from_ring(ring: BroadcastRing<T>) -> Self {
+        Self { ring, policy: SlowSubscriberPolicy::Block }
+    }
+
+    pub fn slow_subscriber_policy(&self) -> SlowSubscriberPolicy {
+        self.policy
+    }
+
+    // Each publisher has its own policy; a ring where one blocks and another
+    // doesn't is only as strict as the laxest
+    pub fn set_slow_subscriber_policy(&mut self, policy: SlowSubscriberPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn name(&self) -> &str {
+        self.ring.segment.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.ring.header().attached.load(Ordering::Acquire) as usize
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.ring.set_owner(unlink);
+    }
+
+    // Fails with `Full` while the slowest subscriber is a whole ring behind,
+    // unless the policy says to leave it behind
+    pub fn push(&self, item: T) -> Result<(), PushError<T>> {
+        let header = self.ring.header();
+        let capacity = header.capacity() as u64;
+        let mut tail = header.tail.load(Ordering::Acquire);
+        loop {
+            let block = self.policy == SlowSubscriberPolicy::Block;
+            if block && self.ring.max_lag(tail) >= capacity {
+                return Err(PushError::new(RbufError::Full, item));
+            }
+
+            match header.tail.compare_exchange_weak(
+                tail,
+                tail.wrapping_add(1),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(current) => tail = current,
+            }
+        }
+
+        if self.policy == SlowSubscriberPolicy::Evict {
+            self.evict_behind(tail);
+        }
+
+        let slot = self.ring.slot(tail);
+        slot.stamp.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        slot.time.store(nanos(SystemTime::now()), Ordering::Relaxed);
+        unsafe { (*slot.value.get()).write(item) };
+        slot.stamp.store(tail.wrapping_add(1), Ordering::Release);
+        header.data_ready.notify();
+        Ok(())
+    }
+
+    // Evict every subscriber that still has to read the message `seq` is
+    // about to overwrite
+    fn evict_behind(&self, seq: u64) {
+        let capacity = self.ring.header().capacity() as u64;
+        for entry in self.ring.subscribers.entries().iter().filter(|entry| entry.is_active()) {
+            let lag = seq.wrapping_sub(entry.cursor.load(Ordering::Acquire));
+            if lag >= capacity && lag <= i64::MAX as u64 && entry.evict() {
+                event!(
+                    warn,
+                    ring = self.name(),
+                    pid = entry.pid.load(Ordering::Relaxed),
+                    lag,
+                    "evicted slow subscriber"
+                );
+            }
+        }
+    }
+
+    // Push, sleeping until the slowest subscriber catches up if the ring is full
+    pub fn push_blocking(&self, mut item: T) {
+        loop {
+            match self.push(item) {
+                Ok(()) => return,
+                Err(e) => item = e.into_inner(),
+            }
+
+            let space_ready = &self.ring.header().space_ready;
+            let seq = space_ready.prepare_wait();
+            match self.push(item) {
+                Ok(()) => {
+                    space_ready.cancel_wait();
+                    return;
+                }
+                Err(e) => item = e.into_inner(),
+            }
+            space_ready.wait(seq);
+        }
+    }
+}
+
+// --- Subscriber ---
+
+// Where `Subscriber::seek` moves to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    // The oldest message still in the ring
+    Oldest,
+    // Past every message published so far, as if just subscribed
+    Latest,
+    // The message with this sequence number, or the nearest one still in
+    // the ring
+    Sequence(u64),
+    // The first message published at or after this time
+    Timestamp(SystemTime),
+}
+
+pub struct Subscriber<T: ShmSafe + Copy> {
+    ring: BroadcastRing<T>,
+    entry: usize,
+}
+
+impl<T: ShmSafe + Copy> Subscriber<T> {
+    // Register in the subscriber table. The new subscriber only sees messages
+    // published after it joined.
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        let ring = BroadcastRing::open(name, true)?;
+        let tail = &ring.header().tail;
+        let entry = ring.subscribers.register(|| tail.load(Ordering::Acquire))?;
+        Ok(Self { ring, entry })
+    }
+
+    pub fn name(&self) -> &str {
+        self.ring.segment.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.ring.header().attached.load(Ordering::Acquire) as usize
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.ring.set_owner(unlink);
+    }
+
+    fn entry(&self) -> &ConsumerEntry {
+        self.ring.subscribers.entry(self.entry)
+    }
+
+    // Record that this subscriber is still alive, for tools watching the ring
+    pub fn heartbeat(&self) {
+        self.entry().beat();
+    }
+
+    // Returns `Lagged` (and skips ahead to the oldest message still in the
+    // ring) if publishers overwrote messages this subscriber hadn't read yet,
+    // and `Evicted` once a publisher has evicted it; see `rejoin`.
+    pub fn pop(&mut self) -> Result<T, RbufError> {
+        if self.entry().is_evicted() {
+            return Err(RbufError::Evicted);
+        }
+        let cursor = self.entry().cursor.load(Ordering::Relaxed);
+        let expected = cursor.wrapping_add(1);
+        let slot = self.ring.slot(cursor);
+
+        let stamp = slot.stamp.load(Ordering::Acquire);
+        if stamp == expected {
+            let item = unsafe { (*slot.value.get()).assume_init_read() };
+            // Make sure the slot wasn't reused while we were copying it out
+            fence(Ordering::Acquire);
+            if slot.stamp.load(Ordering::Relaxed) == expected {
+                self.entry().cursor.store(expected, Ordering::Release);
+                self.ring.header().space_ready.notify();
+                return Ok(item);
+            }
+        } else if (stamp.wrapping_sub(expected) as i64) < 0 {
+            return Err(RbufError::Empty);
+        }
+
+        // Evicted while we were reading, and the slot overwritten
+        if self.entry().is_evicted() {
+            return Err(RbufError::Evicted);
+        }
+        let tail = self.ring.header().tail.load(Ordering::Acquire);
+        let oldest = tail.wrapping_sub(self.ring.header().capacity() as u64);
+        self.entry().cursor.store(oldest, Ordering::Release);
+        Err(RbufError::Lagged { missed: oldest.wrapping_sub(cursor) as usize })
+    }
+
+    // Sequence number of the next message `pop` returns. Sequence numbers
+    // count every message ever published to the ring.
+    pub fn sequence(&self) -> u64 {
+        self.entry().cursor.load(Ordering::Relaxed)
+    }
+
+    // Move to another message, so the next `pop` returns it, and return its
+    // sequence number. Lets a subscriber that joins late read what the ring
+    // still holds, or one that fell behind skip to the present. Messages
+    // before the new position aren't waited for; seeking back to some still
+    // to be read means a blocking publisher waits for them again.
+    pub fn seek(&mut self, to: SeekFrom) -> Result<u64, RbufError> {
+        if self.entry().is_evicted() {
+            return Err(RbufError::Evicted);
+        }
+        let tail = self.ring.header().tail.load(Ordering::Acquire);
+        let oldest = tail.saturating_sub(self.ring.header().capacity() as u64);
+        let seq = match to {
+            SeekFrom::Oldest => oldest,
+            SeekFrom::Latest => tail,
+            SeekFrom::Sequence(seq) => seq.clamp(oldest, tail),
+            SeekFrom::Timestamp(time) => self.first_since(oldest, tail, nanos(time)),
+        };
+        self.entry().cursor.store(seq, Ordering::Release);
+        self.ring.header().space_ready.notify();
+        Ok(seq)
+    }
+
+    // The first message from `oldest` up to `tail` published at or after
+    // `time`, or `tail` if there's none, by bisecting on the slots' times.
+    // Publishers racing on `tail` can leave the times slightly out of
+    // order, which makes this a message or two off at worst.
+    fn first_since(&self, mut oldest: u64, mut tail: u64, time: u64) -> u64 {
+        let header = self.ring.header();
+        while oldest < tail {
+            let mid = oldest + (tail - oldest) / 2;
+            let slot = self.ring.slot(mid);
+            let expected = mid.wrapping_add(1);
+            let stamp = slot.stamp.load(Ordering::Acquire);
+            let published = slot.time.load(Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            let before = if stamp == expected {
+                // Unless it changed under us, it's the message's own time
+                slot.stamp.load(Ordering::Relaxed) != expected || published < time
+            } else if stamp == 0 {
+                // Being written: either the message itself, still being
+                // published, or a newer one overwriting it
+                let lag = header.tail.load(Ordering::Acquire).wrapping_sub(mid);
+                lag > header.capacity() as u64
+            } else {
+                // Overwritten since we read `tail`, so older than anything
+                // left, or still holding an older message than the one
+                // being published into it
+                stamp > expected
+            };
+            if before {
+                oldest = mid + 1;
+            } else {
+                tail = mid;
+            }
+        }
+        oldest
+    }
+
+    // Register again after being evicted, again only seeing messages
+    // published from now on
+    pub fn rejoin(&mut self) -> Result<(), RbufError> {
+        let tail = &self.ring.header().tail;
+        let entry = self.ring.subscribers.register(|| tail.load(Ordering::Acquire))?;
+        self.ring.subscribers.unregister(mem::replace(&mut self.entry, entry));
+        Ok(())
+    }
+
+    // Pop, sleeping until a publisher pushes if there's nothing new
+    pub fn pop_blocking(&mut self) -> Result<T, RbufError> {
+        loop {
+            match self.pop() {
+                Err(RbufError::Empty) => {}
+                result => return result,
+            }
+
+            let seq = self.ring.header().data_ready.prepare_wait();
+            match self.pop() {
+                Err(RbufError::Empty) => self.ring.header().data_ready.wait(seq),
+                result => {
+                    self.ring.header().data_ready.cancel_wait();
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+impl<T: ShmSafe + Copy> Drop for Subscriber<T> {
+    // Free our table entry so publishers stop waiting on us
+    fn drop(&mut self) {
+        self.ring.subscribers.unregister(self.entry);
+        self.ring.header().space_ready.notify();
+    }
+}
+
+fn nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
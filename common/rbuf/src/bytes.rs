@@ -0,0 +1,878 @@
+// bytes.rs
+//
+// Variable-length messages. The data region is a plain byte ring holding
+// records of the form [ state: u32 | len: u32 | payload | padding to 8 ].
+// Writers claim space with a CAS on `tail` exactly like the typed ring, copy
+// the payload, and then flip the record state to COMMITTED. A record that
+// would straddle the end of the ring is preceded by a PADDING record that
+// fills the rest of the buffer, so payloads are always contiguous.
+//
+// Rings created with a `Codec` may also hold COMPRESSED records, which the
+// reader decompresses on pop; see codec.rs. On encrypted rings every payload
+// is sealed (after compression) and opened again on pop; see crypto.rs.
+//
+// Rings created with a max message size carry messages too big for one
+// record as a chain of FRAGMENT records, each holding
+//
+//     [ message id: u64 | index: u32 | flags: u32 | piece of the message ]
+//
+// Fragments of messages from different writers may interleave, so the
+// reader collects them per message id and hands the message out when its
+// LAST fragment arrives. Messages are compressed before they are split and
+// every fragment is sealed on its own.
+use std::collections::HashMap;
+use std::mem;
+use std::ops::Deref;
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
+
+use crate::codec::Codec;
+use crate::crypto::Cipher;
+use crate::directory;
+use crate::error::RbufError;
+use crate::header::{RingBufferHeader, RingKind, OPTION_ENCRYPTED};
+use crate::notify::WaitQueue;
+use crate::segment::{Backing, Segment};
+
+const RECORD_EMPTY: u32 = 0;
+const RECORD_COMMITTED: u32 = 1;
+const RECORD_PADDING: u32 = 2;
+const RECORD_COMPRESSED: u32 = 3;
+const RECORD_FRAGMENT: u32 = 4;
+
+const RECORD_HEADER_SIZE: usize = 8;
+const RECORD_ALIGN: usize = 8;
+
+const FRAGMENT_HEADER_SIZE: usize = 16;
+// Fragment flags
+const FRAGMENT_LAST: u32 = 1 << 0;
+const FRAGMENT_COMPRESSED: u32 = 1 << 1;
+
+// Unique among the writers of a ring as long as no process sends 2^32
+// fragmented messages while a fragment of its first is still unread
+fn message_id() -> u64 {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    (process::id() as u64) << 32 | NEXT.fetch_add(1, Ordering::Relaxed) as u64
+}
+
+// Sleep on `queue` after `prepare_wait` returned `seq`, or fail with
+// `Timeout` if `deadline` has passed
+fn wait_until(queue: &WaitQueue, seq: u32, deadline: Option<Instant>) -> Result<(), RbufError> {
+    let Some(deadline) = deadline else {
+        queue.wait(seq);
+        return Ok(());
+    };
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(left) if !left.is_zero() => {
+            queue.wait_timeout(seq, left);
+            Ok(())
+        }
+        _ => {
+            queue.cancel_wait();
+            Err(RbufError::Timeout)
+        }
+    }
+}
+
+fn align_up(n: usize) -> usize {
+    (n + RECORD_ALIGN - 1) & !(RECORD_ALIGN - 1)
+}
+
+fn record_size(len: usize) -> usize {
+    RECORD_HEADER_SIZE + align_up(len)
+}
+
+fn max_record_size(capacity: usize) -> usize {
+    (capacity / 2) & !(RECORD_ALIGN - 1)
+}
+
+pub(crate) fn data_offset() -> usize {
+    align_up(mem::size_of::<RingBufferHeader>())
+}
+
+// The byte ring's view of the segment. `head` and `tail` in the header are
+// free-running byte positions; the offset into the data is `pos & mask`.
+struct ByteRing {
+    segment: Box<dyn Segment>,
+    header: *const RingBufferHeader,
+    data: *mut u8,
+    mask: u64,
+    codec: Codec,
+    cipher: Option<Cipher>,
+}
+
+unsafe impl Send for ByteRing {}
+unsafe impl Sync for ByteRing {}
+
+impl ByteRing {
+    fn from_segment(segment: Box<dyn Segment>, cipher: Option<Cipher>) -> Self {
+        let header = segment.as_ptr() as *const RingBufferHeader;
+        let data = unsafe { segment.as_ptr().add(data_offset()) };
+        let mask = unsafe { (*header).capacity() } as u64 - 1;
+        // Checked by whoever created or attached to the segment
+        let codec = Codec::from_id(unsafe { &*header }.options.codec).unwrap_or_default();
+        unsafe { (*header).attached.fetch_add(1, Ordering::AcqRel) };
+        event!(debug, ring = segment.name(), kind = "bytes", capacity = mask + 1, "mapped ring");
+        Self { segment, header, data, mask, codec, cipher }
+    }
+
+    fn attach(segment: Box<dyn Segment>, cipher: Option<Cipher>) -> Result<Self, RbufError> {
+        let header =
+            RingBufferHeader::validate(segment.as_ptr(), segment.len(), RingKind::Bytes, 1, 1)?;
+
+        let expected = data_offset() + header.capacity();
+        if segment.len() < expected {
+            return Err(RbufError::SizeMismatch { expected, actual: segment.len() });
+        }
+        Codec::check(header.options.codec)?;
+
+        match (header.options.has(OPTION_ENCRYPTED), &cipher) {
+            (false, None) => {}
+            (false, Some(_)) => {
+                return Err(RbufError::IncompatibleLayout("ring isn't encrypted".to_string()));
+            }
+            (true, None) => {
+                return Err(RbufError::IncompatibleLayout(
+                    "ring is encrypted and needs its key".to_string(),
+                ));
+            }
+            (true, Some(cipher)) => {
+                if cipher.key_check() != header.options.key_check {
+                    return Err(RbufError::KeyMismatch);
+                }
+            }
+        }
+
+        Ok(Self::from_segment(segment, cipher))
+    }
+
+    fn header(&self) -> &RingBufferHeader {
+        unsafe { &*self.header }
+    }
+
+    fn attached(&self) -> usize {
+        self.header().attached.load(Ordering::Acquire) as usize
+    }
+
+    fn schema(&self) -> u64 {
+        self.header().options.schema
+    }
+
+    // Bytes each record spends on encryption
+    fn overhead(&self) -> usize {
+        if self.cipher.is_some() {
+            Cipher::OVERHEAD
+        } else {
+            0
+        }
+    }
+
+    // The largest message that fits in one record
+    fn max_record_message(&self) -> usize {
+        max_record_size(self.header().capacity()) - RECORD_HEADER_SIZE - self.overhead()
+    }
+
+    // How much of a message each fragment carries, if the ring takes them
+    fn fragment_size(&self) -> Option<usize> {
+        if self.header().options.max_message == 0 {
+            return None;
+        }
+        self.max_record_message().checked_sub(FRAGMENT_HEADER_SIZE).filter(|&size| size > 0)
+    }
+
+    // The largest message writers may push
+    fn max_message(&self) -> usize {
+        match self.fragment_size() {
+            Some(_) => {
+                let max_message = self.header().options.max_message as usize;
+                max_message.max(self.max_record_message())
+            }
+            None => self.max_record_message(),
+        }
+    }
+
+    fn record_state(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(self.data.add(offset) as *const AtomicU32) }
+    }
+
+    fn record_len_ptr(&self, offset: usize) -> *mut u32 {
+        unsafe { self.data.add(offset + 4) as *mut u32 }
+    }
+
+    fn payload_ptr(&self, offset: usize) -> *mut u8 {
+        unsafe { self.data.add(offset + RECORD_HEADER_SIZE) }
+    }
+
+    // Whether a record of `len` bytes at `offset` is one a writer could have
+    // made: padding runs to the end of the ring, and nothing else is bigger
+    // than the largest record
+    fn fits(&self, offset: usize, len: usize, state: u32) -> bool {
+        // Not the header's capacity, which could have changed since we mapped
+        let capacity = self.mask as usize + 1;
+        let to_end = capacity - offset - RECORD_HEADER_SIZE;
+        let max = match state {
+            RECORD_PADDING => to_end,
+            _ => (max_record_size(capacity) - RECORD_HEADER_SIZE).min(to_end),
+        };
+        len <= max
+    }
+
+    // Hand the record at `head` back to writers
+    fn release(&self, head: u64, offset: usize, len: usize) {
+        let header = self.header();
+        self.record_state(offset).store(RECORD_EMPTY, Ordering::Relaxed);
+        header.head.store(head.wrapping_add(record_size(len) as u64), Ordering::Release);
+        header.space_ready.notify();
+    }
+}
+
+impl Drop for ByteRing {
+    fn drop(&mut self) {
+        self.header().detach();
+        event!(debug, ring = self.segment.name(), kind = "bytes", "detached from ring");
+    }
+}
+
+// Pop every message, for tools that didn't create the ring
+pub(crate) fn drain_raw(
+    segment: Box<dyn Segment>,
+    f: &mut dyn FnMut(&[u8]),
+) -> Result<usize, RbufError> {
+    let mut reader = Reader::from_ring(ByteRing::attach(segment, None)?);
+    let mut buf = Vec::new();
+    let mut drained = 0;
+    while reader.pop_bytes(&mut buf).is_ok() {
+        f(&buf);
+        drained += 1;
+    }
+    Ok(drained)
+}
+
+// --- Writer ---
+
+pub struct Writer {
+    ring: ByteRing,
+}
+
+impl Writer {
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        let segment = Backing::Shm.open(name)?;
+        Ok(Self { ring: ByteRing::attach(segment, None)? })
+    }
+
+    // Open a ring made with `Reader::create_encrypted`. Fails with
+    // `KeyMismatch` if `key` isn't the one it was created with.
+    #[cfg(feature = "encryption")]
+    pub fn open_encrypted(name: &str, key: &[u8; 32]) -> Result<Self, RbufError> {
+        let segment = Backing::Shm.open(name)?;
+        Ok(Self { ring: ByteRing::attach(segment, Some(Cipher::new(key)))? })
+    }
+
+    pub fn name(&self) -> &str {
+        self.ring.segment.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.ring.attached()
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.ring.segment.set_owner(unlink);
+    }
+
+    // The largest payload that can ever be pushed into this ring. Records are
+    // capped at half the ring so one that needs padding always fits once the
+    // reader has caught up; rings that take fragmented messages say how big
+    // those may get instead.
+    pub fn max_message_size(&self) -> usize {
+        self.ring.max_message()
+    }
+
+    // The hash of the message schema the creator recorded, or 0 if it
+    // didn't record one
+    pub fn schema(&self) -> u64 {
+        self.ring.schema()
+    }
+
+    // The compression the ring's creator chose
+    pub fn codec(&self) -> Codec {
+        self.ring.codec
+    }
+
+    // Like `Producer::push`, safe to call from several writers at once. On a
+    // ring with a codec the message is compressed first, and
+    // `MessageTooLarge` is about its compressed size.
+    //
+    // A message that has to be fragmented only fails with `Full` if its
+    // first fragment doesn't fit; after that it waits for the reader to make
+    // room for the rest.
+    pub fn push_bytes(&self, bytes: &[u8]) -> Result<(), RbufError> {
+        let compressed = self.ring.codec.compress(bytes);
+        let (message, state) = match &compressed {
+            Some(record) => (record.as_slice(), RECORD_COMPRESSED),
+            None => (bytes, RECORD_COMMITTED),
+        };
+        match self.ring.fragment_size() {
+            Some(size) if message.len() > self.ring.max_record_message() => {
+                let max = self.max_message_size();
+                if message.len() > max {
+                    return Err(RbufError::MessageTooLarge { size: message.len(), max });
+                }
+                self.push_fragments(message, state == RECORD_COMPRESSED, size)
+            }
+            _ => self.push_record(message, state, false),
+        }
+    }
+
+    fn push_fragments(
+        &self,
+        message: &[u8],
+        compressed: bool,
+        size: usize,
+    ) -> Result<(), RbufError> {
+        let id = message_id();
+        let count = message.len().div_ceil(size);
+        let mut record = Vec::with_capacity(FRAGMENT_HEADER_SIZE + size);
+        for (index, piece) in message.chunks(size).enumerate() {
+            let mut flags = if compressed { FRAGMENT_COMPRESSED } else { 0 };
+            if index + 1 == count {
+                flags |= FRAGMENT_LAST;
+            }
+            record.clear();
+            record.extend_from_slice(&id.to_le_bytes());
+            record.extend_from_slice(&(index as u32).to_le_bytes());
+            record.extend_from_slice(&flags.to_le_bytes());
+            record.extend_from_slice(piece);
+            self.push_record(&record, RECORD_FRAGMENT, index > 0)?;
+        }
+        Ok(())
+    }
+
+    // Write one record, waiting for space if `wait` is set
+    fn push_record(&self, message: &[u8], state: u32, wait: bool) -> Result<(), RbufError> {
+        let len = message.len() + self.ring.overhead();
+        let (pos, offset) = if wait { self.claim_blocking(len)? } else { self.claim(len)? };
+
+        // Sealed in private memory, so the plaintext never touches the segment
+        let sealed = self.ring.cipher.as_ref().map(|cipher| cipher.seal(pos, state, message));
+        let payload = sealed.as_deref().unwrap_or(message);
+        unsafe {
+            let len = payload.len();
+            ptr::copy_nonoverlapping(payload.as_ptr(), self.ring.payload_ptr(offset), len);
+            self.ring.record_len_ptr(offset).write(payload.len() as u32);
+        }
+        self.ring.record_state(offset).store(state, Ordering::Release);
+        self.ring.header().data_ready.notify();
+        Ok(())
+    }
+
+    // Push, sleeping until the reader frees enough space if the ring is full
+    pub fn push_bytes_blocking(&self, bytes: &[u8]) -> Result<(), RbufError> {
+        loop {
+            match self.push_bytes(bytes) {
+                Err(RbufError::Full) => {}
+                result => return result,
+            }
+
+            let space_ready = &self.ring.header().space_ready;
+            let seq = space_ready.prepare_wait();
+            match self.push_bytes(bytes) {
+                Err(RbufError::Full) => space_ready.wait(seq),
+                result => {
+                    space_ready.cancel_wait();
+                    return result;
+                }
+            }
+        }
+    }
+
+    // `push_bytes_blocking` that gives up with `Timeout` at `deadline`
+    pub(crate) fn push_bytes_until(
+        &self,
+        bytes: &[u8],
+        deadline: Instant,
+    ) -> Result<(), RbufError> {
+        let space_ready = &self.ring.header().space_ready;
+        loop {
+            match self.push_bytes(bytes) {
+                Err(RbufError::Full) => {}
+                result => return result,
+            }
+
+            let seq = space_ready.prepare_wait();
+            match self.push_bytes(bytes) {
+                Err(RbufError::Full) => wait_until(space_ready, seq, Some(deadline))?,
+                result => {
+                    space_ready.cancel_wait();
+                    return result;
+                }
+            }
+        }
+    }
+
+    fn claim_blocking(&self, len: usize) -> Result<(u64, usize), RbufError> {
+        let space_ready = &self.ring.header().space_ready;
+        loop {
+            match self.claim(len) {
+                Err(RbufError::Full) => {}
+                result => return result,
+            }
+
+            let seq = space_ready.prepare_wait();
+            match self.claim(len) {
+                Err(RbufError::Full) => space_ready.wait(seq),
+                result => {
+                    space_ready.cancel_wait();
+                    return result;
+                }
+            }
+        }
+    }
+
+    // Reserve room for a record of `len` bytes and return its position and
+    // data offset
+    fn claim(&self, len: usize) -> Result<(u64, usize), RbufError> {
+        let header = self.ring.header();
+        let capacity = header.capacity();
+        let size = record_size(len);
+        if size > max_record_size(capacity) || len > u32::MAX as usize {
+            let size = len - self.ring.overhead();
+            return Err(RbufError::MessageTooLarge { size, max: self.ring.max_record_message() });
+        }
+
+        let mut tail = header.tail.load(Ordering::Acquire);
+        loop {
+            let head = header.head.load(Ordering::Acquire);
+            let offset = (tail & self.ring.mask) as usize;
+            let to_end = capacity - offset;
+            // If the record doesn't fit before the end we also pay for the padding
+            let needed = if size > to_end { to_end + size } else { size };
+
+            if tail.wrapping_sub(head) + needed as u64 > capacity as u64 {
+                return Err(RbufError::Full);
+            }
+
+            match header.tail.compare_exchange_weak(
+                tail,
+                tail.wrapping_add(needed as u64),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    if size > to_end {
+                        let padding = (to_end - RECORD_HEADER_SIZE) as u32;
+                        unsafe { self.ring.record_len_ptr(offset).write(padding) };
+                        self.ring.record_state(offset).store(RECORD_PADDING, Ordering::Release);
+                        return Ok((tail.wrapping_add(to_end as u64), 0));
+                    }
+                    return Ok((tail, offset));
+                }
+                Err(current) => tail = current,
+            }
+        }
+    }
+}
+
+// --- Reader ---
+
+pub struct Reader {
+    ring: ByteRing,
+    // Fragmented messages still missing their last fragment, by message id
+    partial: HashMap<u64, Partial>,
+}
+
+#[derive(Default)]
+struct Partial {
+    data: Vec<u8>,
+    // Index of the fragment that comes next
+    next: u32,
+    // Bytes received so far, kept counting once `data` is dropped for
+    // growing past the max message size
+    size: usize,
+    too_large: bool,
+}
+
+impl Reader {
+    // Create the segment with room for `capacity` bytes of records, rounded
+    // up to a power of two
+    pub fn create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        Self::create_with(name, capacity, 0, Codec::None, None, 0)
+    }
+
+    // `create`, with every writer compressing its messages with `codec`.
+    // Fails with `IncompatibleLayout` if this build doesn't support it.
+    pub fn create_with_codec(name: &str, capacity: usize, codec: Codec) -> Result<Self, RbufError> {
+        Self::create_with(name, capacity, 0, codec, None, 0)
+    }
+
+    // `create_with_codec`, also taking messages too big for one record, up
+    // to `max_message` bytes, which writers split into fragments and `pop`
+    // puts back together. Partly received messages are held in private
+    // memory, so a reader may hold several times `max_message` while
+    // writers interleave them.
+    pub fn create_fragmented(
+        name: &str,
+        capacity: usize,
+        codec: Codec,
+        max_message: usize,
+    ) -> Result<Self, RbufError> {
+        Self::create_with(name, capacity, 0, codec, None, max_message)
+    }
+
+    // `create_with_codec`, with every message encrypted and authenticated
+    // under `key`. Writers have to open the ring with the same key, which
+    // has to reach them some way other than the segment.
+    #[cfg(feature = "encryption")]
+    pub fn create_encrypted(
+        name: &str,
+        capacity: usize,
+        codec: Codec,
+        key: &[u8; 32],
+    ) -> Result<Self, RbufError> {
+        Self::create_with(name, capacity, 0, codec, Some(Cipher::new(key)), 0)
+    }
+
+    // `create`, also recording the schema that writers have to match
+    pub(crate) fn create_with(
+        name: &str,
+        capacity: usize,
+        schema: u64,
+        codec: Codec,
+        cipher: Option<Cipher>,
+        max_message: usize,
+    ) -> Result<Self, RbufError> {
+        Codec::check(codec.id())?;
+        let capacity = capacity.max(2 * RECORD_HEADER_SIZE).next_power_of_two();
+        let segment = Backing::Shm.create(name, data_offset() + capacity)?;
+
+        unsafe {
+            let header = RingBufferHeader::initialize(
+                segment.as_ptr(),
+                RingBufferHeader::new(RingKind::Bytes, 1, 1, capacity)
+                    .with_schema(schema)
+                    .with_codec(codec.id())
+                    .with_option(OPTION_ENCRYPTED, cipher.is_some())
+                    .with_key_check(cipher.as_ref().map_or(0, Cipher::key_check))
+                    .with_max_message(max_message),
+            );
+            // Every record state must start out EMPTY
+            ptr::write_bytes(segment.as_ptr().add(data_offset()), 0, capacity);
+            header.publish();
+            directory::register(name, header);
+        }
+
+        Ok(Self::from_ring(ByteRing::from_segment(segment, cipher)))
+    }
+
+    fn from_ring(ring: ByteRing) -> Self {
+        Self { ring, partial: HashMap::new() }
+    }
+
+    pub fn name(&self) -> &str {
+        self.ring.segment.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.ring.attached()
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.ring.segment.set_owner(unlink);
+    }
+
+    // The compression writers use
+    pub fn codec(&self) -> Codec {
+        self.ring.codec
+    }
+
+    // Step over padding to the next committed record, returning its position,
+    // data offset, stored length and state
+    fn next_record(&self) -> Result<(u64, usize, usize, u32), RbufError> {
+        let header = self.ring.header();
+        loop {
+            let head = header.head.load(Ordering::Relaxed);
+            let tail = header.tail.load(Ordering::Acquire);
+
+            if head == tail {
+                return Err(RbufError::Empty);
+            }
+
+            let offset = (head & self.ring.mask) as usize;
+            if !offset.is_multiple_of(RECORD_ALIGN) {
+                event!(warn, ring = self.ring.segment.name(), head, "misaligned head");
+                return Err(RbufError::CorruptMessage { seq: head });
+            }
+            let state = self.ring.record_state(offset).load(Ordering::Acquire);
+            match state {
+                RECORD_COMMITTED | RECORD_COMPRESSED | RECORD_FRAGMENT | RECORD_PADDING => {}
+                // Claimed but the writer hasn't finished copying yet
+                _ => return Err(RbufError::Empty),
+            }
+
+            let len = unsafe { self.ring.record_len_ptr(offset).read() } as usize;
+            if !self.ring.fits(offset, len, state) {
+                // Something other than a writer wrote the header, so we
+                // can't trust how far to skip either
+                event!(warn, ring = self.ring.segment.name(), head, len, "corrupt record");
+                return Err(RbufError::CorruptMessage { seq: head });
+            }
+            if state != RECORD_PADDING {
+                return Ok((head, offset, len, state));
+            }
+            self.ring.release(head, offset, len);
+        }
+    }
+
+    // The hash of the message schema this ring was created for, or 0
+    pub fn schema(&self) -> u64 {
+        self.ring.schema()
+    }
+
+    // Recover the message (or fragment) in the record at `head` into `out`,
+    // replacing its contents
+    fn read(
+        &self,
+        head: u64,
+        offset: usize,
+        len: usize,
+        state: u32,
+        out: &mut Vec<u8>,
+    ) -> Result<(), RbufError> {
+        let stored = unsafe { std::slice::from_raw_parts(self.ring.payload_ptr(offset), len) };
+        match (&self.ring.cipher, state == RECORD_COMPRESSED) {
+            (None, false) => {
+                out.clear();
+                out.extend_from_slice(stored);
+                Ok(())
+            }
+            (None, true) => self.ring.codec.decompress(stored, out),
+            (Some(cipher), false) => cipher.open(head, state, stored, out),
+            (Some(cipher), true) => {
+                let mut message = Vec::new();
+                cipher.open(head, state, stored, &mut message)?;
+                self.ring.codec.decompress(&message, out)
+            }
+        }
+    }
+
+    // Copy the next message into `buf` (replacing its contents) and return its
+    // length. Fails with `Decode` if a compressed message doesn't decompress,
+    // or `Unauthenticated` if an encrypted one was tampered with; it is
+    // consumed either way. Returns `Empty` while the rest of a fragmented
+    // message is still on its way.
+    pub fn pop_bytes(&mut self, buf: &mut Vec<u8>) -> Result<usize, RbufError> {
+        loop {
+            let (head, offset, len, state) = self.next_record()?;
+            if state != RECORD_FRAGMENT {
+                let result = self.read(head, offset, len, state, buf);
+                self.ring.release(head, offset, len);
+                return result.map(|()| buf.len());
+            }
+            if self.pop_fragment(head, offset, len, buf)? {
+                return Ok(buf.len());
+            }
+        }
+    }
+
+    // Consume the fragment at `head`, returning true once it completes its
+    // message, which is then in `out`
+    fn pop_fragment(
+        &mut self,
+        head: u64,
+        offset: usize,
+        len: usize,
+        out: &mut Vec<u8>,
+    ) -> Result<bool, RbufError> {
+        let mut fragment = Vec::new();
+        let result = self.read(head, offset, len, RECORD_FRAGMENT, &mut fragment);
+        self.ring.release(head, offset, len);
+        result?;
+        if fragment.len() < FRAGMENT_HEADER_SIZE {
+            return Err(RbufError::Decode("fragment too short for its header".to_string()));
+        }
+        let word = |at: usize| u32::from_le_bytes(fragment[at..at + 4].try_into().unwrap());
+        let id = u64::from_le_bytes(fragment[..8].try_into().unwrap());
+        let (index, flags) = (word(8), word(12));
+        let piece = &fragment[FRAGMENT_HEADER_SIZE..];
+
+        let mut partial = self.partial.remove(&id).unwrap_or_default();
+        if index != partial.next {
+            // We missed some of it, or its writer died and the id came round
+            // again; only a first fragment can start afresh
+            partial = Partial::default();
+            if index != 0 {
+                return Err(RbufError::Decode(format!(
+                    "fragment {} of message {:#x} came without the ones before it",
+                    index, id
+                )));
+            }
+        }
+        let max = self.ring.header().options.max_message as usize;
+        partial.next = partial.next.wrapping_add(1);
+        partial.size += piece.len();
+        if partial.size > max {
+            partial.too_large = true;
+            partial.data = Vec::new();
+        } else {
+            partial.data.extend_from_slice(piece);
+        }
+
+        if flags & FRAGMENT_LAST == 0 {
+            self.partial.insert(id, partial);
+            return Ok(false);
+        }
+        if partial.too_large {
+            return Err(RbufError::MessageTooLarge { size: partial.size, max });
+        }
+        if flags & FRAGMENT_COMPRESSED != 0 {
+            self.ring.codec.decompress(&partial.data, out)?;
+        } else {
+            *out = partial.data;
+        }
+        Ok(true)
+    }
+
+    // The next message, borrowed where it lies in the ring. Its space is only
+    // handed back to writers when the guard is dropped. Compressed,
+    // encrypted and fragmented messages can't be read in place, so they are
+    // decoded into the guard.
+    pub fn pop_ref(&mut self) -> Result<BytesRef<'_>, RbufError> {
+        let (head, offset, len, state) = self.next_record()?;
+        if state == RECORD_FRAGMENT {
+            let mut out = Vec::new();
+            self.pop_bytes(&mut out)?;
+            return Ok(self.assembled(out));
+        }
+        let mut bytes = BytesRef { ring: &self.ring, head, offset, len, owned: None, held: true };
+        if state == RECORD_COMPRESSED || self.ring.cipher.is_some() {
+            let mut out = Vec::new();
+            self.read(head, offset, len, state, &mut out)?;
+            bytes.owned = Some(out);
+        }
+        Ok(bytes)
+    }
+
+    // A fragmented message, put back together
+    fn assembled(&self, message: Vec<u8>) -> BytesRef<'_> {
+        let (head, offset, len) = (0, 0, 0);
+        BytesRef { ring: &self.ring, head, offset, len, owned: Some(message), held: false }
+    }
+
+    // `pop_ref`, sleeping until a writer publishes if the ring is empty (or
+    // only holds part of a fragmented message)
+    pub fn pop_ref_blocking(&mut self) -> Result<BytesRef<'_>, RbufError> {
+        loop {
+            let seq = self.ring.header().data_ready.prepare_wait();
+            match self.next_record() {
+                Err(_) => {}
+                Ok((_, _, _, RECORD_FRAGMENT)) => {
+                    let mut out = Vec::new();
+                    match self.pop_bytes(&mut out) {
+                        Err(RbufError::Empty) => {}
+                        result => {
+                            self.ring.header().data_ready.cancel_wait();
+                            return result.map(|_| self.assembled(out));
+                        }
+                    }
+                }
+                Ok(_) => {
+                    self.ring.header().data_ready.cancel_wait();
+                    // Nobody else pops, so the record we found stays put
+                    return self.pop_ref();
+                }
+            }
+            self.ring.header().data_ready.wait(seq);
+        }
+    }
+
+    // `pop_bytes`, sleeping while the ring is empty until `deadline` if
+    // there is one, but giving up on a message that fails to decode. For
+    // the stream adapters and RPC.
+    pub(crate) fn pop_bytes_until(
+        &mut self,
+        buf: &mut Vec<u8>,
+        deadline: Option<Instant>,
+    ) -> Result<usize, RbufError> {
+        loop {
+            match self.pop_bytes(buf) {
+                Err(RbufError::Empty) => {}
+                result => return result,
+            }
+
+            let seq = self.ring.header().data_ready.prepare_wait();
+            match self.pop_bytes(buf) {
+                Err(RbufError::Empty) => wait_until(&self.ring.header().data_ready, seq, deadline)?,
+                result => {
+                    self.ring.header().data_ready.cancel_wait();
+                    return result;
+                }
+            }
+        }
+    }
+
+    // Pop, sleeping until a writer publishes if the ring is empty. Messages
+    // that fail to decompress or authenticate are skipped, straight on to
+    // the next; a corrupt record is waited on like an empty ring, since it
+    // stays where it is.
+    pub fn pop_bytes_blocking(&mut self, buf: &mut Vec<u8>) -> usize {
+        let waits =
+            |e: &RbufError| matches!(e, RbufError::Empty | RbufError::CorruptMessage { .. });
+        loop {
+            match self.pop_bytes(buf) {
+                Ok(len) => return len,
+                Err(e) if waits(&e) => {}
+                Err(_) => continue,
+            }
+
+            let seq = self.ring.header().data_ready.prepare_wait();
+            match self.pop_bytes(buf) {
+                Ok(len) => {
+                    self.ring.header().data_ready.cancel_wait();
+                    return len;
+                }
+                Err(e) if waits(&e) => self.ring.header().data_ready.wait(seq),
+                Err(_) => self.ring.header().data_ready.cancel_wait(),
+            }
+        }
+    }
+}
+
+// A message borrowed in place from a `Reader`. Dropping it frees the record.
+pub struct BytesRef<'a> {
+    ring: &'a ByteRing,
+    head: u64,
+    offset: usize,
+    len: usize,
+    // The decoded message, for records that can't be read in place
+    owned: Option<Vec<u8>>,
+    // Whether the record is still ours to free; fragments are freed as
+    // they're put together
+    held: bool,
+}
+
+impl Deref for BytesRef<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if let Some(owned) = &self.owned {
+            return owned;
+        }
+        unsafe { std::slice::from_raw_parts(self.ring.payload_ptr(self.offset), self.len) }
+    }
+}
+
+impl Drop for BytesRef<'_> {
+    fn drop(&mut self) {
+        if self.held {
+            self.ring.release(self.head, self.offset, self.len);
+        }
+    }
+}
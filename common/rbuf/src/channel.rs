@@ -0,0 +1,132 @@
+// channel.rs
+//
+// Ordinary Rust values, Strings, Vecs and all, over a byte ring. Messages are
+// serialized with postcard on push and deserialized on pop, so nothing in
+// shared memory ever holds a pointer, and the two sides only have to agree on
+// the serde shape of `M`, not on its memory layout.
+//
+//     let mut orders: Receiver<Order> = TypedChannel::create("orders", 1 << 20)?;
+//     let sender: Sender<Order> = TypedChannel::open("orders")?;
+//     sender.push(&Order { symbol: "ACME".to_string(), qty: 100 })?;
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::bytes::{Reader, Writer};
+use crate::error::RbufError;
+
+// Whoever receives creates the channel, like with the byte ring underneath
+pub struct TypedChannel<M> {
+    _phantom: PhantomData<M>,
+}
+
+impl<M: Serialize + DeserializeOwned> TypedChannel<M> {
+    // Create the segment with room for `capacity` bytes of encoded messages
+    pub fn create(name: &str, capacity: usize) -> Result<Receiver<M>, RbufError> {
+        Receiver::create(name, capacity)
+    }
+
+    pub fn open(name: &str) -> Result<Sender<M>, RbufError> {
+        Sender::open(name)
+    }
+}
+
+// --- Sender ---
+
+pub struct Sender<M> {
+    writer: Writer,
+    // Only ever handed a `&M`, so `M` doesn't decide whether this is Send
+    _phantom: PhantomData<fn(&M)>,
+}
+
+impl<M: Serialize> Sender<M> {
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        Ok(Self { writer: Writer::open(name)?, _phantom: PhantomData })
+    }
+
+    pub fn name(&self) -> &str {
+        self.writer.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.writer.attached()
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.writer.set_unlink_on_drop(unlink);
+    }
+
+    // The largest encoded message that can ever be pushed
+    pub fn max_message_size(&self) -> usize {
+        self.writer.max_message_size()
+    }
+
+    // Fails with `Encode` if `msg` can't be serialized, and with
+    // `MessageTooLarge` if its encoding can never fit
+    pub fn push(&self, msg: &M) -> Result<(), RbufError> {
+        self.writer.push_bytes(&encode(msg)?)
+    }
+
+    // Push, sleeping until the receiver frees enough space if the ring is full
+    pub fn push_blocking(&self, msg: &M) -> Result<(), RbufError> {
+        self.writer.push_bytes_blocking(&encode(msg)?)
+    }
+}
+
+fn encode<M: Serialize>(msg: &M) -> Result<Vec<u8>, RbufError> {
+    postcard::to_allocvec(msg).map_err(|e| RbufError::Encode(e.to_string()))
+}
+
+// --- Receiver ---
+
+pub struct Receiver<M> {
+    reader: Reader,
+    // Reused for every message, so popping only allocates inside `M`
+    buf: Vec<u8>,
+    _phantom: PhantomData<fn() -> M>,
+}
+
+impl<M: DeserializeOwned> Receiver<M> {
+    // Create the segment with room for `capacity` bytes of encoded messages,
+    // rounded up to a power of two
+    pub fn create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        Ok(Self { reader: Reader::create(name, capacity)?, buf: Vec::new(), _phantom: PhantomData })
+    }
+
+    pub fn name(&self) -> &str {
+        self.reader.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.reader.attached()
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.reader.set_unlink_on_drop(unlink);
+    }
+
+    // Fails with `Decode` if the next message doesn't deserialize as `M`,
+    // e.g. because the sender was built against a different version of it.
+    // The message is consumed either way.
+    pub fn pop(&mut self) -> Result<M, RbufError> {
+        self.reader.pop_bytes(&mut self.buf)?;
+        self.decode()
+    }
+
+    // Pop, sleeping until a sender pushes if the ring is empty
+    pub fn pop_blocking(&mut self) -> Result<M, RbufError> {
+        self.reader.pop_bytes_blocking(&mut self.buf);
+        self.decode()
+    }
+
+    fn decode(&self) -> Result<M, RbufError> {
+        postcard::from_bytes(&self.buf).map_err(|e| RbufError::Decode(e.to_string()))
+    }
+}
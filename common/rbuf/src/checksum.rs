@@ -0,0 +1,43 @@
+// checksum.rs
+//
+// CRC32 (the IEEE polynomial, as used by zlib and Ethernet) of a slot's
+// bytes. Table driven, one byte at a time: slots are small and this only
+// runs when a ring was created with checksums.
+
+const POLY: u32 = 0xedb8_8320;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc = TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// The well-known check value for the IEEE polynomial
+const _: () = {
+    let bytes = b"123456789";
+    let mut crc = !0u32;
+    let mut i = 0;
+    while i < bytes.len() {
+        crc = TABLE[((crc ^ bytes[i] as u32) & 0xff) as usize] ^ (crc >> 8);
+        i += 1;
+    }
+    assert!(!crc == 0xcbf4_3926);
+};
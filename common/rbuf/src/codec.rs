@@ -0,0 +1,142 @@
+// codec.rs
+//
+// Per-message compression for byte rings. The creator picks a codec and
+// records its id in the header; writers compress with whatever the header
+// says, and the reader decompresses on pop. Compressed records carry the
+// uncompressed length in front:
+//
+//     [ state: COMPRESSED | len | original len: u32 | compressed bytes | padding ]
+//
+// Messages that are small or don't shrink are stored as they are.
+use crate::error::RbufError;
+
+// Below this, compression costs more than it saves
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+const MIN_COMPRESS: usize = 64;
+
+pub(crate) const ORIGINAL_LEN_SIZE: usize = 4;
+
+// How a byte ring compresses its messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    None,
+    // Fast, modest ratio; needs the `lz4` feature
+    Lz4,
+    // Slower, better ratio; needs the `zstd` feature
+    Zstd,
+}
+
+impl Codec {
+    pub(crate) fn id(self) -> u32 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u32) -> Option<Self> {
+        match id {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Lz4),
+            2 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    // Whether this build can compress and decompress with it
+    pub fn is_supported(self) -> bool {
+        match self {
+            Codec::None => true,
+            Codec::Lz4 => cfg!(feature = "lz4"),
+            Codec::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    // The codec a header names, if this build supports it
+    pub(crate) fn check(id: u32) -> Result<Self, RbufError> {
+        match Self::from_id(id) {
+            Some(codec) if codec.is_supported() => Ok(codec),
+            Some(codec) => Err(RbufError::IncompatibleLayout(format!(
+                "ring is compressed with {:?}, which this build doesn't support",
+                codec
+            ))),
+            None => Err(RbufError::IncompatibleLayout(format!("unknown codec id {}", id))),
+        }
+    }
+
+    // `bytes` compressed and prefixed with its length, or None if it isn't
+    // worth it
+    pub(crate) fn compress(self, bytes: &[u8]) -> Option<Vec<u8>> {
+        let original = u32::try_from(bytes.len()).ok()?;
+        let compressed = self.compress_raw(bytes)?;
+        if ORIGINAL_LEN_SIZE + compressed.len() >= bytes.len() {
+            return None;
+        }
+        let mut record = Vec::with_capacity(ORIGINAL_LEN_SIZE + compressed.len());
+        record.extend_from_slice(&original.to_le_bytes());
+        record.extend_from_slice(&compressed);
+        Some(record)
+    }
+
+    #[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    fn compress_raw(self, bytes: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 if bytes.len() >= MIN_COMPRESS => Some(lz4_flex::block::compress(bytes)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd if bytes.len() >= MIN_COMPRESS => zstd::bulk::compress(bytes, 0).ok(),
+            _ => None,
+        }
+    }
+
+    // Decompress a record written by `compress` into `out`, replacing its
+    // contents
+    pub(crate) fn decompress(self, record: &[u8], out: &mut Vec<u8>) -> Result<(), RbufError> {
+        let corrupt = |reason: String| RbufError::Decode(format!("{:?}: {}", self, reason));
+        let (len, compressed) = record
+            .split_first_chunk::<ORIGINAL_LEN_SIZE>()
+            .ok_or_else(|| corrupt("record too short".to_string()))?;
+        let len = u32::from_le_bytes(*len) as usize;
+        out.clear();
+        self.decompress_raw(compressed, len, out).map_err(corrupt)?;
+        if out.len() != len {
+            return Err(corrupt(format!("expected {} bytes, got {}", len, out.len())));
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(
+        not(any(feature = "lz4", feature = "zstd")),
+        allow(unused_variables, clippy::ptr_arg)
+    )]
+    fn decompress_raw(
+        self,
+        compressed: &[u8],
+        len: usize,
+        out: &mut Vec<u8>,
+    ) -> Result<(), String> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => {
+                out.resize(len, 0);
+                let written = lz4_flex::block::decompress_into(compressed, out)
+                    .map_err(|e| e.to_string())?;
+                out.truncate(written);
+                Ok(())
+            }
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => {
+                out.resize(len, 0);
+                let written = zstd::bulk::decompress_to_buffer(compressed, out.as_mut_slice())
+                    .map_err(|e| e.to_string())?;
+                out.truncate(written);
+                Ok(())
+            }
+            // Writers only compress with the codec the header names, and
+            // handles refuse to attach to rings with codecs they lack
+            _ => Err("codec not supported by this build".to_string()),
+        }
+    }
+}
@@ -0,0 +1,183 @@
+// compact.rs
+//
+// A ring small enough to embed in another shared structure: no segment and
+// no header of its own, just `head`, `tail` and `N` slots, so it can sit in
+// an arena or next to an `ShmMutex` like any other `ShmSafe` field. The
+// counters can be `u32` to keep small rings on constrained targets compact.
+//
+// It is a single-producer, single-consumer queue: one process (or thread)
+// pushes and one pops, so neither side needs a CAS or per-slot flags. That
+// can't be checked, so `push` and `pop` are unsafe. There is no waiting
+// either; pair it with an `ShmCondvar` for that.
+//
+// [ head | tail | slots ]
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::error::{PushError, RbufError};
+use crate::shm_safe::ShmSafe;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+// The type of a `CompactRing`'s free-running `head` and `tail`: `u64`, or
+// `u32` for a header half the size. Either wraps safely, as long as the
+// capacity leaves half the counter's range spare.
+pub trait RingIndex: sealed::Sealed + Copy + 'static {
+    type Atomic: Send + Sync;
+
+    // Largest capacity a ring counting with this type can have
+    const MAX_CAPACITY: usize;
+
+    fn load(atomic: &Self::Atomic, order: Ordering) -> Self;
+
+    fn store(atomic: &Self::Atomic, value: Self, order: Ordering);
+
+    fn next(self) -> Self;
+
+    // How far `self` is ahead of `earlier`, across a wrap
+    fn since(self, earlier: Self) -> usize;
+
+    fn slot(self, mask: usize) -> usize;
+}
+
+macro_rules! impl_ring_index {
+    ($($t:ty => $atomic:ty),* $(,)?) => {
+        $(
+            impl sealed::Sealed for $t {}
+
+            impl RingIndex for $t {
+                type Atomic = $atomic;
+
+                const MAX_CAPACITY: usize = 1 << (<$t>::BITS - 1);
+
+                fn load(atomic: &$atomic, order: Ordering) -> Self {
+                    atomic.load(order)
+                }
+
+                fn store(atomic: &$atomic, value: Self, order: Ordering) {
+                    atomic.store(value, order)
+                }
+
+                fn next(self) -> Self {
+                    self.wrapping_add(1)
+                }
+
+                fn since(self, earlier: Self) -> usize {
+                    self.wrapping_sub(earlier) as usize
+                }
+
+                fn slot(self, mask: usize) -> usize {
+                    self as usize & mask
+                }
+            }
+        )*
+    };
+}
+
+impl_ring_index!(u32 => AtomicU32, u64 => AtomicU64);
+
+#[repr(C)]
+pub struct CompactRing<T, const N: usize, I: RingIndex = u64> {
+    // Only the consumer moves it
+    head: I::Atomic,
+    // Only the producer moves it
+    tail: I::Atomic,
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+}
+
+unsafe impl<T: ShmSafe, const N: usize, I: RingIndex> ShmSafe for CompactRing<T, N, I> {}
+unsafe impl<T: Send, const N: usize, I: RingIndex> Send for CompactRing<T, N, I> {}
+unsafe impl<T: Send, const N: usize, I: RingIndex> Sync for CompactRing<T, N, I> {}
+
+impl<T, const N: usize, I: RingIndex> CompactRing<T, N, I> {
+    // Fails the build for any `N` that isn't a power of two or doesn't fit
+    // the counters
+    const MASK: usize = {
+        assert!(N.is_power_of_two(), "a compact ring's capacity must be a power of two");
+        assert!(N <= I::MAX_CAPACITY, "a compact ring's capacity must fit its index type");
+        N - 1
+    };
+
+    // Empty. A zeroed ring is empty too, so one in fresh shared memory
+    // needs no initializing.
+    pub const fn new() -> Self {
+        let _ = Self::MASK;
+        // Zero counters and uninitialized slots
+        unsafe { MaybeUninit::zeroed().assume_init() }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        let head = I::load(&self.head, Ordering::Acquire);
+        let tail = I::load(&self.tail, Ordering::Acquire);
+        // The two loads aren't one snapshot; keep the answer in range
+        tail.since(head).min(N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    fn slot(&self, index: I) -> *mut T {
+        self.slots[index.slot(Self::MASK)].get() as *mut T
+    }
+
+    /// # Safety
+    ///
+    /// Only one producer may push at a time, across every thread and
+    /// process the ring is shared with.
+    pub unsafe fn push(&self, item: T) -> Result<(), PushError<T>> {
+        let tail = I::load(&self.tail, Ordering::Relaxed);
+        if tail.since(I::load(&self.head, Ordering::Acquire)) >= N {
+            return Err(PushError::new(RbufError::Full, item));
+        }
+        self.slot(tail).write(item);
+        I::store(&self.tail, tail.next(), Ordering::Release);
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// Only one consumer may pop at a time, across every thread and process
+    /// the ring is shared with.
+    pub unsafe fn pop(&self) -> Result<T, RbufError> {
+        let head = I::load(&self.head, Ordering::Relaxed);
+        if I::load(&self.tail, Ordering::Acquire).since(head) == 0 {
+            return Err(RbufError::Empty);
+        }
+        let item = self.slot(head).read();
+        I::store(&self.head, head.next(), Ordering::Release);
+        Ok(item)
+    }
+}
+
+impl<T, const N: usize, I: RingIndex> Default for CompactRing<T, N, I> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize, I: RingIndex> fmt::Debug for CompactRing<T, N, I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompactRing").field("capacity", &N).field("len", &self.len()).finish()
+    }
+}
+
+impl<T, const N: usize, I: RingIndex> Drop for CompactRing<T, N, I> {
+    // Leftover items go with the ring, like any other field's. Nobody else
+    // can be pushing or popping while we have it mutably.
+    fn drop(&mut self) {
+        while unsafe { self.pop() }.is_ok() {}
+    }
+}
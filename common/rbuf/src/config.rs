@@ -0,0 +1,397 @@
+// config.rs
+use std::marker::PhantomData;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::consumer::Consumer;
+use crate::directory;
+use crate::error::RbufError;
+#[cfg(feature = "checksum")]
+use crate::header::OPTION_CHECKSUMS;
+#[cfg(target_os = "linux")]
+use crate::header::OPTION_MIRRORED;
+use crate::header::{Role, OPTION_TIMESTAMPS};
+use crate::notify::Notifier;
+use crate::producer::{FullPolicy, Producer};
+#[cfg(unix)]
+use crate::segment::Permissions;
+use crate::segment::{Backing, HugePageSize, SegmentConfig};
+use crate::ring::{FlushPolicy, RingSpec, ShmemRingBuffer};
+use crate::shm_safe::ShmSafe;
+use crate::static_ring::{StaticConsumer, StaticProducer};
+use crate::wait::{Blocking, WaitStrategy};
+
+// Default number of usable slots when no capacity is given
+const DEFAULT_CAPACITY: usize = 1024;
+
+// Whether building a handle creates the segment or attaches to an existing one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    // Create and initialize the segment; fails if it already exists
+    Create,
+    // Attach to a segment someone else created; fails if it doesn't exist
+    Open,
+    // Create the segment unless it already exists, in which case attach to it.
+    // Safe to race from any number of processes.
+    OpenOrCreate,
+}
+
+// Everything needed to create or attach to a ring. New options get a setter
+// here instead of a new constructor argument.
+//
+//     let consumer = RingConfig::new("quotes").capacity(4096).consumer::<Quote>()?;
+//     let producer = RingConfig::new("quotes").full_policy(FullPolicy::Overwrite).producer::<Quote>()?;
+#[derive(Debug, Clone)]
+pub struct RingConfig {
+    name: String,
+    capacity: usize,
+    exact_capacity: bool,
+    max_consumers: usize,
+    // `OPTION_*` flags for the header
+    options: u64,
+    notifier: Notifier,
+    full_policy: FullPolicy,
+    open_mode: Option<OpenMode>,
+    unlink_on_drop: Option<bool>,
+    wait_strategy: Arc<dyn WaitStrategy>,
+    segment: SegmentConfig,
+    schema: Option<u64>,
+    flush_policy: FlushPolicy,
+}
+
+impl RingConfig {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            capacity: DEFAULT_CAPACITY,
+            exact_capacity: false,
+            max_consumers: 0,
+            options: 0,
+            notifier: Notifier::default(),
+            full_policy: FullPolicy::default(),
+            open_mode: None,
+            unlink_on_drop: None,
+            wait_strategy: Arc::new(Blocking),
+            segment: SegmentConfig::default(),
+            schema: None,
+            flush_policy: FlushPolicy::None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Number of items the ring can hold at once (only used on create).
+    // Rounded up to a power of two unless `exact_capacity` is set.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    // Keep the capacity exactly as given, e.g. to bound how far producers
+    // can run ahead. The slots are still allocated in a power of two, so
+    // the rest of them go unused.
+    pub fn exact_capacity(mut self, exact: bool) -> Self {
+        self.exact_capacity = exact;
+        self
+    }
+
+    // Room in the consumer table for `n` consumers to `register` (only used
+    // on create). Defaults to 0: no table, and `register` fails with
+    // `ConsumerTableFull`.
+    pub fn max_consumers(mut self, n: usize) -> Self {
+        self.max_consumers = n;
+        self
+    }
+
+    // Keep a CRC32 of every slot, written on push and checked on pop, so that
+    // a third party scribbling over the segment shows up as `CorruptMessage`
+    // instead of garbage data (only used on create). Costs a pass over each
+    // item on both sides. Processes built without the `checksum` feature
+    // can't attach to such a ring.
+    #[cfg(feature = "checksum")]
+    pub fn checksums(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.options |= OPTION_CHECKSUMS;
+        } else {
+            self.options &= !OPTION_CHECKSUMS;
+        }
+        self
+    }
+
+    // Stamp every item with the time it was pushed, so that the consumer can
+    // tell how long it waited (`Consumer::pop_with_latency`, and the
+    // histogram in `Stats::latency`). Costs a clock read on each push and
+    // pop batch (only used on create).
+    pub fn timestamps(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.options |= OPTION_TIMESTAMPS;
+        } else {
+            self.options &= !OPTION_TIMESTAMPS;
+        }
+        self
+    }
+
+    // Map the slots twice in a row so that `Consumer::pop_slice_ref` and
+    // `Producer::reserve_slice` never stop short at the end of the buffer
+    // (only used on create). The slots have to fill whole pages, e.g. 512
+    // `u64`s with 4 KiB pages, and the segment has to be a shared mapping,
+    // so not `Backing::Heap`.
+    #[cfg(target_os = "linux")]
+    pub fn mirrored(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.options |= OPTION_MIRRORED;
+        } else {
+            self.options &= !OPTION_MIRRORED;
+        }
+        self
+    }
+
+    // Identify the item type by `id` rather than by `directory::type_hash`,
+    // which hashes `T`'s name, size and alignment. The creator records it
+    // and every handle attaching has to give the same one, e.g. to keep
+    // two builds that name the type differently (or different toolchains)
+    // talking, or to tell apart versions of a type whose name didn't
+    // change.
+    pub fn schema(mut self, id: u64) -> Self {
+        self.schema = Some(id);
+        self
+    }
+
+    // How blocking calls on the ring wake each other (only used on create).
+    // Defaults to the best the target has; every process attaching uses
+    // whatever the ring was created with.
+    pub fn notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    // What producers built from this config do when the ring is full
+    pub fn full_policy(mut self, policy: FullPolicy) -> Self {
+        self.full_policy = policy;
+        self
+    }
+
+    // Defaults to `Create` for consumers and `Open` for producers. Either
+    // side may create the ring.
+    pub fn open_mode(mut self, mode: OpenMode) -> Self {
+        self.open_mode = Some(mode);
+        self
+    }
+
+    // Defaults to true for whichever handle created the segment
+    pub fn unlink_on_drop(mut self, unlink: bool) -> Self {
+        self.unlink_on_drop = Some(unlink);
+        self
+    }
+
+    // Where the ring's memory comes from. With `Backing::File` the name is
+    // ignored and every handle has to be given the same path instead.
+    pub fn backing(mut self, backing: Backing) -> Self {
+        self.segment.backing = backing;
+        self
+    }
+
+    // A persistent queue in the file at `path`: the ring outlives every
+    // handle, and the machine too as far as `policy` makes sure of, and a
+    // consumer that restarts carries on from the first item it didn't pop
+    // (or ack, with `Consumer::begin_pop`). Items pushed while no consumer
+    // was attached wait for the next one. Either side may create the file.
+    //
+    //     let consumer = RingConfig::new("orders")
+    //         .journal("/var/lib/bear_cave/orders.ring", FlushPolicy::FsyncPerBatch)
+    //         .consumer::<Order>()?;
+    #[cfg(unix)]
+    pub fn journal(self, path: impl Into<PathBuf>, policy: FlushPolicy) -> Self {
+        self.backing(Backing::File(path.into()))
+            .open_mode(OpenMode::OpenOrCreate)
+            .unlink_on_drop(false)
+            .flush_policy(policy)
+    }
+
+    // How soon pushes and pops through handles built from this config reach
+    // the disk, for `Backing::File` rings. Defaults to `FlushPolicy::None`.
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.flush_policy = policy;
+        self
+    }
+
+    // Back the ring with huge pages to cut TLB misses on big rings. Needs
+    // pages reserved in /proc/sys/vm/nr_hugepages; fails with
+    // `HugePagesUnavailable` rather than quietly using small pages. With
+    // `Backing::Shm` the segment is a file on the matching hugetlbfs mount,
+    // so every handle has to ask for the same page size to find it.
+    pub fn huge_pages(mut self, size: HugePageSize) -> Self {
+        self.segment.huge_pages = Some(size);
+        self
+    }
+
+    // Touch every page of the segment when mapping it, so that the first
+    // pushes and pops don't pay for page faults
+    pub fn prefault(mut self, prefault: bool) -> Self {
+        self.segment.prefault = prefault;
+        self
+    }
+
+    // mlock the segment so it's never paged out. Needs a big enough
+    // RLIMIT_MEMLOCK (or CAP_IPC_LOCK), or on Windows minimum working set;
+    // fails with `MemoryLock` otherwise.
+    pub fn lock_memory(mut self, lock: bool) -> Self {
+        self.segment.lock = lock;
+        self
+    }
+
+    // Mode and owner for the segment (only used on create), e.g. to keep a
+    // ring on a shared host to one user or group:
+    //
+    //     .permissions(Permissions { mode: Some(0o660), gid: Some(gid), ..Default::default() })
+    //
+    // Fails with `Permissions`, and removes the segment again, if they can't
+    // be set.
+    #[cfg(unix)]
+    pub fn permissions(mut self, permissions: Permissions) -> Self {
+        self.segment.permissions = Some(permissions);
+        self
+    }
+
+    // How blocking calls on handles built from this config wait. Defaults to
+    // `Blocking`, which parks until the other side signals.
+    pub fn wait_strategy(mut self, strategy: impl WaitStrategy + 'static) -> Self {
+        self.wait_strategy = Arc::new(strategy);
+        self
+    }
+
+    pub fn consumer<T: ShmSafe>(&self) -> Result<Consumer<T>, RbufError> {
+        let mut consumer = Consumer::from_ring(self.map(Role::Consumer)?);
+        consumer.set_wait_strategy(self.wait_strategy.clone());
+        Ok(consumer)
+    }
+
+    pub fn producer<T: ShmSafe>(&self) -> Result<Producer<T>, RbufError> {
+        let mut producer = Producer::from_ring(self.map(Role::Producer)?);
+        producer.set_full_policy(self.full_policy);
+        producer.set_wait_strategy(self.wait_strategy.clone());
+        Ok(producer)
+    }
+
+    // A consumer for a ring of exactly `N` items, whatever `capacity` says.
+    // Fails with `IncompatibleLayout` for rings with checksums or timestamps.
+    pub fn static_consumer<T: ShmSafe, const N: usize>(
+        &self,
+    ) -> Result<StaticConsumer<T, N>, RbufError> {
+        let mut consumer = StaticConsumer::from_ring(self.map_static::<T, N>(Role::Consumer)?)?;
+        consumer.set_wait_strategy(self.wait_strategy.clone());
+        Ok(consumer)
+    }
+
+    // Like `static_consumer`. The full policy doesn't apply: a static
+    // producer always rejects.
+    pub fn static_producer<T: ShmSafe, const N: usize>(
+        &self,
+    ) -> Result<StaticProducer<T, N>, RbufError> {
+        let mut producer = StaticProducer::from_ring(self.map_static::<T, N>(Role::Producer)?)?;
+        producer.set_wait_strategy(self.wait_strategy.clone());
+        Ok(producer)
+    }
+
+    fn map_static<T, const N: usize>(&self, role: Role) -> Result<ShmemRingBuffer<T>, RbufError> {
+        self.clone().capacity(N).exact_capacity(true).map(role)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "rbuf::map",
+            level = "debug",
+            skip_all,
+            fields(ring = %self.name, ?role, mode = ?self.mode_for(role)),
+            err(level = "debug")
+        )
+    )]
+    fn map<T>(&self, role: Role) -> Result<ShmemRingBuffer<T>, RbufError> {
+        let capacity = if self.exact_capacity {
+            self.capacity
+        } else {
+            self.capacity.next_power_of_two()
+        };
+        let spec = RingSpec {
+            capacity,
+            max_consumers: self.max_consumers,
+            options: self.options,
+            notifier: self.notifier,
+            schema: self.schema.unwrap_or_else(directory::type_hash::<T>),
+        };
+        let mut rb = match self.mode_for(role) {
+            OpenMode::Create => ShmemRingBuffer::create(&self.segment, &self.name, &spec, role)?,
+            OpenMode::Open => {
+                ShmemRingBuffer::open(&self.segment, &self.name, role, spec.schema)?
+            }
+            OpenMode::OpenOrCreate => {
+                ShmemRingBuffer::open_or_create(&self.segment, &self.name, &spec, role)?
+            }
+        };
+        if let Some(unlink) = self.unlink_on_drop {
+            rb.set_owner(unlink);
+        }
+        rb.set_flush_policy(self.flush_policy);
+        Ok(rb)
+    }
+
+    fn mode_for(&self, role: Role) -> OpenMode {
+        let default_mode = match role {
+            Role::Consumer => OpenMode::Create,
+            Role::Producer => OpenMode::Open,
+        };
+        self.open_mode.unwrap_or(default_mode)
+    }
+}
+
+// --- Role-agnostic construction ---
+
+// A typed ring handle that can be built from a `RingConfig`
+pub trait RingHandle<T>: Sized {
+    const ROLE: Role;
+
+    fn from_config(config: &RingConfig) -> Result<Self, RbufError>;
+}
+
+impl<T: ShmSafe> RingHandle<T> for Consumer<T> {
+    const ROLE: Role = Role::Consumer;
+
+    fn from_config(config: &RingConfig) -> Result<Self, RbufError> {
+        config.consumer()
+    }
+}
+
+impl<T: ShmSafe> RingHandle<T> for Producer<T> {
+    const ROLE: Role = Role::Producer;
+
+    fn from_config(config: &RingConfig) -> Result<Self, RbufError> {
+        config.producer()
+    }
+}
+
+// Whichever side starts first creates the ring, without the startup order
+// being baked into which handle type can do it:
+//
+//     let producer: Producer<Quote> = Ring::create("quotes", 4096)?;
+//     let consumer: Consumer<Quote> = Ring::open("quotes")?;
+pub struct Ring<T> {
+    _phantom: PhantomData<T>,
+}
+
+impl<T: ShmSafe> Ring<T> {
+    pub fn create<H: RingHandle<T>>(name: &str, capacity: usize) -> Result<H, RbufError> {
+        H::from_config(&RingConfig::new(name).capacity(capacity).open_mode(OpenMode::Create))
+    }
+
+    pub fn open<H: RingHandle<T>>(name: &str) -> Result<H, RbufError> {
+        H::from_config(&RingConfig::new(name).open_mode(OpenMode::Open))
+    }
+
+    pub fn open_or_create<H: RingHandle<T>>(name: &str, capacity: usize) -> Result<H, RbufError> {
+        H::from_config(&RingConfig::new(name).capacity(capacity).open_mode(OpenMode::OpenOrCreate))
+    }
+}
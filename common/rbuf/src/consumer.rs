@@ -0,0 +1,805 @@
+// consumer.rs
+use std::mem::{self, ManuallyDrop, MaybeUninit};
+use std::ops::Deref;
+#[cfg(target_os = "linux")]
+use std::io;
+#[cfg(unix)]
+use std::os::unix::io::BorrowedFd;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::config::{OpenMode, RingConfig};
+use crate::error::RbufError;
+#[cfg(target_os = "linux")]
+use crate::eventfd::ReadableFd;
+use crate::header::{Role, NO_CREDIT_LIMIT};
+use crate::peer::{Peer, HEARTBEAT_INTERVAL};
+use crate::ring::{Recovery, ShmemRingBuffer, SLOT_ABORTED, SLOT_COMMITTED, SLOT_EMPTY};
+use crate::shm_safe::ShmSafe;
+use crate::slots;
+use crate::stats::Stats;
+use crate::wait::{self, Blocking, WaitStrategy};
+
+pub struct Consumer<T> {
+    rb: ShmemRingBuffer<T>,
+    wait: Arc<dyn WaitStrategy>,
+    // Our entry in the consumer table, once registered
+    registration: Option<usize>,
+    recovery: Recovery,
+    // Segments `grow` moved the ring out of, still mapped for handles (async
+    // adapters, guards) that may be waiting on them
+    grown: Vec<ShmemRingBuffer<T>>,
+    // Started by the first `readable_fd`
+    #[cfg(target_os = "linux")]
+    readable: OnceLock<ReadableFd>,
+}
+
+impl<T: ShmSafe> Consumer<T> {
+    // Shorthand for `RingConfig::new(name).capacity(capacity).consumer()`
+    pub fn create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        RingConfig::new(name).capacity(capacity).consumer()
+    }
+
+    // Attach to a ring someone else created, e.g. a producer that went first
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        RingConfig::new(name).open_mode(OpenMode::Open).consumer()
+    }
+
+    // Shorthand for `RingConfig` with `OpenMode::OpenOrCreate`
+    pub fn open_or_create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        RingConfig::new(name)
+            .capacity(capacity)
+            .open_mode(OpenMode::OpenOrCreate)
+            .consumer()
+    }
+
+    pub(crate) fn from_ring(rb: ShmemRingBuffer<T>) -> Self {
+        let recovery = rb.recover();
+        Self {
+            rb,
+            wait: Arc::new(Blocking),
+            registration: None,
+            recovery,
+            grown: Vec::new(),
+            #[cfg(target_os = "linux")]
+            readable: OnceLock::new(),
+        }
+    }
+
+    // What attaching had to discard from a ring left inconsistent by a
+    // producer or consumer that crashed mid-operation. Items that had been
+    // claimed but never committed are lost.
+    pub fn recovery(&self) -> Recovery {
+        self.recovery
+    }
+
+    // How `pop_blocking` and `pop_timeout` wait for items
+    pub fn set_wait_strategy(&mut self, strategy: Arc<dyn WaitStrategy>) {
+        self.wait = strategy;
+    }
+
+    pub(crate) fn header(&self) -> &crate::header::RingBufferHeader {
+        self.rb.header()
+    }
+
+    // The OS identifier of the shared memory segment
+    pub fn name(&self) -> &str {
+        self.rb.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.rb.header().attached.load(Ordering::Acquire) as usize
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.rb.set_owner(unlink);
+    }
+
+    // The descriptor behind a `Backing::Memfd` or `Backing::Fd` ring, for
+    // handing to another process with `send_fd`
+    #[cfg(unix)]
+    pub fn fd(&self) -> Option<BorrowedFd<'_>> {
+        self.rb.fd()
+    }
+
+    // An eventfd that turns readable when producers push, for registering
+    // the ring with epoll or mio next to sockets. Read it to reset it, then
+    // pop until `Empty`. The first call starts a thread that relays the
+    // ring's wakeups to the descriptor; it stops when the consumer drops.
+    #[cfg(target_os = "linux")]
+    pub fn readable_fd(&self) -> io::Result<BorrowedFd<'_>> {
+        if self.readable.get().is_none() {
+            // Losing a race with another thread drops ours again
+            let _ = self.readable.set(ReadableFd::new(self.rb.header())?);
+        }
+        Ok(self.readable.get().expect("just set").fd())
+    }
+
+    // Take an entry in the ring's consumer table, so that other processes can
+    // see our PID, how far we have read and when we last called `heartbeat`.
+    // Fails with `ConsumerTableFull` if the ring was created without room
+    // (see `RingConfig::max_consumers`). Dropping the consumer unregisters it.
+    pub fn register(&mut self) -> Result<(), RbufError> {
+        if self.registration.is_none() {
+            let head = &self.rb.header().head;
+            let index = self.rb.consumers().register(|| head.load(Ordering::Acquire))?;
+            self.registration = Some(index);
+            event!(debug, ring = self.name(), index, "registered consumer");
+        }
+        Ok(())
+    }
+
+    pub fn unregister(&mut self) {
+        if let Some(index) = self.registration.take() {
+            self.rb.consumers().unregister(index);
+            event!(debug, ring = self.name(), index, "unregistered consumer");
+        }
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.registration.is_some()
+    }
+
+    // Tell producers we're still here, in the header and in our consumer
+    // table entry if registered. `pop_blocking` does this while it waits;
+    // consumers that go long without popping should call it periodically.
+    pub fn heartbeat(&self) {
+        self.rb.header().roles.beat(Role::Consumer);
+        if let Some(index) = self.registration {
+            self.rb.consumers().entry(index).beat();
+        }
+    }
+
+    // The producer that last showed signs of life, if any is attached
+    pub fn peer(&self) -> Option<Peer> {
+        self.rb.header().roles.peer(Role::Producer)
+    }
+
+    // Whether a producer is attached and its process is still running. With
+    // several producers this only speaks for the last one to beat.
+    pub fn is_peer_alive(&self) -> bool {
+        self.peer().is_some_and(|peer| peer.alive)
+    }
+
+    // Check that the ring's name still leads to the segment this handle
+    // maps, like `Producer::ping`
+    pub fn ping(&self) -> Result<(), RbufError> {
+        self.rb.ping()
+    }
+
+    // Give the ring room for `capacity` items without stopping anyone. The
+    // ring moves into a new segment under the same name, taking the items
+    // still in it along in order, and producers follow it there on their
+    // next push; pushes racing the move are redone in the new segment, so
+    // nothing is lost or reordered. Rate limits, pauses and credits carry
+    // over; the stats start from zero. The old segment is unlinked at once
+    // and its memory goes with the last handle still mapping it.
+    //
+    // Fails with `IncompatibleLayout` unless `capacity` is more than the
+    // ring has, or if the ring has no name to move (memfds and passed
+    // descriptors).
+    pub fn grow(&mut self, capacity: usize) -> Result<(), RbufError> {
+        let next = self.rb.grow(capacity)?;
+        #[cfg(target_os = "linux")]
+        if let Some(readable) = self.readable.get() {
+            readable.retarget(next.header());
+        }
+        let registered = self.registration.is_some();
+        self.unregister();
+        self.grown.push(mem::replace(&mut self.rb, next));
+        if registered {
+            self.register()?;
+        }
+        Ok(())
+    }
+
+    // Only let producers push as many items as we grant credit for, rather
+    // than as many as fit, so that what waits in the ring is never more
+    // than we promised to drain. Starts with `credits` granted. Producers
+    // out of credit fail with `NoCredit`, or wait in `push_blocking`.
+    pub fn enable_credits(&self, credits: usize) {
+        let header = self.rb.header();
+        let limit = header.tail.load(Ordering::Acquire).saturating_add(credits as u64);
+        header.control.credit_limit.store(limit.min(NO_CREDIT_LIMIT - 1), Ordering::Release);
+        header.space_ready.notify();
+        event!(debug, ring = self.name(), credits, "enabled credits");
+    }
+
+    // Go back to letting producers push whatever fits
+    pub fn disable_credits(&self) {
+        let header = self.rb.header();
+        header.control.credit_limit.store(NO_CREDIT_LIMIT, Ordering::Release);
+        header.space_ready.notify();
+        event!(debug, ring = self.name(), "disabled credits");
+    }
+
+    // Let producers push `credits` more items, e.g. as many as we just
+    // finished with. Does nothing unless credits are enabled.
+    pub fn grant(&self, credits: usize) {
+        let header = self.rb.header();
+        let granted = header.control.credit_limit.fetch_update(
+            Ordering::AcqRel,
+            Ordering::Acquire,
+            |limit| {
+                (limit != NO_CREDIT_LIMIT)
+                    .then(|| limit.saturating_add(credits as u64).min(NO_CREDIT_LIMIT - 1))
+            },
+        );
+        if granted.is_ok() {
+            header.space_ready.notify();
+            event!(trace, ring = self.name(), credits, "granted credit");
+        }
+    }
+
+    // Credit granted and not yet used by producers, or None if credits
+    // aren't enabled
+    pub fn credits(&self) -> Option<usize> {
+        let header = self.rb.header();
+        header.control.credits(header.tail.load(Ordering::Acquire)).map(|c| c as usize)
+    }
+
+    // Whether a producer has closed the ring. Items pushed before may still
+    // be waiting; pops fail with `Disconnected` once they're gone.
+    pub fn is_closed(&self) -> bool {
+        self.rb.header().roles.is_closed(Role::Producer)
+    }
+
+    // Keep our table entry in step with `head`
+    fn advance_cursor(&self, head: u64) {
+        if let Some(index) = self.registration {
+            self.rb.consumers().entry(index).cursor.store(head, Ordering::Release);
+        }
+    }
+
+    // Move `head` forward, handing the slots behind it back to producers
+    fn release_to(&self, head: u64) {
+        self.rb.header().head.store(head, Ordering::Release);
+        self.advance_cursor(head);
+        self.rb.flush_head();
+        self.rb.header().space_ready.notify();
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.rb.capacity()
+    }
+
+    // Items in the ring, counting slots that producers have claimed but not
+    // finished writing. Only a snapshot while producers keep pushing.
+    pub fn len(&self) -> usize {
+        self.rb.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity()
+    }
+
+    pub fn remaining_capacity(&self) -> usize {
+        self.capacity().saturating_sub(self.len())
+    }
+
+    // Counters shared by every handle on this ring
+    pub fn stats(&self) -> Stats {
+        Stats { latency: self.rb.latency(), ..self.rb.header().stats() }
+    }
+
+    // Fails with `CorruptMessage` when the next item doesn't match its
+    // checksum; the item is discarded and the next call moves on. An empty
+    // ring fails with `Empty`, or `Disconnected` once a producer closed it.
+    pub fn pop(&mut self) -> Result<T, RbufError> {
+        self.try_pop()
+    }
+
+    // `pop`, plus how long the item waited in the ring since its producer
+    // committed it. Needs a ring created with `RingConfig::timestamps`.
+    pub fn pop_with_latency(&mut self) -> Result<(T, Duration), RbufError> {
+        if !self.rb.has_timestamps() {
+            return Err(RbufError::IncompatibleLayout(
+                "ring was created without timestamps".to_string(),
+            ));
+        }
+        let (item, waited) = self.try_pop_timed()?;
+        Ok((item, waited.unwrap_or_default()))
+    }
+
+    // `pop` without the exclusive borrow, for the blocking loops
+    // Only one handle consumes a typed ring, so `&self` is enough here; the
+    // public methods take `&mut self` to keep it that way
+    pub(crate) fn try_pop(&self) -> Result<T, RbufError> {
+        self.try_pop_timed().map(|(item, _)| item)
+    }
+
+    fn try_pop_timed(&self) -> Result<(T, Option<Duration>), RbufError> {
+        let mut item = None;
+        self.pop_batch(1, |popped, waited| item = Some((popped, waited)))?;
+        item.ok_or_else(|| self.nothing_left())
+    }
+
+    // What popping from an empty ring fails with: `Disconnected` once a
+    // producer has closed it, since nothing more will come
+    fn nothing_left(&self) -> RbufError {
+        let header = self.rb.header();
+        let closed = header.roles.is_closed(Role::Producer);
+        // Checked after the flag, so that items pushed before `close` count
+        let empty = header.tail.load(Ordering::Acquire) == header.head.load(Ordering::Acquire);
+        if closed && empty {
+            RbufError::Disconnected
+        } else {
+            RbufError::Empty
+        }
+    }
+
+    // Move up to `max` items onto the end of `out`, returning how many were
+    // moved. Corrupt items are discarded and only show up in the stats.
+    pub fn pop_into(&mut self, out: &mut Vec<T>, max: usize) -> usize {
+        out.reserve(max.min(self.rb.header().capacity()));
+        self.pop_batch(max, |item, _| out.push(item)).unwrap_or(0)
+    }
+
+    // Fill the front of `out`, returning how many slots were initialized.
+    // Corrupt items are discarded like with `pop_into`.
+    pub fn pop_slice(&mut self, out: &mut [MaybeUninit<T>]) -> usize {
+        let mut slots = out.iter_mut();
+        let max = slots.len();
+        self.pop_batch(max, |item, _| {
+            if let Some(slot) = slots.next() {
+                slot.write(item);
+            }
+        })
+        .unwrap_or(0)
+    }
+
+    // Pop whatever is in the ring right now, e.g. before shutting down, so
+    // that leftovers are handled rather than dropped with the ring. Ends at
+    // the first empty slot; corrupt items are skipped like in `pop_into`.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || loop {
+            match self.try_pop() {
+                Ok(item) => return Some(item),
+                Err(RbufError::CorruptMessage { .. }) => {}
+                Err(_) => return None,
+            }
+        })
+    }
+
+    // The next item, left in the ring. The borrow keeps `pop` from freeing
+    // its slot while the reference is alive.
+    pub fn peek(&self) -> Option<&T> {
+        self.peek_many(1).next()
+    }
+
+    // Up to `max` items from the front of the ring, oldest first, without
+    // consuming them. Stops early at a slot a producer is still writing, or
+    // one that fails its checksum.
+    //
+    // Always empty once any producer uses `FullPolicy::Overwrite`: those
+    // producers may reclaim a slot at any moment, so nothing in the ring can
+    // be borrowed.
+    pub fn peek_many(&self, max: usize) -> impl Iterator<Item = &T> + '_ {
+        let header = self.rb.header();
+        let overwrite = header.overwrite.load(Ordering::Acquire) != 0;
+        let tail = header.tail.load(Ordering::Acquire);
+
+        let mut seq = header.head.load(Ordering::Relaxed);
+        let mut remaining = if overwrite { 0 } else { max };
+        std::iter::from_fn(move || {
+            while remaining > 0 && seq != tail {
+                let slot = seq;
+                seq += 1;
+                match self.rb.slot_flag(slot).load(Ordering::Acquire) {
+                    SLOT_COMMITTED if self.rb.verify(slot).is_ok() => {
+                        remaining -= 1;
+                        return Some(unsafe { &*self.rb.buffer_ptr(slot) });
+                    }
+                    SLOT_ABORTED => {}
+                    _ => break,
+                }
+            }
+            remaining = 0;
+            None
+        })
+    }
+
+    // Two-phase pop for at-least-once delivery: the next item stays in the
+    // ring until the guard is `ack`ed, so if the guard is dropped, or this
+    // process dies, before that, the next pop gets the same item again.
+    // Fails with `CorruptMessage` like `pop`, and with `IncompatibleLayout`
+    // once any producer uses `FullPolicy::Overwrite`, since those may
+    // reclaim the item while it is held.
+    pub fn begin_pop(&mut self) -> Result<PopGuard<'_, T>, RbufError> {
+        self.try_begin_pop()
+    }
+
+    fn try_begin_pop(&self) -> Result<PopGuard<'_, T>, RbufError> {
+        let header = self.rb.header();
+        if header.overwrite.load(Ordering::Acquire) != 0 {
+            return Err(RbufError::IncompatibleLayout(
+                "producers may overwrite items, so they can't be held".to_string(),
+            ));
+        }
+
+        let tail = header.tail.load(Ordering::Acquire);
+        let mut head = header.head.load(Ordering::Relaxed);
+        loop {
+            if head == tail {
+                return Err(self.nothing_left());
+            }
+            let flag = self.rb.slot_flag(head);
+            match flag.load(Ordering::Acquire) {
+                SLOT_COMMITTED => match self.rb.verify(head) {
+                    Ok(()) => return Ok(PopGuard { consumer: self, seq: head }),
+                    Err(e) => {
+                        header.consumer_stats.record_corrupt();
+                        event!(warn, ring = self.name(), seq = head, "discarded corrupt item");
+                        flag.store(SLOT_EMPTY, Ordering::Relaxed);
+                        self.release_to(head + 1);
+                        return Err(e);
+                    }
+                },
+                // Nothing to hold on to; give it up right away
+                SLOT_ABORTED => {
+                    flag.store(SLOT_EMPTY, Ordering::Relaxed);
+                    head += 1;
+                    self.release_to(head);
+                }
+                _ => return Err(RbufError::Empty),
+            }
+        }
+    }
+
+    // Hand the next item to `f` where it lies in the ring and consume it
+    // once `f` returns, so a large `T` is never moved. If `f` panics the item
+    // stays in the ring, as with a dropped `begin_pop` guard. Rings whose
+    // producers may overwrite items copy them out first, like `pop`.
+    pub fn pop_with<R>(&mut self, f: impl FnOnce(&T) -> R) -> Result<R, RbufError> {
+        if self.rb.header().overwrite.load(Ordering::Acquire) != 0 {
+            return self.try_pop().map(|item| f(&item));
+        }
+        let guard = self.begin_pop()?;
+        let result = f(&guard);
+        guard.ack();
+        Ok(result)
+    }
+
+    // The next item, borrowed in place; dropping the `PopRef` consumes it.
+    // Corrupt items are skipped and only show up in the stats. Always `None`
+    // once any producer uses `FullPolicy::Overwrite`, like `peek_many`.
+    pub fn pop_ref(&mut self) -> Option<PopRef<'_, T>> {
+        loop {
+            match self.try_begin_pop() {
+                Ok(guard) => return Some(PopRef { guard }),
+                Err(RbufError::CorruptMessage { .. }) => {}
+                Err(_) => return None,
+            }
+        }
+    }
+
+    // Up to `max` items borrowed in place as one slice; dropping the
+    // `PopSlice` consumes all of them. The slice ends before a slot that is
+    // still being written, aborted or corrupt, and at the end of the buffer
+    // unless the ring is mirrored (see `RingConfig::mirrored`). Aborted and
+    // corrupt slots at the front are skipped, the latter showing up in the
+    // stats. Always `None` once any producer uses `FullPolicy::Overwrite`.
+    pub fn pop_slice_ref(&mut self, max: usize) -> Option<PopSlice<'_, T>> {
+        let header = self.rb.header();
+        if max == 0 || header.overwrite.load(Ordering::Acquire) != 0 {
+            return None;
+        }
+
+        let tail = header.tail.load(Ordering::Acquire);
+        let mut head = header.head.load(Ordering::Relaxed);
+        let ready = |seq| {
+            self.rb.slot_flag(seq).load(Ordering::Acquire) == SLOT_COMMITTED
+                && self.rb.verify(seq).is_ok()
+        };
+        // Clear whatever can't be handed out from the front
+        loop {
+            if head == tail {
+                return None;
+            }
+            let flag = self.rb.slot_flag(head);
+            match flag.load(Ordering::Acquire) {
+                SLOT_COMMITTED if self.rb.verify(head).is_ok() => break,
+                SLOT_COMMITTED => {
+                    header.consumer_stats.record_corrupt();
+                    event!(warn, ring = self.name(), seq = head, "discarded corrupt item");
+                }
+                SLOT_ABORTED => {}
+                _ => return None,
+            }
+            flag.store(SLOT_EMPTY, Ordering::Relaxed);
+            head += 1;
+            self.release_to(head);
+        }
+
+        let limit = max.min((tail - head) as usize).min(self.rb.contiguous(head));
+        let mut count = 1;
+        while count < limit && ready(head + count as u64) {
+            count += 1;
+        }
+        Some(PopSlice { consumer: self, start: head, count })
+    }
+
+    // Read committed items from `head` onwards, then release all of their
+    // slots with a single store to `head` and a single notification. A
+    // corrupt item ends the batch; if it comes first it is released and
+    // reported instead. `sink` also gets how long each item waited, on rings
+    // with timestamps.
+    fn pop_batch(
+        &self,
+        max: usize,
+        mut sink: impl FnMut(T, Option<Duration>),
+    ) -> Result<usize, RbufError> {
+        let header = self.rb.header();
+        if header.overwrite.load(Ordering::Acquire) != 0 {
+            let mut popped = 0;
+            while popped < max {
+                match self.pop_contended() {
+                    Ok(Some((item, waited))) => sink(item, waited),
+                    Ok(None) => break,
+                    Err(e) if popped == 0 => return Err(e),
+                    Err(_) => break,
+                }
+                popped += 1;
+            }
+            return Ok(popped);
+        }
+
+        let start = header.head.load(Ordering::Relaxed);
+        let tail = header.tail.load(Ordering::Acquire);
+
+        let mut head = start;
+        let mut popped = 0;
+        let mut corrupt = None;
+        let now = self.rb.now();
+        while head != tail && popped < max {
+            let flag = self.rb.slot_flag(head);
+            match flag.load(Ordering::Acquire) {
+                SLOT_COMMITTED => match self.rb.verify(head) {
+                    // Read the data from the buffer slot
+                    Ok(()) => {
+                        let waited = self.rb.waited(head, now);
+                        self.rb.record_latency(waited);
+                        sink(unsafe { self.rb.buffer_ptr(head).read() }, waited);
+                        popped += 1;
+                    }
+                    // Leave it to be reported by the next call
+                    Err(_) if popped > 0 => break,
+                    Err(e) => {
+                        header.consumer_stats.record_corrupt();
+                        event!(warn, ring = self.name(), seq = head, "discarded corrupt item");
+                        corrupt = Some(e);
+                        flag.store(SLOT_EMPTY, Ordering::Relaxed);
+                        head += 1;
+                        break;
+                    }
+                },
+                // An abandoned reservation: nothing to read, just step over it
+                SLOT_ABORTED => {}
+                // The slot is reserved but its producer hasn't finished writing yet
+                _ => break,
+            }
+            flag.store(SLOT_EMPTY, Ordering::Relaxed);
+            head += 1;
+        }
+
+        if popped > 0 {
+            header.consumer_stats.record_pop(popped);
+            event!(trace, ring = self.name(), seq = start, count = popped, "popped");
+        }
+
+        // Hand the slots back to producers
+        if head != start {
+            self.release_to(head);
+        }
+        match corrupt {
+            Some(e) => Err(e),
+            None => Ok(popped),
+        }
+    }
+
+    // Pop a single item when producers may be dropping items from under us.
+    // The item is copied out first and only kept if our CAS on `head` wins;
+    // if a producer dropped the slot meanwhile the copy may be torn and is
+    // discarded without running its destructor.
+    fn pop_contended(&self) -> Result<Option<(T, Option<Duration>)>, RbufError> {
+        let header = self.rb.header();
+        let now = self.rb.now();
+        loop {
+            // The copy, checksum and timestamp are only trusted if we win the
+            // slot, so the copy isn't dropped if we don't
+            let read = |head| {
+                let item = ManuallyDrop::new(unsafe { self.rb.buffer_ptr(head).read() });
+                (item, self.rb.verify(head), self.rb.waited(head, now))
+            };
+            let flag = |head| self.rb.slot_flag(head);
+            let Some((head, copy)) = slots::pop(&*header.head, &*header.tail, flag, read) else {
+                return Ok(None);
+            };
+
+            self.advance_cursor(head + 1);
+            header.space_ready.notify();
+            match copy {
+                Some((_, Err(e), _)) => {
+                    header.consumer_stats.record_corrupt();
+                    event!(warn, ring = self.name(), seq = head, "discarded corrupt item");
+                    return Err(e);
+                }
+                Some((item, Ok(()), waited)) => {
+                    self.rb.record_latency(waited);
+                    header.consumer_stats.record_pop(1);
+                    event!(trace, ring = self.name(), seq = head, count = 1, "popped");
+                    return Ok(Some((ManuallyDrop::into_inner(item), waited)));
+                }
+                // An aborted reservation
+                None => {}
+            }
+        }
+    }
+
+    // Pop, waiting as the wait strategy says until a producer publishes.
+    // Corrupt items are skipped; they only show up in the stats. Fails with
+    // `Disconnected` once a producer has closed the ring and it's drained.
+    pub fn pop_blocking(&mut self) -> Result<T, RbufError> {
+        loop {
+            match self.wait_for_item(None) {
+                Some(Err(RbufError::CorruptMessage { .. })) => {}
+                Some(result) => return result,
+                None => unreachable!("waiting without a deadline never times out"),
+            }
+        }
+    }
+
+    // Like `pop_blocking`, but gives up after `timeout`, and reports a
+    // corrupt item as `CorruptMessage` like `pop`
+    pub fn pop_timeout(&mut self, timeout: Duration) -> Result<T, RbufError> {
+        self.wait_for_item(Some(Instant::now() + timeout)).unwrap_or(Err(RbufError::Timeout))
+    }
+
+    // Wait in slices of at most `HEARTBEAT_INTERVAL`, beating in between so
+    // producers can tell a quiet consumer from a dead one
+    fn wait_for_item(&self, deadline: Option<Instant>) -> Option<Result<T, RbufError>> {
+        let data_ready = &self.rb.header().data_ready;
+        loop {
+            let slice = Instant::now() + HEARTBEAT_INTERVAL;
+            let until = deadline.map_or(slice, |deadline| deadline.min(slice));
+            let popped = wait::wait_until(&*self.wait, data_ready, Some(until), || {
+                match self.try_pop() {
+                    Err(RbufError::Empty) => None,
+                    result => Some(result),
+                }
+            });
+            if popped.is_some() {
+                return popped;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return None;
+            }
+            event!(trace, ring = self.name(), "still waiting for items");
+            self.heartbeat();
+        }
+    }
+}
+
+impl<T> Drop for Consumer<T> {
+    fn drop(&mut self) {
+        // The relay thread reads the header, so it goes before the mapping
+        #[cfg(target_os = "linux")]
+        drop(self.readable.take());
+        if let Some(index) = self.registration {
+            self.rb.consumers().unregister(index);
+        }
+    }
+}
+
+// An item held in the ring by `Consumer::begin_pop`. `ack` consumes it;
+// dropping the guard without acking leaves it to be delivered again.
+pub struct PopGuard<'a, T> {
+    consumer: &'a Consumer<T>,
+    seq: u64,
+}
+
+impl<T: ShmSafe> PopGuard<'_, T> {
+    // The item's position in the ring, which stays the same when it is
+    // redelivered: a key for spotting duplicates downstream
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    // Consume the item and hand its slot back to producers
+    pub fn ack(self) {
+        self.consume();
+    }
+
+    fn consume(&self) {
+        let rb = &self.consumer.rb;
+        rb.record_latency(rb.waited(self.seq, rb.now()));
+        drop(unsafe { rb.buffer_ptr(self.seq).read() });
+        rb.slot_flag(self.seq).store(SLOT_EMPTY, Ordering::Relaxed);
+        self.consumer.release_to(self.seq + 1);
+        rb.header().consumer_stats.record_pop(1);
+        event!(trace, ring = rb.name(), seq = self.seq, count = 1, "popped");
+    }
+}
+
+impl<T: ShmSafe> Deref for PopGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.consumer.rb.buffer_ptr(self.seq) }
+    }
+}
+
+// An item borrowed in place by `Consumer::pop_ref`, consumed when dropped
+pub struct PopRef<'a, T: ShmSafe> {
+    guard: PopGuard<'a, T>,
+}
+
+impl<T: ShmSafe> PopRef<'_, T> {
+    // The item's position in the ring
+    pub fn seq(&self) -> u64 {
+        self.guard.seq
+    }
+}
+
+impl<T: ShmSafe> Deref for PopRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ShmSafe> Drop for PopRef<'_, T> {
+    fn drop(&mut self) {
+        self.guard.consume();
+    }
+}
+
+// A run of items borrowed in place by `Consumer::pop_slice_ref`, consumed
+// together when dropped
+pub struct PopSlice<'a, T: ShmSafe> {
+    consumer: &'a Consumer<T>,
+    start: u64,
+    count: usize,
+}
+
+impl<T: ShmSafe> PopSlice<'_, T> {
+    // The position in the ring of the first item
+    pub fn seq(&self) -> u64 {
+        self.start
+    }
+}
+
+impl<T: ShmSafe> Deref for PopSlice<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        let first = self.consumer.rb.buffer_ptr(self.start);
+        unsafe { std::slice::from_raw_parts(first, self.count) }
+    }
+}
+
+impl<T: ShmSafe> Drop for PopSlice<'_, T> {
+    fn drop(&mut self) {
+        let rb = &self.consumer.rb;
+        let now = rb.now();
+        for seq in self.start..self.start + self.count as u64 {
+            rb.record_latency(rb.waited(seq, now));
+            unsafe { std::ptr::drop_in_place(rb.buffer_ptr(seq)) };
+            rb.slot_flag(seq).store(SLOT_EMPTY, Ordering::Relaxed);
+        }
+        self.consumer.release_to(self.start + self.count as u64);
+        rb.header().consumer_stats.record_pop(self.count);
+        event!(trace, ring = rb.name(), seq = self.start, count = self.count, "popped");
+    }
+}
@@ -0,0 +1,185 @@
+// control.rs
+//
+// Commands an admin tool sends to whatever processes are attached to a
+// ring, through the control line of its header: pause and resume
+// publishing, reset the stats, change the log level. Sending one does
+// what the library can do about it on the spot (a paused typed ring's
+// producers fail with `Paused`, reset stats read zero) and leaves the
+// rest to applications, which see every command through a
+// `ControlListener`.
+//
+// The last `COMMAND_SLOTS` commands sit in a small ring of their own, each
+// as one word:
+//
+//     [ sequence number + 1: u32 | command: u16 | argument: u16 ]
+//
+// so that a listener can tell a slot it's waiting for from one written
+// before or since. One that falls more than `COMMAND_SLOTS` behind loses
+// the oldest. Whether publishing is paused and the last log level asked
+// for are kept apart from that, so they are never lost.
+use std::sync::atomic::Ordering;
+
+use crate::error::RbufError;
+use crate::header::{Control, RingBufferHeader, COMMAND_SLOTS};
+use crate::ring::RingBuffer;
+use crate::segment::{Backing, Segment};
+
+// How verbose an admin wants an application's logging
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u16)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl LogLevel {
+    fn from_u16(level: u16) -> Option<Self> {
+        match level {
+            1 => Some(LogLevel::Error),
+            2 => Some(LogLevel::Warn),
+            3 => Some(LogLevel::Info),
+            4 => Some(LogLevel::Debug),
+            5 => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    // Stop publishing: pushes on a typed ring fail with `Paused`, and
+    // `push_blocking` waits for `Resume`
+    Pause,
+    Resume,
+    // Counters in the header start again from zero
+    ResetStats,
+    SetLogLevel(LogLevel),
+}
+
+impl Command {
+    fn encode(self) -> (u16, u16) {
+        match self {
+            Command::Pause => (1, 0),
+            Command::Resume => (2, 0),
+            Command::ResetStats => (3, 0),
+            Command::SetLogLevel(level) => (4, level as u16),
+        }
+    }
+
+    fn decode(command: u16, argument: u16) -> Option<Self> {
+        match command {
+            1 => Some(Command::Pause),
+            2 => Some(Command::Resume),
+            3 => Some(Command::ResetStats),
+            4 => LogLevel::from_u16(argument).map(Command::SetLogLevel),
+            _ => None,
+        }
+    }
+}
+
+fn tag(seq: u64) -> u32 {
+    (seq as u32).wrapping_add(1)
+}
+
+pub(crate) fn send(header: &RingBufferHeader, command: Command) {
+    let control = &header.control;
+    match command {
+        Command::Pause => control.paused.store(1, Ordering::Release),
+        Command::Resume => {
+            control.paused.store(0, Ordering::Release);
+            // Wake producers waiting out the pause in `push_blocking`
+            header.space_ready.notify();
+        }
+        Command::ResetStats => header.reset_stats(),
+        Command::SetLogLevel(level) => control.log_level.store(level as u32, Ordering::Release),
+    }
+    let seq = control.commands_sent.fetch_add(1, Ordering::AcqRel);
+    let (command, argument) = command.encode();
+    let word = (tag(seq) as u64) << 32 | (command as u64) << 16 | argument as u64;
+    control.commands[seq as usize % COMMAND_SLOTS].store(word, Ordering::Release);
+}
+
+impl RingBuffer {
+    // Send `command` to every process attached to the named ring, of any
+    // kind, without attaching to it
+    pub fn send_command(name: &str, command: Command) -> Result<(), RbufError> {
+        let segment = Backing::Shm.open(name)?;
+        let header = RingBufferHeader::validate_any(segment.as_ptr(), segment.len())?;
+        send(header, command);
+        event!(debug, ring = name, ?command, "sent command");
+        Ok(())
+    }
+}
+
+// Sees the commands sent to a ring from when it was opened on
+pub struct ControlListener {
+    segment: Box<dyn Segment>,
+    header: *const RingBufferHeader,
+    // Sequence number of the next command to hand out
+    next: u64,
+}
+
+unsafe impl Send for ControlListener {}
+unsafe impl Sync for ControlListener {}
+
+impl ControlListener {
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        let segment = Backing::Shm.open(name)?;
+        let header = RingBufferHeader::validate_any(segment.as_ptr(), segment.len())?;
+        let next = header.control.commands_sent.load(Ordering::Acquire);
+        let header = header as *const RingBufferHeader;
+        Ok(Self { segment, header, next })
+    }
+
+    fn control(&self) -> &Control {
+        unsafe { &(*self.header).control }
+    }
+
+    pub fn name(&self) -> &str {
+        self.segment.name()
+    }
+
+    // The next command, or None if there isn't one yet. Fails with `Lagged`
+    // if commands came faster than we polled and the oldest were lost; the
+    // next poll carries on with the oldest left. Commands from a newer
+    // version of the library are skipped.
+    pub fn poll(&mut self) -> Result<Option<Command>, RbufError> {
+        // The header lives as long as the mapping, not just this borrow
+        let control = unsafe { &(*self.header).control };
+        loop {
+            let sent = control.commands_sent.load(Ordering::Acquire);
+            if self.next >= sent {
+                return Ok(None);
+            }
+            let word = control.commands[self.next as usize % COMMAND_SLOTS].load(Ordering::Acquire);
+            let ahead = ((word >> 32) as u32).wrapping_sub(tag(self.next)) as i32;
+            if ahead < 0 {
+                // Sent, but its sender hasn't written the slot yet
+                return Ok(None);
+            }
+            if ahead > 0 || sent - self.next > COMMAND_SLOTS as u64 {
+                let oldest = sent.saturating_sub(COMMAND_SLOTS as u64).max(self.next + 1);
+                let missed = (oldest - self.next) as usize;
+                self.next = oldest;
+                return Err(RbufError::Lagged { missed });
+            }
+            self.next += 1;
+            if let Some(command) = Command::decode((word >> 16) as u16, word as u16) {
+                return Ok(Some(command));
+            }
+        }
+    }
+
+    // Whether publishing is paused, however long ago that was sent
+    pub fn is_paused(&self) -> bool {
+        self.control().paused.load(Ordering::Acquire) != 0
+    }
+
+    // The log level an admin last asked for, if any
+    pub fn log_level(&self) -> Option<LogLevel> {
+        LogLevel::from_u16(self.control().log_level.load(Ordering::Acquire) as u16)
+    }
+}
@@ -0,0 +1,137 @@
+// crypto.rs
+//
+// Authenticated encryption of byte-ring messages, for hosts where processes
+// we don't trust could map the segment. Every handle is given the same
+// 256-bit key out of band; the segment itself only holds a check value that
+// lets handles with the wrong key fail at open.
+//
+// Encrypted records hold [ nonce: 24 | ciphertext | tag: 16 ]. The nonce is
+// random (XChaCha20-Poly1305's 192 bits make collisions a non-issue), and
+// the record's position in the ring, state and stored length are
+// authenticated along with the message, so a process that can write to the
+// segment can't read, alter, reorder or replay messages. It can still drop
+// them or wedge the ring.
+//
+// Messages are sealed and opened in private memory and only copied to or
+// from the segment whole, so plaintext never sits in shared memory.
+
+#[cfg(feature = "encryption")]
+pub(crate) use self::aead::Cipher;
+
+#[cfg(feature = "encryption")]
+mod aead {
+    use chacha20poly1305::aead::{AeadCore, AeadInPlace, KeyInit, OsRng};
+    use chacha20poly1305::{Tag, XChaCha20Poly1305, XNonce};
+
+    use crate::error::RbufError;
+
+    const NONCE_SIZE: usize = 24;
+    const TAG_SIZE: usize = 16;
+
+    pub(crate) struct Cipher {
+        aead: XChaCha20Poly1305,
+    }
+
+    impl Cipher {
+        // Bytes an encrypted record needs on top of its message
+        pub(crate) const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+        pub(crate) fn new(key: &[u8; 32]) -> Self {
+            Self { aead: XChaCha20Poly1305::new(key.into()) }
+        }
+
+        // Part of the tag of an empty message under a fixed nonce: the same
+        // for every handle with the same key, and says nothing about the key
+        pub(crate) fn key_check(&self) -> u64 {
+            let tag = self
+                .aead
+                .encrypt_in_place_detached(&XNonce::default(), b"rbuf key check", &mut [])
+                .expect("an empty message always encrypts");
+            u64::from_le_bytes(tag[..8].try_into().expect("tags are 16 bytes"))
+        }
+
+        // Encrypt `message` for the record at `pos`, returning what to store
+        pub(crate) fn seal(&self, pos: u64, state: u32, message: &[u8]) -> Vec<u8> {
+            let len = Self::OVERHEAD + message.len();
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let mut record = Vec::with_capacity(len);
+            record.extend_from_slice(&nonce);
+            record.extend_from_slice(message);
+            let tag = self
+                .aead
+                .encrypt_in_place_detached(
+                    &nonce,
+                    &associated_data(pos, state, len),
+                    &mut record[NONCE_SIZE..],
+                )
+                .expect("messages under 256 GiB always encrypt");
+            record.extend_from_slice(&tag);
+            record
+        }
+
+        // Decrypt a stored record into `out`, replacing its contents
+        pub(crate) fn open(
+            &self,
+            pos: u64,
+            state: u32,
+            record: &[u8],
+            out: &mut Vec<u8>,
+        ) -> Result<(), RbufError> {
+            let unauthenticated = RbufError::Unauthenticated { pos };
+            if record.len() < Self::OVERHEAD {
+                return Err(unauthenticated);
+            }
+            let (nonce, rest) = record.split_at(NONCE_SIZE);
+            let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+            out.clear();
+            out.extend_from_slice(ciphertext);
+            self.aead
+                .decrypt_in_place_detached(
+                    XNonce::from_slice(nonce),
+                    &associated_data(pos, state, record.len()),
+                    out,
+                    Tag::from_slice(tag),
+                )
+                .map_err(|_| unauthenticated)
+        }
+    }
+
+    fn associated_data(pos: u64, state: u32, len: usize) -> [u8; 16] {
+        let mut data = [0; 16];
+        data[..8].copy_from_slice(&pos.to_le_bytes());
+        data[8..12].copy_from_slice(&state.to_le_bytes());
+        data[12..].copy_from_slice(&(len as u32).to_le_bytes());
+        data
+    }
+}
+
+// Without the feature no ring can be opened with a key, so there is never a
+// cipher to call
+#[cfg(not(feature = "encryption"))]
+pub(crate) enum Cipher {}
+
+#[cfg(not(feature = "encryption"))]
+use crate::error::RbufError;
+
+#[cfg(not(feature = "encryption"))]
+impl Cipher {
+    pub(crate) const OVERHEAD: usize = 0;
+
+    pub(crate) fn key_check(&self) -> u64 {
+        match *self {}
+    }
+
+    pub(crate) fn seal(&self, _pos: u64, _state: u32, _message: &[u8]) -> Vec<u8> {
+        match *self {}
+    }
+
+    pub(crate) fn open(
+        &self,
+        _pos: u64,
+        _state: u32,
+        _record: &[u8],
+        _out: &mut Vec<u8>,
+    ) -> Result<(), RbufError> {
+        match *self {}
+    }
+}
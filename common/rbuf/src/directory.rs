@@ -0,0 +1,376 @@
+// directory.rs
+//
+// A well-known segment listing the rings on the system, so that tools and
+// processes can find what's there without being told the names up front.
+// Every ring created under `Backing::Shm` (without huge pages) registers
+// itself on create and `RingBuffer::unlink` takes it out again. Rings whose
+// segment went away some other way, dropped by its owner or left behind by
+// a crash and collected, are pruned the next time someone looks.
+//
+//     for entry in Directory::list()? {
+//         if entry.kind == RingKind::Typed && entry.is::<Quote>() {
+//             let consumer = Consumer::<Quote>::open(&entry.name)?;
+//         }
+//     }
+//
+// Registering is best effort: a full directory, or one another user created
+// without letting us write to it, just leaves the ring unlisted.
+//
+// [ header | entries ]
+use std::any;
+use std::mem;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::error::RbufError;
+use crate::header::{RingBufferHeader, RingKind};
+#[cfg(unix)]
+use crate::segment::Permissions;
+use crate::segment::{self, Backing, Segment, SegmentConfig};
+
+// The segment's name, the same in every process
+pub const DIRECTORY_NAME: &str = "rbuf.directory";
+
+// Number of rings the directory can list at once
+const ENTRIES: usize = 1024;
+
+// Longest ring name that fits in an entry; longer ones go unlisted
+const MAX_NAME: usize = 128;
+
+// How long `open` keeps retrying while another process creates the segment
+const OPEN_WAIT: Duration = Duration::from_secs(1);
+
+// Entry states, in the low two bits of `Entry::state`. The rest counts
+// changes, so that a reader can tell an entry was rewritten under it.
+const FREE: u64 = 0;
+const WRITING: u64 = 1;
+const LIVE: u64 = 2;
+
+fn tag(state: u64) -> u64 {
+    state & 3
+}
+
+fn next(state: u64, tag: u64) -> u64 {
+    ((state & !3) + 4) | tag
+}
+
+// One ring. Everything is atomic since readers copy entries that a writer
+// in another process may be filling in; they check `state` before and
+// after, like a seqlock.
+#[repr(C)]
+struct Entry {
+    state: AtomicU64,
+    type_hash: AtomicU64,
+    capacity: AtomicU64,
+    // Nanoseconds since the Unix epoch
+    created: AtomicU64,
+    kind: AtomicU32,
+    creator_pid: AtomicU32,
+    name_len: AtomicU32,
+    _reserved: AtomicU32,
+    name: [AtomicU8; MAX_NAME],
+}
+
+const _: () = assert!(mem::size_of::<Entry>() == 48 + MAX_NAME);
+
+fn segment_size() -> usize {
+    mem::size_of::<RingBufferHeader>() + ENTRIES * mem::size_of::<Entry>()
+}
+
+// --- Type hashes ---
+
+pub(crate) const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+pub(crate) fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+// The fingerprint rings of `T` record and check handles against, and are
+// listed with: a hash of the type's name, size and alignment. Type names
+// aren't guaranteed stable between compiler versions, so processes built by
+// different toolchains may disagree; see `RingConfig::schema`.
+pub fn type_hash<T>() -> u64 {
+    let hash = fnv1a(FNV_OFFSET, any::type_name::<T>().as_bytes());
+    let hash = fnv1a(hash, &(mem::size_of::<T>() as u64).to_le_bytes());
+    fnv1a(hash, &(mem::align_of::<T>() as u64).to_le_bytes())
+}
+
+// --- Entries ---
+
+// A ring as listed in the directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    pub name: String,
+    pub kind: RingKind,
+    // The fingerprint the ring checks handles against: `type_hash::<T>()`
+    // for typed, broadcast and MPMC rings (unless set with
+    // `RingConfig::schema`), the schema for byte rings, 0 for pools and
+    // arenas
+    pub type_hash: u64,
+    // Slots, or blocks for pools and bytes for arenas and byte rings
+    pub capacity: usize,
+    pub creator_pid: u32,
+    pub created: SystemTime,
+}
+
+impl DirectoryEntry {
+    // Whether the ring holds `T`s, going by `type_hash`
+    pub fn is<T>(&self) -> bool {
+        self.type_hash == type_hash::<T>()
+    }
+}
+
+impl Entry {
+    // The entry and the state it was read at, if it lists a ring and wasn't
+    // changed while being copied
+    fn read(&self) -> Option<(u64, DirectoryEntry)> {
+        let state = self.state.load(Ordering::Acquire);
+        if tag(state) != LIVE {
+            return None;
+        }
+        let len = (self.name_len.load(Ordering::Relaxed) as usize).min(MAX_NAME);
+        let name: Vec<u8> = self.name[..len].iter().map(|b| b.load(Ordering::Relaxed)).collect();
+        let kind = RingKind::from_u32(self.kind.load(Ordering::Relaxed));
+        let entry = DirectoryEntry {
+            name: String::from_utf8(name).ok()?,
+            kind: kind?,
+            type_hash: self.type_hash.load(Ordering::Relaxed),
+            capacity: self.capacity.load(Ordering::Relaxed) as usize,
+            creator_pid: self.creator_pid.load(Ordering::Relaxed),
+            created: UNIX_EPOCH + Duration::from_nanos(self.created.load(Ordering::Relaxed)),
+        };
+        fence(Ordering::Acquire);
+        (self.state.load(Ordering::Relaxed) == state).then_some((state, entry))
+    }
+
+    // Only called while holding the entry in WRITING
+    fn write(&self, name: &str, header: &RingBufferHeader) {
+        let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.type_hash.store(header.options.schema, Ordering::Relaxed);
+        self.capacity.store(header.capacity() as u64, Ordering::Relaxed);
+        self.created.store(created.as_nanos() as u64, Ordering::Relaxed);
+        self.kind.store(header.kind, Ordering::Relaxed);
+        self.creator_pid.store(std::process::id(), Ordering::Relaxed);
+        self.name_len.store(name.len() as u32, Ordering::Relaxed);
+        for (slot, &byte) in self.name.iter().zip(name.as_bytes()) {
+            slot.store(byte, Ordering::Relaxed);
+        }
+    }
+
+    // Free the entry if it's still in `state`
+    fn remove(&self, state: u64) {
+        let _ = self.state.compare_exchange(
+            state,
+            next(state, FREE),
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+// --- Directory ---
+
+// A mapping of the directory segment. Rings don't keep one: each lookup
+// maps it for as long as it takes.
+pub struct Directory {
+    segment: Box<dyn Segment>,
+}
+
+impl Directory {
+    // Every ring currently listed, oldest first
+    pub fn list() -> Result<Vec<DirectoryEntry>, RbufError> {
+        let directory = Self::open()?;
+        let mut rings = directory.live(|_| true);
+        rings.sort_by_key(|entry| entry.created);
+        Ok(rings)
+    }
+
+    // The ring called `name`, if it's listed
+    pub fn lookup(name: &str) -> Result<Option<DirectoryEntry>, RbufError> {
+        let directory = Self::open()?;
+        let found = directory.live(|entry| entry.name == name).pop();
+        Ok(found)
+    }
+
+    // Map the directory, creating it if this is the first process to ask.
+    // Anyone may register in it, so it's created world-writable.
+    fn open() -> Result<Self, RbufError> {
+        let config = SegmentConfig {
+            #[cfg(unix)]
+            permissions: Some(Permissions { mode: Some(0o666), ..Default::default() }),
+            ..Default::default()
+        };
+        let deadline = Instant::now() + OPEN_WAIT;
+        loop {
+            match config.create(DIRECTORY_NAME, segment_size()) {
+                Ok(segment) => return Ok(Self::initialize(segment)),
+                Err(e) if segment::already_exists(&e) => {}
+                Err(e) => return Err(e),
+            }
+            match Self::attach() {
+                // Being created, or just unlinked; try again from the top
+                Err(RbufError::ShmemOpen(_))
+                | Err(RbufError::Backend(_))
+                | Err(RbufError::SizeMismatch { .. })
+                | Err(RbufError::StaleSegment)
+                    if Instant::now() < deadline =>
+                {
+                    thread::sleep(Duration::from_micros(100));
+                }
+                // Left behind by a build with another format. It only lists
+                // what exists, so start over rather than stay unusable.
+                Err(RbufError::IncompatibleLayout(_)) if Instant::now() < deadline => {
+                    if let Ok(mut old) = Backing::Shm.open(DIRECTORY_NAME) {
+                        old.set_owner(true);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn initialize(mut segment: Box<dyn Segment>) -> Self {
+        let header = RingBufferHeader::new(
+            RingKind::Directory,
+            mem::size_of::<Entry>(),
+            mem::align_of::<Entry>(),
+            ENTRIES,
+        );
+        unsafe {
+            let base = segment.as_ptr();
+            let header = RingBufferHeader::initialize(base, header);
+            let entries = base.add(mem::size_of::<RingBufferHeader>());
+            std::ptr::write_bytes(entries, 0, ENTRIES * mem::size_of::<Entry>());
+            header.publish();
+        }
+        // Outlives every process that uses it
+        segment.set_owner(false);
+        Self::from_segment(segment)
+    }
+
+    fn attach() -> Result<Self, RbufError> {
+        let segment = Backing::Shm.open(DIRECTORY_NAME)?;
+        let header = RingBufferHeader::validate(
+            segment.as_ptr(),
+            segment.len(),
+            RingKind::Directory,
+            mem::size_of::<Entry>(),
+            mem::align_of::<Entry>(),
+        )?;
+        let expected = mem::size_of::<RingBufferHeader>()
+            + header.capacity() * mem::size_of::<Entry>();
+        if segment.len() < expected {
+            return Err(RbufError::SizeMismatch { expected, actual: segment.len() });
+        }
+        Ok(Self::from_segment(segment))
+    }
+
+    fn from_segment(segment: Box<dyn Segment>) -> Self {
+        let directory = Self { segment };
+        directory.header().attached.fetch_add(1, Ordering::AcqRel);
+        directory
+    }
+
+    fn header(&self) -> &RingBufferHeader {
+        unsafe { &*(self.segment.as_ptr() as *const RingBufferHeader) }
+    }
+
+    fn entries(&self) -> &[Entry] {
+        unsafe {
+            let entries = self.segment.as_ptr().add(mem::size_of::<RingBufferHeader>());
+            std::slice::from_raw_parts(entries as *const Entry, self.header().capacity())
+        }
+    }
+
+    // The listed rings that `wanted` picks and whose segment still exists,
+    // freeing the ones that are gone. Rings this process may not open stay
+    // listed for those that may.
+    fn live(&self, wanted: impl Fn(&DirectoryEntry) -> bool) -> Vec<DirectoryEntry> {
+        let entries = self.entries().iter().filter_map(|slot| {
+            let (state, entry) = slot.read().filter(|(_, entry)| wanted(entry))?;
+            if !segment::shm_exists(&entry.name) {
+                slot.remove(state);
+                return None;
+            }
+            Some(entry)
+        });
+        entries.collect()
+    }
+
+    fn remove(&self, name: &str) {
+        for slot in self.entries() {
+            if let Some((state, entry)) = slot.read() {
+                if entry.name == name {
+                    slot.remove(state);
+                }
+            }
+        }
+    }
+
+    fn insert(&self, name: &str, header: &RingBufferHeader) -> bool {
+        for slot in self.entries() {
+            let state = slot.state.load(Ordering::Relaxed);
+            if tag(state) != FREE {
+                continue;
+            }
+            let writing = next(state, WRITING);
+            if slot
+                .state
+                .compare_exchange(state, writing, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                continue;
+            }
+            slot.write(name, header);
+            slot.state.store(next(writing, LIVE), Ordering::Release);
+            return true;
+        }
+        false
+    }
+}
+
+impl Drop for Directory {
+    fn drop(&mut self) {
+        self.header().detach();
+    }
+}
+
+// List a ring that was just created and published, replacing whatever was
+// listed under its name before
+pub(crate) fn register(name: &str, header: &RingBufferHeader) {
+    if name.len() > MAX_NAME || name == DIRECTORY_NAME {
+        return;
+    }
+    let Ok(directory) = Directory::open() else {
+        return;
+    };
+    directory.remove(name);
+    if !directory.insert(name, header) {
+        event!(debug, ring = name, "directory full, ring left unlisted");
+    }
+}
+
+// Take a ring out of the directory, if it's listed
+pub(crate) fn unregister(name: &str) {
+    if let Ok(directory) = Directory::attach() {
+        directory.remove(name);
+    }
+}
+
+// Rings listed, for tools that don't hold a `Directory`
+pub(crate) fn listed_raw(base: *const u8, len: usize) -> Option<usize> {
+    if len < segment_size() {
+        return None;
+    }
+    let entries = unsafe {
+        let entries = base.add(mem::size_of::<RingBufferHeader>()) as *const Entry;
+        std::slice::from_raw_parts(entries, ENTRIES)
+    };
+    Some(entries.iter().filter(|slot| tag(slot.state.load(Ordering::Acquire)) == LIVE).count())
+}
@@ -0,0 +1,212 @@
+// error.rs
+use shared_memory::ShmemError;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum RbufError {
+    // The OS refused to create the segment (already exists, no permission, ...)
+    ShmemCreate(ShmemError),
+    // The OS refused to open an existing segment
+    ShmemOpen(ShmemError),
+    // Creating or mapping the backing file failed
+    FileCreate(io::Error),
+    // Opening or mapping an existing backing file failed
+    FileOpen(io::Error),
+    // A heap segment couldn't be allocated, or the name is taken
+    HeapCreate(io::Error),
+    // No heap segment with that name exists in this process
+    HeapOpen(io::Error),
+    // Creating or sealing a memfd failed
+    FdCreate(io::Error),
+    // Mapping a passed descriptor failed
+    FdOpen(io::Error),
+    // Huge pages were asked for but can't be had, and why
+    HugePagesUnavailable(String),
+    // mlock refused to pin the segment, usually because of RLIMIT_MEMLOCK
+    MemoryLock(io::Error),
+    // The segment was created but its mode or owner couldn't be set. It has
+    // been removed again.
+    Permissions(io::Error),
+    // The slots of a mirrored ring couldn't be mapped a second time
+    Mirror(io::Error),
+    // A custom `Backend` failed to create or open its segment
+    Backend(io::Error),
+    // The mapped segment is smaller than the layout it claims to hold
+    SizeMismatch { expected: usize, actual: usize },
+    // The segment exists but its creator never finished setting it up
+    NotInitialized,
+    // The segment was created with a layout this handle can't work with
+    IncompatibleLayout(String),
+    // The ring has no free slot
+    Full,
+    // The ring has nothing to pop
+    Empty,
+    // Every entry in the consumer table is taken
+    ConsumerTableFull,
+    // A subscriber fell so far behind that messages were overwritten before it read them
+    Lagged { missed: usize },
+    // A publisher evicted this subscriber for falling too far behind
+    Evicted,
+    // A pool handle refers to a block that has been freed since
+    StaleHandle,
+    // A `ShmPtr` or `ShmSlice` doesn't point inside the segment it was
+    // resolved against, or is misaligned or null
+    InvalidPointer { offset: u64 },
+    // A blocking call gave up after its deadline
+    Timeout,
+    // A message can never fit in the ring, no matter how empty it is
+    MessageTooLarge { size: usize, max: usize },
+    // The process on the other side exited without detaching
+    PeerDead { pid: u32 },
+    // The other side is done with the ring: a producer closed it and every
+    // item pushed before has been popped, or no consumer is attached any
+    // more
+    Disconnected,
+    // A message didn't match the checksum its producer recorded, so something
+    // else wrote to the segment. The message has been discarded. From a byte
+    // ring, the record header at `seq` is one no writer could have written;
+    // it's left where it is, since its length can't be trusted to skip it.
+    CorruptMessage { seq: u64 },
+    // A message couldn't be serialized for sending
+    Encode(String),
+    // A received message couldn't be deserialized. It has been consumed.
+    Decode(String),
+    // An encrypted ring was opened with a key other than the one it was
+    // created with
+    KeyMismatch,
+    // A message on an encrypted ring was altered, moved or forged by
+    // something without the key. The message has been discarded.
+    Unauthenticated { pos: u64 },
+    // The segment this handle maps was unlinked, or its name now belongs to
+    // a newer one. Nothing pushed to it will be seen; open the ring again.
+    StaleSegment,
+    // The producer pushed as much as the ring's rate limit allows for now
+    RateLimited,
+    // The consumer hasn't granted credit for another item yet
+    NoCredit,
+    // An admin paused publishing on the ring with `Command::Pause`
+    Paused,
+}
+
+impl fmt::Display for RbufError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RbufError::ShmemCreate(e) => write!(f, "failed to create shared memory: {}", e),
+            RbufError::ShmemOpen(e) => write!(f, "failed to open shared memory: {}", e),
+            RbufError::FileCreate(e) => write!(f, "failed to create ring file: {}", e),
+            RbufError::FileOpen(e) => write!(f, "failed to open ring file: {}", e),
+            RbufError::HeapCreate(e) => write!(f, "failed to create heap segment: {}", e),
+            RbufError::HeapOpen(e) => write!(f, "failed to open heap segment: {}", e),
+            RbufError::FdCreate(e) => write!(f, "failed to create memfd segment: {}", e),
+            RbufError::FdOpen(e) => write!(f, "failed to map segment descriptor: {}", e),
+            RbufError::HugePagesUnavailable(reason) => write!(f, "huge pages unavailable: {}", reason),
+            RbufError::MemoryLock(e) => write!(f, "failed to lock the ring in memory: {}", e),
+            RbufError::Permissions(e) => write!(f, "failed to set segment permissions: {}", e),
+            RbufError::Mirror(e) => write!(f, "failed to mirror the ring's slots: {}", e),
+            RbufError::Backend(e) => write!(f, "ring backend failed: {}", e),
+            RbufError::SizeMismatch { expected, actual } => write!(
+                f,
+                "shared memory segment is {} bytes but the ring needs {}",
+                actual, expected
+            ),
+            RbufError::NotInitialized => write!(f, "shared memory segment was never initialized"),
+            RbufError::IncompatibleLayout(reason) => write!(f, "incompatible ring layout: {}", reason),
+            RbufError::Full => write!(f, "ring buffer is full"),
+            RbufError::Empty => write!(f, "ring buffer is empty"),
+            RbufError::ConsumerTableFull => write!(f, "no free consumer slot in the ring"),
+            RbufError::Lagged { missed } => write!(f, "consumer lagged behind and missed {} messages", missed),
+            RbufError::Evicted => write!(f, "subscriber was evicted for falling behind"),
+            RbufError::StaleHandle => write!(f, "pool handle refers to a freed block"),
+            RbufError::InvalidPointer { offset } => {
+                write!(f, "offset {:#x} doesn't point inside the segment", offset)
+            }
+            RbufError::Timeout => write!(f, "timed out waiting on the ring buffer"),
+            RbufError::MessageTooLarge { size, max } => {
+                write!(f, "message of {} bytes exceeds the {} byte maximum", size, max)
+            }
+            RbufError::PeerDead { pid } => write!(f, "peer process {} died", pid),
+            RbufError::Disconnected => write!(f, "the other side closed the ring"),
+            RbufError::CorruptMessage { seq } => {
+                write!(f, "message {} failed its checksum and was discarded", seq)
+            }
+            RbufError::Encode(reason) => write!(f, "failed to encode message: {}", reason),
+            RbufError::Decode(reason) => write!(f, "failed to decode message: {}", reason),
+            RbufError::KeyMismatch => {
+                write!(f, "key doesn't match the one the ring was created with")
+            }
+            RbufError::Unauthenticated { pos } => {
+                write!(f, "message at byte {} failed authentication and was discarded", pos)
+            }
+            RbufError::StaleSegment => {
+                write!(f, "the segment was unlinked or recreated; open the ring again")
+            }
+            RbufError::RateLimited => write!(f, "the ring's rate limit holds the producer back"),
+            RbufError::NoCredit => write!(f, "the consumer has granted no credit for more items"),
+            RbufError::Paused => write!(f, "publishing on the ring is paused"),
+        }
+    }
+}
+
+impl Error for RbufError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RbufError::ShmemCreate(e) | RbufError::ShmemOpen(e) => Some(e),
+            RbufError::FileCreate(e)
+            | RbufError::FileOpen(e)
+            | RbufError::HeapCreate(e)
+            | RbufError::HeapOpen(e)
+            | RbufError::FdCreate(e)
+            | RbufError::FdOpen(e)
+            | RbufError::MemoryLock(e)
+            | RbufError::Permissions(e)
+            | RbufError::Mirror(e)
+            | RbufError::Backend(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// A failed push hands the item back so the caller can retry or drop it
+pub struct PushError<T> {
+    error: RbufError,
+    item: T,
+}
+
+impl<T> PushError<T> {
+    pub(crate) fn new(error: RbufError, item: T) -> Self {
+        Self { error, item }
+    }
+
+    pub fn error(&self) -> &RbufError {
+        &self.error
+    }
+
+    pub fn into_inner(self) -> T {
+        self.item
+    }
+
+    // Drop the item, keeping only why it wasn't pushed
+    pub fn into_error(self) -> RbufError {
+        self.error
+    }
+}
+
+impl<T> fmt::Debug for PushError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PushError").field("error", &self.error).finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Display for PushError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<T> Error for PushError<T> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
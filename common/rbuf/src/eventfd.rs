@@ -0,0 +1,102 @@
+// eventfd.rs
+//
+// An eventfd that becomes readable when a typed ring has items, so that a
+// consumer can sit in an epoll or mio loop alongside its sockets. Producers
+// in other processes can't reach a descriptor of ours, so a thread here
+// sleeps on the ring's wait queue and writes to the eventfd for them: once
+// when it starts if the ring already has items, then on every push that
+// wakes it.
+use std::io;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::header::{RingBufferHeader, Role};
+
+// The ring's header, which lives in shared memory that outlives the relay
+// thread; a different one once the consumer grows the ring
+struct HeaderPtr(AtomicPtr<RingBufferHeader>);
+
+impl HeaderPtr {
+    fn get(&self) -> &RingBufferHeader {
+        unsafe { &*self.0.load(Ordering::Acquire) }
+    }
+}
+
+// Must be dropped before the mappings holding any header it relayed for
+pub(crate) struct ReadableFd {
+    header: Arc<HeaderPtr>,
+    fd: Arc<OwnedFd>,
+    closed: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ReadableFd {
+    pub(crate) fn new(header: &RingBufferHeader) -> io::Result<Self> {
+        let raw = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if raw < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = Arc::new(unsafe { OwnedFd::from_raw_fd(raw) });
+        let closed = Arc::new(AtomicBool::new(false));
+        let header = Arc::new(HeaderPtr(AtomicPtr::new(header as *const _ as *mut _)));
+        let thread = {
+            let (header, fd, closed) = (header.clone(), fd.clone(), closed.clone());
+            thread::Builder::new()
+                .name("rbuf-eventfd".into())
+                .spawn(move || Self::run(&header, &fd, &closed))?
+        };
+        Ok(Self { header, fd, closed, thread: Some(thread) })
+    }
+
+    fn run(header: &HeaderPtr, fd: &OwnedFd, closed: &AtomicBool) {
+        loop {
+            let header = header.get();
+            let seq = header.data_ready.prepare_wait();
+            if closed.load(Ordering::Acquire) {
+                header.data_ready.cancel_wait();
+                return;
+            }
+            // A closed ring stays readable so that the consumer's pop sees
+            // `Disconnected`
+            let closed = header.roles.is_closed(Role::Producer);
+            let tail = header.tail.load(Ordering::Acquire);
+            if closed || tail != header.head.load(Ordering::Acquire) {
+                signal(fd);
+            }
+            header.data_ready.wait(seq);
+        }
+    }
+
+    pub(crate) fn fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+
+    // Relay for `header` from now on, for a consumer that grew the ring. The
+    // old one has to stay mapped until we drop.
+    pub(crate) fn retarget(&self, header: &RingBufferHeader) {
+        let old = self.header.0.swap(header as *const _ as *mut _, Ordering::AcqRel);
+        // Kick the thread over to the new header's queue
+        unsafe { (*old).data_ready.notify() };
+    }
+}
+
+// Add one to the counter. A full counter is readable already, so EAGAIN
+// loses nothing.
+fn signal(fd: &OwnedFd) {
+    let one = 1u64.to_ne_bytes();
+    unsafe { libc::write(fd.as_raw_fd(), one.as_ptr() as *const _, one.len()) };
+}
+
+impl Drop for ReadableFd {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Release);
+        // Kick the thread out of the wait queue. Anyone else waiting on it
+        // sees a spurious wakeup and re-checks.
+        self.header.get().data_ready.notify();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
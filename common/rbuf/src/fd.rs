@@ -0,0 +1,103 @@
+// fd.rs
+//
+// Passing a segment's descriptor to another process over a Unix socket
+// (SCM_RIGHTS). The receiver gets its own descriptor for the same memory:
+//
+//     // creator
+//     let consumer = RingConfig::new("quotes").backing(Backing::Memfd).consumer::<Quote>()?;
+//     rbuf::send_fd(&stream, consumer.fd().unwrap())?;
+//
+//     // other process
+//     let fd = rbuf::recv_fd(&stream)?;
+//     let producer = RingConfig::new("quotes").backing(Backing::Fd(Arc::new(fd))).producer::<Quote>()?;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+// Room for a control message carrying exactly one descriptor
+#[repr(C)]
+union ControlBuffer {
+    _align: libc::cmsghdr,
+    bytes: [u8; 64],
+}
+
+// Send `fd` along with a single byte of ordinary data, so that the receiver
+// has something to block on
+pub fn send_fd(socket: &UnixStream, fd: BorrowedFd<'_>) -> io::Result<()> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr() as *mut _, iov_len: 1 };
+    let mut control = ControlBuffer { bytes: [0; 64] };
+    let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = unsafe { control.bytes.as_mut_ptr() } as *mut _;
+    msg.msg_controllen = space as _;
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd.as_raw_fd());
+    }
+
+    loop {
+        if unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) } >= 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+// Wait for a descriptor sent with `send_fd`
+pub fn recv_fd(socket: &UnixStream) -> io::Result<OwnedFd> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr() as *mut _, iov_len: 1 };
+    let mut control = ControlBuffer { bytes: [0; 64] };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = unsafe { control.bytes.as_mut_ptr() } as *mut _;
+    msg.msg_controllen = mem::size_of::<ControlBuffer>() as _;
+
+    let received = loop {
+        let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, cmsg_cloexec()) };
+        if n >= 0 {
+            break n;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+    if received == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if header.cmsg_level == libc::SOL_SOCKET && header.cmsg_type == libc::SCM_RIGHTS {
+            let raw = unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd) };
+            return Ok(unsafe { OwnedFd::from_raw_fd(raw) });
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "message carried no descriptor"))
+}
+
+// Have the kernel mark the received descriptor close-on-exec where it can
+fn cmsg_cloexec() -> libc::c_int {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    return libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    return 0;
+}
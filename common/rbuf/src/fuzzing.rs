@@ -0,0 +1,70 @@
+// fuzzing.rs
+//
+// Entry points for the cargo-fuzz targets in fuzz/, built only with
+// `--cfg fuzzing`. Whatever another process left in a segment reaches the
+// attach path unchecked, so no header it could write should make us panic
+// or touch memory outside the mapping.
+use std::sync::atomic::Ordering;
+
+use crate::bytes;
+use crate::config::{OpenMode, RingConfig};
+use crate::header::{RingBufferHeader, RingKind};
+use crate::inspect;
+use crate::segment::Backing;
+
+const NAME: &str = "rbuf-fuzz";
+
+// Data region of the byte ring `read_bytes` fills
+const BYTES_CAPACITY: usize = 4096;
+
+// Put `data` in a segment as if another process had written it, then
+// inspect it, attach to it as either side of a typed ring and drain it
+pub fn attach(data: &[u8]) {
+    let Ok(segment) = Backing::Heap.create(NAME, data.len()) else {
+        return;
+    };
+    unsafe {
+        std::ptr::copy_nonoverlapping(data.as_ptr(), segment.as_ptr(), data.len());
+        if data.len() >= std::mem::size_of::<RingBufferHeader>() {
+            (*(segment.as_ptr() as *const RingBufferHeader)).force_ready();
+        }
+    }
+
+    let _ = inspect::info(NAME, &*segment);
+    let config = RingConfig::new(NAME).backing(Backing::Heap).open_mode(OpenMode::Open);
+    if let Ok(mut consumer) = config.consumer::<u64>() {
+        while consumer.pop().is_ok() {}
+    }
+    if let Ok(producer) = config.producer::<u64>() {
+        let _ = producer.push(0);
+    }
+    let _ = inspect::drain_segment(segment, &mut |_| {});
+}
+
+// A byte ring with a valid header, whose head and tail are the first 16
+// bytes of `data` and whose records are the rest, drained by a `Reader`.
+// Record headers are the reader's to check, not `validate`'s.
+pub fn read_bytes(data: &[u8]) {
+    let Some((positions, records)) = data.split_first_chunk::<16>() else {
+        return;
+    };
+    let size = bytes::data_offset() + BYTES_CAPACITY;
+    let Ok(segment) = Backing::Heap.create(NAME, size) else {
+        return;
+    };
+    unsafe {
+        let header = RingBufferHeader::initialize(
+            segment.as_ptr(),
+            RingBufferHeader::new(RingKind::Bytes, 1, 1, BYTES_CAPACITY),
+        );
+        let position = |at: usize| u64::from_le_bytes(positions[at..at + 8].try_into().unwrap());
+        header.head.store(position(0), Ordering::Relaxed);
+        header.tail.store(position(8), Ordering::Relaxed);
+        let data = segment.as_ptr().add(bytes::data_offset());
+        std::ptr::write_bytes(data, 0, BYTES_CAPACITY);
+        let copied = records.len().min(BYTES_CAPACITY);
+        std::ptr::copy_nonoverlapping(records.as_ptr(), data, copied);
+        header.publish();
+    }
+    let _ = bytes::drain_raw(segment, &mut |_| {});
+}
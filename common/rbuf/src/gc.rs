@@ -0,0 +1,73 @@
+// gc.rs
+//
+// Cleaning up after crashed processes. A ring whose creator died and that
+// nothing is attached to any more stays in /dev/shm until someone unlinks
+// it; after a crash loop that is a lot of rings.
+//
+//     let removed = rbuf::gc::collect(&Namespace::new("bear_cave.quotes"))?;
+//
+// `attached` is never decremented by a process that crashed, so a ring also
+// counts as unused once every process its header knows to be attached (the
+// peers of a typed ring, registered consumers and subscribers) has exited.
+// Byte and MPMC rings don't track who is attached, so those are only
+// collected after a clean detach.
+use crate::error::RbufError;
+use crate::header::RingBufferHeader;
+use crate::inspect::RingInfo;
+use crate::namespace::Namespace;
+use crate::peer;
+use crate::ring::RingBuffer;
+use crate::segment::Backing;
+
+// Unlink every stale ring in `namespace` and return their names within it
+pub fn collect(namespace: &Namespace) -> Result<Vec<String>, RbufError> {
+    let mut collected = Vec::new();
+    for ring in namespace.list()? {
+        let name = namespace.name(&ring);
+        if is_stale(&name) && RingBuffer::unlink(&name).is_ok() {
+            event!(debug, ring = name.as_str(), "collected stale ring");
+            collected.push(ring);
+        }
+    }
+    Ok(collected)
+}
+
+// Whether the named ring's creator is gone and nothing uses the ring
+pub fn is_stale(name: &str) -> bool {
+    match RingBuffer::inspect(name) {
+        Ok(info) => !creator_alive(info.creator_pid) && unused(&info),
+        // The creator never finished setting it up
+        Err(RbufError::NotInitialized) => !creator_alive(uninitialized_creator(name)),
+        // Gone already, or made by a build we can't read
+        Err(_) => false,
+    }
+}
+
+fn creator_alive(pid: Option<u32>) -> bool {
+    pid.is_some_and(peer::process_alive)
+}
+
+fn unused(info: &RingInfo) -> bool {
+    if info.attached == 0 {
+        return true;
+    }
+    let mut alive = info
+        .consumer_peer
+        .iter()
+        .chain(&info.producer_peer)
+        .map(|peer| peer.alive)
+        .chain(info.registrations.iter().map(|registration| registration.alive))
+        .peekable();
+    alive.peek().is_some() && !alive.any(|alive| alive)
+}
+
+// The creator of a ring whose header was never published, if it got as far
+// as writing one
+fn uninitialized_creator(name: &str) -> Option<u32> {
+    let segment = Backing::Shm.open(name).ok()?;
+    if segment.len() < std::mem::size_of::<RingBufferHeader>() {
+        return None;
+    }
+    let header = unsafe { &*(segment.as_ptr() as *const RingBufferHeader) };
+    Some(header.roles.creator_pid()).filter(|&pid| pid != 0)
+}
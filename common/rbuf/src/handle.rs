@@ -0,0 +1,89 @@
+// handle.rs
+//
+// What a producer and a consumer can do, whatever ring is behind them. Code
+// written against `Push` and `Pop` rather than `Producer` and `Consumer`
+// can be handed a `MockRing` in its tests:
+//
+//     fn forward(from: &mut impl Pop<Quote>, to: &mut impl Push<Quote>) -> Result<(), RbufError> {
+//         let quote = from.pop_blocking()?;
+//         to.push_blocking(quote).map_err(PushError::into_error)
+//     }
+use std::time::Duration;
+
+use crate::consumer::Consumer;
+use crate::error::{PushError, RbufError};
+use crate::producer::Producer;
+use crate::shm_safe::ShmSafe;
+
+pub trait Push<T> {
+    // Fails with `Full` straight away if there's no free slot
+    fn push(&mut self, item: T) -> Result<(), PushError<T>>;
+
+    // Waits for a free slot
+    fn push_blocking(&mut self, item: T) -> Result<(), PushError<T>>;
+
+    // No more items are coming; pops fail with `Disconnected` once the ring
+    // is drained
+    fn close(&mut self);
+
+    fn is_closed(&self) -> bool;
+}
+
+pub trait Pop<T> {
+    // Fails with `Empty` straight away if there's nothing to pop
+    fn pop(&mut self) -> Result<T, RbufError>;
+
+    // Waits for an item, skipping corrupt ones
+    fn pop_blocking(&mut self) -> Result<T, RbufError>;
+
+    // Waits up to `timeout` for an item, then fails with `Timeout`
+    fn pop_timeout(&mut self, timeout: Duration) -> Result<T, RbufError>;
+
+    fn is_closed(&self) -> bool;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: ShmSafe> Push<T> for Producer<T> {
+    fn push(&mut self, item: T) -> Result<(), PushError<T>> {
+        Producer::push(self, item)
+    }
+
+    fn push_blocking(&mut self, item: T) -> Result<(), PushError<T>> {
+        Producer::push_blocking(self, item)
+    }
+
+    fn close(&mut self) {
+        Producer::close(self)
+    }
+
+    fn is_closed(&self) -> bool {
+        Producer::is_closed(self)
+    }
+}
+
+impl<T: ShmSafe> Pop<T> for Consumer<T> {
+    fn pop(&mut self) -> Result<T, RbufError> {
+        Consumer::pop(self)
+    }
+
+    fn pop_blocking(&mut self) -> Result<T, RbufError> {
+        Consumer::pop_blocking(self)
+    }
+
+    fn pop_timeout(&mut self, timeout: Duration) -> Result<T, RbufError> {
+        Consumer::pop_timeout(self, timeout)
+    }
+
+    fn is_closed(&self) -> bool {
+        Consumer::is_closed(self)
+    }
+
+    fn len(&self) -> usize {
+        Consumer::len(self)
+    }
+}
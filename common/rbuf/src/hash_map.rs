@@ -0,0 +1,333 @@
+// hash_map.rs
+//
+// A hash map in a shared segment, for lookup tables every process needs the
+// same view of (symbol to instrument id, session to account), without a
+// ring in sight. Any process can insert, look up and remove.
+//
+// Entries are found by linear probing from a hash of the key. A key, once
+// written into an entry, stays there for good: removing it only marks the
+// entry removed, and inserting it again revives the same entry. That keeps
+// probing lock-free, at the price that the table only ever holds as many
+// distinct keys as it has room for, however many were removed since. Size
+// it for every key it will ever see.
+//
+// Each entry's value, and whether it's there, sit behind a seqlock in
+// `version`: writers make it odd while they change either, and readers copy
+// the value out and retry if `version` changed meanwhile. A process that
+// dies while it holds one leaves that entry locked for good.
+//
+// [ header | entries ]
+use std::cell::UnsafeCell;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::thread;
+
+use crate::directory::{self, fnv1a, FNV_OFFSET};
+use crate::error::RbufError;
+use crate::header::{RingBufferHeader, RingKind, CACHE_LINE};
+use crate::segment::{Backing, Segment};
+use crate::shm_safe::ShmSafe;
+
+// Entry states
+const FREE: u32 = 0;
+// A process is writing the key
+const CLAIMED: u32 = 1;
+const PRESENT: u32 = 2;
+const REMOVED: u32 = 3;
+
+#[repr(C)]
+struct Entry<K, V> {
+    state: AtomicU32,
+    // Even, and odd while a writer changes `state` or `value`
+    version: AtomicU64,
+    key: UnsafeCell<MaybeUninit<K>>,
+    value: UnsafeCell<MaybeUninit<V>>,
+}
+
+fn entries_offset() -> usize {
+    (mem::size_of::<RingBufferHeader>() + CACHE_LINE - 1) & !(CACHE_LINE - 1)
+}
+
+// Keys present in a map, for tools that don't hold one
+pub(crate) fn len_raw(base: *const u8, len: usize, header: &RingBufferHeader) -> Option<usize> {
+    let (stride, slots) = (header.elem_size(), header.capacity());
+    if stride < mem::size_of::<AtomicU32>() || len < entries_offset() + stride * slots {
+        return None;
+    }
+    let states = (0..slots).map(|index| {
+        let state = base.wrapping_add(entries_offset() + index * stride) as *const AtomicU32;
+        unsafe { (*state).load(Ordering::Acquire) }
+    });
+    Some(states.filter(|&state| state == PRESENT).count())
+}
+
+// Hashes keys the same way in every process, unlike `RandomState`
+struct KeyHasher(u64);
+
+impl Hasher for KeyHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0 = fnv1a(self.0, bytes);
+    }
+}
+
+// Where to start probing for `key` in a table shared between processes
+pub(crate) fn hash<K: Hash + ?Sized>(key: &K) -> u64 {
+    let mut hasher = KeyHasher(FNV_OFFSET);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub struct ShmHashMap<K, V> {
+    segment: Box<dyn Segment>,
+    header: *const RingBufferHeader,
+    entries: *const Entry<K, V>,
+    mask: u64,
+    _phantom: PhantomData<(K, V)>,
+}
+
+unsafe impl<K: Send, V: Send> Send for ShmHashMap<K, V> {}
+unsafe impl<K: Send, V: Send> Sync for ShmHashMap<K, V> {}
+
+impl<K: ShmSafe + Copy + Eq + Hash, V: ShmSafe + Copy> ShmHashMap<K, V> {
+    // Create a map with room for at least `max_keys` distinct keys
+    pub fn create(name: &str, max_keys: usize) -> Result<Self, RbufError> {
+        // Keep the table at most half full, so probes stay short
+        let slots = max_keys.max(1).saturating_mul(2).next_power_of_two();
+        let size = entries_offset() + slots * mem::size_of::<Entry<K, V>>();
+        let segment = Backing::Shm.create(name, size)?;
+        unsafe {
+            let header = RingBufferHeader::initialize(
+                segment.as_ptr(),
+                RingBufferHeader::new(
+                    RingKind::HashMap,
+                    mem::size_of::<Entry<K, V>>(),
+                    mem::align_of::<Entry<K, V>>(),
+                    slots,
+                )
+                .with_schema(directory::type_hash::<(K, V)>()),
+            );
+            // Free entries are all zeroes
+            let entries = segment.as_ptr().add(entries_offset());
+            std::ptr::write_bytes(entries, 0, size - entries_offset());
+            header.publish();
+            directory::register(name, header);
+        }
+        Ok(Self::from_segment(segment))
+    }
+
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        let segment = Backing::Shm.open(name)?;
+        let header = RingBufferHeader::validate(
+            segment.as_ptr(),
+            segment.len(),
+            RingKind::HashMap,
+            mem::size_of::<Entry<K, V>>(),
+            mem::align_of::<Entry<K, V>>(),
+        )?;
+        header.check_schema(directory::type_hash::<(K, V)>())?;
+        let expected = entries_offset() + header.capacity() * mem::size_of::<Entry<K, V>>();
+        if segment.len() < expected {
+            return Err(RbufError::SizeMismatch { expected, actual: segment.len() });
+        }
+        Ok(Self::from_segment(segment))
+    }
+
+    fn from_segment(segment: Box<dyn Segment>) -> Self {
+        let header = segment.as_ptr() as *const RingBufferHeader;
+        let capacity = unsafe { (*header).capacity() };
+        let entries = unsafe { segment.as_ptr().add(entries_offset()) } as *const Entry<K, V>;
+        unsafe { (*header).attached.fetch_add(1, Ordering::AcqRel) };
+        event!(debug, ring = segment.name(), kind = "hash_map", capacity, "mapped map");
+        let mask = capacity as u64 - 1;
+        Self { segment, header, entries, mask, _phantom: PhantomData }
+    }
+
+    pub fn name(&self) -> &str {
+        self.segment.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        unsafe { (*self.header).attached.load(Ordering::Acquire) as usize }
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.segment.set_owner(unlink);
+    }
+
+    // Distinct keys the table has entries for, present or removed
+    pub fn capacity(&self) -> usize {
+        self.mask as usize + 1
+    }
+
+    fn entry(&self, index: u64) -> &Entry<K, V> {
+        unsafe { &*self.entries.add((index & self.mask) as usize) }
+    }
+
+    // The key of an entry that isn't free, once its writer has written it
+    fn key(&self, entry: &Entry<K, V>) -> K {
+        while entry.state.load(Ordering::Acquire) == CLAIMED {
+            thread::yield_now();
+        }
+        unsafe { (*entry.key.get()).assume_init_read() }
+    }
+
+    // The entry for `key`, if it ever had one
+    fn find(&self, key: &K) -> Option<&Entry<K, V>> {
+        let start = hash(key);
+        for probe in 0..=self.mask {
+            let entry = self.entry(start.wrapping_add(probe));
+            if entry.state.load(Ordering::Acquire) == FREE {
+                return None;
+            }
+            if self.key(entry) == *key {
+                return Some(entry);
+            }
+        }
+        None
+    }
+
+    // Make `value` the value for `key` and return the one it replaces.
+    // Fails with `Full` if `key` is new and the table has no room for it.
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>, RbufError> {
+        let start = hash(&key);
+        for probe in 0..=self.mask {
+            let entry = self.entry(start.wrapping_add(probe));
+            if entry.state.load(Ordering::Acquire) == FREE {
+                let claimed = entry.state.compare_exchange(
+                    FREE,
+                    CLAIMED,
+                    Ordering::Acquire,
+                    Ordering::Acquire,
+                );
+                if claimed.is_ok() {
+                    // Nobody reads the entry until it's present
+                    unsafe {
+                        (*entry.key.get()).write(key);
+                        (*entry.value.get()).write(value);
+                    }
+                    entry.state.store(PRESENT, Ordering::Release);
+                    return Ok(None);
+                }
+            }
+            if self.key(entry) == key {
+                let _lock = EntryLock::acquire(entry);
+                let old = entry.state.load(Ordering::Relaxed) == PRESENT;
+                let old = old.then(|| unsafe { (*entry.value.get()).assume_init_read() });
+                entry.state.store(PRESENT, Ordering::Relaxed);
+                unsafe { (*entry.value.get()).write(value) };
+                return Ok(old);
+            }
+        }
+        Err(RbufError::Full)
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let entry = self.find(key)?;
+        loop {
+            let version = entry.version.load(Ordering::Acquire);
+            if version & 1 == 0 {
+                let present = entry.state.load(Ordering::Relaxed) == PRESENT;
+                let value = unsafe { (*entry.value.get()).assume_init_read() };
+                fence(Ordering::Acquire);
+                if entry.version.load(Ordering::Relaxed) == version {
+                    return present.then_some(value);
+                }
+            }
+            thread::yield_now();
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Take `key` out of the map and return its value
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let entry = self.find(key)?;
+        let _lock = EntryLock::acquire(entry);
+        if entry.state.load(Ordering::Relaxed) != PRESENT {
+            return None;
+        }
+        entry.state.store(REMOVED, Ordering::Relaxed);
+        Some(unsafe { (*entry.value.get()).assume_init_read() })
+    }
+
+    // Keys present, counted entry by entry; only a snapshot
+    pub fn len(&self) -> usize {
+        let present = |&index: &u64| self.entry(index).state.load(Ordering::Acquire) == PRESENT;
+        (0..=self.mask).filter(present).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Every key present and its value. Keys inserted or removed while this
+    // runs may or may not be in it.
+    pub fn snapshot(&self) -> Vec<(K, V)> {
+        let mut pairs = Vec::new();
+        for index in 0..=self.mask {
+            let entry = self.entry(index);
+            if entry.state.load(Ordering::Acquire) == FREE {
+                continue;
+            }
+            let key = self.key(entry);
+            pairs.extend(self.get(&key).map(|value| (key, value)));
+        }
+        pairs
+    }
+}
+
+impl<K, V> Drop for ShmHashMap<K, V> {
+    fn drop(&mut self) {
+        unsafe { (*self.header).detach() };
+        event!(debug, ring = self.segment.name(), kind = "hash_map", "detached from map");
+    }
+}
+
+// An entry's seqlock, held to change its state or value
+struct EntryLock<'a> {
+    version: &'a AtomicU64,
+    locked: u64,
+}
+
+impl<'a> EntryLock<'a> {
+    fn acquire<K, V>(entry: &'a Entry<K, V>) -> Self {
+        let version = &entry.version;
+        let mut current = version.load(Ordering::Relaxed);
+        loop {
+            if current & 1 == 1 {
+                thread::yield_now();
+                current = version.load(Ordering::Relaxed);
+                continue;
+            }
+            match version.compare_exchange_weak(
+                current,
+                current + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+        // Readers that see what we write next see the odd version too
+        fence(Ordering::Release);
+        Self { version, locked: current + 1 }
+    }
+}
+
+impl Drop for EntryLock<'_> {
+    fn drop(&mut self) {
+        self.version.store(self.locked + 1, Ordering::Release);
+    }
+}
@@ -0,0 +1,753 @@
+// header.rs
+use std::mem;
+use std::ops::Deref;
+use std::process;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::RbufError;
+use crate::notify::{Notifier, WaitQueue};
+use crate::peer::{self, Peer};
+use crate::registry;
+use crate::stats::{from_nanos, now_nanos, ConsumerCounters, ProducerCounters, Stats};
+
+// "BEARRBUF" as a little-endian integer; anything else isn't one of ours
+pub(crate) const RBUF_MAGIC: u64 = u64::from_le_bytes(*b"BEARRBUF");
+// Bump whenever the in-memory layout changes
+pub(crate) const RBUF_VERSION: u32 = 32;
+
+// Lifecycle of a segment's header. A freshly created segment is all zeroes
+// (not initialized); only the creator moves it to INITIALIZING and then,
+// once every field and the data region are set up, to READY. Unlinking it
+// by name moves it on to RETIRED, so that handles still mapping it know to
+// open the ring again. A typed ring that grew into a bigger segment under
+// the same name moves on to MIGRATED instead, and its producers follow the
+// name on their own.
+const INIT_INITIALIZING: u32 = 1;
+const INIT_READY: u32 = 2;
+const INIT_RETIRED: u32 = 3;
+const INIT_MIGRATED: u32 = 4;
+
+// How long an opener waits for a racing creator to finish initializing
+const INIT_WAIT: Duration = Duration::from_secs(1);
+
+// Assumed cache line size. 64 bytes covers x86_64 and most aarch64 parts.
+pub(crate) const CACHE_LINE: usize = 64;
+
+// Gives a value a cache line to itself so that writes to it don't invalidate
+// the line holding its neighbours in the other process.
+#[repr(C, align(64))]
+pub(crate) struct CachePadded<T>(T);
+
+impl<T> CachePadded<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+// What the data region after the header holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum RingKind {
+    // Fixed-size `T` slots with per-slot commit flags
+    Typed = 1,
+    // Variable-length byte records
+    Bytes = 2,
+    // Fixed-size `T` slots read by every registered subscriber
+    Broadcast = 3,
+    // Fixed-size `T` slots with per-slot sequence numbers, many consumers
+    Mpmc = 4,
+    // Reference-counted blocks of `elem_size` bytes rather than a ring
+    Pool = 5,
+    // A bump allocator's `capacity` bytes rather than a ring
+    Arena = 6,
+    // The table of rings in `directory`
+    Directory = 7,
+    // An `rbuf_core` ring of fixed-size `T` slots, one producer and one
+    // consumer
+    Region = 8,
+    // The latest value for each key of a `LastValueMap`, whose updates go
+    // through a broadcast ring of their own
+    LastValue = 9,
+    // A `ShmHashMap`'s entries rather than a ring
+    HashMap = 10,
+}
+
+impl RingKind {
+    pub(crate) fn from_u32(kind: u32) -> Option<Self> {
+        match kind {
+            1 => Some(RingKind::Typed),
+            2 => Some(RingKind::Bytes),
+            3 => Some(RingKind::Broadcast),
+            4 => Some(RingKind::Mpmc),
+            5 => Some(RingKind::Pool),
+            6 => Some(RingKind::Arena),
+            7 => Some(RingKind::Directory),
+            8 => Some(RingKind::Region),
+            9 => Some(RingKind::LastValue),
+            10 => Some(RingKind::HashMap),
+            _ => None,
+        }
+    }
+}
+
+// Optional features a ring was created with, in `Options::flags`. Every
+// handle has to understand all of them to attach.
+//
+// A CRC32 of every typed slot, checked on pop
+pub(crate) const OPTION_CHECKSUMS: u64 = 1 << 0;
+// A monotonic push time in every typed slot, and a latency histogram
+pub(crate) const OPTION_TIMESTAMPS: u64 = 1 << 1;
+// Every byte ring message sealed under a key only its handles know
+pub(crate) const OPTION_ENCRYPTED: u64 = 1 << 2;
+// Broadcast slots in a segment of their own that subscribers map read-only
+pub(crate) const OPTION_SPLIT_DATA: u64 = 1 << 3;
+// Typed slots page-aligned, and mapped twice in a row by every handle
+pub(crate) const OPTION_MIRRORED: u64 = 1 << 4;
+
+const SUPPORTED_OPTIONS: u64 = OPTION_TIMESTAMPS
+    | if cfg!(feature = "checksum") { OPTION_CHECKSUMS } else { 0 }
+    | if cfg!(feature = "encryption") { OPTION_ENCRYPTED } else { 0 }
+    | if cfg!(unix) { OPTION_SPLIT_DATA } else { 0 }
+    | if cfg!(target_os = "linux") { OPTION_MIRRORED } else { 0 };
+
+// Settings fixed at creation that only some rings use
+#[repr(C)]
+pub(crate) struct Options {
+    pub(crate) flags: u64,
+    // Identifies the message schema of a ring whose messages are encoded,
+    // or the item type of a ring of `T`s, so mismatched handles fail to
+    // open it; 0 if the ring doesn't say
+    pub(crate) schema: u64,
+    // Derived from the key of an encrypted ring, so handles given a
+    // different key fail to open it; 0 otherwise
+    pub(crate) key_check: u64,
+    // `Codec::id` of the compression byte ring writers use; 0 for none
+    pub(crate) codec: u32,
+    // Largest message a byte ring reassembles from fragments; 0 if every
+    // message has to fit in one record
+    pub(crate) max_message: u64,
+    // Different for every segment ever created, so that a handle can tell
+    // whether the segment now under its ring's name is the one it mapped
+    pub(crate) generation: u64,
+}
+
+impl Options {
+    fn new() -> Self {
+        let generation = new_generation();
+        Self { flags: 0, schema: 0, key_check: 0, codec: 0, max_message: 0, generation }
+    }
+
+    pub(crate) fn has(&self, option: u64) -> bool {
+        self.flags & option != 0
+    }
+}
+
+// The creation time, mixed with the PID and a count of segments this
+// process created, so that two creators can't pick the same one
+fn new_generation() -> u64 {
+    static CREATED: AtomicU64 = AtomicU64::new(0);
+    let count = CREATED.fetch_add(1, Ordering::Relaxed);
+    now_nanos() ^ ((process::id() as u64) << 32) ^ count.wrapping_mul(0x9e37_79b9_7f4a_7c15)
+}
+
+// Which side of a typed ring a handle is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Role {
+    Consumer = 1,
+    Producer = 2,
+}
+
+impl Role {
+    pub(crate) fn from_u32(role: u32) -> Option<Self> {
+        match role {
+            1 => Some(Role::Consumer),
+            2 => Some(Role::Producer),
+            _ => None,
+        }
+    }
+}
+
+// The process that last showed signs of life in one role
+#[repr(C)]
+struct PeerSlot {
+    // 0 once the last handle of the role has detached
+    pid: AtomicU32,
+    // See `stats::now_nanos`
+    heartbeat: AtomicU64,
+}
+
+impl PeerSlot {
+    const fn new() -> Self {
+        Self { pid: AtomicU32::new(0), heartbeat: AtomicU64::new(0) }
+    }
+}
+
+// Who created a ring, how many handles of each role of a typed ring are
+// attached (stale after a crash, like `attached`) and which process of each
+// role last beat its heart
+#[repr(C)]
+pub(crate) struct Roles {
+    // A `Role`, or 0 for rings that don't have roles
+    creator: u32,
+    consumers: AtomicU32,
+    producers: AtomicU32,
+    // The process that created the ring, whatever its kind
+    creator_pid: u32,
+    consumer: PeerSlot,
+    producer: PeerSlot,
+    // Bit `role` set once that side is gone: for producers when one calls
+    // `close`, for good; for consumers while none is attached after one was
+    closed: AtomicU32,
+}
+
+impl Roles {
+    fn new() -> Self {
+        Self {
+            creator: 0,
+            consumers: AtomicU32::new(0),
+            producers: AtomicU32::new(0),
+            creator_pid: std::process::id(),
+            consumer: PeerSlot::new(),
+            producer: PeerSlot::new(),
+            closed: AtomicU32::new(0),
+        }
+    }
+
+    fn count(&self, role: Role) -> &AtomicU32 {
+        match role {
+            Role::Consumer => &self.consumers,
+            Role::Producer => &self.producers,
+        }
+    }
+
+    fn peer_slot(&self, role: Role) -> &PeerSlot {
+        match role {
+            Role::Consumer => &self.consumer,
+            Role::Producer => &self.producer,
+        }
+    }
+
+    pub(crate) fn attach(&self, role: Role) {
+        self.count(role).fetch_add(1, Ordering::AcqRel);
+        if role == Role::Consumer {
+            self.closed.fetch_and(!(Role::Consumer as u32), Ordering::AcqRel);
+        }
+        self.beat(role);
+    }
+
+    pub(crate) fn detach(&self, role: Role) {
+        // A handle that leaves while its process is the recorded peer takes
+        // the record with it, so that it isn't found dead later on; whoever
+        // is still attached is recorded again once it beats
+        let pid = &self.peer_slot(role).pid;
+        let _ = pid.compare_exchange(std::process::id(), 0, Ordering::AcqRel, Ordering::Relaxed);
+        if self.count(role).fetch_sub(1, Ordering::AcqRel) == 1 {
+            // The last one out leaves no peer behind at all
+            pid.store(0, Ordering::Release);
+            if role == Role::Consumer {
+                self.close(Role::Consumer);
+                // Unless a new consumer attached in between
+                if self.consumers.load(Ordering::Acquire) != 0 {
+                    self.closed.fetch_and(!(Role::Consumer as u32), Ordering::AcqRel);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn close(&self, role: Role) {
+        self.closed.fetch_or(role as u32, Ordering::AcqRel);
+    }
+
+    // Whether `role`'s side of the ring is gone
+    pub(crate) fn is_closed(&self, role: Role) -> bool {
+        self.closed.load(Ordering::Acquire) & role as u32 != 0
+    }
+
+    // Record this process as the live `role`
+    pub(crate) fn beat(&self, role: Role) {
+        let slot = self.peer_slot(role);
+        slot.heartbeat.store(now_nanos(), Ordering::Relaxed);
+        slot.pid.store(std::process::id(), Ordering::Release);
+    }
+
+    // The last process to beat as `role`, unless every such handle detached
+    pub(crate) fn peer(&self, role: Role) -> Option<Peer> {
+        let slot = self.peer_slot(role);
+        let pid = slot.pid.load(Ordering::Acquire);
+        if pid == 0 {
+            return None;
+        }
+        Some(Peer {
+            pid,
+            last_heartbeat: from_nanos(slot.heartbeat.load(Ordering::Relaxed))?,
+            alive: peer::process_alive(pid),
+        })
+    }
+
+    // The PID of a `role` process that attached and has since gone without
+    // detaching
+    pub(crate) fn dead_peer(&self, role: Role) -> Option<u32> {
+        let pid = self.peer_slot(role).pid.load(Ordering::Acquire);
+        (pid != 0 && !peer::process_alive(pid)).then_some(pid)
+    }
+
+    pub(crate) fn creator(&self) -> Option<Role> {
+        Role::from_u32(self.creator)
+    }
+
+    // 0 if the creator never got as far as writing the header
+    pub(crate) fn creator_pid(&self) -> u32 {
+        self.creator_pid
+    }
+
+    pub(crate) fn attached(&self, role: Role) -> usize {
+        self.count(role).load(Ordering::Acquire) as usize
+    }
+}
+
+// Settings anyone may change while a ring is in use, e.g. ops throttling a
+// publisher from the shell. Typed rings only.
+#[repr(C)]
+pub(crate) struct Control {
+    // Most items each producer pushes per second; 0 for no limit
+    pub(crate) messages_per_sec: AtomicU64,
+    // Most bytes of items each producer pushes per second; 0 for no limit
+    pub(crate) bytes_per_sec: AtomicU64,
+    // The `tail` producers may claim slots up to, moved on by the consumer
+    // granting credit; `NO_CREDIT_LIMIT` unless the consumer uses credits
+    pub(crate) credit_limit: AtomicU64,
+    // Non-zero while an admin has paused publishing
+    pub(crate) paused: AtomicU32,
+    // The `LogLevel` an admin last asked for, 0 if none
+    pub(crate) log_level: AtomicU32,
+    // Commands ever sent, and the last few of them; see `control`
+    pub(crate) commands_sent: AtomicU64,
+    pub(crate) commands: [AtomicU64; COMMAND_SLOTS],
+}
+
+pub(crate) const NO_CREDIT_LIMIT: u64 = u64::MAX;
+pub(crate) const COMMAND_SLOTS: usize = 3;
+
+impl Control {
+    fn new() -> Self {
+        Self {
+            messages_per_sec: AtomicU64::new(0),
+            bytes_per_sec: AtomicU64::new(0),
+            credit_limit: AtomicU64::new(NO_CREDIT_LIMIT),
+            paused: AtomicU32::new(0),
+            log_level: AtomicU32::new(0),
+            commands_sent: AtomicU64::new(0),
+            commands: [const { AtomicU64::new(0) }; COMMAND_SLOTS],
+        }
+    }
+
+    // Slots producers may still claim past `tail`, if credits are in use
+    pub(crate) fn credits(&self, tail: u64) -> Option<u64> {
+        let limit = self.credit_limit.load(Ordering::Acquire);
+        (limit != NO_CREDIT_LIMIT).then(|| limit.saturating_sub(tail))
+    }
+}
+
+// The header that lives at the start of the shared memory.
+//
+// Every group of fields written by a different party gets its own cache line:
+//
+//   line 0  magic, version, kind, elem_size, elem_align, capacity,
+//           max_consumers, overwrite, init_state, attached
+//           (written at creation, read-mostly afterwards)
+//   line 1  head         (consumer)
+//   line 2  tail         (producers)
+//   line 3  data_ready   (producers notify, consumer waits)
+//   line 4  space_ready  (consumer notifies, producers wait)
+//   line 5  producer_stats
+//   line 6  consumer_stats
+//   line 7  roles        (written when handles attach, detach and beat)
+//   line 8  options      (written at creation, read-only afterwards)
+//   line 9  control      (written by whoever reconfigures the ring)
+//
+// Only fixed-width fields, so that 32-bit and 64-bit processes agree on the
+// layout. Line 0 byte by byte:
+//
+//    0  magic          u64
+//    8  version        u32
+//   12  kind           u32
+//   16  elem_size      u64
+//   24  elem_align     u64
+//   32  capacity       u64
+//   40  max_consumers  u64
+//   48  overwrite      u32
+//   52  init_state     u32
+//   56  attached       u64
+#[repr(C)]
+pub struct RingBufferHeader {
+    pub(crate) magic: u64,
+    pub(crate) version: u32,
+    pub(crate) kind: u32,
+    elem_size: u64,
+    elem_align: u64,
+    capacity: u64,
+    // Entries in the consumer table that follows the header (0 if none)
+    max_consumers: u64,
+    // Set (and never cleared) once any producer may drop the oldest item,
+    // after which the consumer has to advance `head` with a CAS
+    pub(crate) overwrite: AtomicU32,
+    // 0 -> INITIALIZING -> READY, only ever advanced by the creator, then
+    // RETIRED once unlinked or MIGRATED once grown
+    pub(crate) init_state: AtomicU32,
+    // Number of live handles mapping this segment (stale after a crash)
+    pub(crate) attached: AtomicU64,
+    pub(crate) head: CachePadded<AtomicU64>,
+    pub(crate) tail: CachePadded<AtomicU64>,
+    // Signalled by producers after publishing an item
+    pub(crate) data_ready: CachePadded<WaitQueue>,
+    // Signalled by the consumer after freeing a slot
+    pub(crate) space_ready: CachePadded<WaitQueue>,
+    pub(crate) producer_stats: CachePadded<ProducerCounters>,
+    pub(crate) consumer_stats: CachePadded<ConsumerCounters>,
+    pub(crate) roles: CachePadded<Roles>,
+    pub(crate) options: CachePadded<Options>,
+    pub(crate) control: CachePadded<Control>,
+}
+
+const _: () = assert!(mem::size_of::<RingBufferHeader>() == 10 * CACHE_LINE);
+const _: () = assert!(mem::align_of::<RingBufferHeader>() == CACHE_LINE);
+const _: () = {
+    use std::mem::offset_of;
+    assert!(offset_of!(RingBufferHeader, magic) == 0);
+    assert!(offset_of!(RingBufferHeader, version) == 8);
+    assert!(offset_of!(RingBufferHeader, kind) == 12);
+    assert!(offset_of!(RingBufferHeader, elem_size) == 16);
+    assert!(offset_of!(RingBufferHeader, elem_align) == 24);
+    assert!(offset_of!(RingBufferHeader, capacity) == 32);
+    assert!(offset_of!(RingBufferHeader, max_consumers) == 40);
+    assert!(offset_of!(RingBufferHeader, overwrite) == 48);
+    assert!(offset_of!(RingBufferHeader, init_state) == 52);
+    assert!(offset_of!(RingBufferHeader, attached) == 56);
+    assert!(offset_of!(RingBufferHeader, head) == CACHE_LINE);
+    assert!(offset_of!(RingBufferHeader, tail) == 2 * CACHE_LINE);
+    assert!(offset_of!(RingBufferHeader, data_ready) == 3 * CACHE_LINE);
+    assert!(offset_of!(RingBufferHeader, space_ready) == 4 * CACHE_LINE);
+    assert!(offset_of!(RingBufferHeader, producer_stats) == 5 * CACHE_LINE);
+    assert!(offset_of!(RingBufferHeader, consumer_stats) == 6 * CACHE_LINE);
+    assert!(offset_of!(RingBufferHeader, roles) == 7 * CACHE_LINE);
+    assert!(offset_of!(RingBufferHeader, options) == 8 * CACHE_LINE);
+    assert!(offset_of!(RingBufferHeader, control) == 9 * CACHE_LINE);
+    assert!(offset_of!(Roles, consumers) == 4);
+    assert!(offset_of!(Roles, producers) == 8);
+    assert!(offset_of!(Roles, creator_pid) == 12);
+    assert!(offset_of!(Roles, consumer) == 16);
+    assert!(offset_of!(Roles, producer) == 32);
+    assert!(offset_of!(Roles, closed) == 48);
+    assert!(mem::size_of::<Roles>() <= CACHE_LINE);
+    assert!(mem::size_of::<Control>() <= CACHE_LINE);
+};
+
+impl RingBufferHeader {
+    pub(crate) fn new(kind: RingKind, elem_size: usize, elem_align: usize, capacity: usize) -> Self {
+        Self {
+            magic: RBUF_MAGIC,
+            version: RBUF_VERSION,
+            kind: kind as u32,
+            elem_size: elem_size as u64,
+            elem_align: elem_align as u64,
+            capacity: capacity as u64,
+            max_consumers: 0,
+            overwrite: AtomicU32::new(0),
+            init_state: AtomicU32::new(INIT_INITIALIZING),
+            attached: AtomicU64::new(0),
+            head: CachePadded::new(AtomicU64::new(0)),
+            tail: CachePadded::new(AtomicU64::new(0)),
+            data_ready: CachePadded::new(WaitQueue::with_notifier(Notifier::default())),
+            space_ready: CachePadded::new(WaitQueue::with_notifier(Notifier::default())),
+            producer_stats: CachePadded::new(ProducerCounters::new()),
+            consumer_stats: CachePadded::new(ConsumerCounters::new()),
+            roles: CachePadded::new(Roles::new()),
+            options: CachePadded::new(Options::new()),
+            control: CachePadded::new(Control::new()),
+        }
+    }
+
+    pub(crate) fn with_option(mut self, option: u64, enabled: bool) -> Self {
+        if enabled {
+            self.options.0.flags |= option;
+        }
+        self
+    }
+
+    pub(crate) fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.data_ready = CachePadded::new(WaitQueue::with_notifier(notifier));
+        self.space_ready = CachePadded::new(WaitQueue::with_notifier(notifier));
+        self
+    }
+
+    pub(crate) fn with_schema(mut self, schema: u64) -> Self {
+        self.options.0.schema = schema;
+        self
+    }
+
+    pub(crate) fn with_key_check(mut self, key_check: u64) -> Self {
+        self.options.0.key_check = key_check;
+        self
+    }
+
+    pub(crate) fn with_codec(mut self, codec: u32) -> Self {
+        self.options.0.codec = codec;
+        self
+    }
+
+    pub(crate) fn with_max_message(mut self, max_message: usize) -> Self {
+        self.options.0.max_message = max_message as u64;
+        self
+    }
+
+    pub(crate) fn with_creator(mut self, role: Role) -> Self {
+        self.roles.0.creator = role as u32;
+        self
+    }
+
+    pub(crate) fn with_max_consumers(mut self, max_consumers: usize) -> Self {
+        self.max_consumers = max_consumers as u64;
+        self
+    }
+
+    // The layout fields, which `validate` has checked fit in a usize
+
+    pub(crate) fn elem_size(&self) -> usize {
+        self.elem_size as usize
+    }
+
+    pub(crate) fn elem_align(&self) -> usize {
+        self.elem_align as usize
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+
+    pub(crate) fn max_consumers(&self) -> usize {
+        self.max_consumers as usize
+    }
+
+    pub(crate) fn stats(&self) -> Stats {
+        Stats::read(&self.producer_stats, &self.consumer_stats)
+    }
+
+    // Zero the counters `stats` reads. The latency histogram of a ring with
+    // timestamps lives past the header and keeps its counts.
+    pub(crate) fn reset_stats(&self) {
+        self.producer_stats.reset();
+        self.consumer_stats.reset();
+    }
+
+    // Write `header` into a freshly created segment. Until the creator calls
+    // `publish` (after setting up the data region too) the state reads
+    // INITIALIZING, and openers keep waiting.
+    pub(crate) unsafe fn initialize<'a>(ptr: *mut u8, header: Self) -> &'a Self {
+        debug_assert_eq!(header.init_state.load(Ordering::Relaxed), INIT_INITIALIZING);
+        (ptr as *mut Self).write(header);
+        &*(ptr as *const Self)
+    }
+
+    // Mark the segment as fully initialized
+    pub(crate) fn publish(&self) {
+        self.init_state.store(INIT_READY, Ordering::Release);
+    }
+
+    // Fuzz inputs are headers nobody is still initializing; don't make every
+    // one that doesn't say so wait out INIT_WAIT
+    #[cfg(fuzzing)]
+    pub(crate) fn force_ready(&self) {
+        if self.init_state.load(Ordering::Relaxed) != INIT_RETIRED {
+            self.publish();
+        }
+    }
+
+    // Mark the segment as no longer reachable by name
+    pub(crate) fn retire(&self) {
+        self.init_state.store(INIT_RETIRED, Ordering::Release);
+    }
+
+    pub(crate) fn is_retired(&self) -> bool {
+        self.init_state.load(Ordering::Acquire) == INIT_RETIRED
+    }
+
+    // Mark the segment as replaced by a bigger one under the same name.
+    // SeqCst, like the CAS producers claim slots with: see
+    // `ShmemRingBuffer::grow`.
+    pub(crate) fn migrate(&self) {
+        self.init_state.store(INIT_MIGRATED, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_migrated(&self) -> bool {
+        self.init_state.load(Ordering::SeqCst) == INIT_MIGRATED
+    }
+
+    // Whether `other`, mapped from whatever is under the ring's name now, is
+    // this same segment
+    pub(crate) fn is_same_segment(&self, other: &Self) -> bool {
+        other.init_state.load(Ordering::Acquire) == INIT_READY
+            && other.magic == RBUF_MAGIC
+            && other.version == RBUF_VERSION
+            && other.options.generation == self.options.generation
+    }
+
+    // Count a handle out, cleaning up after the wait queues with the last one
+    pub(crate) fn detach(&self) {
+        if self.attached.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.data_ready.release();
+            self.space_ready.release();
+        }
+    }
+
+    // Check that a mapped segment of `len` bytes starts with a header this
+    // build understands and that it describes the ring the caller expects.
+    // The caller still has to check that `len` covers the data region.
+    pub(crate) fn validate<'a>(
+        ptr: *const u8,
+        len: usize,
+        kind: RingKind,
+        elem_size: usize,
+        elem_align: usize,
+    ) -> Result<&'a Self, RbufError> {
+        let header = Self::validate_any(ptr, len)?;
+        if header.kind != kind as u32 {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "segment holds ring kind {} but a {:?} ring was expected",
+                header.kind, kind
+            )));
+        }
+        if header.elem_size != elem_size as u64 || header.elem_align != elem_align as u64 {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "segment holds elements of size {} / align {} but this handle uses size {} / align {}",
+                header.elem_size, header.elem_align, elem_size, elem_align
+            )));
+        }
+
+        Ok(header)
+    }
+
+    // Check that the creator recorded `schema` for the items, so that a
+    // handle built for some other type of the same size can't reinterpret
+    // them
+    pub(crate) fn check_schema(&self, schema: u64) -> Result<(), RbufError> {
+        if self.options.schema != schema {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "segment holds items with type fingerprint {:#018x} but this handle expects {:#018x}",
+                self.options.schema, schema
+            )));
+        }
+        Ok(())
+    }
+
+    // The checks every ring needs, whatever its kind and element type
+    pub(crate) fn validate_any<'a>(ptr: *const u8, len: usize) -> Result<&'a Self, RbufError> {
+        let header_size = mem::size_of::<Self>();
+        if len < header_size {
+            return Err(RbufError::SizeMismatch { expected: header_size, actual: len });
+        }
+
+        let header = unsafe { &*(ptr as *const Self) };
+        let deadline = Instant::now() + INIT_WAIT;
+        loop {
+            match header.init_state.load(Ordering::Acquire) {
+                INIT_READY => break,
+                // Unlinked or grown between being looked up and mapped
+                INIT_RETIRED | INIT_MIGRATED => return Err(RbufError::StaleSegment),
+                _ => {}
+            }
+            if Instant::now() >= deadline {
+                return Err(RbufError::NotInitialized);
+            }
+            thread::sleep(Duration::from_micros(100));
+        }
+
+        if header.magic != RBUF_MAGIC {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "bad magic {:#018x}, not an rbuf segment",
+                header.magic
+            )));
+        }
+        if header.version != RBUF_VERSION {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "segment has format version {} but this build uses {}",
+                header.version, RBUF_VERSION
+            )));
+        }
+        if RingKind::from_u32(header.kind).is_none() {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "segment holds unknown ring kind {}",
+                header.kind
+            )));
+        }
+        if header.elem_align == 0 || !header.elem_align.is_power_of_two() {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "segment has invalid element alignment {}",
+                header.elem_align
+            )));
+        }
+        if header.capacity == 0 {
+            return Err(RbufError::IncompatibleLayout("segment has zero capacity".to_string()));
+        }
+        // Everything but a typed ring with an exact capacity indexes by mask
+        if header.kind != RingKind::Typed as u32 && !header.capacity.is_power_of_two() {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "segment capacity {} is not a power of two",
+                header.capacity
+            )));
+        }
+        // Only possible when a 32-bit process opens a ring made on a 64-bit one
+        let too_big = [header.elem_size, header.capacity, header.max_consumers]
+            .iter()
+            .any(|&field| usize::try_from(field).is_err());
+        if too_big {
+            return Err(RbufError::IncompatibleLayout(
+                "segment is too large for this address space".to_string(),
+            ));
+        }
+        // Nothing the header describes fits in fewer bytes than it counts,
+        // which also keeps every layout worked out from it from overflowing
+        let len = len as u64;
+        let entry = registry::table_size(1) as u64;
+        if header.capacity > len
+            || header.elem_size > len / header.capacity
+            || header.max_consumers > len / entry
+        {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "segment of {} bytes can't hold the ring its header describes",
+                len
+            )));
+        }
+        // `head` never passes `tail`, so read in this order they only disagree
+        // in a corrupt header, as they do if either is anywhere near wrapping:
+        // that takes centuries of pushing. Trusting either would spin or
+        // overflow whoever did.
+        let head = header.head.load(Ordering::Acquire);
+        let tail = header.tail.load(Ordering::Acquire);
+        if head > tail || tail > i64::MAX as u64 {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "segment has head {} and tail {}, which no ring gets to",
+                head, tail
+            )));
+        }
+        let unsupported = header.options.flags & !SUPPORTED_OPTIONS;
+        if unsupported != 0 {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "segment uses options {:#x} that this build doesn't support",
+                unsupported
+            )));
+        }
+
+        Ok(header)
+    }
+}
@@ -0,0 +1,216 @@
+// inspect.rs
+//
+// Looking at a ring from the outside: everything here works from the header
+// alone, without knowing the element type the ring was created with.
+use std::sync::atomic::Ordering;
+
+use crate::codec::Codec;
+use crate::error::RbufError;
+use crate::header::{RingBufferHeader, RingKind, Role, OPTION_ENCRYPTED, OPTION_MIRRORED};
+use crate::notify::Notifier;
+use crate::peer::Peer;
+use crate::rate::RateLimit;
+use crate::registry::{self, ConsumerTable, Registration};
+use crate::ring::{self, RingBuffer};
+use crate::segment::{Backing, Segment};
+use crate::stats::Stats;
+use crate::{arena, bytes, directory, hash_map, last_value, mpmc, pool, region};
+
+// What the header of a ring says about it
+#[derive(Debug, Clone)]
+pub struct RingInfo {
+    pub name: String,
+    pub kind: RingKind,
+    pub version: u32,
+    // Size of the whole mapping, header included
+    pub segment_size: usize,
+    pub elem_size: usize,
+    pub elem_align: usize,
+    // Usable capacity: items, or bytes for byte rings
+    pub capacity: usize,
+    pub max_consumers: usize,
+    pub overwrite: bool,
+    // Hash of the message schema an encoded channel was created for, or the
+    // fingerprint of a typed, broadcast or MPMC ring's item type; 0 if none
+    pub schema: u64,
+    // Different for every segment created, even under the same name
+    pub generation: u64,
+    // How a byte ring's messages are compressed. None if the id is one this
+    // build doesn't know.
+    pub codec: Option<Codec>,
+    // Whether a byte ring's messages are encrypted
+    pub encrypted: bool,
+    // Whether a typed ring's slots are mapped twice in a row
+    pub mirrored: bool,
+    // Largest message a byte ring takes in fragments, or 0 if it doesn't
+    pub max_message: usize,
+    // How blocking calls on the ring wake each other
+    pub notifier: Notifier,
+    pub attached: usize,
+    // The process that created the ring
+    pub creator_pid: Option<u32>,
+    // Which side created a typed ring, and how many of each are attached.
+    // None and zeroes for the other kinds.
+    pub creator: Option<Role>,
+    pub consumers: usize,
+    pub producers: usize,
+    // Whether a producer closed a typed ring with `Producer::close`
+    pub closed: bool,
+    // Whether an admin paused publishing with `Command::Pause`
+    pub paused: bool,
+    // The last process of each role to beat, while any is attached
+    pub consumer_peer: Option<Peer>,
+    pub producer_peer: Option<Peer>,
+    // Free-running positions: `tail` counts every slot (byte for byte
+    // rings) ever claimed by a producer and `head` every one ever released
+    pub head: u64,
+    pub tail: u64,
+    // Items (bytes for byte rings) waiting to be read. None for broadcast
+    // rings, where every subscriber has its own cursor.
+    pub len: Option<usize>,
+    // Entries in the consumer table: registered consumers of a typed ring,
+    // or every subscriber of a broadcast ring
+    pub registrations: Vec<Registration>,
+    // What a typed ring's producers are held to; no limit for the others
+    pub rate_limit: RateLimit,
+    // Items a typed ring's consumer has granted credit for that producers
+    // haven't pushed yet, or None if it doesn't use credits
+    pub credits: Option<u64>,
+    pub stats: Stats,
+}
+
+fn validate(segment: &dyn Segment) -> Result<&'static RingBufferHeader, RbufError> {
+    // The header lives as long as the mapping, which the caller keeps alive
+    RingBufferHeader::validate_any(segment.as_ptr(), segment.len())
+}
+
+fn kind(header: &RingBufferHeader) -> RingKind {
+    RingKind::from_u32(header.kind).expect("validated header has a known kind")
+}
+
+impl RingBuffer {
+    // Read the header of any ring without attaching to it
+    pub fn inspect(name: &str) -> Result<RingInfo, RbufError> {
+        info(name, &*Backing::Shm.open(name)?)
+    }
+
+    // Pop everything waiting in the ring and hand each message's raw bytes
+    // to `f`, returning how many there were. Stop the real consumer of a
+    // typed ring first: it assumes nobody else advances `head`. Broadcast
+    // rings have no single read position and can't be drained.
+    pub fn drain(name: &str, mut f: impl FnMut(&[u8])) -> Result<usize, RbufError> {
+        drain_segment(Backing::Shm.open(name)?, &mut f)
+    }
+}
+
+// `RingBuffer::inspect` on a segment that is already mapped
+pub(crate) fn info(name: &str, segment: &dyn Segment) -> Result<RingInfo, RbufError> {
+    let header = validate(segment)?;
+    let kind = kind(header);
+    let head = header.head.load(Ordering::Acquire);
+    let tail = header.tail.load(Ordering::Acquire);
+
+    let len = match kind {
+        RingKind::Typed | RingKind::Bytes | RingKind::Mpmc => {
+            Some(tail.wrapping_sub(head) as usize)
+        }
+        RingKind::Broadcast => None,
+        // Blocks in use
+        RingKind::Pool => pool::in_use_raw(segment.as_ptr(), segment.len()),
+        // Bytes allocated
+        RingKind::Arena => arena::used_raw(segment.as_ptr(), segment.len()),
+        // Rings listed
+        RingKind::Directory => directory::listed_raw(segment.as_ptr(), segment.len()),
+        // Its own counters, not the header's
+        RingKind::Region => region::len_raw(segment.as_ptr(), segment.len()),
+        // Keys with a value
+        RingKind::LastValue => last_value::len_raw(segment.as_ptr(), segment.len(), header),
+        RingKind::HashMap => hash_map::len_raw(segment.as_ptr(), segment.len(), header),
+    };
+
+    let latency = match kind {
+        RingKind::Typed => ring::latency_raw(segment.as_ptr(), segment.len(), header),
+        _ => None,
+    };
+
+    let table_end = registry::table_offset() + registry::table_size(header.max_consumers());
+    let registrations = if segment.len() >= table_end {
+        let table = unsafe { ConsumerTable::new(segment.as_ptr(), header.max_consumers()) };
+        table.registrations(tail)
+    } else {
+        Vec::new()
+    };
+
+    Ok(RingInfo {
+        name: name.to_string(),
+        kind,
+        version: header.version,
+        segment_size: segment.len(),
+        elem_size: header.elem_size(),
+        elem_align: header.elem_align(),
+        capacity: header.capacity(),
+        max_consumers: header.max_consumers(),
+        overwrite: header.overwrite.load(Ordering::Acquire) != 0,
+        schema: header.options.schema,
+        generation: header.options.generation,
+        codec: Codec::from_id(header.options.codec),
+        encrypted: header.options.has(OPTION_ENCRYPTED),
+        mirrored: header.options.has(OPTION_MIRRORED),
+        max_message: header.options.max_message as usize,
+        notifier: header.data_ready.notifier(),
+        attached: header.attached.load(Ordering::Acquire) as usize,
+        creator_pid: Some(header.roles.creator_pid()).filter(|&pid| pid != 0),
+        creator: header.roles.creator(),
+        consumers: header.roles.attached(Role::Consumer),
+        producers: header.roles.attached(Role::Producer),
+        closed: header.roles.is_closed(Role::Producer),
+        paused: header.control.paused.load(Ordering::Acquire) != 0,
+        consumer_peer: header.roles.peer(Role::Consumer),
+        producer_peer: header.roles.peer(Role::Producer),
+        head,
+        tail,
+        len,
+        registrations,
+        rate_limit: RateLimit::load(&header.control),
+        credits: header.control.credits(tail),
+        stats: Stats { latency, ..header.stats() },
+    })
+}
+
+// `RingBuffer::drain` on a segment that is already mapped
+pub(crate) fn drain_segment(
+    segment: Box<dyn Segment>,
+    f: &mut dyn FnMut(&[u8]),
+) -> Result<usize, RbufError> {
+    let header = validate(&*segment)?;
+    match kind(header) {
+        RingKind::Typed => {
+            let expected = ring::SegmentLayout::of(header).size;
+            if segment.len() < expected {
+                return Err(RbufError::SizeMismatch { expected, actual: segment.len() });
+            }
+            Ok(ring::drain_raw(segment.as_ptr(), header, f))
+        }
+        RingKind::Mpmc => mpmc::drain_raw(segment.as_ptr(), segment.len(), header, f),
+        RingKind::Bytes => bytes::drain_raw(segment, f),
+        RingKind::Broadcast => Err(RbufError::IncompatibleLayout(
+            "broadcast rings can't be drained".to_string(),
+        )),
+        RingKind::Pool => Err(RbufError::IncompatibleLayout(
+            "buffer pools can't be drained".to_string(),
+        )),
+        RingKind::Arena => Err(RbufError::IncompatibleLayout(
+            "arenas can't be drained".to_string(),
+        )),
+        RingKind::Directory => Err(RbufError::IncompatibleLayout(
+            "the directory can't be drained".to_string(),
+        )),
+        RingKind::Region => region::drain_raw(segment.as_ptr(), segment.len(), f),
+        RingKind::LastValue => Err(RbufError::IncompatibleLayout(
+            "last value tables can't be drained".to_string(),
+        )),
+        RingKind::HashMap => Err(RbufError::IncompatibleLayout(
+            "hash maps can't be drained".to_string(),
+        )),
+    }
+}
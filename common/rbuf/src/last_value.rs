@@ -0,0 +1,417 @@
+// last_value.rs
+//
+// The latest value for every key, for state that a process joining late
+// needs in full (configuration, positions, the last quote per symbol) and
+// not only as the updates published after it arrived. Publishers write each
+// update into a table of the latest value per key, then broadcast it. A
+// subscriber joins the broadcast first, reads the table, and from then on
+// applies whatever updates the broadcast brings that are newer than what
+// it read, so it neither misses an update nor goes back to an older value.
+//
+// Updates go through the broadcast ring `<name>`; the table lives in a
+// segment of its own, `<name>.values`:
+//
+// [ header | entries ]
+//
+// Entries are found by linear probing from a hash of the key and are never
+// removed, so the table holds `max_keys` keys over its lifetime. Each one
+// counts the updates to its key in `version`, which doubles as a seqlock:
+// it's odd while a publisher writes the value.
+//
+// Publishers overwrite updates a subscriber hasn't read rather than wait
+// for it. A subscriber that falls a whole ring behind reads the table again
+// and carries on from there, so it skips intermediate values but always
+// ends up with the latest.
+use std::cell::UnsafeCell;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::thread;
+
+use crate::broadcast::{Publisher, SlowSubscriberPolicy, Subscriber};
+use crate::directory;
+use crate::error::RbufError;
+use crate::hash_map::hash;
+use crate::header::{RingBufferHeader, RingKind, CACHE_LINE};
+use crate::segment::{Backing, Segment};
+use crate::shm_safe::ShmSafe;
+
+// Entry states
+const FREE: u32 = 0;
+// A publisher is writing the key
+const CLAIMED: u32 = 1;
+const READY: u32 = 2;
+
+#[repr(C)]
+struct Entry<K, V> {
+    state: AtomicU32,
+    // Twice the number of updates to the key, plus one while one is being
+    // written
+    version: AtomicU64,
+    key: UnsafeCell<MaybeUninit<K>>,
+    value: UnsafeCell<MaybeUninit<V>>,
+}
+
+// What goes through the broadcast ring
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Update<K, V> {
+    // The key's `version` once this value was written, halved
+    version: u64,
+    key: K,
+    value: V,
+}
+
+unsafe impl<K: ShmSafe, V: ShmSafe> ShmSafe for Update<K, V> {}
+
+// The segment holding the table of the map called `name`
+pub(crate) fn values_name(name: &str) -> String {
+    format!("{}.values", name)
+}
+
+fn entries_offset() -> usize {
+    (mem::size_of::<RingBufferHeader>() + CACHE_LINE - 1) & !(CACHE_LINE - 1)
+}
+
+// Keys present in a table, for tools that don't hold one
+pub(crate) fn len_raw(base: *const u8, len: usize, header: &RingBufferHeader) -> Option<usize> {
+    let (stride, slots) = (header.elem_size(), header.capacity());
+    if stride < mem::size_of::<AtomicU32>() || len < entries_offset() + stride * slots {
+        return None;
+    }
+    let states = (0..slots).map(|index| {
+        let state = base.wrapping_add(entries_offset() + index * stride) as *const AtomicU32;
+        unsafe { (*state).load(Ordering::Acquire) }
+    });
+    Some(states.filter(|&state| state == READY).count())
+}
+
+// --- Table ---
+
+struct Table<K, V> {
+    segment: Box<dyn Segment>,
+    header: *const RingBufferHeader,
+    entries: *const Entry<K, V>,
+    mask: u64,
+    _phantom: PhantomData<(K, V)>,
+}
+
+unsafe impl<K: Send, V: Send> Send for Table<K, V> {}
+unsafe impl<K: Send, V: Send> Sync for Table<K, V> {}
+
+impl<K: ShmSafe + Copy + Eq + Hash, V: ShmSafe + Copy> Table<K, V> {
+    fn create(name: &str, max_keys: usize) -> Result<Self, RbufError> {
+        // Keep the table at most half full, so probes stay short
+        let slots = max_keys.max(1).saturating_mul(2).next_power_of_two();
+        let size = entries_offset() + slots * mem::size_of::<Entry<K, V>>();
+        let segment = Backing::Shm.create(&values_name(name), size)?;
+        unsafe {
+            let header = RingBufferHeader::initialize(
+                segment.as_ptr(),
+                RingBufferHeader::new(
+                    RingKind::LastValue,
+                    mem::size_of::<Entry<K, V>>(),
+                    mem::align_of::<Entry<K, V>>(),
+                    slots,
+                )
+                .with_schema(directory::type_hash::<Update<K, V>>()),
+            );
+            // Free entries are all zeroes
+            let entries = segment.as_ptr().add(entries_offset());
+            std::ptr::write_bytes(entries, 0, size - entries_offset());
+            header.publish();
+            directory::register(segment.name(), header);
+        }
+        Ok(Self::from_segment(segment))
+    }
+
+    fn open(name: &str) -> Result<Self, RbufError> {
+        let segment = Backing::Shm.open(&values_name(name))?;
+        let header = RingBufferHeader::validate(
+            segment.as_ptr(),
+            segment.len(),
+            RingKind::LastValue,
+            mem::size_of::<Entry<K, V>>(),
+            mem::align_of::<Entry<K, V>>(),
+        )?;
+        header.check_schema(directory::type_hash::<Update<K, V>>())?;
+        let expected = entries_offset() + header.capacity() * mem::size_of::<Entry<K, V>>();
+        if segment.len() < expected {
+            return Err(RbufError::SizeMismatch { expected, actual: segment.len() });
+        }
+        Ok(Self::from_segment(segment))
+    }
+
+    fn from_segment(segment: Box<dyn Segment>) -> Self {
+        let header = segment.as_ptr() as *const RingBufferHeader;
+        let capacity = unsafe { (*header).capacity() };
+        let entries = unsafe { segment.as_ptr().add(entries_offset()) } as *const Entry<K, V>;
+        unsafe { (*header).attached.fetch_add(1, Ordering::AcqRel) };
+        event!(debug, ring = segment.name(), kind = "last_value", capacity, "mapped table");
+        let mask = capacity as u64 - 1;
+        Self { segment, header, entries, mask, _phantom: PhantomData }
+    }
+
+    fn entry(&self, index: u64) -> &Entry<K, V> {
+        unsafe { &*self.entries.add((index & self.mask) as usize) }
+    }
+
+    // The key of an entry that isn't free, once its publisher has written it
+    fn key(&self, entry: &Entry<K, V>) -> K {
+        while entry.state.load(Ordering::Acquire) == CLAIMED {
+            thread::yield_now();
+        }
+        unsafe { (*entry.key.get()).assume_init_read() }
+    }
+
+    // The entry holding `key`, claiming a free one for it if `insert` says
+    // to. Fails with `Full` once every entry holds some other key.
+    fn find(&self, key: &K, insert: bool) -> Result<Option<&Entry<K, V>>, RbufError> {
+        let start = hash(key);
+        for probe in 0..=self.mask {
+            let entry = self.entry(start.wrapping_add(probe));
+            if entry.state.load(Ordering::Acquire) == FREE {
+                if !insert {
+                    return Ok(None);
+                }
+                let claimed = entry.state.compare_exchange(
+                    FREE,
+                    CLAIMED,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                );
+                if claimed.is_ok() {
+                    unsafe { (*entry.key.get()).write(*key) };
+                    entry.state.store(READY, Ordering::Release);
+                    return Ok(Some(entry));
+                }
+            }
+            if self.key(entry) == *key {
+                return Ok(Some(entry));
+            }
+        }
+        if insert {
+            Err(RbufError::Full)
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Write `value` and return the key's new version
+    fn write(&self, entry: &Entry<K, V>, value: V) -> u64 {
+        let mut version = entry.version.load(Ordering::Relaxed);
+        loop {
+            if version & 1 == 1 {
+                // Another publisher is writing the same key
+                thread::yield_now();
+                version = entry.version.load(Ordering::Relaxed);
+                continue;
+            }
+            match entry.version.compare_exchange_weak(
+                version,
+                version + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => version = current,
+            }
+        }
+        fence(Ordering::Release);
+        unsafe { (*entry.value.get()).write(value) };
+        entry.version.store(version + 2, Ordering::Release);
+        (version + 2) / 2
+    }
+
+    // The value of an entry and its version, or None if it has none yet
+    fn read(&self, entry: &Entry<K, V>) -> Option<(V, u64)> {
+        loop {
+            let version = entry.version.load(Ordering::Acquire);
+            if version == 0 {
+                return None;
+            }
+            if version & 1 == 0 {
+                let value = unsafe { (*entry.value.get()).assume_init_read() };
+                fence(Ordering::Acquire);
+                if entry.version.load(Ordering::Relaxed) == version {
+                    return Some((value, version / 2));
+                }
+            }
+            thread::yield_now();
+        }
+    }
+
+    // Every key with a value, with its version
+    fn snapshot(&self) -> Vec<(K, V, u64)> {
+        let mut values = Vec::new();
+        for index in 0..=self.mask {
+            let entry = self.entry(index);
+            if entry.state.load(Ordering::Acquire) == FREE {
+                continue;
+            }
+            let key = self.key(entry);
+            if let Some((value, version)) = self.read(entry) {
+                values.push((key, value, version));
+            }
+        }
+        values
+    }
+}
+
+impl<K, V> Drop for Table<K, V> {
+    fn drop(&mut self) {
+        unsafe { (*self.header).detach() };
+        event!(debug, ring = self.segment.name(), kind = "last_value", "detached from table");
+    }
+}
+
+// --- Publishing ---
+
+// A publisher's handle on the map
+pub struct LastValueMap<K: ShmSafe + Copy + Eq + Hash, V: ShmSafe + Copy> {
+    table: Table<K, V>,
+    updates: Publisher<Update<K, V>>,
+}
+
+impl<K: ShmSafe + Copy + Eq + Hash, V: ShmSafe + Copy> LastValueMap<K, V> {
+    // Create a map with room for `max_keys` keys, whose updates go through
+    // a broadcast ring of `capacity` slots with room for `max_subscribers`
+    pub fn create(
+        name: &str,
+        max_keys: usize,
+        capacity: usize,
+        max_subscribers: usize,
+    ) -> Result<Self, RbufError> {
+        // Room for at least `max_keys`
+        let table = Table::create(name, max_keys)?;
+        let updates = Publisher::create(name, capacity, max_subscribers)?;
+        Ok(Self::from_parts(table, updates))
+    }
+
+    // Attach an additional publisher to an existing map
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        let updates = Publisher::open(name)?;
+        Ok(Self::from_parts(Table::open(name)?, updates))
+    }
+
+    fn from_parts(table: Table<K, V>, mut updates: Publisher<Update<K, V>>) -> Self {
+        updates.set_slow_subscriber_policy(SlowSubscriberPolicy::DropOldest);
+        Self { table, updates }
+    }
+
+    pub fn name(&self) -> &str {
+        self.updates.name()
+    }
+
+    // Whether dropping this handle removes the map from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.table.segment.set_owner(unlink);
+        self.updates.set_unlink_on_drop(unlink);
+    }
+
+    // Make `value` the latest for `key` and broadcast it. Fails with `Full`
+    // if `key` is new and the table has no room left for it.
+    pub fn publish(&self, key: K, value: V) -> Result<(), RbufError> {
+        let entry = self.table.find(&key, true)?.expect("inserting always finds an entry");
+        let version = self.table.write(entry, value);
+        // Never `Full`: slow subscribers get overwritten
+        self.updates.push(Update { version, key, value }).map_err(|e| e.into_error())
+    }
+
+    // The latest value for `key`
+    pub fn get(&self, key: &K) -> Option<V> {
+        let entry = self.table.find(key, false).ok().flatten()?;
+        self.table.read(entry).map(|(value, _)| value)
+    }
+}
+
+// --- Subscribing ---
+
+// `Subscriber::pop` or `pop_blocking`
+type PopFn<K, V> = fn(&mut Subscriber<Update<K, V>>) -> Result<Update<K, V>, RbufError>;
+
+// A subscriber's handle on the map. `pop` first hands out the latest value
+// of every key the map held when it joined, then every update since.
+pub struct LastValueSubscriber<K: ShmSafe + Copy + Eq + Hash, V: ShmSafe + Copy> {
+    table: Table<K, V>,
+    updates: Subscriber<Update<K, V>>,
+    // The version of each key's value handed out last
+    seen: HashMap<K, u64>,
+    // Values read from the table, still to hand out
+    pending: VecDeque<(K, V)>,
+}
+
+impl<K: ShmSafe + Copy + Eq + Hash, V: ShmSafe + Copy> LastValueSubscriber<K, V> {
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        // Join the broadcast before reading the table, so that any update
+        // the table doesn't have yet is still to come
+        let updates = Subscriber::open(name)?;
+        let table = Table::open(name)?;
+        let mut subscriber =
+            Self { table, updates, seen: HashMap::new(), pending: VecDeque::new() };
+        subscriber.catch_up();
+        Ok(subscriber)
+    }
+
+    pub fn name(&self) -> &str {
+        self.updates.name()
+    }
+
+    // Queue whatever the table holds that's newer than what we handed out
+    fn catch_up(&mut self) {
+        for (key, value, version) in self.table.snapshot() {
+            if self.seen.get(&key).is_none_or(|&seen| version > seen) {
+                self.seen.insert(key, version);
+                self.pending.push_back((key, value));
+            }
+        }
+    }
+
+    // The next value of some key: one from the table while any are left,
+    // then the next update. Updates older than a value already handed out
+    // are skipped. Fails with `Empty` if there's nothing new.
+    pub fn pop(&mut self) -> Result<(K, V), RbufError> {
+        self.next(Subscriber::pop)
+    }
+
+    // Pop, sleeping until a publisher publishes if there's nothing new
+    pub fn pop_blocking(&mut self) -> Result<(K, V), RbufError> {
+        self.next(Subscriber::pop_blocking)
+    }
+
+    fn next(&mut self, pop: PopFn<K, V>) -> Result<(K, V), RbufError> {
+        loop {
+            if let Some(value) = self.pending.pop_front() {
+                return Ok(value);
+            }
+            let update = match pop(&mut self.updates) {
+                Ok(update) => update,
+                // Whatever we missed is in the table
+                Err(RbufError::Lagged { .. }) => {
+                    self.catch_up();
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let seen = self.seen.entry(update.key).or_default();
+            if update.version > *seen {
+                *seen = update.version;
+                return Ok((update.key, update.value));
+            }
+        }
+    }
+
+    // The latest value for `key`, whether or not `pop` has handed it out
+    pub fn get(&self, key: &K) -> Option<V> {
+        let entry = self.table.find(key, false).ok().flatten()?;
+        self.table.read(entry).map(|(value, _)| value)
+    }
+
+    // The latest value of every key
+    pub fn snapshot(&self) -> Vec<(K, V)> {
+        self.table.snapshot().into_iter().map(|(key, value, _)| (key, value)).collect()
+    }
+}
@@ -0,0 +1,125 @@
+// latency.rs
+//
+// How long messages sit in a ring. Producers stamp every slot with a
+// monotonic clock reading when they commit it, and the consumer records
+// `now - stamp` into a histogram that lives in the segment, so any process
+// can read the distribution.
+//
+// The histogram is log-linear like HdrHistogram: values below 8ns get a
+// bucket each, and every power of two above that is split into 8 equal
+// buckets, so any recorded value is known to within 12.5%.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+pub(crate) const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+// Nanoseconds on a clock that every process on the machine shares and that
+// never jumps backwards
+#[cfg(unix)]
+pub(crate) fn now_monotonic() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+// The performance counter is system-wide, so also fine across processes
+#[cfg(windows)]
+pub(crate) fn now_monotonic() -> u64 {
+    use windows::Win32::System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency};
+
+    let (mut count, mut frequency) = (0i64, 0i64);
+    unsafe {
+        QueryPerformanceCounter(&mut count);
+        QueryPerformanceFrequency(&mut frequency);
+    }
+    (count as u128 * 1_000_000_000 / frequency.max(1) as u128) as u64
+}
+
+// Only comparable within one process; good enough for single-process tests
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn now_monotonic() -> u64 {
+    use std::sync::OnceLock;
+    use std::time::Instant;
+
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
+
+fn bucket(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+    let exp = 63 - nanos.leading_zeros();
+    let sub = (nanos >> (exp - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+    (exp - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + sub
+}
+
+// The largest value that lands in `bucket`
+fn bucket_max(bucket: usize) -> u64 {
+    if bucket < SUB_BUCKETS {
+        return bucket as u64;
+    }
+    let exp = (bucket / SUB_BUCKETS) as u32 + SUB_BUCKET_BITS - 1;
+    let sub = (bucket % SUB_BUCKETS) as u64;
+    let width = 1u64 << (exp - SUB_BUCKET_BITS);
+    ((SUB_BUCKETS as u64 + sub) << (exp - SUB_BUCKET_BITS)).saturating_add(width - 1)
+}
+
+// The histogram as it lives in shared memory, written by the consumer
+#[repr(C)]
+pub(crate) struct SharedHistogram {
+    counts: [AtomicU64; BUCKETS],
+}
+
+impl SharedHistogram {
+    pub(crate) fn record(&self, nanos: u64) {
+        self.counts[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> LatencyHistogram {
+        let counts = self.counts.iter().map(|count| count.load(Ordering::Relaxed)).collect();
+        LatencyHistogram { counts }
+    }
+}
+
+// A snapshot of how long popped items spent in the ring
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    counts: Box<[u64]>,
+}
+
+impl LatencyHistogram {
+    // Items recorded
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // The latency that `quantile` (0.0 to 1.0) of the items stayed under,
+    // e.g. `percentile(0.99)`. Zero if nothing was recorded.
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let total = self.count();
+        if total == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_max(bucket));
+            }
+        }
+        self.max()
+    }
+
+    pub fn max(&self) -> Duration {
+        self.counts
+            .iter()
+            .rposition(|&count| count > 0)
+            .map_or(Duration::ZERO, |bucket| Duration::from_nanos(bucket_max(bucket)))
+    }
+}
+
+const _: () = assert!(BUCKETS == 496);
@@ -0,0 +1,112 @@
+// lib.rs
+//
+// A single-segment ring buffer living in shared memory. Whichever side starts
+// first, consumer or producer, creates the segment through `Ring::create` and
+// the other side attaches to it.
+
+// Defines `event!`, so it has to come before every module that logs
+#[macro_use]
+mod trace;
+
+#[cfg(feature = "rkyv")]
+pub mod archive;
+#[cfg(feature = "async")]
+mod async_ring;
+pub mod arena;
+pub mod broadcast;
+pub mod bytes;
+#[cfg(feature = "serde")]
+pub mod channel;
+mod checksum;
+mod codec;
+mod compact;
+mod config;
+mod control;
+mod consumer;
+mod crypto;
+pub mod directory;
+mod error;
+#[cfg(target_os = "linux")]
+mod eventfd;
+#[cfg(unix)]
+mod fd;
+#[cfg(fuzzing)]
+pub mod fuzzing;
+#[cfg(target_os = "linux")]
+pub mod gc;
+mod handle;
+pub mod hash_map;
+mod header;
+mod inspect;
+pub mod last_value;
+mod latency;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod mock;
+pub mod mpmc;
+pub mod mux;
+mod namespace;
+mod notify;
+mod peer;
+pub mod pool;
+pub mod priority;
+mod producer;
+#[cfg(feature = "prost")]
+pub mod proto;
+mod ptr;
+mod rate;
+pub mod region;
+mod registry;
+mod ring;
+pub mod rpc;
+mod segment;
+mod select;
+pub mod sharded;
+mod shm_safe;
+#[cfg(loom)]
+pub mod slots;
+#[cfg(not(loom))]
+mod slots;
+mod static_ring;
+mod stats;
+mod stream;
+mod sync;
+mod wait;
+mod watermark;
+
+#[cfg(feature = "async")]
+pub use async_ring::{AsyncConsumer, AsyncProducer};
+pub use codec::Codec;
+pub use compact::{CompactRing, RingIndex};
+pub use config::{OpenMode, Ring, RingConfig, RingHandle};
+pub use control::{Command, ControlListener, LogLevel};
+pub use consumer::{Consumer, PopGuard, PopRef, PopSlice};
+pub use directory::{Directory, DirectoryEntry};
+pub use error::{PushError, RbufError};
+#[cfg(unix)]
+pub use fd::{recv_fd, send_fd};
+pub use handle::{Pop, Push};
+pub use header::{RingBufferHeader, RingKind, Role};
+pub use inspect::RingInfo;
+pub use latency::LatencyHistogram;
+pub use namespace::Namespace;
+pub use notify::Notifier;
+pub use peer::Peer;
+pub use producer::{FullPolicy, Producer, WriteGuard, WriteSliceGuard};
+pub use ptr::{ShmPtr, ShmSlice};
+pub use rate::RateLimit;
+pub use registry::Registration;
+pub use ring::{FlushPolicy, Recovery, RingBuffer};
+#[cfg(unix)]
+pub use segment::Permissions;
+pub use segment::{Backend, Backing, HugePageSize, Segment};
+pub use select::Selector;
+pub use shm_safe::ShmSafe;
+pub use static_ring::{StaticConsumer, StaticProducer, StaticRing};
+pub use stats::Stats;
+pub use stream::{RingReader, RingWriter};
+pub use sync::{
+    ShmBarrier, ShmCondvar, ShmMutex, ShmMutexGuard, ShmReadGuard, ShmRwLock, ShmWriteGuard,
+};
+pub use wait::{Blocking, BusySpin, Idle, Sleep, SpinThenYield, WaitStrategy};
+pub use watermark::{Pressure, Watermarks};
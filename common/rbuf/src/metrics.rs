@@ -0,0 +1,106 @@
+// metrics.rs
+//
+// Ring stats through the `metrics` facade, so whatever recorder the process
+// installs (e.g. `metrics-exporter-prometheus`) serves them. Everything is
+// read from the header like `RingBuffer::inspect`, so one reporter can watch
+// rings it has no handle on, whatever their element type.
+//
+//     let interval = Duration::from_secs(5);
+//     let _reporter = rbuf::metrics::Reporter::spawn(["quotes", "orders"], interval);
+//
+// Counters are exported as absolute totals; rates are left to the dashboard
+// (`rate(rbuf_pushes_total[1m])`).
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use ::metrics::{counter, gauge};
+
+use crate::error::RbufError;
+use crate::inspect::RingInfo;
+use crate::ring::RingBuffer;
+
+// The latency percentiles exported as `rbuf_latency_seconds{quantile=...}`
+const QUANTILES: [(f64, &str); 4] =
+    [(0.5, "0.5"), (0.9, "0.9"), (0.99, "0.99"), (0.999, "0.999")];
+
+// Publish one snapshot of a ring, labelled with its name
+pub fn record(info: &RingInfo) {
+    let ring = info.name.clone();
+    let stats = &info.stats;
+
+    gauge!("rbuf_capacity", "ring" => ring.clone()).set(info.capacity as f64);
+    gauge!("rbuf_attached", "ring" => ring.clone()).set(info.attached as f64);
+    if let Some(len) = info.len {
+        gauge!("rbuf_occupancy", "ring" => ring.clone()).set(len as f64);
+    }
+    gauge!("rbuf_high_watermark", "ring" => ring.clone()).set(stats.high_watermark as f64);
+
+    counter!("rbuf_pushes_total", "ring" => ring.clone()).absolute(stats.pushes);
+    counter!("rbuf_pops_total", "ring" => ring.clone()).absolute(stats.pops);
+    counter!("rbuf_full_total", "ring" => ring.clone()).absolute(stats.full);
+    counter!("rbuf_overwritten_total", "ring" => ring.clone()).absolute(stats.overwritten);
+    counter!("rbuf_corrupt_total", "ring" => ring.clone()).absolute(stats.corrupt);
+
+    for registration in &info.registrations {
+        let pid = registration.pid.to_string();
+        gauge!("rbuf_consumer_lag", "ring" => ring.clone(), "pid" => pid)
+            .set(registration.lag as f64);
+    }
+
+    if let Some(latency) = &stats.latency {
+        for (quantile, label) in QUANTILES {
+            gauge!("rbuf_latency_seconds", "ring" => ring.clone(), "quantile" => label)
+                .set(latency.percentile(quantile).as_secs_f64());
+        }
+        counter!("rbuf_latency_samples_total", "ring" => ring).absolute(latency.count());
+    }
+}
+
+// Inspect the named ring and publish what it says
+pub fn record_ring(name: &str) -> Result<(), RbufError> {
+    record(&RingBuffer::inspect(name)?);
+    Ok(())
+}
+
+// A background thread publishing a set of rings every `interval`. Rings that
+// don't exist (yet, or any more) are skipped. Stops when dropped.
+pub struct Reporter {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reporter {
+    pub fn spawn<I, S>(rings: I, interval: Duration) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let rings: Vec<String> = rings.into_iter().map(Into::into).collect();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::Builder::new()
+            .name("rbuf-metrics".to_string())
+            .spawn(move || {
+                while !stopped.load(Ordering::Acquire) {
+                    for ring in &rings {
+                        let _ = record_ring(ring);
+                    }
+                    thread::park_timeout(interval);
+                }
+            })
+            .expect("failed to spawn the metrics thread");
+        Self { stop, thread: Some(thread) }
+    }
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,214 @@
+// mock.rs
+//
+// An in-process stand-in for a ring, for unit testing code written against
+// `Push` and `Pop` without shared memory. Clones share one queue: hand one
+// to the code under test as its producer and keep another to pop from, or
+// the other way round. Failures a real ring only shows under load or after
+// a crash can be forced at will:
+//
+//     let ring = MockRing::new(16);
+//     ring.set_full(true);
+//     assert!(matches!(publish(&mut ring.clone()), Err(RbufError::Full)));
+//     ring.corrupt_next(1);
+//     assert!(matches!(ring.clone().pop(), Err(RbufError::CorruptMessage { .. })));
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::{PushError, RbufError};
+use crate::handle::{Pop, Push};
+
+pub struct MockRing<T> {
+    shared: Arc<Shared<T>>,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    // Signalled whenever an item comes or goes, or a behavior changes
+    changed: Condvar,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    capacity: usize,
+    // Pushes fail with `Full` however much room there is
+    full: bool,
+    // How long every push and pop takes before doing anything
+    delay: Duration,
+    // How many of the next items popped fail with `CorruptMessage`
+    corrupt: usize,
+    closed: bool,
+    // Items ever popped, the position reported with `CorruptMessage`
+    popped: u64,
+}
+
+impl<T> MockRing<T> {
+    pub fn new(capacity: usize) -> Self {
+        let state = State {
+            items: VecDeque::with_capacity(capacity),
+            capacity,
+            full: false,
+            delay: Duration::ZERO,
+            corrupt: 0,
+            closed: false,
+            popped: 0,
+        };
+        Self { shared: Arc::new(Shared { state: Mutex::new(state), changed: Condvar::new() }) }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.state().capacity
+    }
+
+    // --- Injected behaviors ---
+
+    // While set, pushes fail with `Full` and `push_blocking` waits, as if a
+    // consumer had stopped popping
+    pub fn set_full(&self, full: bool) {
+        self.state().full = full;
+        self.shared.changed.notify_all();
+    }
+
+    // Make every push and pop sleep for `delay` first, as if the other side
+    // were slow to wake or the machine were loaded. `Duration::ZERO` turns
+    // it off.
+    pub fn set_delay(&self, delay: Duration) {
+        self.state().delay = delay;
+    }
+
+    // Discard the next `count` items popped as if they failed their
+    // checksum: `pop` and `pop_timeout` report each as `CorruptMessage`,
+    // `pop_blocking` skips them like `Consumer::pop_blocking` does
+    pub fn corrupt_next(&self, count: usize) {
+        self.state().corrupt = count;
+    }
+
+    // --- Internals ---
+
+    fn state(&self) -> MutexGuard<'_, State<T>> {
+        // A test that panicked mid-call has failed already; carry on
+        self.shared.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sleep(&self) {
+        let delay = self.state().delay;
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+
+    // Wait on `changed` until `ready` or `deadline`, whichever is first
+    fn wait_until(
+        &self,
+        deadline: Option<Instant>,
+        mut ready: impl FnMut(&State<T>) -> bool,
+    ) -> Option<MutexGuard<'_, State<T>>> {
+        let mut state = self.state();
+        while !ready(&state) {
+            state = match deadline {
+                None => self.shared.changed.wait(state).unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let left = deadline.checked_duration_since(Instant::now())?;
+                    let waited = self.shared.changed.wait_timeout(state, left);
+                    waited.unwrap_or_else(|e| e.into_inner()).0
+                }
+            };
+        }
+        Some(state)
+    }
+
+    fn try_push(&self, state: &mut State<T>, item: T) -> Result<(), PushError<T>> {
+        if state.closed {
+            return Err(PushError::new(RbufError::Disconnected, item));
+        }
+        if state.full || state.items.len() >= state.capacity {
+            return Err(PushError::new(RbufError::Full, item));
+        }
+        state.items.push_back(item);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    fn try_pop(&self, state: &mut State<T>) -> Result<T, RbufError> {
+        let Some(item) = state.items.pop_front() else {
+            return Err(if state.closed { RbufError::Disconnected } else { RbufError::Empty });
+        };
+        let seq = state.popped;
+        state.popped += 1;
+        self.shared.changed.notify_all();
+        if state.corrupt > 0 {
+            state.corrupt -= 1;
+            return Err(RbufError::CorruptMessage { seq });
+        }
+        Ok(item)
+    }
+
+    fn pop_until(&self, deadline: Option<Instant>) -> Result<T, RbufError> {
+        self.sleep();
+        let ready = |state: &State<T>| !state.items.is_empty() || state.closed;
+        match self.wait_until(deadline, ready) {
+            Some(mut state) => self.try_pop(&mut state),
+            None => Err(RbufError::Timeout),
+        }
+    }
+}
+
+impl<T> Clone for MockRing<T> {
+    fn clone(&self) -> Self {
+        Self { shared: Arc::clone(&self.shared) }
+    }
+}
+
+impl<T> Push<T> for MockRing<T> {
+    fn push(&mut self, item: T) -> Result<(), PushError<T>> {
+        self.sleep();
+        self.try_push(&mut self.state(), item)
+    }
+
+    fn push_blocking(&mut self, item: T) -> Result<(), PushError<T>> {
+        self.sleep();
+        let ready = |state: &State<T>| {
+            state.closed || (!state.full && state.items.len() < state.capacity)
+        };
+        let mut state = self.wait_until(None, ready).expect("no deadline");
+        self.try_push(&mut state, item)
+    }
+
+    fn close(&mut self) {
+        self.state().closed = true;
+        self.shared.changed.notify_all();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state().closed
+    }
+}
+
+impl<T> Pop<T> for MockRing<T> {
+    fn pop(&mut self) -> Result<T, RbufError> {
+        self.sleep();
+        self.try_pop(&mut self.state())
+    }
+
+    fn pop_blocking(&mut self) -> Result<T, RbufError> {
+        loop {
+            match self.pop_until(None) {
+                Err(RbufError::CorruptMessage { .. }) => {}
+                result => return result,
+            }
+        }
+    }
+
+    fn pop_timeout(&mut self, timeout: Duration) -> Result<T, RbufError> {
+        self.pop_until(Some(Instant::now() + timeout))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state().closed
+    }
+
+    fn len(&self) -> usize {
+        self.state().items.len()
+    }
+}
@@ -0,0 +1,538 @@
+// mpmc.rs
+//
+// A work queue: any number of producers and any number of consumers, in any
+// number of processes, each item delivered to exactly one consumer.
+//
+// This is Dmitry Vyukov's bounded MPMC queue. `head` and `tail` are
+// free-running positions and every slot carries a sequence number saying
+// whose turn it is:
+//
+//   seq == pos            free, the producer claiming `pos` may write it
+//   seq == pos + 1        holds the item at `pos`, a consumer may take it
+//   seq == pos + capacity taken, free again for the producer one lap later
+//
+// Producers race for `tail` and consumers for `head` with a CAS, but only
+// after the slot's sequence number says the slot is theirs, so nobody ever
+// waits on a lock.
+//
+// Consumers can also join the queue's consumer group as a `GroupMember`,
+// which holds on to each item until it is acked; see below.
+//
+// [ header | group member table | slots ]
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::directory;
+use crate::error::{PushError, RbufError};
+use crate::header::{RingBufferHeader, RingKind};
+use crate::peer;
+use crate::registry::{self, ConsumerEntry, ConsumerTable, Registration};
+use crate::segment::{Backing, Segment};
+use crate::shm_safe::ShmSafe;
+use crate::slots;
+use crate::wait::{self, Blocking, WaitStrategy};
+
+#[repr(C)]
+struct Slot<T> {
+    seq: AtomicU64,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+fn slots_offset<T>(max_members: usize) -> usize {
+    let align = mem::align_of::<Slot<T>>();
+    let table_end = registry::table_offset() + registry::table_size(max_members);
+    (table_end + align - 1) & !(align - 1)
+}
+
+fn segment_size<T>(capacity: usize, max_members: usize) -> usize {
+    slots_offset::<T>(max_members) + capacity * mem::size_of::<Slot<T>>()
+}
+
+struct MpmcRing<T> {
+    segment: Box<dyn Segment>,
+    header: *const RingBufferHeader,
+    members: ConsumerTable,
+    slots: *const Slot<T>,
+    mask: u64,
+    wait: Arc<dyn WaitStrategy>,
+    _phantom: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for MpmcRing<T> {}
+unsafe impl<T: Send> Sync for MpmcRing<T> {}
+
+impl<T: ShmSafe> MpmcRing<T> {
+    fn create(name: &str, capacity: usize, max_members: usize) -> Result<Self, RbufError> {
+        let capacity = capacity.max(1).next_power_of_two();
+        let segment = Backing::Shm.create(name, segment_size::<T>(capacity, max_members))?;
+
+        unsafe {
+            let header = RingBufferHeader::initialize(
+                segment.as_ptr(),
+                RingBufferHeader::new(
+                    RingKind::Mpmc,
+                    mem::size_of::<T>(),
+                    mem::align_of::<T>(),
+                    capacity,
+                )
+                .with_max_consumers(max_members)
+                .with_schema(directory::type_hash::<T>()),
+            );
+            // Free member entries are all zeroes
+            std::ptr::write_bytes(
+                segment.as_ptr().add(registry::table_offset()),
+                0,
+                registry::table_size(max_members),
+            );
+            let slots = segment.as_ptr().add(slots_offset::<T>(max_members)) as *mut Slot<T>;
+            for i in 0..capacity {
+                (*slots.add(i)).seq = AtomicU64::new(i as u64);
+            }
+            header.publish();
+            directory::register(name, header);
+        }
+
+        Ok(Self::from_segment(segment))
+    }
+
+    fn open(name: &str) -> Result<Self, RbufError> {
+        let segment = Backing::Shm.open(name)?;
+
+        let header = RingBufferHeader::validate(
+            segment.as_ptr(),
+            segment.len(),
+            RingKind::Mpmc,
+            mem::size_of::<T>(),
+            mem::align_of::<T>(),
+        )?;
+        header.check_schema(directory::type_hash::<T>())?;
+        let expected = segment_size::<T>(header.capacity(), header.max_consumers());
+        if segment.len() < expected {
+            return Err(RbufError::SizeMismatch { expected, actual: segment.len() });
+        }
+
+        Ok(Self::from_segment(segment))
+    }
+
+    fn from_segment(segment: Box<dyn Segment>) -> Self {
+        let header = segment.as_ptr() as *const RingBufferHeader;
+        let max_members = unsafe { (*header).max_consumers() };
+        let members = unsafe { ConsumerTable::new(segment.as_ptr(), max_members) };
+        let offset = slots_offset::<T>(max_members);
+        let slots = unsafe { segment.as_ptr().add(offset) } as *const Slot<T>;
+        let mask = unsafe { (*header).capacity() } as u64 - 1;
+        unsafe { (*header).attached.fetch_add(1, Ordering::AcqRel) };
+        event!(debug, ring = segment.name(), kind = "mpmc", capacity = mask + 1, "mapped ring");
+        let wait = Arc::new(Blocking);
+        Self { segment, header, members, slots, mask, wait, _phantom: PhantomData }
+    }
+
+    fn header(&self) -> &RingBufferHeader {
+        unsafe { &*self.header }
+    }
+
+    fn slot(&self, pos: u64) -> &Slot<T> {
+        unsafe { &*self.slots.add((pos & self.mask) as usize) }
+    }
+
+    // Claim the slot at `tail` and write `item` into it
+    fn push(&self, item: T) -> Result<(), PushError<T>> {
+        let header = self.header();
+        let seq = |pos| &self.slot(pos).seq;
+        // Not free while it still holds the item from one lap ago
+        let Some(pos) = slots::claim_turn(&*header.tail, seq, 0, |_| {}) else {
+            return Err(PushError::new(RbufError::Full, item));
+        };
+
+        let slot = self.slot(pos);
+        unsafe { (*slot.value.get()).write(item) };
+        slots::hand_over(&slot.seq, pos.wrapping_add(1));
+        header.data_ready.notify();
+        Ok(())
+    }
+
+    // Claim the slot at `head` and take its item
+    fn pop(&self) -> Result<T, RbufError> {
+        let header = self.header();
+        let seq = |pos| &self.slot(pos).seq;
+        let pos = slots::claim_turn(&*header.head, seq, 1, |_| {}).ok_or(RbufError::Empty)?;
+
+        let slot = self.slot(pos);
+        let item = unsafe { (*slot.value.get()).assume_init_read() };
+        slots::hand_over(&slot.seq, pos.wrapping_add(header.capacity() as u64));
+        header.space_ready.notify();
+        Ok(item)
+    }
+}
+
+impl<T> Drop for MpmcRing<T> {
+    fn drop(&mut self) {
+        unsafe { (*self.header).detach() };
+        event!(debug, ring = self.segment.name(), kind = "mpmc", "detached from ring");
+    }
+}
+
+// Pop every item as raw bytes, for tools that don't know `T`. Safe to run
+// alongside live producers and consumers.
+pub(crate) fn drain_raw(
+    base: *const u8,
+    len: usize,
+    header: &RingBufferHeader,
+    f: &mut dyn FnMut(&[u8]),
+) -> Result<usize, RbufError> {
+    // Mirror the `#[repr(C)]` layout of `Slot<T>`
+    let align_up = |n: usize, align: usize| (n + align - 1) & !(align - 1);
+    let slot_align = header.elem_align().max(mem::align_of::<AtomicU64>());
+    let value_offset = align_up(mem::size_of::<AtomicU64>(), header.elem_align());
+    let stride = align_up(value_offset + header.elem_size(), slot_align);
+    let table_end = registry::table_offset() + registry::table_size(header.max_consumers());
+    let slots_offset = align_up(table_end, slot_align);
+    let expected = slots_offset + header.capacity() * stride;
+    if len < expected {
+        return Err(RbufError::SizeMismatch { expected, actual: len });
+    }
+    let mask = header.capacity() as u64 - 1;
+
+    let mut item = vec![0; header.elem_size()];
+    let mut drained = 0;
+    let slot = |pos: u64| unsafe { base.add(slots_offset + (pos & mask) as usize * stride) };
+    let seq = |pos| unsafe { &*(slot(pos) as *const AtomicU64) };
+    loop {
+        let Some(pos) = slots::claim_turn(&*header.head, seq, 1, |_| {}) else {
+            return Ok(drained);
+        };
+
+        let value = unsafe { slot(pos).add(value_offset) };
+        unsafe { std::ptr::copy_nonoverlapping(value, item.as_mut_ptr(), header.elem_size()) };
+        slots::hand_over(seq(pos), pos.wrapping_add(header.capacity() as u64));
+        header.space_ready.notify();
+        f(&item);
+        drained += 1;
+    }
+}
+
+// --- Producer ---
+
+pub struct Producer<T> {
+    ring: MpmcRing<T>,
+}
+
+impl<T: ShmSafe> Producer<T> {
+    // Create the queue with room for `capacity` items, rounded up to a power of two
+    pub fn create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        Ok(Self { ring: MpmcRing::create(name, capacity, 0)? })
+    }
+
+    // `create`, with room for `max_members` in the consumer group
+    pub fn create_with_group(
+        name: &str,
+        capacity: usize,
+        max_members: usize,
+    ) -> Result<Self, RbufError> {
+        Ok(Self { ring: MpmcRing::create(name, capacity, max_members)? })
+    }
+
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        Ok(Self { ring: MpmcRing::open(name)? })
+    }
+
+    pub fn name(&self) -> &str {
+        self.ring.segment.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.ring.header().attached.load(Ordering::Acquire) as usize
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.ring.segment.set_owner(unlink);
+    }
+
+    // How `push_blocking` waits for space
+    pub fn set_wait_strategy(&mut self, strategy: Arc<dyn WaitStrategy>) {
+        self.ring.wait = strategy;
+    }
+
+    pub fn push(&self, item: T) -> Result<(), PushError<T>> {
+        self.ring.push(item)
+    }
+
+    // Push, waiting as the wait strategy says until a consumer frees a slot
+    pub fn push_blocking(&self, item: T) {
+        let mut item = Some(item);
+        let space_ready = &self.ring.header().space_ready;
+        wait::wait_until(&*self.ring.wait, space_ready, None, || {
+            match self.ring.push(item.take()?) {
+                Ok(()) => Some(()),
+                Err(e) => {
+                    item = Some(e.into_inner());
+                    None
+                }
+            }
+        });
+    }
+}
+
+// --- Consumer ---
+
+// Unlike the single-consumer ring, `pop` takes `&self`: any number of
+// consumers may share the queue, including threads sharing one handle.
+pub struct Consumer<T> {
+    ring: MpmcRing<T>,
+}
+
+impl<T: ShmSafe> Consumer<T> {
+    // Create the queue with room for `capacity` items, rounded up to a power of two
+    pub fn create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        Ok(Self { ring: MpmcRing::create(name, capacity, 0)? })
+    }
+
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        Ok(Self { ring: MpmcRing::open(name)? })
+    }
+
+    pub fn name(&self) -> &str {
+        self.ring.segment.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.ring.header().attached.load(Ordering::Acquire) as usize
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.ring.segment.set_owner(unlink);
+    }
+
+    // How `pop_blocking` waits for items
+    pub fn set_wait_strategy(&mut self, strategy: Arc<dyn WaitStrategy>) {
+        self.ring.wait = strategy;
+    }
+
+    pub fn pop(&self) -> Result<T, RbufError> {
+        self.ring.pop()
+    }
+
+    // Pop, waiting as the wait strategy says until a producer publishes
+    pub fn pop_blocking(&self) -> T {
+        let data_ready = &self.ring.header().data_ready;
+        wait::wait_until(&*self.ring.wait, data_ready, None, || self.ring.pop().ok())
+            .expect("waiting without a deadline never times out")
+    }
+}
+
+// --- Consumer groups ---
+
+// Members of the consumer group share `head` like plain consumers, so each
+// item goes to exactly one of them. Unlike a plain pop, taking an item only
+// claims it: its slot stays published, with the claim recorded in the
+// member's table entry, until the member acks it. Members that die holding
+// a claim have it stolen by the next member to pop, which gets the item
+// again, so nothing taken by a crashed worker is lost (but it may be
+// processed twice).
+pub struct GroupMember<T> {
+    ring: MpmcRing<T>,
+    entry: usize,
+}
+
+impl<T: ShmSafe> GroupMember<T> {
+    // Create the queue with room for `capacity` items and `max_members`,
+    // and join its group
+    pub fn create(name: &str, capacity: usize, max_members: usize) -> Result<Self, RbufError> {
+        Self::join_ring(MpmcRing::create(name, capacity, max_members)?)
+    }
+
+    // Join the group of an existing queue. Fails with `ConsumerTableFull`
+    // once `max_members` are in it.
+    pub fn join(name: &str) -> Result<Self, RbufError> {
+        Self::join_ring(MpmcRing::open(name)?)
+    }
+
+    fn join_ring(ring: MpmcRing<T>) -> Result<Self, RbufError> {
+        // Make room: members that died holding nothing have nothing to steal
+        for (index, entry) in ring.members.entries().iter().enumerate() {
+            let pid = entry.pid.load(Ordering::Relaxed);
+            if entry.is_active() && !peer::process_alive(pid) {
+                ring.members.release_dead(index, pid);
+            }
+        }
+        let head = &ring.header().head;
+        let entry = ring.members.register(|| head.load(Ordering::Acquire))?;
+        Ok(Self { ring, entry })
+    }
+
+    pub fn name(&self) -> &str {
+        self.ring.segment.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.ring.header().attached.load(Ordering::Acquire) as usize
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.ring.segment.set_owner(unlink);
+    }
+
+    // How `pop_blocking` waits for items
+    pub fn set_wait_strategy(&mut self, strategy: Arc<dyn WaitStrategy>) {
+        self.ring.wait = strategy;
+    }
+
+    // Every member of the group, with how far behind `tail` each one's
+    // latest claim is
+    pub fn members(&self) -> Vec<Registration> {
+        self.ring.members.registrations(self.ring.header().tail.load(Ordering::Acquire))
+    }
+
+    fn entry(&self) -> &ConsumerEntry {
+        self.ring.members.entry(self.entry)
+    }
+
+    // Claim the next item: the one this member took and didn't ack, then one
+    // stolen from a dead member, then the next one in the queue
+    pub fn pop(&mut self) -> Result<PopGuard<'_, T>, RbufError> {
+        let pos = self.next()?;
+        Ok(PopGuard { member: self, pos })
+    }
+
+    // Pop, waiting as the wait strategy says until a producer publishes
+    pub fn pop_blocking(&mut self) -> PopGuard<'_, T> {
+        let data_ready = &self.ring.header().data_ready;
+        let pos = wait::wait_until(&*self.ring.wait, data_ready, None, || self.next().ok())
+            .expect("waiting without a deadline never times out");
+        PopGuard { member: self, pos }
+    }
+
+    fn next(&self) -> Result<u64, RbufError> {
+        self.entry().beat();
+        if let Some(pos) = self.held() {
+            return Ok(pos);
+        }
+        if let Some(pos) = self.steal() {
+            return Ok(pos);
+        }
+        self.claim_next()
+    }
+
+    // Whether the slot of `pos` still holds the item claimed there
+    fn published(&self, pos: u64) -> bool {
+        self.ring.slot(pos).seq.load(Ordering::Acquire) == pos.wrapping_add(1)
+    }
+
+    fn held(&self) -> Option<u64> {
+        let pos = self.entry().claim.load(Ordering::Acquire).checked_sub(1)?;
+        if self.published(pos) {
+            return Some(pos);
+        }
+        // Acked before we could clear the claim
+        self.entry().claim.store(0, Ordering::Release);
+        None
+    }
+
+    // Take over the claim of a member whose process is gone
+    fn steal(&self) -> Option<u64> {
+        let own = self.entry();
+        for (index, entry) in self.ring.members.entries().iter().enumerate() {
+            let claim = entry.claim.load(Ordering::Acquire);
+            let pid = entry.pid.load(Ordering::Relaxed);
+            if index == self.entry || claim == 0 || peer::process_alive(pid) {
+                continue;
+            }
+            // Ours before it stops being theirs, so it's never unclaimed
+            own.claim.store(claim, Ordering::Release);
+            let stolen =
+                entry.claim.compare_exchange(claim, 0, Ordering::AcqRel, Ordering::Relaxed);
+            if stolen.is_err() {
+                own.claim.store(0, Ordering::Release);
+                continue;
+            }
+            self.ring.members.release_dead(index, pid);
+            if let Some(pos) = self.held() {
+                event!(warn, ring = self.name(), pos, dead_pid = pid, "stole claim of dead member");
+                return Some(pos);
+            }
+        }
+        None
+    }
+
+    // The plain MPMC pop, except that the item is left in its slot
+    fn claim_next(&self) -> Result<u64, RbufError> {
+        let header = self.ring.header();
+        let own = self.entry();
+        let seq = |pos| &self.ring.slot(pos).seq;
+        // Claimed before it's taken, so a crash in between can't lose it
+        let on_turn = |pos: u64| own.claim.store(pos.wrapping_add(1), Ordering::Release);
+        let Some(pos) = slots::claim_turn(&*header.head, seq, 1, on_turn) else {
+            own.claim.store(0, Ordering::Release);
+            return Err(RbufError::Empty);
+        };
+        own.cursor.store(pos.wrapping_add(1), Ordering::Release);
+        Ok(pos)
+    }
+}
+
+impl<T> Drop for GroupMember<T> {
+    fn drop(&mut self) {
+        let entry = self.ring.members.entry(self.entry);
+        if entry.claim.load(Ordering::Acquire) == 0 {
+            self.ring.members.unregister(self.entry);
+        } else {
+            // Leave the unacked item for the others to steal
+            entry.pid.store(0, Ordering::Release);
+        }
+    }
+}
+
+// An item claimed by a `GroupMember`, read in place. `ack` frees its slot;
+// dropping the guard without acking keeps the claim, and the member's next
+// pop returns the same item.
+pub struct PopGuard<'a, T> {
+    member: &'a GroupMember<T>,
+    pos: u64,
+}
+
+impl<T: ShmSafe> PopGuard<'_, T> {
+    // The item's position in the queue, which stays the same when it is
+    // delivered again: a key for spotting duplicates downstream
+    pub fn seq(&self) -> u64 {
+        self.pos
+    }
+
+    // Consume the item and hand its slot back to producers
+    pub fn ack(self) {
+        let ring = &self.member.ring;
+        let slot = ring.slot(self.pos);
+        drop(unsafe { (*slot.value.get()).assume_init_read() });
+        let lap = self.pos.wrapping_add(ring.header().capacity() as u64);
+        // Only fails if a member that stole it from us acked it already
+        let _ = slot.seq.compare_exchange(
+            self.pos.wrapping_add(1),
+            lap,
+            Ordering::Release,
+            Ordering::Relaxed,
+        );
+        self.member.entry().claim.store(0, Ordering::Release);
+        ring.header().space_ready.notify();
+    }
+}
+
+impl<T: ShmSafe> Deref for PopGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        let slot = self.member.ring.slot(self.pos);
+        unsafe { (*slot.value.get()).assume_init_ref() }
+    }
+}
@@ -0,0 +1,215 @@
+// mux.rs
+//
+// Many logical channels over one byte ring, for when there are too many
+// minor streams to give each a segment of its own. Every record carries the
+// channel it was pushed on:
+//
+//     [ channel id: u32 | payload ]
+//
+// and the consumer hands each payload to whichever handler is registered
+// for its channel. Channels share the ring's space and its order: a channel
+// nobody drains holds up all the others, so the consumer should register a
+// handler for every channel it expects, or a catch-all with `on_other`.
+//
+//     let mut mux = MuxConsumer::create("quotes", 1 << 20)?;
+//     mux.on(7, |payload| apply(payload));
+//
+//     let producer = MuxProducer::open("quotes")?;
+//     producer.channel(7).push_bytes(b"ACME 101.5")?;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::bytes::{Reader, Writer};
+use crate::error::RbufError;
+
+const CHANNEL_SIZE: usize = 4;
+
+fn frame(channel: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(CHANNEL_SIZE + payload.len());
+    frame.extend_from_slice(&channel.to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// --- Producer ---
+
+pub struct MuxProducer {
+    writer: Writer,
+}
+
+impl MuxProducer {
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        Ok(Self { writer: Writer::open(name)? })
+    }
+
+    pub fn name(&self) -> &str {
+        self.writer.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.writer.attached()
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.writer.set_unlink_on_drop(unlink);
+    }
+
+    // The largest payload that can ever be pushed on a channel
+    pub fn max_message_size(&self) -> usize {
+        self.writer.max_message_size().saturating_sub(CHANNEL_SIZE)
+    }
+
+    // A handle for pushing on channel `id`. Channels need no setting up;
+    // any id can be pushed on at any time.
+    pub fn channel(&self, id: u32) -> MuxChannel<'_> {
+        MuxChannel { producer: self, id }
+    }
+
+    // Like `Writer::push_bytes`, on channel `channel`
+    pub fn push_bytes(&self, channel: u32, payload: &[u8]) -> Result<(), RbufError> {
+        self.writer.push_bytes(&frame(channel, payload))
+    }
+
+    // Push, sleeping until the consumer frees enough space if the ring is full
+    pub fn push_bytes_blocking(&self, channel: u32, payload: &[u8]) -> Result<(), RbufError> {
+        self.writer.push_bytes_blocking(&frame(channel, payload))
+    }
+}
+
+// One channel of a `MuxProducer`
+#[derive(Clone, Copy)]
+pub struct MuxChannel<'a> {
+    producer: &'a MuxProducer,
+    id: u32,
+}
+
+impl MuxChannel<'_> {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn push_bytes(&self, payload: &[u8]) -> Result<(), RbufError> {
+        self.producer.push_bytes(self.id, payload)
+    }
+
+    pub fn push_bytes_blocking(&self, payload: &[u8]) -> Result<(), RbufError> {
+        self.producer.push_bytes_blocking(self.id, payload)
+    }
+}
+
+// --- Consumer ---
+
+type Handler = Box<dyn FnMut(&[u8]) + Send>;
+type OtherHandler = Box<dyn FnMut(u32, &[u8]) + Send>;
+
+pub struct MuxConsumer {
+    reader: Reader,
+    handlers: HashMap<u32, Handler>,
+    // For channels without a handler of their own
+    other: Option<OtherHandler>,
+    // Messages that came on a channel nobody handles
+    unhandled: u64,
+    // Reused for every message
+    buf: Vec<u8>,
+}
+
+impl MuxConsumer {
+    // Create the segment with room for `capacity` bytes of records, rounded
+    // up to a power of two
+    pub fn create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        Ok(Self::from_reader(Reader::create(name, capacity)?))
+    }
+
+    // Demultiplex a byte ring created some other way, e.g. with a codec or
+    // encryption
+    pub fn from_reader(reader: Reader) -> Self {
+        Self { reader, handlers: HashMap::new(), other: None, unhandled: 0, buf: Vec::new() }
+    }
+
+    pub fn name(&self) -> &str {
+        self.reader.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.reader.attached()
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.reader.set_unlink_on_drop(unlink);
+    }
+
+    // Hand every payload on channel `id` to `handler`, in place of whatever
+    // handled it before
+    pub fn on(&mut self, id: u32, handler: impl FnMut(&[u8]) + Send + 'static) {
+        self.handlers.insert(id, Box::new(handler));
+    }
+
+    // Stop handling channel `id`; returns whether it had a handler
+    pub fn remove(&mut self, id: u32) -> bool {
+        self.handlers.remove(&id).is_some()
+    }
+
+    // Hand payloads on channels without a handler to `handler`, with their
+    // channel id. Without one they are dropped and counted in `unhandled`.
+    pub fn on_other(&mut self, handler: impl FnMut(u32, &[u8]) + Send + 'static) {
+        self.other = Some(Box::new(handler));
+    }
+
+    // Messages dropped so far for coming on a channel nothing handles
+    pub fn unhandled(&self) -> u64 {
+        self.unhandled
+    }
+
+    // Pop the next message and hand it to its channel's handler, returning
+    // the channel. Fails with `Empty` if there's nothing to pop, and with
+    // `Decode` if the record is too short to name a channel; it's consumed
+    // either way.
+    pub fn dispatch(&mut self) -> Result<u32, RbufError> {
+        self.reader.pop_bytes(&mut self.buf)?;
+        self.deliver()
+    }
+
+    // Dispatch until the ring is empty, returning how many messages it took.
+    // Stops at the first message that fails to decode.
+    pub fn dispatch_all(&mut self) -> Result<usize, RbufError> {
+        let mut dispatched = 0;
+        loop {
+            match self.dispatch() {
+                Ok(_) => dispatched += 1,
+                Err(RbufError::Empty) => return Ok(dispatched),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // `dispatch`, sleeping until a producer pushes if the ring is empty,
+    // until `timeout` if there is one. Fails with `Timeout` if nothing came.
+    pub fn dispatch_blocking(&mut self, timeout: Option<Duration>) -> Result<u32, RbufError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.reader.pop_bytes_until(&mut self.buf, deadline)?;
+        self.deliver()
+    }
+
+    fn deliver(&mut self) -> Result<u32, RbufError> {
+        let Some(channel) = self.buf.get(..CHANNEL_SIZE) else {
+            return Err(RbufError::Decode("message shorter than its channel id".to_string()));
+        };
+        let channel = u32::from_le_bytes(channel.try_into().unwrap());
+        let payload = &self.buf[CHANNEL_SIZE..];
+        match (self.handlers.get_mut(&channel), &mut self.other) {
+            (Some(handler), _) => handler(payload),
+            (None, Some(other)) => other(channel, payload),
+            (None, None) => {
+                self.unhandled += 1;
+                event!(trace, ring = self.reader.name(), channel, "dropped unhandled message");
+            }
+        }
+        Ok(channel)
+    }
+}
@@ -0,0 +1,81 @@
+// namespace.rs
+//
+// Ring names are global to the host, so rings of different applications can
+// collide. A `Namespace` prefixes every name it hands out, and can find and
+// clean up the rings under its prefix:
+//
+//     let ns = Namespace::new("bear_cave.quotes");
+//     let consumer = ns.config("ticks").capacity(4096).consumer::<Tick>()?;
+//     let writer = bytes::Writer::open(&ns.name("orders"))?;
+//
+// Listing only sees shm segments (/dev/shm), so rings backed by files or
+// memfds are neither listed nor purged.
+use crate::config::RingConfig;
+#[cfg(target_os = "linux")]
+use crate::error::RbufError;
+#[cfg(target_os = "linux")]
+use crate::gc;
+
+// Where Linux keeps POSIX shared memory objects
+#[cfg(target_os = "linux")]
+const SHM_DIR: &str = "/dev/shm";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Namespace {
+    prefix: String,
+}
+
+impl Namespace {
+    // Rings are named `<prefix>.<ring>`. Dots in the prefix nest namespaces,
+    // e.g. `bear_cave.<app>`.
+    pub fn new(prefix: &str) -> Self {
+        Self { prefix: prefix.trim_end_matches('.').to_string() }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    // The namespace `<prefix>.<child>`
+    pub fn child(&self, child: &str) -> Self {
+        Self::new(&self.name(child))
+    }
+
+    // The OS id of the ring `ring` in this namespace
+    pub fn name(&self, ring: &str) -> String {
+        format!("{}.{}", self.prefix, ring)
+    }
+
+    // A config for the ring `ring` in this namespace
+    pub fn config(&self, ring: &str) -> RingConfig {
+        RingConfig::new(&self.name(ring))
+    }
+
+    // The rings in this namespace (and any nested in it) by their name
+    // within it, sorted
+    #[cfg(target_os = "linux")]
+    pub fn list(&self) -> Result<Vec<String>, RbufError> {
+        let start = format!("{}.", self.prefix);
+        let mut rings = Vec::new();
+        for entry in std::fs::read_dir(SHM_DIR).map_err(RbufError::Backend)? {
+            let entry = entry.map_err(RbufError::Backend)?;
+            if let Some(ring) = entry.file_name().to_str().and_then(|id| id.strip_prefix(&start)) {
+                rings.push(ring.to_string());
+            }
+        }
+        rings.sort();
+        // The slots of a split broadcast ring aren't a ring of their own
+        let all = rings.clone();
+        rings.retain(|ring| {
+            ring.strip_suffix(".data").is_none_or(|base| all.iter().all(|other| other != base))
+        });
+        Ok(rings)
+    }
+
+    // Unlink every ring in the namespace that nobody is using any more, and
+    // return their names; see `gc::collect`
+    #[cfg(target_os = "linux")]
+    pub fn purge(&self) -> Result<Vec<String>, RbufError> {
+        gc::collect(self)
+    }
+}
@@ -0,0 +1,391 @@
+// notify.rs
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// Without a native primitive, sleepers re-check this often
+const POLL_INTERVAL: Duration = Duration::from_micros(500);
+
+// Most posts one wake makes on a semaphore. `waiters` is in the segment, and
+// a corrupt count mustn't make us post billions of times.
+#[cfg(any(unix, windows))]
+const MAX_POSTS: i32 = 1 << 12;
+
+// How sleepers on a ring's wait queues are woken. Chosen when the ring is
+// created and kept in the segment, so every process attached agrees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Notifier {
+    // A futex on the queue itself. Linux only; elsewhere it polls.
+    Futex = 1,
+    // A named semaphore per queue, for targets without futexes. On Unix a
+    // POSIX one, which lives as long as some handle is attached to its ring,
+    // so one that died attached leaves it behind; on Windows a kernel object
+    // that goes away with the last process using it.
+    Semaphore = 2,
+    // Sleep and re-check every half millisecond; works anywhere
+    Poll = 3,
+}
+
+impl Notifier {
+    pub(crate) fn from_u32(notifier: u32) -> Option<Self> {
+        match notifier {
+            1 => Some(Notifier::Futex),
+            2 => Some(Notifier::Semaphore),
+            3 => Some(Notifier::Poll),
+            _ => None,
+        }
+    }
+}
+
+// The best the target has: futexes on Linux, semaphores on other Unix and
+// Windows
+impl Default for Notifier {
+    fn default() -> Self {
+        if cfg!(target_os = "linux") {
+            Notifier::Futex
+        } else if cfg!(any(unix, windows)) {
+            Notifier::Semaphore
+        } else {
+            Notifier::Poll
+        }
+    }
+}
+
+// A cross-process wait queue that lives inside the shared segment.
+//
+// Waiters register themselves, snapshot `seq`, re-check their condition and
+// then sleep until `seq` changes. Notifiers bump `seq` after making their
+// change visible and only pay for a syscall when someone is registered.
+#[repr(C)]
+pub(crate) struct WaitQueue {
+    seq: AtomicU32,
+    waiters: AtomicU32,
+    notifier: u32,
+    // Names the queue's semaphore, picked by whoever needs it first (0 until
+    // then)
+    key: AtomicU64,
+}
+
+impl WaitQueue {
+    // A queue needing nothing outside the segment: a futex on Linux, polling
+    // elsewhere
+    pub(crate) const fn new() -> Self {
+        Self::with_notifier(Notifier::Futex)
+    }
+
+    pub(crate) const fn with_notifier(notifier: Notifier) -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+            waiters: AtomicU32::new(0),
+            notifier: notifier as u32,
+            key: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn notifier(&self) -> Notifier {
+        Notifier::from_u32(self.notifier).unwrap_or(Notifier::Poll)
+    }
+
+    // Register as a waiter. The caller must re-check its condition after this
+    // and then call either `wait` or `cancel_wait`.
+    pub(crate) fn prepare_wait(&self) -> u32 {
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        self.seq.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn cancel_wait(&self) {
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // Sleep until notified (or spuriously woken) and deregister
+    pub(crate) fn wait(&self, seq: u32) {
+        self.sleep(seq, None);
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // Like `wait`, but gives up after `timeout`
+    pub(crate) fn wait_timeout(&self, seq: u32, timeout: Duration) {
+        self.sleep(seq, Some(timeout));
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn notify(&self) {
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        let waiters = self.waiters.load(Ordering::SeqCst);
+        if waiters > 0 {
+            self.wake(waiters.min(i32::MAX as u32) as i32);
+        }
+    }
+
+    // Like `notify`, but wakes at most one sleeper. The others stay asleep
+    // even though `seq` moved, until the next notify or their timeout.
+    pub(crate) fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            self.wake(1);
+        }
+    }
+
+    // Remove whatever the queue created outside the segment. Called when the
+    // last handle on the ring detaches; anyone attaching later creates it
+    // afresh.
+    pub(crate) fn release(&self) {
+        let key = self.key.load(Ordering::Acquire);
+        if self.notifier() == Notifier::Semaphore && key != 0 {
+            #[cfg(any(unix, windows))]
+            sem::unlink(key);
+        }
+    }
+
+    fn sleep(&self, seq: u32, timeout: Option<Duration>) {
+        match self.notifier() {
+            Notifier::Futex => sys::wait(&self.seq, seq, timeout),
+            #[cfg(any(unix, windows))]
+            Notifier::Semaphore => {
+                let pending = || self.seq.load(Ordering::SeqCst) == seq;
+                if !sem::wait(self.key(), timeout, pending) {
+                    poll(&self.seq, seq, timeout);
+                }
+            }
+            _ => poll(&self.seq, seq, timeout),
+        }
+    }
+
+    // Sleepers that already went can leave surplus posts on a semaphore,
+    // which only make later waits return early
+    fn wake(&self, count: i32) {
+        match self.notifier() {
+            Notifier::Futex => sys::wake(&self.seq, count),
+            #[cfg(any(unix, windows))]
+            Notifier::Semaphore => sem::post(self.key(), count.min(MAX_POSTS) as u32),
+            _ => {}
+        }
+    }
+
+    fn key(&self) -> u64 {
+        let key = self.key.load(Ordering::Acquire);
+        if key != 0 {
+            return key;
+        }
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        hasher.write_usize(self as *const Self as usize);
+        let fresh = hasher.finish() | 1;
+        match self.key.compare_exchange(0, fresh, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => fresh,
+            Err(key) => key,
+        }
+    }
+}
+
+// Sleep a little if `word` still holds `expected`
+fn poll(word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    if word.load(Ordering::SeqCst) == expected {
+        thread::sleep(timeout.map_or(POLL_INTERVAL, |t| t.min(POLL_INTERVAL)));
+    }
+}
+
+// Sleep while `word` holds `expected`, for primitives that keep their own
+// state in the word rather than using a `WaitQueue`
+pub(crate) fn wait_word(word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    sys::wait(word, expected, timeout);
+}
+
+// Wake up to `count` sleepers in `wait_word`
+pub(crate) fn wake_word(word: &AtomicU32, count: i32) {
+    sys::wake(word, count);
+}
+
+// --- Platform primitives ---
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ptr;
+    use std::sync::atomic::AtomicU32;
+    use std::time::Duration;
+
+    // No FUTEX_PRIVATE_FLAG: the word is shared between processes
+    pub(super) fn wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+        let ts = timeout.map(|t| libc::timespec {
+            tv_sec: t.as_secs() as libc::time_t,
+            tv_nsec: t.subsec_nanos() as libc::c_long,
+        });
+        let ts_ptr = ts.as_ref().map_or(ptr::null(), |t| t as *const libc::timespec);
+        unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                word.as_ptr(),
+                libc::FUTEX_WAIT,
+                expected,
+                ts_ptr,
+                ptr::null::<u32>(),
+                0,
+            );
+        }
+    }
+
+    pub(super) fn wake(word: &AtomicU32, count: i32) {
+        unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                word.as_ptr(),
+                libc::FUTEX_WAKE,
+                count,
+                ptr::null::<libc::timespec>(),
+                ptr::null::<u32>(),
+                0,
+            );
+        }
+    }
+}
+
+// Without a native primitive we fall back to short sleeps and re-checks
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::sync::atomic::AtomicU32;
+    use std::time::Duration;
+
+    pub(super) fn wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+        super::poll(word, expected, timeout);
+    }
+
+    pub(super) fn wake(_word: &AtomicU32, _count: i32) {}
+}
+
+// Named semaphores, opened for each use rather than kept, so a process
+// never holds on to one the last detach has since unlinked
+#[cfg(unix)]
+mod sem {
+    use std::ffi::CString;
+    use std::time::Duration;
+
+    fn name(key: u64) -> CString {
+        CString::new(format!("/rbuf-{:016x}", key)).expect("no NUL in a hex name")
+    }
+
+    fn open(key: u64) -> Option<*mut libc::sem_t> {
+        let name = name(key);
+        let mode = 0o666 as libc::c_uint;
+        let sem = unsafe { libc::sem_open(name.as_ptr(), libc::O_CREAT, mode, 0 as libc::c_uint) };
+        (sem != libc::SEM_FAILED).then_some(sem)
+    }
+
+    // Sleep until posted or `timeout` passes, if `pending` still holds once
+    // the semaphore is open: posts from then on are kept, and whoever posts
+    // changed what `pending` checks first. False if the semaphore can't be
+    // opened, so the caller has to fall back to something else.
+    pub(super) fn wait(
+        key: u64,
+        timeout: Option<Duration>,
+        pending: impl FnOnce() -> bool,
+    ) -> bool {
+        let Some(sem) = open(key) else {
+            return false;
+        };
+        match timeout {
+            _ if !pending() => {}
+            None => unsafe {
+                libc::sem_wait(sem);
+            },
+            Some(timeout) => timed_wait(sem, timeout),
+        }
+        unsafe { libc::sem_close(sem) };
+        true
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    fn timed_wait(sem: *mut libc::sem_t, timeout: Duration) {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        // sem_timedwait takes a deadline on the realtime clock
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let deadline = now + timeout;
+        let ts = libc::timespec {
+            tv_sec: deadline.as_secs() as libc::time_t,
+            tv_nsec: deadline.subsec_nanos() as libc::c_long,
+        };
+        unsafe { libc::sem_timedwait(sem, &ts) };
+    }
+
+    // No sem_timedwait on Apple targets
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    fn timed_wait(sem: *mut libc::sem_t, timeout: Duration) {
+        let deadline = std::time::Instant::now() + timeout;
+        while unsafe { libc::sem_trywait(sem) } != 0 && std::time::Instant::now() < deadline {
+            std::thread::sleep(super::POLL_INTERVAL);
+        }
+    }
+
+    pub(super) fn post(key: u64, count: u32) {
+        let Some(sem) = open(key) else {
+            return;
+        };
+        for _ in 0..count {
+            unsafe { libc::sem_post(sem) };
+        }
+        unsafe { libc::sem_close(sem) };
+    }
+
+    pub(super) fn unlink(key: u64) {
+        unsafe { libc::sem_unlink(name(key).as_ptr()) };
+    }
+}
+
+// Semaphore objects, opened (created by whoever comes first) for each use
+// like the POSIX ones. They go away with their last handle, so a post while
+// nobody has one open is lost; waiters open theirs before checking.
+#[cfg(windows)]
+mod sem {
+    use std::ptr;
+    use std::time::Duration;
+
+    use windows::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows::Win32::System::Threading::{
+        CreateSemaphoreW, ReleaseSemaphore, WaitForSingleObject,
+    };
+
+    const INFINITE: u32 = u32::MAX;
+
+    fn open(key: u64) -> Option<HANDLE> {
+        let name = format!("Local\\rbuf-{:016x}", key);
+        let sem = unsafe { CreateSemaphoreW(ptr::null(), 0, i32::MAX, name.as_str()) };
+        (!sem.is_invalid()).then_some(sem)
+    }
+
+    // See the Unix `wait`
+    pub(super) fn wait(
+        key: u64,
+        timeout: Option<Duration>,
+        pending: impl FnOnce() -> bool,
+    ) -> bool {
+        let Some(sem) = open(key) else {
+            return false;
+        };
+        if pending() {
+            // Rounded up, so a short timeout doesn't become a busy loop
+            let millis = timeout.map_or(INFINITE, |t| {
+                t.as_nanos().div_ceil(1_000_000).min(INFINITE as u128 - 1) as u32
+            });
+            unsafe { WaitForSingleObject(sem, millis) };
+        }
+        unsafe { CloseHandle(sem) };
+        true
+    }
+
+    pub(super) fn post(key: u64, count: u32) {
+        let Some(sem) = open(key) else {
+            return;
+        };
+        let count = count.min(i32::MAX as u32) as i32;
+        unsafe {
+            ReleaseSemaphore(sem, count, ptr::null_mut());
+            CloseHandle(sem);
+        }
+    }
+
+    // Nothing outlives its users
+    pub(super) fn unlink(_key: u64) {}
+}
@@ -0,0 +1,62 @@
+// peer.rs
+//
+// Telling whether the process on the other side of a ring is still there.
+// Every handle records its PID and a heartbeat timestamp in the header when
+// it attaches, and blocking calls refresh the heartbeat while they wait. A
+// PID that no longer exists means the peer crashed without detaching.
+use std::time::{Duration, SystemTime};
+
+// How often blocking calls wake up to refresh their own heartbeat and check
+// on their peer
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+// The last handle of one role to show signs of life
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub pid: u32,
+    pub last_heartbeat: SystemTime,
+    // Whether a process with that PID still exists. PIDs are only meaningful
+    // inside one PID namespace, and may be reused once the peer is gone.
+    pub alive: bool,
+}
+
+// Whether `pid` names a live process. A process we aren't allowed to signal
+// still exists.
+#[cfg(unix)]
+pub(crate) fn process_alive(pid: u32) -> bool {
+    if pid == 0 || pid > i32::MAX as u32 {
+        return false;
+    }
+    if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+        return true;
+    }
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// A process we aren't allowed to query still exists, as above
+#[cfg(windows)]
+pub(crate) fn process_alive(pid: u32) -> bool {
+    use windows::Win32::Foundation::{CloseHandle, GetLastError, ERROR_ACCESS_DENIED, STILL_ACTIVE};
+    use windows::Win32::System::Threading::{
+        GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    if pid == 0 {
+        return false;
+    }
+    let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) };
+    if process.is_invalid() {
+        return unsafe { GetLastError() } == ERROR_ACCESS_DENIED;
+    }
+    // A handle keeps an exited process's entry around, so ask how it's doing
+    let mut code = 0;
+    let queried = unsafe { GetExitCodeProcess(process, &mut code) }.as_bool();
+    unsafe { CloseHandle(process) };
+    !queried || code == STILL_ACTIVE.0 as u32
+}
+
+// No cheap way to ask elsewhere; assume the best
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn process_alive(pid: u32) -> bool {
+    pid != 0
+}
@@ -0,0 +1,772 @@
+// pool.rs
+//
+// A shared-memory pool of fixed-size blocks, for payloads too big to copy
+// through a ring: the frame goes in a block and only a `PoolHandle` (a few
+// words) goes through the ring. Blocks are reference counted across
+// processes and go back on the free list when the last reference is dropped.
+//
+// A pool either has a segment of its own or lives in a `ShmArena`:
+//
+// [ header | pool ]  or  [ arena ... | pool | ... ]
+//
+// where a pool is
+//
+// [ PoolHeader | one BlockMeta per block | blocks ]
+//
+// `PoolHeader` counts every allocation and every block freed, so the
+// difference is the number of blocks in use, and `space_ready` is signalled
+// whenever a block is freed. The free list is a stack threaded through
+// `BlockMeta::next`, with a tag in the upper half of its head that changes
+// on every push and pop so a stale CAS can't succeed.
+//
+// Every handle on the pool takes one of `MAX_HOLDERS` entries in the
+// holder table, recording its PID, and every block has a bit per entry set
+// while that handle holds a reference to it. References carried by a
+// `PoolHandle` on its way through a ring belong to nobody, and are counted
+// in the block's `in_flight` instead. A block whose holders all died and
+// that no handle is on its way to is a leak, and `reclaim` frees it; a pool
+// that runs out of blocks tries that before it reports `Full`. References
+// in handles left unread in a ring are still never given back.
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::arena::ShmArena;
+use crate::directory;
+use crate::error::RbufError;
+use crate::header::{CachePadded, RingBufferHeader, RingKind, CACHE_LINE};
+use crate::notify::WaitQueue;
+use crate::peer;
+use crate::ptr::ShmPtr;
+use crate::segment::{Backing, Segment};
+use crate::shm_safe::ShmSafe;
+
+// Blocks start on a cache line, which is enough for any frame format
+const BLOCK_ALIGN: usize = CACHE_LINE;
+
+// Handles that can be attached to a pool at once, one per bit of
+// `BlockMeta::holders`
+const MAX_HOLDERS: usize = 64;
+
+// The start of a pool, wherever it lives. Only useful to pass around as a
+// `ShmPtr` so other processes can `BufferPool::open_in` it.
+#[repr(C)]
+pub struct PoolHeader {
+    // Index plus one of the top free block in the lower half, tag in the
+    // upper (0 = none free)
+    free: CachePadded<AtomicU64>,
+    allocated: AtomicU64,
+    freed: AtomicU64,
+    block_size: u64,
+    blocks: u64,
+    // Signalled whenever a block is freed
+    space_ready: WaitQueue,
+    // The PID of the process behind each handle on the pool (0 = free)
+    holders: [AtomicU32; MAX_HOLDERS],
+}
+
+unsafe impl ShmSafe for PoolHeader {}
+
+#[repr(C)]
+struct BlockMeta {
+    refs: AtomicU32,
+    // Bumped every time the block is freed, so handles to an earlier use of
+    // it are refused
+    generation: AtomicU32,
+    // Index plus one of the block below this one on the free list (0 = none)
+    next: AtomicU32,
+    // References carried by `PoolHandle`s nobody has taken yet
+    in_flight: AtomicU32,
+    // A bit per entry in the holder table whose handle holds a reference
+    holders: AtomicU64,
+}
+
+// Offsets from the start of the pool
+struct PoolLayout {
+    blocks_offset: usize,
+    size: usize,
+}
+
+impl PoolLayout {
+    fn new(block_size: usize, blocks: usize) -> Self {
+        let meta_end = mem::size_of::<PoolHeader>() + blocks * mem::size_of::<BlockMeta>();
+        let blocks_offset = (meta_end + BLOCK_ALIGN - 1) & !(BLOCK_ALIGN - 1);
+        Self { blocks_offset, size: blocks_offset + blocks * block_size }
+    }
+}
+
+// Refuse pools a `PoolHandle` can't index, and round the block size up
+fn check_shape(block_size: usize, blocks: usize) -> Result<usize, RbufError> {
+    if blocks == 0 || blocks >= u32::MAX as usize || block_size == 0 {
+        return Err(RbufError::IncompatibleLayout(format!(
+            "a pool can't have {} blocks of {} bytes",
+            blocks, block_size
+        )));
+    }
+    Ok((block_size + BLOCK_ALIGN - 1) & !(BLOCK_ALIGN - 1))
+}
+
+// Lay out a pool whose every block is free, lowest index on top
+unsafe fn initialize(pool: *mut u8, block_size: usize, blocks: usize) {
+    pool.cast::<PoolHeader>().write(PoolHeader {
+        free: CachePadded::new(AtomicU64::new(1)),
+        allocated: AtomicU64::new(0),
+        freed: AtomicU64::new(0),
+        block_size: block_size as u64,
+        blocks: blocks as u64,
+        space_ready: WaitQueue::new(),
+        holders: [const { AtomicU32::new(0) }; MAX_HOLDERS],
+    });
+    let meta = pool.add(mem::size_of::<PoolHeader>()) as *mut BlockMeta;
+    for index in 0..blocks {
+        let next = if index + 1 < blocks { index as u32 + 2 } else { 0 };
+        meta.add(index).write(BlockMeta {
+            refs: AtomicU32::new(0),
+            generation: AtomicU32::new(0),
+            next: AtomicU32::new(next),
+            in_flight: AtomicU32::new(0),
+            holders: AtomicU64::new(0),
+        });
+    }
+}
+
+// Blocks in use in a pool segment, for tools that don't hold the pool
+pub(crate) fn in_use_raw(base: *const u8, len: usize) -> Option<usize> {
+    let offset = mem::size_of::<RingBufferHeader>();
+    if len < offset + mem::size_of::<PoolHeader>() {
+        return None;
+    }
+    let pool = unsafe { &*(base.add(offset) as *const PoolHeader) };
+    Some(pool.in_use())
+}
+
+impl PoolHeader {
+    fn in_use(&self) -> usize {
+        let freed = self.freed.load(Ordering::Acquire);
+        self.allocated.load(Ordering::Acquire).wrapping_sub(freed) as usize
+    }
+}
+
+// A reference to a block, to send through a ring. Whoever receives it owns
+// the reference it carries and has to `take` it exactly once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct PoolHandle {
+    index: u32,
+    generation: u32,
+    // Bytes of the block in use
+    len: u64,
+}
+
+unsafe impl ShmSafe for PoolHandle {}
+
+impl PoolHandle {
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+enum Mapping {
+    // A segment of the pool's own, starting with a ring header
+    Own(Box<dyn Segment>),
+    // Somewhere in an arena, which keeps the segment mapped
+    Arena(ShmArena),
+}
+
+struct PoolShared {
+    mapping: Mapping,
+    pool: *const PoolHeader,
+    meta: *const BlockMeta,
+    blocks: *mut u8,
+    block_size: usize,
+    count: u32,
+    // Our entry in the holder table
+    holder: usize,
+    // References this handle holds to each block
+    held: Box<[AtomicU32]>,
+    // Taken to bring a block's holder bit in line with `held`
+    holding: Mutex<()>,
+    // Handed to the segment when the last clone goes, since the clones
+    // can't all borrow it mutably
+    unlink: AtomicBool,
+}
+
+unsafe impl Send for PoolShared {}
+unsafe impl Sync for PoolShared {}
+
+impl PoolShared {
+    // `offset` is where the pool starts in the mapping, already checked to
+    // hold a whole initialized pool. Fails with `ConsumerTableFull` if
+    // `MAX_HOLDERS` handles are attached already.
+    fn new(mapping: Mapping, offset: usize, owner: bool) -> Result<Self, RbufError> {
+        let base = unsafe { mapping.segment().as_ptr().add(offset) };
+        let pool = base as *const PoolHeader;
+        let (block_size, count) =
+            unsafe { ((*pool).block_size as usize, (*pool).blocks as usize) };
+        let layout = PoolLayout::new(block_size, count);
+        let meta = unsafe { base.add(mem::size_of::<PoolHeader>()) } as *const BlockMeta;
+        let blocks = unsafe { base.add(layout.blocks_offset) };
+        let mut shared = Self {
+            mapping,
+            pool,
+            meta,
+            blocks,
+            block_size,
+            count: count as u32,
+            holder: MAX_HOLDERS,
+            held: (0..count).map(|_| AtomicU32::new(0)).collect(),
+            holding: Mutex::new(()),
+            unlink: AtomicBool::new(owner),
+        };
+        if let Some(header) = shared.mapping.header() {
+            header.attached.fetch_add(1, Ordering::AcqRel);
+        }
+        shared.holder = match shared.register() {
+            Some(holder) => holder,
+            // Entries of handles that died may free up once their blocks go
+            None => {
+                shared.reclaim();
+                shared.register().ok_or(RbufError::ConsumerTableFull)?
+            }
+        };
+        event!(
+            debug,
+            ring = shared.mapping.segment().name(),
+            kind = "pool",
+            blocks = count,
+            "mapped pool"
+        );
+        Ok(shared)
+    }
+
+    // Take a free entry in the holder table
+    fn register(&self) -> Option<usize> {
+        let pid = std::process::id();
+        self.pool().holders.iter().position(|holder| {
+            holder.compare_exchange(0, pid, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+        })
+    }
+
+    fn pool(&self) -> &PoolHeader {
+        unsafe { &*self.pool }
+    }
+
+    fn meta(&self, index: u32) -> &BlockMeta {
+        unsafe { &*self.meta.add(index as usize) }
+    }
+
+    fn block(&self, index: u32) -> *mut u8 {
+        unsafe { self.blocks.add(index as usize * self.block_size) }
+    }
+
+    fn pop_free(&self) -> Option<u32> {
+        let free = &self.pool().free;
+        let mut head = free.load(Ordering::Acquire);
+        loop {
+            let top = (head as u32).checked_sub(1)?;
+            let next = self.meta(top).next.load(Ordering::Relaxed);
+            let tag = (head >> 32).wrapping_add(1);
+            match free.compare_exchange_weak(
+                head,
+                tag << 32 | next as u64,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(top),
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn push_free(&self, index: u32) {
+        let free = &self.pool().free;
+        let mut head = free.load(Ordering::Relaxed);
+        loop {
+            self.meta(index).next.store(head as u32, Ordering::Relaxed);
+            let tag = (head >> 32).wrapping_add(1);
+            match free.compare_exchange_weak(
+                head,
+                tag << 32 | (index as u64 + 1),
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn alloc(&self) -> Option<u32> {
+        let index = self.pop_free()?;
+        let meta = self.meta(index);
+        // Whatever a dead handle left in these went with the last reference
+        meta.in_flight.store(0, Ordering::Relaxed);
+        meta.holders.store(0, Ordering::Relaxed);
+        meta.refs.store(1, Ordering::Relaxed);
+        self.hold(index);
+        self.pool().allocated.fetch_add(1, Ordering::AcqRel);
+        Some(index)
+    }
+
+    // Drop one reference, freeing the block with the last one
+    fn release(&self, index: u32) {
+        let meta = self.meta(index);
+        if meta.refs.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.free(index);
+        }
+        self.unhold(index);
+    }
+
+    fn free(&self, index: u32) {
+        self.meta(index).generation.fetch_add(1, Ordering::Release);
+        self.push_free(index);
+        self.pool().freed.fetch_add(1, Ordering::AcqRel);
+        self.pool().space_ready.notify();
+    }
+
+    // Count a reference this handle took to `index`. Call before giving up
+    // whatever reference it came from, so the block never looks abandoned.
+    fn hold(&self, index: u32) {
+        if self.held[index as usize].fetch_add(1, Ordering::AcqRel) == 0 {
+            self.sync_holder_bit(index);
+        }
+    }
+
+    // Count a reference this handle gave up, or passed on
+    fn unhold(&self, index: u32) {
+        if self.held[index as usize].fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.sync_holder_bit(index);
+        }
+    }
+
+    // Set our bit in the block's holders if we hold a reference to it and
+    // clear it if not. Whoever moves `held` between zero and not last gets
+    // the last word, under the lock.
+    fn sync_holder_bit(&self, index: u32) {
+        let _holding = self.holding.lock().unwrap_or_else(|e| e.into_inner());
+        let bit = 1 << self.holder;
+        let holders = &self.meta(index).holders;
+        if self.held[index as usize].load(Ordering::Acquire) > 0 {
+            holders.fetch_or(bit, Ordering::AcqRel);
+        } else {
+            holders.fetch_and(!bit, Ordering::AcqRel);
+        }
+    }
+
+    // Free every block that only dead handles held a reference to and that
+    // no `PoolHandle` is on its way to, and the holder entries of dead
+    // handles that hold nothing any more. Returns how many blocks it freed.
+    fn reclaim(&self) -> usize {
+        let holders = &self.pool().holders;
+        let mut dead = 0u64;
+        for (entry, holder) in holders.iter().enumerate() {
+            let pid = holder.load(Ordering::Acquire);
+            if pid != 0 && !peer::process_alive(pid) {
+                dead |= 1 << entry;
+            }
+        }
+        if dead == 0 {
+            return 0;
+        }
+
+        let mut freed = 0;
+        // Dead handles' bits still set on blocks we had to leave alone
+        let mut lingering = 0;
+        for index in 0..self.count {
+            let meta = self.meta(index);
+            let mask = meta.holders.load(Ordering::Acquire);
+            if mask & dead == 0 || meta.refs.load(Ordering::Acquire) == 0 {
+                continue;
+            }
+            // Nobody alive can take a new reference to a block that no live
+            // handle holds and no handle is on its way to
+            let abandoned = mask & !dead == 0 && meta.in_flight.load(Ordering::Acquire) == 0;
+            let claimed = abandoned
+                && meta
+                    .holders
+                    .compare_exchange(mask, 0, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok();
+            if !claimed {
+                lingering |= mask & dead;
+                continue;
+            }
+            meta.refs.store(0, Ordering::Release);
+            self.free(index);
+            freed += 1;
+        }
+
+        // A dead handle can't set bits again, so once none are left its
+        // entry can go to the next handle
+        for (entry, holder) in holders.iter().enumerate() {
+            if (dead & !lingering) & (1 << entry) != 0 {
+                let pid = holder.load(Ordering::Acquire);
+                let _ = holder.compare_exchange(pid, 0, Ordering::AcqRel, Ordering::Relaxed);
+            }
+        }
+        if freed > 0 {
+            event!(
+                warn,
+                ring = self.mapping.segment().name(),
+                kind = "pool",
+                blocks = freed,
+                "reclaimed blocks leaked by dead processes"
+            );
+        }
+        freed
+    }
+}
+
+impl Mapping {
+    fn segment(&self) -> &dyn Segment {
+        match self {
+            Mapping::Own(segment) => &**segment,
+            Mapping::Arena(arena) => arena.segment(),
+        }
+    }
+
+    fn header(&self) -> Option<&RingBufferHeader> {
+        match self {
+            Mapping::Own(segment) => Some(unsafe { &*(segment.as_ptr() as *const _) }),
+            Mapping::Arena(_) => None,
+        }
+    }
+}
+
+impl Drop for PoolShared {
+    fn drop(&mut self) {
+        // Every buffer holds a clone, so none of our references are left
+        if let Some(holder) = self.pool().holders.get(self.holder) {
+            holder.store(0, Ordering::Release);
+        }
+        let unlink = self.unlink.load(Ordering::Relaxed);
+        if let Mapping::Own(segment) = &mut self.mapping {
+            let header = unsafe { &*(segment.as_ptr() as *const RingBufferHeader) };
+            header.detach();
+            segment.set_owner(unlink);
+        }
+        event!(debug, ring = self.mapping.segment().name(), kind = "pool", "detached from pool");
+    }
+}
+
+// --- Pool ---
+
+// A handle on the pool; clones share one mapping
+#[derive(Clone)]
+pub struct BufferPool {
+    shared: Arc<PoolShared>,
+}
+
+impl BufferPool {
+    // Create a pool of `blocks` blocks of `block_size` bytes each, rounded
+    // up to a multiple of 64
+    pub fn create(name: &str, block_size: usize, blocks: usize) -> Result<Self, RbufError> {
+        let block_size = check_shape(block_size, blocks)?;
+        let layout = PoolLayout::new(block_size, blocks);
+        let offset = mem::size_of::<RingBufferHeader>();
+        let segment = Backing::Shm.create(name, offset + layout.size)?;
+
+        unsafe {
+            let base = segment.as_ptr();
+            let header = RingBufferHeader::initialize(
+                base,
+                RingBufferHeader::new(RingKind::Pool, block_size, BLOCK_ALIGN, blocks),
+            );
+            initialize(base.add(offset), block_size, blocks);
+            header.publish();
+            directory::register(name, header);
+        }
+
+        let shared = PoolShared::new(Mapping::Own(segment), offset, true)?;
+        Ok(Self { shared: Arc::new(shared) })
+    }
+
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        let segment = Backing::Shm.open(name)?;
+        let header = RingBufferHeader::validate_any(segment.as_ptr(), segment.len())?;
+        if header.kind != RingKind::Pool as u32 {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "segment holds ring kind {} but a buffer pool was expected",
+                header.kind
+            )));
+        }
+        let offset = mem::size_of::<RingBufferHeader>();
+        let expected = offset + PoolLayout::new(header.elem_size(), header.capacity()).size;
+        if segment.len() < expected {
+            return Err(RbufError::SizeMismatch { expected, actual: segment.len() });
+        }
+        let shared = PoolShared::new(Mapping::Own(segment), offset, false)?;
+        Ok(Self { shared: Arc::new(shared) })
+    }
+
+    // Create a pool inside `arena`, so it shares the arena's segment with
+    // whatever refers to its blocks. Fails with `Full` if the arena has no
+    // room for it. Other processes find it through `region`.
+    pub fn create_in(
+        arena: &ShmArena,
+        block_size: usize,
+        blocks: usize,
+    ) -> Result<Self, RbufError> {
+        let block_size = check_shape(block_size, blocks)?;
+        let layout = PoolLayout::new(block_size, blocks);
+        let offset = arena.alloc_raw(layout.size, BLOCK_ALIGN)? as usize;
+        unsafe { initialize(arena.segment().as_ptr().add(offset), block_size, blocks) };
+        let shared = PoolShared::new(Mapping::Arena(arena.clone()), offset, false)?;
+        Ok(Self { shared: Arc::new(shared) })
+    }
+
+    // Attach to a pool some process created in `arena`
+    pub fn open_in(arena: &ShmArena, region: ShmPtr<PoolHeader>) -> Result<Self, RbufError> {
+        let pool = unsafe { region.as_ref(arena.segment())? };
+        let invalid = || RbufError::InvalidPointer { offset: region.offset() };
+        let blocks = usize::try_from(pool.blocks).map_err(|_| invalid())?;
+        let block_size = usize::try_from(pool.block_size).map_err(|_| invalid())?;
+        if check_shape(block_size, blocks)? != block_size {
+            return Err(invalid());
+        }
+        let size = PoolLayout::new(block_size, blocks).size;
+        let offset = region.offset() as usize;
+        if offset.checked_add(size).is_none_or(|end| end > arena.segment().len()) {
+            return Err(invalid());
+        }
+        let shared = PoolShared::new(Mapping::Arena(arena.clone()), offset, false)?;
+        Ok(Self { shared: Arc::new(shared) })
+    }
+
+    // Where the pool starts in an arena, to hand to `open_in`; None for a
+    // pool with a segment of its own
+    pub fn region(&self) -> Option<ShmPtr<PoolHeader>> {
+        match self.shared.mapping {
+            Mapping::Own(_) => None,
+            Mapping::Arena(ref arena) => ShmPtr::from_ptr(arena.segment(), self.shared.pool).ok(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.shared.mapping.segment().name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment,
+    // or to the arena the pool lives in
+    pub fn attached(&self) -> usize {
+        match self.shared.mapping {
+            Mapping::Own(_) => {
+                let header = self.shared.mapping.header().expect("own segment has a header");
+                header.attached.load(Ordering::Acquire) as usize
+            }
+            Mapping::Arena(ref arena) => arena.attached(),
+        }
+    }
+
+    // Whether dropping the last clone of this handle (or buffer from it)
+    // removes the segment from the system. Defaults to true for the side
+    // that created it. A pool in an arena leaves that to the arena.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.shared.unlink.store(unlink, Ordering::Relaxed);
+    }
+
+    // The mapping, for resolving `ShmPtr`s into blocks
+    pub fn segment(&self) -> &dyn Segment {
+        self.shared.mapping.segment()
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.shared.block_size
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.shared.count as usize
+    }
+
+    // Blocks some process holds a reference to; only a snapshot
+    pub fn in_use(&self) -> usize {
+        self.shared.pool().in_use()
+    }
+
+    // Free the blocks leaked by processes that died holding references to
+    // them, and return how many. A block a dead process sent a handle to is
+    // left for whoever takes the handle.
+    pub fn reclaim(&self) -> usize {
+        self.shared.reclaim()
+    }
+
+    // A free block to write into. Fails with `Full` if every block is in
+    // use, even after reclaiming what dead processes leaked.
+    pub fn alloc(&self) -> Result<BufferMut, RbufError> {
+        let index = self
+            .shared
+            .alloc()
+            .or_else(|| (self.shared.reclaim() > 0).then(|| self.shared.alloc()).flatten())
+            .ok_or(RbufError::Full)?;
+        Ok(BufferMut { buffer: Buffer { shared: self.shared.clone(), index, len: 0 } })
+    }
+
+    // Alloc, sleeping until some process frees a block if none is free
+    pub fn alloc_blocking(&self) -> BufferMut {
+        let space_ready = &self.shared.pool().space_ready;
+        loop {
+            if let Ok(buffer) = self.alloc() {
+                return buffer;
+            }
+
+            let seq = space_ready.prepare_wait();
+            if let Ok(buffer) = self.alloc() {
+                space_ready.cancel_wait();
+                return buffer;
+            }
+            space_ready.wait(seq);
+        }
+    }
+
+    // Turn a handle received from another process back into a buffer, taking
+    // over the reference it carries. Fails with `StaleHandle` if the block
+    // was freed since, which means the handle was taken more than once.
+    pub fn take(&self, handle: PoolHandle) -> Result<Buffer, RbufError> {
+        let shared = &self.shared;
+        if handle.index >= shared.count || handle.len() > shared.block_size {
+            return Err(RbufError::StaleHandle);
+        }
+        let meta = shared.meta(handle.index);
+        let live = meta.refs.load(Ordering::Acquire) > 0;
+        if !live || meta.generation.load(Ordering::Acquire) != handle.generation {
+            return Err(RbufError::StaleHandle);
+        }
+        shared.hold(handle.index);
+        meta.in_flight.fetch_sub(1, Ordering::AcqRel);
+        Ok(Buffer { shared: shared.clone(), index: handle.index, len: handle.len() })
+    }
+}
+
+// --- Buffers ---
+
+// A reference to a block, read-only since others may hold one too. Clones
+// are further references to the same block.
+pub struct Buffer {
+    shared: Arc<PoolShared>,
+    index: u32,
+    len: usize,
+}
+
+impl Buffer {
+    // A handle carrying a new reference, for sending while keeping this one
+    pub fn share(&self) -> PoolHandle {
+        let meta = self.shared.meta(self.index);
+        meta.refs.fetch_add(1, Ordering::Relaxed);
+        meta.in_flight.fetch_add(1, Ordering::AcqRel);
+        self.handle()
+    }
+
+    // A handle carrying this reference
+    pub fn into_handle(self) -> PoolHandle {
+        let handle = self.handle();
+        self.shared.meta(self.index).in_flight.fetch_add(1, Ordering::AcqRel);
+        self.shared.unhold(self.index);
+        mem::forget(self);
+        handle
+    }
+
+    fn handle(&self) -> PoolHandle {
+        let generation = self.shared.meta(self.index).generation.load(Ordering::Acquire);
+        PoolHandle { index: self.index, generation, len: self.len as u64 }
+    }
+}
+
+impl Clone for Buffer {
+    fn clone(&self) -> Self {
+        self.shared.meta(self.index).refs.fetch_add(1, Ordering::Relaxed);
+        self.shared.hold(self.index);
+        Self { shared: self.shared.clone(), index: self.index, len: self.len }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.shared.block(self.index), self.len) }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        self.shared.release(self.index);
+    }
+}
+
+// A freshly allocated block, only referenced from here so it can be written
+pub struct BufferMut {
+    buffer: Buffer,
+}
+
+impl BufferMut {
+    // Bytes written so far, which is what readers will see
+    pub fn len(&self) -> usize {
+        self.buffer.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.len == 0
+    }
+
+    // Room left in the block
+    pub fn remaining(&self) -> usize {
+        self.buffer.shared.block_size - self.buffer.len
+    }
+
+    // Set how much of the block is in use. Fails with `MessageTooLarge` past
+    // the block size; bytes not yet written read as whatever the block held
+    // before.
+    pub fn set_len(&mut self, len: usize) -> Result<(), RbufError> {
+        let max = self.buffer.shared.block_size;
+        if len > max {
+            return Err(RbufError::MessageTooLarge { size: len, max });
+        }
+        self.buffer.len = len;
+        Ok(())
+    }
+
+    // Append `bytes`, failing with `MessageTooLarge` if they don't fit
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) -> Result<(), RbufError> {
+        let start = self.buffer.len;
+        self.set_len(start + bytes.len())?;
+        self[start..].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    // The whole block, whatever `len` says, for writing in place before
+    // `set_len`
+    pub fn block_mut(&mut self) -> &mut [u8] {
+        let shared = &self.buffer.shared;
+        let block = shared.block(self.buffer.index);
+        unsafe { std::slice::from_raw_parts_mut(block, shared.block_size) }
+    }
+
+    // Stop writing, so the buffer can be shared
+    pub fn freeze(self) -> Buffer {
+        self.buffer
+    }
+
+    // A handle carrying this reference; see `PoolHandle`
+    pub fn into_handle(self) -> PoolHandle {
+        self.buffer.into_handle()
+    }
+}
+
+impl Deref for BufferMut {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl DerefMut for BufferMut {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        let len = self.buffer.len;
+        &mut self.block_mut()[..len]
+    }
+}
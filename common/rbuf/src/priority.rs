@@ -0,0 +1,230 @@
+// priority.rs
+//
+// Priority lanes: one typed ring per `Priority`, named `<name>.high`,
+// `<name>.normal` and `<name>.low`, behind a producer and a consumer that
+// pick the lane. The consumer always drains higher lanes first, so control
+// messages overtake bulk data queued before them, and a busy high lane can
+// starve the lower ones.
+//
+// A blocked consumer waits on the high lane; pushes to the lower lanes
+// signal it there too.
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::consumer::Consumer;
+use crate::error::{PushError, RbufError};
+use crate::peer::HEARTBEAT_INTERVAL;
+use crate::producer::Producer;
+use crate::shm_safe::ShmSafe;
+use crate::wait::{self, Blocking, WaitStrategy};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    // Highest first, the order the consumer drains them in
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    fn suffix(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+}
+
+// The ring holding the messages of one lane
+pub fn lane_name(name: &str, priority: Priority) -> String {
+    format!("{}.{}", name, priority.suffix())
+}
+
+// --- Producer ---
+
+pub struct PriorityProducer<T> {
+    // Indexed by `Priority as usize`
+    lanes: Vec<Producer<T>>,
+}
+
+impl<T: ShmSafe> PriorityProducer<T> {
+    // Attach to every lane of the rings a `PriorityConsumer` created
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        let lanes = Priority::ALL
+            .iter()
+            .map(|&priority| Producer::open(&lane_name(name, priority)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { lanes })
+    }
+
+    fn lane(&self, priority: Priority) -> &Producer<T> {
+        &self.lanes[priority as usize]
+    }
+
+    // Fails with `Full` (handing the item back) when the item's lane is
+    // full, even if the others have room
+    pub fn push(&self, item: T, priority: Priority) -> Result<(), PushError<T>> {
+        self.lane(priority).push(item)?;
+        self.wake(priority);
+        Ok(())
+    }
+
+    // Push, waiting as the wait strategy says until the lane has room
+    pub fn push_blocking(&self, item: T, priority: Priority) -> Result<(), PushError<T>> {
+        self.lane(priority).push_blocking(item)?;
+        self.wake(priority);
+        Ok(())
+    }
+
+    // The consumer only waits on the high lane
+    fn wake(&self, priority: Priority) {
+        if priority != Priority::High {
+            self.lane(Priority::High).header().data_ready.notify();
+        }
+    }
+
+    // How `push_blocking` waits for space
+    pub fn set_wait_strategy(&mut self, strategy: Arc<dyn WaitStrategy>) {
+        for lane in &mut self.lanes {
+            lane.set_wait_strategy(strategy.clone());
+        }
+    }
+
+    // Items waiting in one lane
+    pub fn len(&self, priority: Priority) -> usize {
+        self.lane(priority).len()
+    }
+
+    pub fn heartbeat(&self) {
+        self.lanes.iter().for_each(Producer::heartbeat);
+    }
+
+    // Close every lane, like `Producer::close`. The consumer sees
+    // `Disconnected` once all of them are drained.
+    pub fn close(&self) {
+        self.lanes.iter().for_each(Producer::close);
+    }
+}
+
+// --- Consumer ---
+
+pub struct PriorityConsumer<T> {
+    name: String,
+    // Indexed by `Priority as usize`
+    lanes: Vec<Consumer<T>>,
+    wait: Arc<dyn WaitStrategy>,
+}
+
+impl<T: ShmSafe> PriorityConsumer<T> {
+    // Create a ring of `capacity` for every lane
+    pub fn create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        // Lanes created before a failure unlink themselves on drop
+        let lanes = Priority::ALL
+            .iter()
+            .map(|&priority| Consumer::create(&lane_name(name, priority), capacity))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { name: name.to_string(), lanes, wait: Arc::new(Blocking) })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Whether dropping this handle removes the lanes from the system.
+    // Defaults to true, as the consumer creates them.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        for lane in &mut self.lanes {
+            lane.set_unlink_on_drop(unlink);
+        }
+    }
+
+    // How `pop_blocking` and `pop_timeout` wait for items
+    pub fn set_wait_strategy(&mut self, strategy: Arc<dyn WaitStrategy>) {
+        self.wait = strategy;
+    }
+
+    // Items waiting in one lane
+    pub fn len(&self, priority: Priority) -> usize {
+        self.lanes[priority as usize].len()
+    }
+
+    // Whether every lane is empty
+    pub fn is_empty(&self) -> bool {
+        self.lanes.iter().all(Consumer::is_empty)
+    }
+
+    pub fn heartbeat(&self) {
+        self.lanes.iter().for_each(Consumer::heartbeat);
+    }
+
+    // Pop from the highest lane that has anything
+    pub fn pop(&mut self) -> Result<T, RbufError> {
+        self.pop_with_priority().map(|(item, _)| item)
+    }
+
+    // `pop`, also saying which lane the item came from
+    pub fn pop_with_priority(&mut self) -> Result<(T, Priority), RbufError> {
+        self.try_pop()
+    }
+
+    fn try_pop(&self) -> Result<(T, Priority), RbufError> {
+        let mut closed = 0;
+        for (lane, priority) in self.lanes.iter().zip(Priority::ALL) {
+            match lane.try_pop() {
+                Err(RbufError::Empty) => {}
+                // Lower lanes may still hold items
+                Err(RbufError::Disconnected) => closed += 1,
+                result => return result.map(|item| (item, priority)),
+            }
+        }
+        if closed == self.lanes.len() {
+            Err(RbufError::Disconnected)
+        } else {
+            Err(RbufError::Empty)
+        }
+    }
+
+    // Pop, waiting as the wait strategy says until a producer pushes to any
+    // lane. Corrupt items are skipped; they only show up in the stats. Fails
+    // with `Disconnected` once every lane is closed and drained.
+    pub fn pop_blocking(&mut self) -> Result<T, RbufError> {
+        loop {
+            match self.wait_for_item(None) {
+                Some(Err(RbufError::CorruptMessage { .. })) => {}
+                Some(result) => return result,
+                None => unreachable!("waiting without a deadline never times out"),
+            }
+        }
+    }
+
+    // Like `pop_blocking`, but gives up after `timeout`, and reports a
+    // corrupt item as `CorruptMessage` like `pop`
+    pub fn pop_timeout(&mut self, timeout: Duration) -> Result<T, RbufError> {
+        self.wait_for_item(Some(Instant::now() + timeout)).unwrap_or(Err(RbufError::Timeout))
+    }
+
+    // Like `Consumer`, wake up every `HEARTBEAT_INTERVAL` to beat
+    fn wait_for_item(&self, deadline: Option<Instant>) -> Option<Result<T, RbufError>> {
+        let data_ready = &self.lanes[Priority::High as usize].header().data_ready;
+        loop {
+            let slice = Instant::now() + HEARTBEAT_INTERVAL;
+            let until = deadline.map_or(slice, |deadline| deadline.min(slice));
+            let popped = wait::wait_until(&*self.wait, data_ready, Some(until), || {
+                match self.try_pop() {
+                    Err(RbufError::Empty) => None,
+                    result => Some(result.map(|(item, _)| item)),
+                }
+            });
+            if popped.is_some() {
+                return popped;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return None;
+            }
+            self.heartbeat();
+        }
+    }
+}
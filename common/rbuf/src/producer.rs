@@ -0,0 +1,641 @@
+// producer.rs
+use std::collections::LinkedList;
+use std::io;
+use std::mem::{self, MaybeUninit};
+use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::os::unix::io::BorrowedFd;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{OpenMode, RingConfig};
+use crate::error::{PushError, RbufError};
+use crate::header::Role;
+use crate::peer::{Peer, HEARTBEAT_INTERVAL};
+use crate::rate::{RateLimit, RateLimiter};
+use crate::ring::{ShmemRingBuffer, SLOT_ABORTED, SLOT_COMMITTED};
+use crate::shm_safe::ShmSafe;
+use crate::slots;
+use crate::stats::Stats;
+use crate::wait::{self, Blocking, WaitStrategy};
+use crate::watermark::{Pressure, Watcher, Watermarks};
+
+// How often an overwriting producer re-checks a slot it can't drop yet
+const DROP_ATTEMPTS: usize = 64;
+
+// What `push` does when the ring has no free slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FullPolicy {
+    // Fail with `RbufError::Full` and hand the item back
+    #[default]
+    Reject,
+    // Drop the oldest unread item to make room; for lossy, newest-wins data
+    Overwrite,
+}
+
+pub struct Producer<T> {
+    // The segment pushes go to, the last of `rings`. A consumer's `grow`
+    // moves the ring to a new one, which we follow on our next push; the
+    // ones before stay mapped until we drop, since guards and waits may
+    // still be using them. A list, so that none of them ever moves.
+    rb: AtomicPtr<ShmemRingBuffer<T>>,
+    rings: Mutex<LinkedList<ShmemRingBuffer<T>>>,
+    policy: FullPolicy,
+    wait: Arc<dyn WaitStrategy>,
+    // Started by `set_watermarks`
+    watermarks: Option<Watcher>,
+    // Holds this handle to the ring's `RateLimit`
+    rate: RateLimiter,
+}
+
+impl<T: ShmSafe> Producer<T> {
+    // Shorthand for `RingConfig::new(name).producer()`
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        RingConfig::new(name).producer()
+    }
+
+    // Shorthand for `RingConfig` with `OpenMode::OpenOrCreate`; `capacity` is
+    // only used if this producer ends up creating the segment
+    pub fn open_or_create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        RingConfig::new(name)
+            .capacity(capacity)
+            .open_mode(OpenMode::OpenOrCreate)
+            .producer()
+    }
+
+    pub(crate) fn from_ring(rb: ShmemRingBuffer<T>) -> Self {
+        let mut rings = LinkedList::from([rb]);
+        Self {
+            rb: AtomicPtr::new(rings.back_mut().expect("just pushed")),
+            rings: Mutex::new(rings),
+            policy: FullPolicy::Reject,
+            wait: Arc::new(Blocking),
+            watermarks: None,
+            rate: RateLimiter::new(),
+        }
+    }
+
+    fn rb(&self) -> &ShmemRingBuffer<T> {
+        // Only freed when we drop
+        unsafe { &*self.rb.load(Ordering::Acquire) }
+    }
+
+    pub(crate) fn header(&self) -> &crate::header::RingBufferHeader {
+        self.rb().header()
+    }
+
+    // Move over to the segment that replaced `from`, unless another thread
+    // already has
+    fn follow(&self, from: &ShmemRingBuffer<T>) -> Result<(), RbufError> {
+        let mut rings = self.rings.lock().unwrap_or_else(|e| e.into_inner());
+        if !ptr::eq(self.rb(), from) {
+            return Ok(());
+        }
+        rings.push_back(from.successor()?);
+        let next = rings.back_mut().expect("just pushed");
+        if self.policy == FullPolicy::Overwrite {
+            next.header().overwrite.store(1, Ordering::SeqCst);
+        }
+        if let Some(watermarks) = &self.watermarks {
+            watermarks.retarget(next.header());
+        }
+        self.rb.store(next, Ordering::Release);
+        event!(debug, ring = self.name(), capacity = next.capacity(), "followed grown ring");
+        Ok(())
+    }
+
+    pub fn full_policy(&self) -> FullPolicy {
+        self.policy
+    }
+
+    // How `push_blocking` waits for space
+    pub fn set_wait_strategy(&mut self, strategy: Arc<dyn WaitStrategy>) {
+        self.wait = strategy;
+    }
+
+    // Report when the ring fills to the high watermark and when it drains
+    // back to the low one; see `Watermarks`. Replaces any set before. Starts
+    // a thread that watches the ring, which stops when the producer drops.
+    pub fn set_watermarks(&mut self, watermarks: Watermarks) -> io::Result<()> {
+        // The old thread goes first, so it can't report after the new one
+        self.watermarks = None;
+        self.watermarks = Some(Watcher::new(self.rb().header(), watermarks)?);
+        Ok(())
+    }
+
+    // Whether the ring is over the watermarks; `Low` if none are set
+    pub fn pressure(&self) -> Pressure {
+        let rb = self.rb();
+        self.watermarks.as_ref().map_or(Pressure::Low, |w| w.pressure(rb.len(), rb.capacity()))
+    }
+
+    // An eventfd that turns readable on every watermark crossing, for
+    // throttling sources in an epoll or mio loop. Read it to reset it, then
+    // look at `pressure`. None until `set_watermarks`.
+    #[cfg(target_os = "linux")]
+    pub fn watermark_fd(&self) -> Option<BorrowedFd<'_>> {
+        self.watermarks.as_ref().map(Watcher::fd)
+    }
+
+    // The rate every producer on the ring is held to
+    pub fn rate_limit(&self) -> RateLimit {
+        RateLimit::load(&self.rb().header().control)
+    }
+
+    // Hold every producer on the ring, in any process, to `limit` from its
+    // next push on. Pushes over it fail with `RateLimited`, and
+    // `push_blocking` waits them out. See also `RingBuffer::set_rate_limit`.
+    pub fn set_rate_limit(&self, limit: RateLimit) {
+        limit.store(&self.rb().header().control);
+        event!(debug, ring = self.name(), ?limit, "set rate limit");
+    }
+
+    // How long until a push would get past the rate limit
+    pub(crate) fn rate_delay(&self) -> Duration {
+        self.rate.delay(&self.rb().header().control, mem::size_of::<T>())
+    }
+
+    pub fn set_full_policy(&mut self, policy: FullPolicy) {
+        if policy == FullPolicy::Overwrite {
+            // Tell the consumer before we ever move `head` under it
+            self.rb().header().overwrite.store(1, Ordering::SeqCst);
+        }
+        self.policy = policy;
+    }
+
+    // The OS identifier of the shared memory segment
+    pub fn name(&self) -> &str {
+        self.rb().name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.rb().header().attached.load(Ordering::Acquire) as usize
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        let rings = self.rings.get_mut().unwrap_or_else(|e| e.into_inner());
+        rings.back_mut().expect("a producer always has a ring").set_owner(unlink);
+    }
+
+    // The descriptor behind a `Backing::Memfd` or `Backing::Fd` ring, for
+    // handing to another process with `send_fd`
+    #[cfg(unix)]
+    pub fn fd(&self) -> Option<BorrowedFd<'_>> {
+        self.rb().fd()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.rb().capacity()
+    }
+
+    // Only a snapshot: the consumer may be draining the ring meanwhile
+    pub fn len(&self) -> usize {
+        self.rb().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity()
+    }
+
+    pub fn remaining_capacity(&self) -> usize {
+        self.capacity().saturating_sub(self.len())
+    }
+
+    // Counters shared by every handle on this ring
+    pub fn stats(&self) -> Stats {
+        Stats { latency: self.rb().latency(), ..self.rb().header().stats() }
+    }
+
+    // Tell the other side we're still here. `push_blocking` does this while
+    // it waits; producers that go long without pushing should call it
+    // periodically.
+    pub fn heartbeat(&self) {
+        self.rb().header().roles.beat(Role::Producer);
+    }
+
+    // The consumer that last showed signs of life, if one is attached
+    pub fn peer(&self) -> Option<Peer> {
+        self.rb().header().roles.peer(Role::Consumer)
+    }
+
+    // Whether a consumer is attached and its process is still running
+    pub fn is_peer_alive(&self) -> bool {
+        self.peer().is_some_and(|peer| peer.alive)
+    }
+
+    // Check that the ring's name still leads to the segment this handle
+    // maps. Fails with `StaleSegment` once it was unlinked or recreated,
+    // e.g. by a consumer that restarted after a crash, after which nothing
+    // pushed here reaches anyone: open the ring again. A ring the consumer
+    // grew is followed into its new segment instead, as pushes do.
+    pub fn ping(&self) -> Result<(), RbufError> {
+        let rb = self.rb();
+        if rb.header().is_migrated() {
+            self.follow(rb)?;
+        }
+        self.rb().ping()
+    }
+
+    // Tell the consumer that no more items are coming: once it has popped
+    // everything already pushed, its pops fail with `Disconnected` rather
+    // than `Empty`. Closes the ring for every producer, in every process;
+    // their pushes fail with `Disconnected` from here on. Can't be undone.
+    pub fn close(&self) {
+        let header = self.rb().header();
+        header.roles.close(Role::Producer);
+        header.data_ready.notify();
+        event!(debug, ring = self.name(), "closed ring");
+    }
+
+    // Items the consumer has granted credit for and nobody has pushed yet,
+    // or None if it doesn't use credits; see `Consumer::enable_credits`
+    pub fn credits(&self) -> Option<usize> {
+        let header = self.rb().header();
+        header.control.credits(header.tail.load(Ordering::Acquire)).map(|c| c as usize)
+    }
+
+    // Whether an admin paused publishing; see `Command::Pause`
+    pub fn is_paused(&self) -> bool {
+        self.rb().header().control.paused.load(Ordering::Acquire) != 0
+    }
+
+    // Whether pushes fail with `Disconnected`: a producer closed the ring,
+    // or the consumer detached and no other has attached since
+    pub fn is_closed(&self) -> bool {
+        let roles = &self.rb().header().roles;
+        roles.is_closed(Role::Producer) || roles.is_closed(Role::Consumer)
+    }
+
+    // Safe to call from any number of producers, in any number of processes:
+    // the slot is claimed with a CAS on `tail` before anything is written.
+    // Fails with `NoCredit` if the consumer uses credits and has none left
+    // to give, whatever the full policy.
+    pub fn push(&self, item: T) -> Result<(), PushError<T>> {
+        self.try_push(item).inspect_err(|e| {
+            if matches!(e.error(), RbufError::Full) {
+                self.rb().header().producer_stats.record_full();
+                event!(trace, ring = self.name(), capacity = self.capacity(), "ring full");
+            }
+        })
+    }
+
+    // `push` without counting a full ring in the stats, for `push_blocking`
+    fn try_push(&self, item: T) -> Result<(), PushError<T>> {
+        let (rb, seq) = match self.claim_slot() {
+            Ok(claimed) => claimed,
+            Err(e) => return Err(PushError::new(e, item)),
+        };
+
+        unsafe {
+            // Write the data into the slot we reserved
+            rb.buffer_ptr(seq).write(item);
+        }
+
+        self.publish(rb, seq, 1, SLOT_COMMITTED);
+        Ok(())
+    }
+
+    // Claim as many slots as are free (up to `items.len()`) with one CAS and
+    // copy the front of `items` into them. Returns how many were pushed.
+    pub fn push_slice(&self, items: &[T]) -> usize
+    where
+        T: Copy,
+    {
+        let (rb, start, count) = match self.claim_slots(items.len(), false) {
+            Ok(claimed) => claimed,
+            Err(RbufError::Full) => {
+                self.rb().header().producer_stats.record_full();
+                return 0;
+            }
+            Err(_) => return 0,
+        };
+        if count < items.len() {
+            rb.header().producer_stats.record_full();
+        }
+
+        for (seq, item) in (start..).zip(&items[..count]) {
+            unsafe { rb.buffer_ptr(seq).write(*item) };
+        }
+        self.publish(rb, start, count, SLOT_COMMITTED);
+        count
+    }
+
+    // Claim a slot and hand out direct access to it, so large items can be
+    // built in place. Nothing is visible to the consumer until `commit`.
+    pub fn reserve(&self) -> Result<WriteGuard<'_, T>, RbufError> {
+        let (rb, seq) = self.claim_slot().inspect_err(|e| self.record_full(e))?;
+        Ok(WriteGuard { producer: self, rb, seq, done: false })
+    }
+
+    // Claim between one and `n` consecutive slots as one slice, to be filled
+    // in place and committed together. Fewer than `n` when fewer are free, or
+    // when the run would wrap past the end of a ring that isn't mirrored
+    // (see `RingConfig::mirrored`).
+    pub fn reserve_slice(&self, n: usize) -> Result<WriteSliceGuard<'_, T>, RbufError> {
+        let (rb, start, count) =
+            self.claim_slots(n.max(1), true).inspect_err(|e| self.record_full(e))?;
+        Ok(WriteSliceGuard { producer: self, rb, start, count, done: false })
+    }
+
+    /// Build the next item directly in its slot, e.g. by decoding into it,
+    /// and publish it once `f` returns. If `f` panics the slot is aborted
+    /// and the consumer skips it, as when a `reserve` guard is dropped.
+    ///
+    /// # Safety
+    /// `f` must fully initialize the slot it is handed.
+    pub unsafe fn push_with<R>(
+        &self,
+        f: impl FnOnce(&mut MaybeUninit<T>) -> R,
+    ) -> Result<R, RbufError> {
+        let mut guard = self.reserve()?;
+        let result = f(&mut guard);
+        guard.commit();
+        Ok(result)
+    }
+
+    fn claim_slot(&self) -> Result<(&ShmemRingBuffer<T>, u64), RbufError> {
+        self.claim_slots(1, false).map(|(rb, seq, _)| (rb, seq))
+    }
+
+    fn record_full(&self, error: &RbufError) {
+        if matches!(error, RbufError::Full) {
+            self.rb().header().producer_stats.record_full();
+        }
+    }
+
+    // Claim up to `wanted` consecutive slots, returning the segment they're
+    // in, the first sequence number and how many were claimed. With
+    // `contiguous`, only as many as can be reached as one slice. Fails with
+    // `RateLimited` if the rate limit lets none through yet.
+    fn claim_slots(
+        &self,
+        wanted: usize,
+        contiguous: bool,
+    ) -> Result<(&ShmemRingBuffer<T>, u64, usize), RbufError> {
+        loop {
+            let rb = self.rb();
+            let header = rb.header();
+            if header.is_migrated() {
+                self.follow(rb)?;
+                continue;
+            }
+            if header.roles.is_closed(Role::Producer) || header.roles.is_closed(Role::Consumer) {
+                return Err(RbufError::Disconnected);
+            }
+            if header.control.paused.load(Ordering::Acquire) != 0 {
+                return Err(RbufError::Paused);
+            }
+            let size = mem::size_of::<T>();
+            let Ok(allowed) = self.rate.acquire(&header.control, wanted, size) else {
+                event!(trace, ring = self.name(), "rate limited");
+                return Err(RbufError::RateLimited);
+            };
+            let claimed = self.claim_free(rb, allowed, contiguous);
+            let count = claimed.as_ref().map_or(0, |&(_, count)| count);
+            self.rate.refund(allowed - count, size);
+            let (start, count) = claimed?;
+            // The ring grew meanwhile, and the consumer may be done moving
+            // items over: give the slots up and push in the new segment
+            if header.is_migrated() {
+                for seq in start..start + count as u64 {
+                    slots::publish(rb.slot_flag(seq), SLOT_ABORTED);
+                }
+                self.rate.refund(count, size);
+                continue;
+            }
+            return Ok((rb, start, count));
+        }
+    }
+
+    fn claim_free(
+        &self,
+        rb: &ShmemRingBuffer<T>,
+        wanted: usize,
+        contiguous: bool,
+    ) -> Result<(u64, usize), RbufError> {
+        let header = rb.header();
+        let limit = |tail| {
+            // Out of credit is not full: dropping items wouldn't help
+            let credits = header.control.credits(tail).unwrap_or(u64::MAX);
+            if credits == 0 {
+                return Err(RbufError::NoCredit);
+            }
+            Ok(if contiguous { credits.min(rb.contiguous(tail) as u64) } else { credits })
+        };
+        let make_room = |head| self.policy == FullPolicy::Overwrite && self.drop_oldest(rb, head);
+        let capacity = header.capacity() as u64;
+        slots::claim(&*header.head, &*header.tail, capacity, wanted, limit, make_room)
+    }
+
+    // Advance `head` past the oldest item, racing the consumer for it. Returns
+    // false if the oldest slot is still being written and can't be dropped.
+    fn drop_oldest(&self, rb: &ShmemRingBuffer<T>, head: u64) -> bool {
+        let header = rb.header();
+        let flag = rb.slot_flag(head);
+        // Losing means someone else took the slot first, which made room
+        // just the same; the caller starts over either way
+        let Some(taken) = slots::drop_oldest(&*header.head, flag, head, DROP_ATTEMPTS) else {
+            return false;
+        };
+        if taken {
+            header.producer_stats.record_overwrite();
+            event!(trace, ring = self.name(), seq = head, "overwrote oldest item");
+            header.space_ready.notify();
+        }
+        true
+    }
+
+    // Mark `count` slots of `rb` from `start` on, all written by us, as
+    // committed or aborted, with a single notification
+    fn publish(&self, rb: &ShmemRingBuffer<T>, start: u64, count: usize, state: u32) {
+        let header = rb.header();
+        let now = rb.now();
+        for seq in start..start + count as u64 {
+            if state == SLOT_COMMITTED {
+                rb.seal(seq);
+                rb.stamp(seq, now);
+            }
+            slots::publish(rb.slot_flag(seq), state);
+        }
+        rb.flush_slots(start, count);
+        if state == SLOT_COMMITTED {
+            header.producer_stats.record_push(count, rb.len());
+            event!(trace, ring = self.name(), seq = start, count, occupancy = rb.len(), "pushed");
+            if let Some(watermarks) = &self.watermarks {
+                watermarks.pressure(rb.len(), rb.capacity());
+            }
+        } else {
+            event!(debug, ring = self.name(), seq = start, count, "reservation dropped");
+        }
+        header.data_ready.notify();
+    }
+
+    // Push, waiting as the wait strategy says until the consumer frees a slot
+    // (and grants credit for it, if it uses credits), and until an admin
+    // resumes publishing if it's paused. Fails with `PeerDead` if the
+    // consumer crashes meanwhile, with `Disconnected` if it detaches and with
+    // `StaleSegment` if the ring is unlinked, rather than waiting forever for
+    // space that will never come. If no consumer ever attached it keeps
+    // waiting for one.
+    pub fn push_blocking(&self, item: T) -> Result<(), PushError<T>> {
+        let waits = |e: &PushError<T>| {
+            matches!(e.error(), RbufError::Full | RbufError::NoCredit | RbufError::Paused)
+        };
+        let mut item = Some(item);
+        loop {
+            let rb = self.rb();
+            let header = rb.header();
+            // Wake up every so often to beat and look for a dead consumer
+            let deadline = Instant::now() + HEARTBEAT_INTERVAL;
+            let pushed = wait::wait_until(&*self.wait, &header.space_ready, Some(deadline), || {
+                match self.try_push(item.take()?) {
+                    Ok(()) => Some(Ok(())),
+                    Err(e) if waits(&e) && ptr::eq(self.rb(), rb) => {
+                        item = Some(e.into_inner());
+                        None
+                    }
+                    Err(e) => Some(Err(e)),
+                }
+            });
+            match pushed {
+                // We followed the ring into a new segment; wait on that one
+                Some(Err(e)) if waits(&e) => {
+                    item = Some(e.into_inner());
+                    continue;
+                }
+                Some(Err(e)) if matches!(e.error(), RbufError::RateLimited) => {
+                    item = Some(e.into_inner());
+                    let delay = self.rate_delay();
+                    thread::sleep(delay.min(deadline.saturating_duration_since(Instant::now())));
+                }
+                Some(result) => return result,
+                None => {}
+            }
+
+            event!(
+                debug,
+                ring = self.name(),
+                occupancy = self.len(),
+                capacity = self.capacity(),
+                "still waiting for space"
+            );
+            header.roles.beat(Role::Producer);
+            if header.is_retired() {
+                event!(warn, ring = self.name(), "ring unlinked while waiting for space");
+                let item = item.take().expect("a failed push hands the item back");
+                return Err(PushError::new(RbufError::StaleSegment, item));
+            }
+            if let Some(pid) = header.roles.dead_peer(Role::Consumer) {
+                event!(warn, ring = self.name(), pid, "consumer died while waiting for space");
+                let item = item.take().expect("a failed push hands the item back");
+                return Err(PushError::new(RbufError::PeerDead { pid }, item));
+            }
+        }
+    }
+}
+
+impl<T> Drop for Producer<T> {
+    fn drop(&mut self) {
+        // The watching thread reads the header, so it goes before the mapping
+        drop(self.watermarks.take());
+    }
+}
+
+// --- Zero-copy reservation ---
+
+// A claimed slot in shared memory. Dropping it without committing marks the
+// slot as aborted, which the consumer skips over.
+pub struct WriteGuard<'a, T: ShmSafe> {
+    producer: &'a Producer<T>,
+    rb: &'a ShmemRingBuffer<T>,
+    seq: u64,
+    done: bool,
+}
+
+impl<T: ShmSafe> WriteGuard<'_, T> {
+    /// Publish the slot to the consumer.
+    ///
+    /// # Safety
+    /// The slot must have been fully initialized through the guard.
+    pub unsafe fn commit(mut self) {
+        self.done = true;
+        self.producer.publish(self.rb, self.seq, 1, SLOT_COMMITTED);
+    }
+}
+
+impl<T: ShmSafe> Deref for WriteGuard<'_, T> {
+    type Target = MaybeUninit<T>;
+
+    fn deref(&self) -> &MaybeUninit<T> {
+        unsafe { &*(self.rb.buffer_ptr(self.seq) as *const MaybeUninit<T>) }
+    }
+}
+
+impl<T: ShmSafe> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut MaybeUninit<T> {
+        unsafe { &mut *(self.rb.buffer_ptr(self.seq) as *mut MaybeUninit<T>) }
+    }
+}
+
+impl<T: ShmSafe> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.producer.publish(self.rb, self.seq, 1, SLOT_ABORTED);
+        }
+    }
+}
+
+// A run of claimed slots from `Producer::reserve_slice`. Dropping it without
+// committing aborts all of them.
+pub struct WriteSliceGuard<'a, T: ShmSafe> {
+    producer: &'a Producer<T>,
+    rb: &'a ShmemRingBuffer<T>,
+    start: u64,
+    count: usize,
+    done: bool,
+}
+
+impl<T: ShmSafe> WriteSliceGuard<'_, T> {
+    /// Publish every slot to the consumer at once.
+    ///
+    /// # Safety
+    /// Every slot must have been fully initialized through the guard.
+    pub unsafe fn commit(mut self) {
+        self.done = true;
+        self.producer.publish(self.rb, self.start, self.count, SLOT_COMMITTED);
+    }
+}
+
+impl<T: ShmSafe> Deref for WriteSliceGuard<'_, T> {
+    type Target = [MaybeUninit<T>];
+
+    fn deref(&self) -> &[MaybeUninit<T>] {
+        let first = self.rb.buffer_ptr(self.start) as *const MaybeUninit<T>;
+        unsafe { std::slice::from_raw_parts(first, self.count) }
+    }
+}
+
+impl<T: ShmSafe> DerefMut for WriteSliceGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut [MaybeUninit<T>] {
+        let first = self.rb.buffer_ptr(self.start) as *mut MaybeUninit<T>;
+        unsafe { std::slice::from_raw_parts_mut(first, self.count) }
+    }
+}
+
+impl<T: ShmSafe> Drop for WriteSliceGuard<'_, T> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.producer.publish(self.rb, self.start, self.count, SLOT_ABORTED);
+        }
+    }
+}
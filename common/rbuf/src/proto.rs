@@ -0,0 +1,173 @@
+// proto.rs
+//
+// Protobuf messages over a byte ring. The receiver creates the ring with a
+// hash of the message type's descriptor in the header, and senders built
+// against a different version of the schema fail to open it instead of
+// pushing bytes the receiver would decode as garbage.
+//
+//     let mut quotes: Receiver<Quote> = ProtoChannel::create("quotes", 1 << 20)?;
+//     let sender: Sender<Quote> = ProtoChannel::open("quotes")?;
+//
+// `M` has to implement `prost_reflect::ReflectMessage`, e.g. by deriving it
+// with prost-reflect-build, so the descriptor is known at runtime.
+use std::collections::HashSet;
+use std::marker::PhantomData;
+
+use prost::Message;
+use prost_reflect::{Kind, MessageDescriptor, ReflectMessage};
+
+use crate::bytes::{Reader, Writer};
+use crate::codec::Codec;
+use crate::directory::{fnv1a, FNV_OFFSET};
+use crate::error::RbufError;
+
+// A hash of `M`'s descriptor and of every message and enum type it refers
+// to, so changing a nested type changes it too. Field names, numbers, types
+// and labels all count; comments don't. Never 0, which means "no schema".
+pub fn schema_hash<M: ReflectMessage + Default>() -> u64 {
+    descriptor_hash(&M::default().descriptor())
+}
+
+fn descriptor_hash(descriptor: &MessageDescriptor) -> u64 {
+    let mut hash = FNV_OFFSET;
+    let mut seen = HashSet::new();
+    let mut pending = vec![descriptor.clone()];
+    while let Some(message) = pending.pop() {
+        if !seen.insert(message.full_name().to_string()) {
+            continue;
+        }
+        hash = fnv1a(hash, &message.descriptor_proto().encode_to_vec());
+        for field in message.fields() {
+            match field.kind() {
+                Kind::Message(nested) => pending.push(nested),
+                Kind::Enum(nested) if seen.insert(nested.full_name().to_string()) => {
+                    hash = fnv1a(hash, &nested.enum_descriptor_proto().encode_to_vec());
+                }
+                _ => {}
+            }
+        }
+    }
+    hash.max(1)
+}
+
+// Whoever receives creates the channel, like with the byte ring underneath
+pub struct ProtoChannel<M> {
+    _phantom: PhantomData<M>,
+}
+
+impl<M: ReflectMessage + Default> ProtoChannel<M> {
+    // Create the segment with room for `capacity` bytes of encoded messages
+    pub fn create(name: &str, capacity: usize) -> Result<Receiver<M>, RbufError> {
+        Receiver::create(name, capacity)
+    }
+
+    pub fn open(name: &str) -> Result<Sender<M>, RbufError> {
+        Sender::open(name)
+    }
+}
+
+// --- Sender ---
+
+pub struct Sender<M> {
+    writer: Writer,
+    // Only ever handed a `&M`, so `M` doesn't decide whether this is Send
+    _phantom: PhantomData<fn(&M)>,
+}
+
+impl<M: ReflectMessage + Default> Sender<M> {
+    // Fails with `IncompatibleLayout` unless the ring was created for the
+    // same schema as `M`
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        let writer = Writer::open(name)?;
+        let expected = schema_hash::<M>();
+        if writer.schema() != expected {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "ring carries schema {:#x} but {} is {:#x}",
+                writer.schema(),
+                M::default().descriptor().full_name(),
+                expected
+            )));
+        }
+        Ok(Self { writer, _phantom: PhantomData })
+    }
+
+    pub fn name(&self) -> &str {
+        self.writer.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.writer.attached()
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.writer.set_unlink_on_drop(unlink);
+    }
+
+    // The largest encoded message that can ever be pushed
+    pub fn max_message_size(&self) -> usize {
+        self.writer.max_message_size()
+    }
+
+    // Fails with `MessageTooLarge` if the encoded message can never fit
+    pub fn push(&self, msg: &M) -> Result<(), RbufError> {
+        self.writer.push_bytes(&msg.encode_to_vec())
+    }
+
+    // Push, sleeping until the receiver frees enough space if the ring is full
+    pub fn push_blocking(&self, msg: &M) -> Result<(), RbufError> {
+        self.writer.push_bytes_blocking(&msg.encode_to_vec())
+    }
+}
+
+// --- Receiver ---
+
+pub struct Receiver<M> {
+    reader: Reader,
+    // Reused for every message, so popping only allocates inside `M`
+    buf: Vec<u8>,
+    _phantom: PhantomData<fn() -> M>,
+}
+
+impl<M: ReflectMessage + Default> Receiver<M> {
+    // Create the segment with room for `capacity` bytes of encoded messages,
+    // rounded up to a power of two, and record `M`'s schema hash in it
+    pub fn create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        let reader = Reader::create_with(name, capacity, schema_hash::<M>(), Codec::None, None, 0)?;
+        Ok(Self { reader, buf: Vec::new(), _phantom: PhantomData })
+    }
+
+    pub fn name(&self) -> &str {
+        self.reader.name()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.reader.attached()
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.reader.set_unlink_on_drop(unlink);
+    }
+
+    // Fails with `Decode` if the next message isn't a valid `M`. The message
+    // is consumed either way.
+    pub fn pop(&mut self) -> Result<M, RbufError> {
+        self.reader.pop_bytes(&mut self.buf)?;
+        self.decode()
+    }
+
+    // Pop, sleeping until a sender pushes if the ring is empty
+    pub fn pop_blocking(&mut self) -> Result<M, RbufError> {
+        self.reader.pop_bytes_blocking(&mut self.buf);
+        self.decode()
+    }
+
+    fn decode(&self) -> Result<M, RbufError> {
+        M::decode(self.buf.as_slice()).map_err(|e| RbufError::Decode(e.to_string()))
+    }
+}
@@ -0,0 +1,269 @@
+// ptr.rs
+//
+// Pointers that mean the same thing in every process. A segment is mapped at
+// a different address in each process that attaches to it, so anything in
+// shared memory that refers to something else in the same segment has to
+// store where it is relative to the segment's start. `ShmPtr` and `ShmSlice`
+// are those offsets, typed; they are only turned back into addresses against
+// a mapping of the segment, after checking that what they point to lies
+// inside it and is aligned.
+//
+// Offset 0 is the segment's header, which nothing points to, so it doubles
+// as null.
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr::NonNull;
+
+use crate::error::RbufError;
+use crate::segment::Segment;
+use crate::shm_safe::ShmSafe;
+
+// Where `len` bytes starting at `offset` lie in `segment`, if they do and
+// `offset` is aligned for `T`
+fn locate<T, S: Segment + ?Sized>(
+    segment: &S,
+    offset: u64,
+    len: usize,
+) -> Result<NonNull<u8>, RbufError> {
+    let invalid = || RbufError::InvalidPointer { offset };
+    let start = usize::try_from(offset).map_err(|_| invalid())?;
+    let end = start.checked_add(len).ok_or_else(invalid)?;
+    if start == 0 || end > segment.len() || start % mem::align_of::<T>() != 0 {
+        return Err(invalid());
+    }
+    // Segments are mapped, so their pointer is never null
+    Ok(unsafe { NonNull::new_unchecked(segment.as_ptr().add(start)) })
+}
+
+// The offset of `ptr` into `segment`, if it points inside it
+fn offset_of<S: Segment + ?Sized>(segment: &S, ptr: *const u8) -> Result<u64, RbufError> {
+    let offset = (ptr as usize).wrapping_sub(segment.as_ptr() as usize) as u64;
+    if offset >= segment.len() as u64 {
+        return Err(RbufError::InvalidPointer { offset });
+    }
+    Ok(offset)
+}
+
+// --- ShmPtr ---
+
+// A `*const T` into a segment, stored as an offset from its start
+#[repr(C)]
+pub struct ShmPtr<T> {
+    offset: u64,
+    _phantom: PhantomData<*const T>,
+}
+
+// Just an offset; only what it resolves to is bound by `T`'s rules
+unsafe impl<T: ShmSafe> ShmSafe for ShmPtr<T> {}
+unsafe impl<T> Send for ShmPtr<T> {}
+unsafe impl<T> Sync for ShmPtr<T> {}
+
+impl<T> ShmPtr<T> {
+    pub const fn null() -> Self {
+        Self { offset: 0, _phantom: PhantomData }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.offset == 0
+    }
+
+    // Bytes from the start of the segment
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // For allocators, which know what lies at `offset`
+    pub(crate) fn from_offset(offset: u64) -> Self {
+        Self { offset, _phantom: PhantomData }
+    }
+
+    // The pointer to `ptr`, which has to point into `segment`
+    pub fn from_ptr<S: Segment + ?Sized>(segment: &S, ptr: *const T) -> Result<Self, RbufError> {
+        let offset = offset_of(segment, ptr as *const u8)?;
+        // A `T` that doesn't fit after `ptr` can't be resolved either
+        locate::<T, S>(segment, offset, mem::size_of::<T>())?;
+        Ok(Self { offset, _phantom: PhantomData })
+    }
+
+    // Where this points in `segment`'s mapping. Fails with `InvalidPointer`
+    // if it's null, misaligned or any part of the `T` lies outside the
+    // segment, which means it came from another segment or the segment
+    // holding it was written to by something else.
+    pub fn resolve<S: Segment + ?Sized>(&self, segment: &S) -> Result<NonNull<T>, RbufError> {
+        Ok(locate::<T, S>(segment, self.offset, mem::size_of::<T>())?.cast())
+    }
+
+    /// # Safety
+    ///
+    /// The `T` must have been initialized, and nothing may write to it, in
+    /// this or any other process, while the reference lives.
+    pub unsafe fn as_ref<'a, S: Segment + ?Sized>(
+        &self,
+        segment: &'a S,
+    ) -> Result<&'a T, RbufError> {
+        Ok(self.resolve(segment)?.as_ref())
+    }
+
+    // The segment is borrowed mutably so this process can't resolve
+    // anything else in it meanwhile; `resolve` for finer-grained access.
+    //
+    /// # Safety
+    ///
+    /// Nothing else, in this or any other process, may read or write the `T`
+    /// while the reference lives.
+    pub unsafe fn as_mut<'a, S: Segment + ?Sized>(
+        &self,
+        segment: &'a mut S,
+    ) -> Result<&'a mut T, RbufError> {
+        Ok(self.resolve(segment)?.as_mut())
+    }
+}
+
+impl<T> Clone for ShmPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ShmPtr<T> {}
+
+impl<T> PartialEq for ShmPtr<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.offset == other.offset
+    }
+}
+
+impl<T> Eq for ShmPtr<T> {}
+
+impl<T> Default for ShmPtr<T> {
+    fn default() -> Self {
+        Self::null()
+    }
+}
+
+impl<T> fmt::Debug for ShmPtr<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ShmPtr({:#x})", self.offset)
+    }
+}
+
+// --- ShmSlice ---
+
+// A `*const [T]` into a segment: the offset of its first element and how
+// many there are
+#[repr(C)]
+pub struct ShmSlice<T> {
+    offset: u64,
+    len: u64,
+    _phantom: PhantomData<*const T>,
+}
+
+unsafe impl<T: ShmSafe> ShmSafe for ShmSlice<T> {}
+unsafe impl<T> Send for ShmSlice<T> {}
+unsafe impl<T> Sync for ShmSlice<T> {}
+
+impl<T> ShmSlice<T> {
+    // The empty slice, which resolves against any segment
+    pub const fn empty() -> Self {
+        Self { offset: 0, len: 0, _phantom: PhantomData }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub(crate) fn from_raw(offset: u64, len: usize) -> Self {
+        Self { offset, len: len as u64, _phantom: PhantomData }
+    }
+
+    // The slice `slice`, which has to lie in `segment`
+    pub fn from_slice<S: Segment + ?Sized>(segment: &S, slice: &[T]) -> Result<Self, RbufError> {
+        if slice.is_empty() {
+            return Ok(Self::empty());
+        }
+        let offset = offset_of(segment, slice.as_ptr() as *const u8)?;
+        let this = Self { offset, len: slice.len() as u64, _phantom: PhantomData };
+        this.resolve(segment)?;
+        Ok(this)
+    }
+
+    // The element at `index`, or None past the end
+    pub fn get(&self, index: usize) -> Option<ShmPtr<T>> {
+        if index >= self.len() {
+            return None;
+        }
+        let offset = self.offset + (index * mem::size_of::<T>()) as u64;
+        Some(ShmPtr { offset, _phantom: PhantomData })
+    }
+
+    // Where the slice lies in `segment`'s mapping; see `ShmPtr::resolve`
+    pub fn resolve<S: Segment + ?Sized>(&self, segment: &S) -> Result<NonNull<[T]>, RbufError> {
+        if self.is_empty() {
+            return Ok(NonNull::slice_from_raw_parts(NonNull::dangling(), 0));
+        }
+        let invalid = || RbufError::InvalidPointer { offset: self.offset };
+        let len = usize::try_from(self.len).map_err(|_| invalid())?;
+        let size = len.checked_mul(mem::size_of::<T>()).ok_or_else(invalid)?;
+        let start = locate::<T, S>(segment, self.offset, size)?;
+        Ok(NonNull::slice_from_raw_parts(start.cast(), len))
+    }
+
+    /// # Safety
+    ///
+    /// Every element must have been initialized, and nothing may write to
+    /// them, in this or any other process, while the reference lives.
+    pub unsafe fn as_slice<'a, S: Segment + ?Sized>(
+        &self,
+        segment: &'a S,
+    ) -> Result<&'a [T], RbufError> {
+        Ok(self.resolve(segment)?.as_ref())
+    }
+
+    /// # Safety
+    ///
+    /// Nothing else, in this or any other process, may read or write the
+    /// elements while the reference lives.
+    pub unsafe fn as_mut_slice<'a, S: Segment + ?Sized>(
+        &self,
+        segment: &'a mut S,
+    ) -> Result<&'a mut [T], RbufError> {
+        Ok(self.resolve(segment)?.as_mut())
+    }
+}
+
+impl<T> Clone for ShmSlice<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ShmSlice<T> {}
+
+impl<T> PartialEq for ShmSlice<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.offset == other.offset && self.len == other.len
+    }
+}
+
+impl<T> Eq for ShmSlice<T> {}
+
+impl<T> Default for ShmSlice<T> {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl<T> fmt::Debug for ShmSlice<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ShmSlice({:#x}, len {})", self.offset, self.len)
+    }
+}
@@ -0,0 +1,157 @@
+// rate.rs
+//
+// Token buckets that hold a producer to the rates in its ring's control
+// line. The rates are read on every push, so a limit set from another
+// process (`RingBuffer::set_rate_limit`, or `rbuf-cli limit`) applies from
+// the next push on, without the producer doing anything. Each producer
+// handle has buckets of its own, so the limit is per producer rather than
+// for the ring as a whole.
+//
+// A bucket holds one second's worth of tokens, which is how much a producer
+// that has been idle may push at once before it's held to the rate.
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::header::Control;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RateLimit {
+    // Most items each producer pushes per second; 0 for no limit
+    pub messages_per_sec: u64,
+    // Most bytes of items each producer pushes per second; 0 for no limit
+    pub bytes_per_sec: u64,
+}
+
+impl RateLimit {
+    pub(crate) fn load(control: &Control) -> Self {
+        Self {
+            messages_per_sec: control.messages_per_sec.load(Ordering::Relaxed),
+            bytes_per_sec: control.bytes_per_sec.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn store(&self, control: &Control) {
+        control.messages_per_sec.store(self.messages_per_sec, Ordering::Relaxed);
+        control.bytes_per_sec.store(self.bytes_per_sec, Ordering::Relaxed);
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.messages_per_sec == 0 && self.bytes_per_sec == 0
+    }
+}
+
+struct Buckets {
+    // The limit the buckets were last filled for
+    limit: RateLimit,
+    messages: f64,
+    bytes: f64,
+    refilled: Instant,
+}
+
+impl Buckets {
+    // Top up for the time since the last refill, starting full whenever the
+    // limit changed
+    fn refill(&mut self, limit: RateLimit) {
+        let now = Instant::now();
+        if limit != self.limit {
+            self.limit = limit;
+            self.messages = limit.messages_per_sec as f64;
+            self.bytes = limit.bytes_per_sec as f64;
+        } else {
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            let top_up = |tokens: f64, rate: u64| (tokens + elapsed * rate as f64).min(rate as f64);
+            self.messages = top_up(self.messages, limit.messages_per_sec);
+            self.bytes = top_up(self.bytes, limit.bytes_per_sec);
+        }
+        self.refilled = now;
+    }
+
+    // How long until both buckets have the tokens for an item of `size`
+    // bytes, or for a full bucket if that's less
+    fn wait(&self, size: usize) -> Duration {
+        let mut wait = 0f64;
+        for (tokens, rate, cost) in [
+            (self.messages, self.limit.messages_per_sec, 1.0),
+            (self.bytes, self.limit.bytes_per_sec, size as f64),
+        ] {
+            if rate > 0 {
+                let needed = cost.min(rate as f64);
+                wait = wait.max((needed - tokens) / rate as f64);
+            }
+        }
+        Duration::from_secs_f64(wait)
+    }
+}
+
+pub(crate) struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new() -> Self {
+        let buckets = Buckets {
+            limit: RateLimit::default(),
+            messages: 0.0,
+            bytes: 0.0,
+            refilled: Instant::now(),
+        };
+        Self { buckets: Mutex::new(buckets) }
+    }
+
+    // Take the tokens for as many of `wanted` items of `size` bytes as
+    // `control` lets through now, at least one, and return how many. Fails
+    // with how long until one would go through otherwise. An item bigger
+    // than a whole bucket goes through once the bucket is full.
+    pub(crate) fn acquire(
+        &self,
+        control: &Control,
+        wanted: usize,
+        size: usize,
+    ) -> Result<usize, Duration> {
+        let limit = RateLimit::load(control);
+        if limit.is_unlimited() {
+            return Ok(wanted);
+        }
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        buckets.refill(limit);
+        let wait = buckets.wait(size);
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        let mut granted = wanted;
+        if limit.messages_per_sec > 0 {
+            granted = granted.min(buckets.messages as usize);
+        }
+        if limit.bytes_per_sec > 0 && size > 0 {
+            granted = granted.min((buckets.bytes / size as f64) as usize);
+        }
+        let granted = granted.max(1);
+        buckets.messages -= granted as f64;
+        buckets.bytes -= (granted * size) as f64;
+        Ok(granted)
+    }
+
+    // How long until an item of `size` bytes would go through
+    pub(crate) fn delay(&self, control: &Control, size: usize) -> Duration {
+        let limit = RateLimit::load(control);
+        if limit.is_unlimited() {
+            return Duration::ZERO;
+        }
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        buckets.refill(limit);
+        buckets.wait(size)
+    }
+
+    // Give back the tokens of items `acquire` let through that weren't
+    // pushed after all
+    pub(crate) fn refund(&self, items: usize, size: usize) {
+        if items == 0 {
+            return;
+        }
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        buckets.messages += items as f64;
+        buckets.bytes += (items * size) as f64;
+    }
+}
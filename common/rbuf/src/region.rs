@@ -0,0 +1,255 @@
+// region.rs
+//
+// The `rbuf_core` ring in a shared memory segment. `rbuf_core` doesn't need
+// `std`, so firmware on another core can run the very same ring over a
+// region it shares with a Linux process; this module is the Linux end when
+// that region is a segment, and a plain single-producer, single-consumer
+// ring between processes otherwise.
+//
+// [ header | rbuf_core region ]
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::sync::atomic::Ordering;
+
+use rbuf_core::RegionRing;
+
+use crate::directory;
+use crate::error::{PushError, RbufError};
+use crate::header::{RingBufferHeader, RingKind};
+use crate::segment::{Backing, Segment};
+use crate::shm_safe::ShmSafe;
+
+// The header is a whole number of cache lines, so this is as aligned as
+// `rbuf_core` needs
+fn region_offset() -> usize {
+    mem::size_of::<RingBufferHeader>()
+}
+
+fn layout_error(error: rbuf_core::Error) -> RbufError {
+    match error {
+        rbuf_core::Error::NotInitialized => RbufError::NotInitialized,
+        error => RbufError::IncompatibleLayout(error.to_string()),
+    }
+}
+
+// The region of a mapped segment, for as long as the mapping lives
+unsafe fn region_of(base: *mut u8, len: usize) -> &'static mut [u8] {
+    std::slice::from_raw_parts_mut(base.add(region_offset()), len - region_offset())
+}
+
+// Items waiting in a region ring, for tools that don't know `T`
+pub(crate) fn len_raw(base: *mut u8, len: usize) -> Option<usize> {
+    if len < region_offset() {
+        return None;
+    }
+    RegionRing::attach(unsafe { region_of(base, len) }).ok().map(|ring| ring.len())
+}
+
+// Pop every item as raw bytes, for tools that don't know `T`. Stop the real
+// consumer first: it assumes nobody else advances `head`.
+pub(crate) fn drain_raw(
+    base: *mut u8,
+    len: usize,
+    f: &mut dyn FnMut(&[u8]),
+) -> Result<usize, RbufError> {
+    if len < region_offset() {
+        return Err(RbufError::SizeMismatch { expected: region_offset(), actual: len });
+    }
+    let mut ring = RegionRing::attach(unsafe { region_of(base, len) }).map_err(layout_error)?;
+    let mut item = vec![0; ring.elem_size()];
+    let mut drained = 0;
+    while ring.pop(&mut item).is_ok() {
+        f(&item);
+        drained += 1;
+    }
+    Ok(drained)
+}
+
+struct Region<T> {
+    // Into `segment`'s mapping, so declared first to go first
+    ring: RegionRing<'static>,
+    segment: Box<dyn Segment>,
+    header: *const RingBufferHeader,
+    _phantom: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for Region<T> {}
+
+impl<T: ShmSafe + Copy> Region<T> {
+    fn create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        let capacity = capacity.max(1).next_power_of_two();
+        let size = rbuf_core::region_size(mem::size_of::<T>(), capacity);
+        let segment = Backing::Shm.create(name, region_offset().saturating_add(size))?;
+
+        let ring = unsafe {
+            let header = RingBufferHeader::initialize(
+                segment.as_ptr(),
+                RingBufferHeader::new(
+                    RingKind::Region,
+                    mem::size_of::<T>(),
+                    mem::align_of::<T>(),
+                    capacity,
+                )
+                .with_schema(directory::type_hash::<T>()),
+            );
+            let region = region_of(segment.as_ptr(), segment.len());
+            let ring = RegionRing::init(region, mem::size_of::<T>(), capacity)
+                .map_err(layout_error)?;
+            header.publish();
+            directory::register(name, header);
+            ring
+        };
+        Ok(Self::from_segment(segment, ring))
+    }
+
+    fn open(name: &str) -> Result<Self, RbufError> {
+        let segment = Backing::Shm.open(name)?;
+        let header = RingBufferHeader::validate(
+            segment.as_ptr(),
+            segment.len(),
+            RingKind::Region,
+            mem::size_of::<T>(),
+            mem::align_of::<T>(),
+        )?;
+        header.check_schema(directory::type_hash::<T>())?;
+        let expected = region_offset()
+            .saturating_add(rbuf_core::region_size(header.elem_size(), header.capacity()));
+        if segment.len() < expected {
+            return Err(RbufError::SizeMismatch { expected, actual: segment.len() });
+        }
+
+        let region = unsafe { region_of(segment.as_ptr(), segment.len()) };
+        let ring = RegionRing::attach(region).map_err(layout_error)?;
+        Ok(Self::from_segment(segment, ring))
+    }
+
+    fn from_segment(segment: Box<dyn Segment>, ring: RegionRing<'static>) -> Self {
+        let header = segment.as_ptr() as *const RingBufferHeader;
+        unsafe { (*header).attached.fetch_add(1, Ordering::AcqRel) };
+        event!(debug, ring = segment.name(), kind = "region", "mapped ring");
+        Self { ring, segment, header, _phantom: PhantomData }
+    }
+
+    fn header(&self) -> &RingBufferHeader {
+        unsafe { &*self.header }
+    }
+}
+
+impl<T> Drop for Region<T> {
+    fn drop(&mut self) {
+        unsafe { (*self.header).detach() };
+        event!(debug, ring = self.segment.name(), kind = "region", "detached from ring");
+    }
+}
+
+// --- Producer ---
+
+pub struct Producer<T> {
+    region: Region<T>,
+}
+
+impl<T: ShmSafe + Copy> Producer<T> {
+    // Create the ring with room for `capacity` items, rounded up to a power
+    // of two
+    pub fn create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        Ok(Self { region: Region::create(name, capacity)? })
+    }
+
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        Ok(Self { region: Region::open(name)? })
+    }
+
+    pub fn name(&self) -> &str {
+        self.region.segment.name()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.region.ring.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self.region.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.region.ring.is_empty()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.region.header().attached.load(Ordering::Acquire) as usize
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.region.segment.set_owner(unlink);
+    }
+
+    // The only producer of the ring: `&mut self` keeps it to one thread, and
+    // no other process may push
+    pub fn push(&mut self, item: T) -> Result<(), PushError<T>> {
+        let pushed = unsafe { self.region.ring.push_raw(&item as *const T as *const u8) };
+        match pushed {
+            Ok(()) => {
+                self.region.header().data_ready.notify();
+                Ok(())
+            }
+            Err(_) => Err(PushError::new(RbufError::Full, item)),
+        }
+    }
+}
+
+// --- Consumer ---
+
+pub struct Consumer<T> {
+    region: Region<T>,
+}
+
+impl<T: ShmSafe + Copy> Consumer<T> {
+    // Create the ring with room for `capacity` items, rounded up to a power
+    // of two
+    pub fn create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        Ok(Self { region: Region::create(name, capacity)? })
+    }
+
+    pub fn open(name: &str) -> Result<Self, RbufError> {
+        Ok(Self { region: Region::open(name)? })
+    }
+
+    pub fn name(&self) -> &str {
+        self.region.segment.name()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.region.ring.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self.region.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.region.ring.is_empty()
+    }
+
+    // Number of handles, in any process, currently attached to the segment
+    pub fn attached(&self) -> usize {
+        self.region.header().attached.load(Ordering::Acquire) as usize
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.region.segment.set_owner(unlink);
+    }
+
+    // The only consumer of the ring, like `Producer::push`
+    pub fn pop(&mut self) -> Result<T, RbufError> {
+        let mut item = MaybeUninit::<T>::uninit();
+        unsafe { self.region.ring.pop_raw(item.as_mut_ptr() as *mut u8) }
+            .map_err(|_| RbufError::Empty)?;
+        self.region.header().space_ready.notify();
+        Ok(unsafe { item.assume_init() })
+    }
+}
@@ -0,0 +1,194 @@
+// registry.rs
+//
+// The consumer table that follows the header of typed, broadcast and MPMC
+// rings (where it holds the consumer group).
+// Each registered consumer owns one entry holding its PID, how far it has
+// read and when it last showed signs of life, so any process attached to the
+// ring can report lag and spot consumers that have stopped reading.
+//
+// [ header | max_consumers entries, one cache line each | data region ]
+use std::mem;
+use std::process;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::RbufError;
+use crate::header::{CachePadded, RingBufferHeader};
+use crate::peer;
+use crate::stats::{from_nanos, now_nanos};
+
+pub(crate) const ENTRY_FREE: u32 = 0;
+// Claimed, but the cursor isn't valid yet so everyone else ignores it
+pub(crate) const ENTRY_JOINING: u32 = 1;
+pub(crate) const ENTRY_ACTIVE: u32 = 2;
+// Pushed out by a publisher for falling behind: ignored like a free entry,
+// but only its owner frees it
+pub(crate) const ENTRY_EVICTED: u32 = 3;
+
+#[repr(C)]
+pub(crate) struct ConsumerEntry {
+    pub(crate) state: AtomicU32,
+    pub(crate) pid: AtomicU32,
+    // Sequence number of the next item this consumer will read
+    pub(crate) cursor: AtomicU64,
+    // See `stats::now_nanos`
+    pub(crate) heartbeat: AtomicU64,
+    // For MPMC group members, the position of the item they hold plus one,
+    // or 0 if they hold none
+    pub(crate) claim: AtomicU64,
+}
+
+const _: () = assert!(mem::size_of::<CachePadded<ConsumerEntry>>() == crate::header::CACHE_LINE);
+
+impl ConsumerEntry {
+    pub(crate) fn is_active(&self) -> bool {
+        self.state.load(Ordering::Acquire) == ENTRY_ACTIVE
+    }
+
+    pub(crate) fn is_evicted(&self) -> bool {
+        self.state.load(Ordering::Acquire) == ENTRY_EVICTED
+    }
+
+    // Returns false if the consumer left (or was evicted) first
+    pub(crate) fn evict(&self) -> bool {
+        let evicted = self.state.compare_exchange(
+            ENTRY_ACTIVE,
+            ENTRY_EVICTED,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+        evicted.is_ok()
+    }
+
+    pub(crate) fn beat(&self) {
+        self.heartbeat.store(now_nanos(), Ordering::Relaxed);
+    }
+}
+
+// A registered consumer, as seen from any process attached to the ring
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+    pub pid: u32,
+    pub cursor: u64,
+    // Items published that this consumer hasn't read yet
+    pub lag: u64,
+    pub last_heartbeat: SystemTime,
+    // Whether the process still exists; see `Peer::alive`
+    pub alive: bool,
+    // Whether a publisher evicted this subscriber for falling behind; it
+    // keeps the entry until it notices
+    pub evicted: bool,
+}
+
+// Where the table starts and how many bytes it takes
+pub(crate) fn table_offset() -> usize {
+    mem::size_of::<RingBufferHeader>()
+}
+
+pub(crate) fn table_size(max_consumers: usize) -> usize {
+    max_consumers * mem::size_of::<CachePadded<ConsumerEntry>>()
+}
+
+// A view of the table inside a mapped segment
+pub(crate) struct ConsumerTable {
+    entries: *const CachePadded<ConsumerEntry>,
+    len: usize,
+}
+
+unsafe impl Send for ConsumerTable {}
+unsafe impl Sync for ConsumerTable {}
+
+impl ConsumerTable {
+    /// # Safety
+    /// `base` must be the start of a mapped segment whose header says it has
+    /// `len` entries, and the view must not outlive the mapping.
+    pub(crate) unsafe fn new(base: *const u8, len: usize) -> Self {
+        Self { entries: base.add(table_offset()) as *const _, len }
+    }
+
+    pub(crate) fn entries(&self) -> &[CachePadded<ConsumerEntry>] {
+        unsafe { std::slice::from_raw_parts(self.entries, self.len) }
+    }
+
+    pub(crate) fn entry(&self, index: usize) -> &ConsumerEntry {
+        &self.entries()[index]
+    }
+
+    // Claim a free entry for this process and return its index. `cursor` is
+    // only asked for once the entry is ours, so it can't go stale while we
+    // search; the entry turns active after it is stored.
+    pub(crate) fn register(&self, cursor: impl FnOnce() -> u64) -> Result<usize, RbufError> {
+        let index = self
+            .entries()
+            .iter()
+            .position(|entry| {
+                let claimed = entry.state.compare_exchange(
+                    ENTRY_FREE,
+                    ENTRY_JOINING,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                );
+                claimed.is_ok()
+            })
+            .ok_or(RbufError::ConsumerTableFull)?;
+
+        let entry = self.entry(index);
+        entry.pid.store(process::id(), Ordering::Relaxed);
+        entry.beat();
+        entry.claim.store(0, Ordering::Relaxed);
+        entry.cursor.store(cursor(), Ordering::Release);
+        entry.state.store(ENTRY_ACTIVE, Ordering::SeqCst);
+        Ok(index)
+    }
+
+    pub(crate) fn unregister(&self, index: usize) {
+        let entry = self.entry(index);
+        entry.pid.store(0, Ordering::Relaxed);
+        entry.state.store(ENTRY_FREE, Ordering::Release);
+    }
+
+    // Free the entry of a consumer whose process `pid` is gone (0 for one
+    // that left), unless it still holds a claim or somebody else got to it
+    // first. Returns whether we freed it.
+    pub(crate) fn release_dead(&self, index: usize, pid: u32) -> bool {
+        let entry = self.entry(index);
+        // JOINING locks the entry against other reapers, and a new consumer
+        // can't take it over before we're done
+        let locked = entry.state.compare_exchange(
+            ENTRY_ACTIVE,
+            ENTRY_JOINING,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+        if locked.is_err() {
+            return false;
+        }
+        if entry.pid.load(Ordering::Relaxed) != pid || entry.claim.load(Ordering::Acquire) != 0 {
+            entry.state.store(ENTRY_ACTIVE, Ordering::Release);
+            return false;
+        }
+        self.unregister(index);
+        true
+    }
+
+    // Every active or evicted entry, with its lag measured against `tail`
+    pub(crate) fn registrations(&self, tail: u64) -> Vec<Registration> {
+        self.entries()
+            .iter()
+            .filter(|entry| entry.is_active() || entry.is_evicted())
+            .map(|entry| {
+                let pid = entry.pid.load(Ordering::Relaxed);
+                let cursor = entry.cursor.load(Ordering::Acquire);
+                let heartbeat = entry.heartbeat.load(Ordering::Relaxed);
+                Registration {
+                    pid,
+                    cursor,
+                    lag: tail.saturating_sub(cursor),
+                    last_heartbeat: from_nanos(heartbeat).unwrap_or(UNIX_EPOCH),
+                    alive: peer::process_alive(pid),
+                    evicted: entry.is_evicted(),
+                }
+            })
+            .collect()
+    }
+}
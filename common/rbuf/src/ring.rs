@@ -0,0 +1,972 @@
+// ring.rs
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+#[cfg(unix)]
+use std::os::unix::io::BorrowedFd;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::broadcast;
+use crate::checksum::crc32;
+use crate::directory;
+use crate::error::RbufError;
+use crate::header::{RingBufferHeader, RingKind, Role, OPTION_CHECKSUMS, OPTION_SPLIT_DATA};
+use crate::header::{NO_CREDIT_LIMIT, OPTION_MIRRORED, OPTION_TIMESTAMPS};
+use crate::latency::{self, LatencyHistogram, SharedHistogram};
+use crate::notify::Notifier;
+use crate::rate::RateLimit;
+use crate::registry::{self, ConsumerTable};
+use crate::segment::{self, Backing, Mirror, Segment, SegmentConfig};
+use crate::slots;
+
+// `head` and `tail` are free-running 64-bit sequence numbers: `tail` counts
+// every slot ever claimed and `head` every slot ever released, so the ring
+// holds `tail - head` items and is full once that reaches the capacity. The
+// slot for sequence `seq` is `seq & mask`, with the slot count rounded up to a
+// power of two so that no division is needed.
+//
+// Per-slot commit flags. A producer reserves a slot by moving `tail` past it,
+// writes the item, and only then marks the slot COMMITTED so the consumer
+// never reads a slot that another producer is still filling. A reservation
+// that is abandoned is marked ABORTED and skipped by the consumer.
+// The handover itself is in slots.rs.
+pub const SLOT_EMPTY: u32 = 0;
+pub const SLOT_COMMITTED: u32 = 1;
+pub const SLOT_ABORTED: u32 = 2;
+
+// How long open_or_create keeps retrying while another process is creating
+const OPEN_OR_CREATE_WAIT: Duration = Duration::from_secs(1);
+
+// Slots backing a ring of `capacity` items
+pub(crate) fn slot_count(capacity: usize) -> usize {
+    capacity.next_power_of_two()
+}
+
+// What a new typed ring looks like, besides its element type
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RingSpec {
+    pub(crate) capacity: usize,
+    pub(crate) max_consumers: usize,
+    // `OPTION_*` flags
+    pub(crate) options: u64,
+    pub(crate) notifier: Notifier,
+    // Fingerprint of the item type, checked by everyone who attaches
+    pub(crate) schema: u64,
+}
+
+// Where everything lives inside the segment:
+// [ header | consumer table | commit flags (one AtomicU32 per slot) |
+//   checksums (one u32 per slot, if enabled) |
+//   timestamps (one u64 per slot) and latency histogram, if enabled |
+//   padding | slots ]
+//
+// The slots of a mirrored ring start on a page boundary and fill whole
+// pages, and every handle maps them a second time (see `Mirror`): twice in a
+// row, so that any run of slots is one slice, wrapping or not.
+pub(crate) struct SegmentLayout {
+    pub(crate) flags_offset: usize,
+    pub(crate) checksums_offset: Option<usize>,
+    pub(crate) timestamps_offset: Option<usize>,
+    pub(crate) histogram_offset: Option<usize>,
+    pub(crate) buffer_offset: usize,
+    pub(crate) size: usize,
+}
+
+impl SegmentLayout {
+    // Everything the layout depends on is in the header, so tools that don't
+    // know `T` can find their way around too
+    pub(crate) fn of(header: &RingBufferHeader) -> Self {
+        let slots = slot_count(header.capacity());
+        let elem_align = header.elem_align();
+        let flags_offset = registry::table_offset() + registry::table_size(header.max_consumers());
+        let mut end = flags_offset + slots * mem::size_of::<AtomicU32>();
+        let checksums_offset = header.options.has(OPTION_CHECKSUMS).then(|| {
+            let offset = end;
+            end += slots * mem::size_of::<AtomicU32>();
+            offset
+        });
+        let (timestamps_offset, histogram_offset) = if header.options.has(OPTION_TIMESTAMPS) {
+            let timestamps = (end + 7) & !7;
+            let histogram = timestamps + slots * mem::size_of::<AtomicU64>();
+            end = histogram + mem::size_of::<SharedHistogram>();
+            (Some(timestamps), Some(histogram))
+        } else {
+            (None, None)
+        };
+        let align = if header.options.has(OPTION_MIRRORED) {
+            segment::page_size().max(elem_align)
+        } else {
+            elem_align
+        };
+        let buffer_offset = (end + align - 1) & !(align - 1);
+        let size = buffer_offset + slots * header.elem_size();
+        Self {
+            flags_offset,
+            checksums_offset,
+            timestamps_offset,
+            histogram_offset,
+            buffer_offset,
+            size,
+        }
+    }
+}
+
+// A handle that gives safe access to the shared memory region
+pub(crate) struct ShmemRingBuffer<T> {
+    segment: Box<dyn Segment>,
+    header: *const RingBufferHeader,
+    flags: *const AtomicU32,
+    // Null unless the ring was created with checksums
+    checksums: *const AtomicU32,
+    // Both null unless the ring was created with timestamps
+    timestamps: *const AtomicU64,
+    histogram: *const SharedHistogram,
+    // Into `mirror` instead of the segment on mirrored rings
+    buffer: *mut UnsafeCell<MaybeUninit<T>>,
+    mirror: Option<Mirror>,
+    mask: u64,
+    role: Role,
+    consumers: ConsumerTable,
+    // Where to look the segment up by name again, for `ping` and `grow`
+    reopen: SegmentConfig,
+    // Whether dropping the handle unlinks the segment
+    owner: bool,
+    flush: FlushPolicy,
+    _phantom: PhantomData<T>,
+}
+
+// This is unsafe because we are dealing with raw pointers and shared memory.
+// The user of this module must ensure that access is properly synchronized.
+unsafe impl<T: Send> Send for ShmemRingBuffer<T> {}
+unsafe impl<T: Sync> Sync for ShmemRingBuffer<T> {}
+
+impl<T> ShmemRingBuffer<T> {
+    // Create and initialize a segment laid out as `spec` says, for a handle
+    // playing `role`
+    pub(crate) fn create(
+        config: &SegmentConfig,
+        name: &str,
+        spec: &RingSpec,
+        role: Role,
+    ) -> Result<Self, RbufError> {
+        Self::create_with(config, name, spec, role, |_| {})
+    }
+
+    // `create`, with `setup` getting to change the header before anyone
+    // can attach
+    fn create_with(
+        config: &SegmentConfig,
+        name: &str,
+        spec: &RingSpec,
+        role: Role,
+        setup: impl FnOnce(&RingBufferHeader),
+    ) -> Result<Self, RbufError> {
+        let capacity = spec.capacity.max(1);
+        let header = RingBufferHeader::new(
+            RingKind::Typed,
+            mem::size_of::<T>(),
+            mem::align_of::<T>(),
+            capacity,
+        )
+        .with_max_consumers(spec.max_consumers)
+        .with_option(OPTION_CHECKSUMS, spec.options & OPTION_CHECKSUMS != 0)
+        .with_option(OPTION_TIMESTAMPS, spec.options & OPTION_TIMESTAMPS != 0)
+        .with_option(OPTION_MIRRORED, spec.options & OPTION_MIRRORED != 0)
+        .with_notifier(spec.notifier)
+        .with_schema(spec.schema)
+        .with_creator(role);
+        let layout = SegmentLayout::of(&header);
+
+        let slot_bytes = slot_count(capacity) * mem::size_of::<T>();
+        if header.options.has(OPTION_MIRRORED) && !slot_bytes.is_multiple_of(segment::page_size()) {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "a mirrored ring's slots must fill whole pages of {} bytes, not {}",
+                segment::page_size(),
+                slot_bytes
+            )));
+        }
+
+        let segment = config.create(name, layout.size)?;
+
+        // Initialize the header in the shared memory
+        let header = unsafe {
+            let header = RingBufferHeader::initialize(segment.as_ptr(), header);
+            // Free table entries, empty commit flags, checksums, timestamps
+            // and histogram counts are all zeroes
+            std::ptr::write_bytes(
+                segment.as_ptr().add(registry::table_offset()),
+                0,
+                layout.buffer_offset - registry::table_offset(),
+            );
+            header
+        };
+        // Before publishing, so that nobody sees a ring that failed to mirror
+        let mirror = Self::mirror(&*segment)?;
+        setup(header);
+        header.publish();
+        // Other backings' names mean nothing to `Backing::Shm.open`
+        if matches!(config.backing, Backing::Shm) && config.huge_pages.is_none() {
+            directory::register(name, header);
+        }
+
+        event!(debug, ring = name, ?role, capacity, bytes = layout.size, "created ring");
+        let mut rb = Self::from_segment(segment, mirror, role, config);
+        rb.owner = true;
+        Ok(rb)
+    }
+
+    // Create the segment, or attach to it if another process beat us to it.
+    // Openers wait for the header to be published, so neither side ever sees
+    // a half-initialized ring.
+    pub(crate) fn open_or_create(
+        config: &SegmentConfig,
+        name: &str,
+        spec: &RingSpec,
+        role: Role,
+    ) -> Result<Self, RbufError> {
+        let deadline = Instant::now() + OPEN_OR_CREATE_WAIT;
+        loop {
+            match Self::create(config, name, spec, role) {
+                Err(e) if segment::already_exists(&e) => {}
+                result => return result,
+            }
+            match Self::open(config, name, role, spec.schema) {
+                // The creator hasn't sized the segment yet, or it was just
+                // unlinked; either way try again from the top
+                Err(RbufError::ShmemOpen(_))
+                | Err(RbufError::FileOpen(_))
+                | Err(RbufError::HeapOpen(_))
+                | Err(RbufError::Backend(_))
+                | Err(RbufError::SizeMismatch { .. })
+                    if Instant::now() < deadline =>
+                {
+                    thread::sleep(Duration::from_micros(100));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Attach to an existing segment, which has to hold items of `schema`
+    pub(crate) fn open(
+        config: &SegmentConfig,
+        name: &str,
+        role: Role,
+        schema: u64,
+    ) -> Result<Self, RbufError> {
+        Self::attach(config.open(name)?, role, schema, config)
+    }
+
+    // The second mapping of a mirrored ring's slots, or None for other rings
+    fn mirror(segment: &dyn Segment) -> Result<Option<Mirror>, RbufError> {
+        let header = unsafe { &*(segment.as_ptr() as *const RingBufferHeader) };
+        if !header.options.has(OPTION_MIRRORED) {
+            return Ok(None);
+        }
+        let len = slot_count(header.capacity()) * header.elem_size();
+        Mirror::new(segment, SegmentLayout::of(header).buffer_offset, len).map(Some)
+    }
+
+    // The header must already be initialized: the layout it describes
+    // determines where the flags and slots are.
+    fn from_segment(
+        segment: Box<dyn Segment>,
+        mirror: Option<Mirror>,
+        role: Role,
+        config: &SegmentConfig,
+    ) -> Self {
+        let header = segment.as_ptr() as *const RingBufferHeader;
+        let layout = SegmentLayout::of(unsafe { &*header });
+        let (slots, max_consumers) =
+            unsafe { (slot_count((*header).capacity()), (*header).max_consumers()) };
+        let consumers = unsafe { ConsumerTable::new(segment.as_ptr(), max_consumers) };
+        let flags = unsafe { segment.as_ptr().add(layout.flags_offset) } as *const AtomicU32;
+        let checksums = match layout.checksums_offset {
+            Some(offset) => unsafe { segment.as_ptr().add(offset) as *const AtomicU32 },
+            None => std::ptr::null(),
+        };
+        let timestamps = match layout.timestamps_offset {
+            Some(offset) => unsafe { segment.as_ptr().add(offset) as *const AtomicU64 },
+            None => std::ptr::null(),
+        };
+        let histogram = match layout.histogram_offset {
+            Some(offset) => unsafe { segment.as_ptr().add(offset) as *const SharedHistogram },
+            None => std::ptr::null(),
+        };
+        let buffer = match &mirror {
+            Some(mirror) => mirror.as_ptr(),
+            None => unsafe { segment.as_ptr().add(layout.buffer_offset) },
+        } as *mut UnsafeCell<MaybeUninit<T>>;
+
+        unsafe {
+            (*header).attached.fetch_add(1, Ordering::AcqRel);
+            (*header).roles.attach(role);
+        }
+        let mask = slots as u64 - 1;
+        let reopen = SegmentConfig {
+            backing: config.backing.clone(),
+            huge_pages: config.huge_pages,
+            ..Default::default()
+        };
+        Self {
+            segment,
+            header,
+            flags,
+            checksums,
+            timestamps,
+            histogram,
+            buffer,
+            mirror,
+            mask,
+            role,
+            consumers,
+            reopen,
+            owner: false,
+            flush: FlushPolicy::None,
+            _phantom: PhantomData,
+        }
+    }
+
+    // Attach to a segment created by someone else, checking that its header
+    // matches `T` and that it is big enough for the layout it describes.
+    fn attach(
+        segment: Box<dyn Segment>,
+        role: Role,
+        schema: u64,
+        config: &SegmentConfig,
+    ) -> Result<Self, RbufError> {
+        let header = RingBufferHeader::validate(
+            segment.as_ptr(),
+            segment.len(),
+            RingKind::Typed,
+            mem::size_of::<T>(),
+            mem::align_of::<T>(),
+        )?;
+        header.check_schema(schema)?;
+
+        let layout = SegmentLayout::of(header);
+        if segment.len() < layout.size {
+            return Err(RbufError::SizeMismatch { expected: layout.size, actual: segment.len() });
+        }
+
+        let mirror = Self::mirror(&*segment)?;
+        event!(debug, ring = segment.name(), ?role, capacity = header.capacity(), "opened ring");
+        Ok(Self::from_segment(segment, mirror, role, config))
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        self.segment.name()
+    }
+
+    pub(crate) fn set_owner(&mut self, owner: bool) {
+        self.segment.set_owner(owner);
+        self.owner = owner;
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn fd(&self) -> Option<BorrowedFd<'_>> {
+        self.segment.fd()
+    }
+
+    pub(crate) fn header(&self) -> &RingBufferHeader {
+        unsafe { &*self.header }
+    }
+
+    // Fail with `StaleSegment` if the segment was unlinked, or if looking
+    // the name up again finds some other segment. Segments without a name
+    // to look up (memfds, passed descriptors) can only be caught by the
+    // former.
+    pub(crate) fn ping(&self) -> Result<(), RbufError> {
+        let header = self.header();
+        if header.is_retired() {
+            return Err(RbufError::StaleSegment);
+        }
+        match &self.reopen.backing {
+            #[cfg(target_os = "linux")]
+            Backing::Memfd => return Ok(()),
+            #[cfg(unix)]
+            Backing::Fd(_) => return Ok(()),
+            _ => {}
+        }
+        let current = self.reopen.open(self.name()).map_err(|_| RbufError::StaleSegment)?;
+        if current.len() < mem::size_of::<RingBufferHeader>() {
+            return Err(RbufError::StaleSegment);
+        }
+        let other = unsafe { &*(current.as_ptr() as *const RingBufferHeader) };
+        if !header.is_same_segment(other) {
+            return Err(RbufError::StaleSegment);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.header().capacity()
+    }
+
+    pub(crate) fn consumers(&self) -> &ConsumerTable {
+        &self.consumers
+    }
+
+    // Slots between head and tail
+    pub(crate) fn len(&self) -> usize {
+        let header = self.header();
+        let head = header.head.load(Ordering::Acquire);
+        let tail = header.tail.load(Ordering::Acquire);
+        // The two loads aren't one snapshot; keep the answer in range
+        (tail.saturating_sub(head) as usize).min(header.capacity())
+    }
+
+    pub(crate) fn slot_flag(&self, seq: u64) -> &AtomicU32 {
+        unsafe { &*self.flags.add((seq & self.mask) as usize) }
+    }
+
+    // How many slots from `seq` on can be reached as one slice: any number
+    // on a mirrored ring, otherwise those up to the end of the buffer
+    pub(crate) fn contiguous(&self, seq: u64) -> usize {
+        match self.mirror {
+            Some(_) => usize::MAX,
+            None => (self.mask + 1 - (seq & self.mask)) as usize,
+        }
+    }
+
+    // The first commit flag and the first slot, for handles that index them
+    // with a mask known at compile time
+    pub(crate) fn slot_bases(&self) -> (*const AtomicU32, *mut T) {
+        (self.flags, self.buffer as *mut T)
+    }
+
+    pub(crate) fn buffer_ptr(&self, seq: u64) -> *mut T {
+        unsafe {
+            let cell_ptr = self.buffer.add((seq & self.mask) as usize);
+            (*cell_ptr).get() as *mut T
+        }
+    }
+
+    fn slot_crc(&self, seq: u64) -> u32 {
+        let bytes = self.buffer_ptr(seq) as *const u8;
+        crc32(unsafe { std::slice::from_raw_parts(bytes, mem::size_of::<T>()) })
+    }
+
+    fn checksum(&self, seq: u64) -> Option<&AtomicU32> {
+        if self.checksums.is_null() {
+            return None;
+        }
+        Some(unsafe { &*self.checksums.add((seq & self.mask) as usize) })
+    }
+
+    // Record the checksum of a slot the caller has just written, before it is
+    // committed. A no-op on rings without checksums.
+    pub(crate) fn seal(&self, seq: u64) {
+        if let Some(checksum) = self.checksum(seq) {
+            checksum.store(self.slot_crc(seq), Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn has_timestamps(&self) -> bool {
+        !self.timestamps.is_null()
+    }
+
+    // Stamp slots the caller has just written with `now`, before they are
+    // committed. A no-op on rings without timestamps.
+    pub(crate) fn stamp(&self, seq: u64, now: u64) {
+        if self.has_timestamps() {
+            let slot = unsafe { &*self.timestamps.add((seq & self.mask) as usize) };
+            slot.store(now, Ordering::Relaxed);
+        }
+    }
+
+    // How long the committed slot `seq` has been waiting at `now`. None on
+    // rings without timestamps.
+    pub(crate) fn waited(&self, seq: u64, now: u64) -> Option<Duration> {
+        if !self.has_timestamps() {
+            return None;
+        }
+        let slot = unsafe { &*self.timestamps.add((seq & self.mask) as usize) };
+        Some(Duration::from_nanos(now.saturating_sub(slot.load(Ordering::Relaxed))))
+    }
+
+    // Add a popped item's `waited` to the histogram
+    pub(crate) fn record_latency(&self, waited: Option<Duration>) {
+        if let Some(waited) = waited {
+            unsafe { (*self.histogram).record(waited.as_nanos() as u64) };
+        }
+    }
+
+    // The clock reading to pass to `stamp` and `record_latency`, or 0 when
+    // the ring has no timestamps and it would be wasted
+    pub(crate) fn now(&self) -> u64 {
+        if self.has_timestamps() {
+            latency::now_monotonic()
+        } else {
+            0
+        }
+    }
+
+    pub(crate) fn latency(&self) -> Option<LatencyHistogram> {
+        self.has_timestamps().then(|| unsafe { (*self.histogram).snapshot() })
+    }
+
+    // Check a committed slot against the checksum its producer recorded
+    pub(crate) fn verify(&self, seq: u64) -> Result<(), RbufError> {
+        match self.checksum(seq) {
+            Some(checksum) if checksum.load(Ordering::Relaxed) != self.slot_crc(seq) => {
+                Err(RbufError::CorruptMessage { seq })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// What the consistency scan on attach threw away
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Recovery {
+    // Uncommitted slots at the end of the ring, given back by moving `tail`
+    pub rolled_back: usize,
+    // Uncommitted slots further in, marked aborted so the consumer skips them
+    pub aborted: usize,
+    // Whether the scan was left undone because a producer that may still be
+    // writing its slots is attached
+    pub skipped: bool,
+}
+
+impl<T> ShmemRingBuffer<T> {
+    // A producer that dies after claiming slots but before committing them,
+    // or a consumer that dies between clearing flags and moving `head`,
+    // leaves slots between `head` and `tail` that will never be committed and
+    // would stall the consumer forever. Give them up, but only once every
+    // attached producer is known to be dead: until then they may just be
+    // mid-write. Only the last producer to beat is recorded, so that's only
+    // known with at most one of them attached.
+    pub(crate) fn recover(&self) -> Recovery {
+        let header = self.header();
+        let mut recovery = Recovery::default();
+        let dead = match header.roles.attached(Role::Producer) {
+            0 => true,
+            1 => header.roles.peer(Role::Producer).is_some_and(|peer| !peer.alive),
+            _ => false,
+        };
+        if !dead {
+            recovery.skipped = true;
+            return recovery;
+        }
+
+        let tail = header.tail.load(Ordering::Acquire);
+        // Only the last lap of slots can be pending, however far behind a
+        // lapped (or corrupt) `head` says the consumer is
+        let head = header.head.load(Ordering::Acquire).max(tail.saturating_sub(self.mask + 1));
+        let uncommitted = |seq| self.slot_flag(seq).load(Ordering::Acquire) == SLOT_EMPTY;
+
+        // Uncommitted slots at the end can simply be unclaimed, unless a new
+        // producer has claimed past them meanwhile
+        let mut end = tail;
+        while end > head && uncommitted(end - 1) {
+            end -= 1;
+        }
+        if end != tail
+            && header.tail.compare_exchange(tail, end, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+        {
+            recovery.rolled_back = (tail - end) as usize;
+        } else {
+            end = tail;
+        }
+
+        for seq in head..end {
+            if uncommitted(seq) {
+                self.slot_flag(seq).store(SLOT_ABORTED, Ordering::Release);
+                recovery.aborted += 1;
+            }
+        }
+
+        if recovery.rolled_back + recovery.aborted > 0 {
+            event!(
+                warn,
+                ring = self.name(),
+                rolled_back = recovery.rolled_back,
+                aborted = recovery.aborted,
+                "gave up slots left uncommitted by a crashed peer"
+            );
+            header.space_ready.notify();
+            header.data_ready.notify();
+        }
+        recovery
+    }
+
+    // Drop the items still in the ring when the last handle is about to take
+    // the segment with it, so that nobody could ever pop them. Slots that
+    // were never committed hold nothing to drop.
+    fn drop_leftovers(&self) {
+        let header = self.header();
+        let tail = header.tail.load(Ordering::Acquire);
+        // Overwriting producers may have lapped a consumer that went away
+        let start = header.head.load(Ordering::Acquire).max(tail.saturating_sub(self.mask + 1));
+        for seq in start..tail {
+            let flag = self.slot_flag(seq);
+            if flag.load(Ordering::Acquire) == SLOT_COMMITTED {
+                unsafe { std::ptr::drop_in_place(self.buffer_ptr(seq)) };
+            }
+            flag.store(SLOT_EMPTY, Ordering::Relaxed);
+        }
+        header.head.store(tail, Ordering::Release);
+        event!(debug, ring = self.name(), slots = tail - start, "dropped undelivered items");
+    }
+}
+
+// --- Growing ---
+
+// How long `grow` waits for producers to finish writing slots they claimed
+// in the old segment before it gives up on them as crashed
+const MIGRATE_WAIT: Duration = Duration::from_secs(1);
+
+impl<T> ShmemRingBuffer<T> {
+    // Move the ring into a new segment of `capacity` items under the same
+    // name, and return a handle on it:
+    //
+    //  1. unlink the name and create the new segment under it, with our
+    //     options and control settings and the first `capacity()` slots
+    //     held back for the items still in this one
+    //  2. mark this segment MIGRATED, which sends its producers over to
+    //     whatever the name leads to now
+    //  3. move the items between `head` and `tail` as of the notice into the
+    //     held-back slots, waiting for producers to finish writing theirs
+    //
+    // A producer that claims slots here and only then sees the notice aborts
+    // them and pushes again in the new segment. Both the notice and the
+    // claim are SeqCst, so either we see the claim in `tail` and wait for
+    // it, or the producer sees the notice; items keep their order either
+    // way. This segment goes once the last handle mapping it does.
+    pub(crate) fn grow(&self, capacity: usize) -> Result<Self, RbufError> {
+        let header = self.header();
+        let held = header.capacity();
+        if capacity <= held {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "a ring of {} items can only grow, not become {}",
+                held, capacity
+            )));
+        }
+        let unnamed = match &self.reopen.backing {
+            #[cfg(target_os = "linux")]
+            Backing::Memfd => true,
+            #[cfg(unix)]
+            Backing::Fd(_) => true,
+            _ => false,
+        };
+        if unnamed {
+            return Err(RbufError::IncompatibleLayout("a ring without a name can't grow".into()));
+        }
+        // Don't unlink a name that already leads somewhere else
+        self.ping()?;
+
+        let options = OPTION_CHECKSUMS | OPTION_TIMESTAMPS | OPTION_MIRRORED;
+        let spec = RingSpec {
+            capacity,
+            max_consumers: header.max_consumers(),
+            options: header.options.flags & options,
+            notifier: header.data_ready.notifier(),
+            schema: header.options.schema,
+        };
+        // Dropping an owning mapping unlinks it
+        let mut old = self.reopen.open(self.name())?;
+        directory::unregister(self.name());
+        old.set_owner(true);
+        drop(old);
+        let control = &header.control;
+        let mut next = Self::create_with(&self.reopen, self.name(), &spec, self.role, |new| {
+            new.tail.store(held as u64, Ordering::Relaxed);
+            RateLimit::load(control).store(&new.control);
+            let paused = control.paused.load(Ordering::Acquire);
+            new.control.paused.store(paused, Ordering::Relaxed);
+            let log_level = control.log_level.load(Ordering::Acquire);
+            new.control.log_level.store(log_level, Ordering::Relaxed);
+            // No credit until we know how much this segment's producers used
+            if control.credit_limit.load(Ordering::Acquire) != NO_CREDIT_LIMIT {
+                new.control.credit_limit.store(held as u64, Ordering::Relaxed);
+            }
+        })?;
+        next.set_owner(self.owner);
+        next.flush = self.flush;
+
+        header.migrate();
+        // Wake producers waiting here, so they move over
+        header.space_ready.notify();
+        header.data_ready.notify();
+        let end = header.tail.load(Ordering::SeqCst);
+        let (moved, lost) = self.move_items(&next, end);
+        for seq in moved..held as u64 {
+            next.slot_flag(seq).store(SLOT_ABORTED, Ordering::Release);
+        }
+        next.flush_slots(0, held);
+        if let Some(credits) = control.credits(end) {
+            let limit = (held as u64).saturating_add(credits).min(NO_CREDIT_LIMIT - 1);
+            next.header().control.credit_limit.store(limit, Ordering::Release);
+            next.header().space_ready.notify();
+        }
+        next.header().data_ready.notify();
+
+        if lost > 0 {
+            event!(warn, ring = self.name(), lost, "gave up slots never finished while growing");
+        }
+        event!(debug, ring = self.name(), from = held, to = capacity, moved, "grew ring");
+        Ok(next)
+    }
+
+    // Move the items up to `end` into `next`'s first slots, returning how
+    // many were moved and how many slots were given up as never committed
+    fn move_items(&self, next: &Self, end: u64) -> (u64, usize) {
+        let header = self.header();
+        let deadline = Instant::now() + MIGRATE_WAIT;
+        let (mut moved, mut lost) = (0, 0);
+        loop {
+            let head = header.head.load(Ordering::Acquire);
+            if head >= end {
+                return (moved, lost);
+            }
+            let flag = self.slot_flag(head);
+            let state = flag.load(Ordering::Acquire);
+            if state == SLOT_EMPTY && Instant::now() < deadline {
+                thread::yield_now();
+                continue;
+            }
+            // Overwriting producers may drop the slot meanwhile; the copy is
+            // only kept if our CAS on `head` wins, as in `pop_contended`
+            let item = (state == SLOT_COMMITTED).then(|| unsafe { self.buffer_ptr(head).read() });
+            let verified = self.verify(head);
+            let stamp = self.stamp_of(head);
+            if !slots::take(&*header.head, flag, head, state) {
+                mem::forget(item);
+                continue;
+            }
+            match item {
+                Some(item) if verified.is_ok() => {
+                    unsafe { next.buffer_ptr(moved).write(item) };
+                    next.seal(moved);
+                    next.stamp(moved, stamp);
+                    next.slot_flag(moved).store(SLOT_COMMITTED, Ordering::Release);
+                    moved += 1;
+                }
+                Some(item) => {
+                    mem::forget(item);
+                    header.consumer_stats.record_corrupt();
+                    event!(warn, ring = self.name(), seq = head, "discarded corrupt item");
+                }
+                None if state == SLOT_EMPTY => lost += 1,
+                None => {}
+            }
+        }
+    }
+
+    fn stamp_of(&self, seq: u64) -> u64 {
+        if !self.has_timestamps() {
+            return 0;
+        }
+        let slot = unsafe { &*self.timestamps.add((seq & self.mask) as usize) };
+        slot.load(Ordering::Relaxed)
+    }
+
+    // A handle on the segment that took this one's name over in `grow`,
+    // for a producer to move to
+    pub(crate) fn successor(&self) -> Result<Self, RbufError> {
+        let schema = self.header().options.schema;
+        let deadline = Instant::now() + OPEN_OR_CREATE_WAIT;
+        loop {
+            match Self::open(&self.reopen, self.name(), self.role, schema) {
+                // Grown again since, and the newest isn't there yet
+                Err(RbufError::StaleSegment) if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_micros(100));
+                }
+                Ok(mut next) => {
+                    next.flush = self.flush;
+                    return Ok(next);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// --- Journaling ---
+
+// How soon pushes and pops on a `Backing::File` ring reach the disk. Rings
+// that only live in memory have nowhere to write back to and ignore it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    // Leave it to the OS: whatever was pushed or popped survives any
+    // process crashing, but not the machine
+    #[default]
+    None,
+    // Start writing back every push and pop, without waiting for the disk
+    Async,
+    // Write back every push (every batch, for `push_slice` and slice
+    // guards) and every pop before returning, so whatever returned survives
+    // a power cut
+    FsyncPerBatch,
+}
+
+impl<T> ShmemRingBuffer<T> {
+    pub(crate) fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.flush = policy;
+    }
+
+    // Write back `count` slots from `start` on, with their flags, checksums
+    // and timestamps, and the header that counts them
+    pub(crate) fn flush_slots(&self, start: u64, count: usize) {
+        if self.flush == FlushPolicy::None {
+            return;
+        }
+        let header = self.header();
+        let layout = SegmentLayout::of(header);
+        let slots = self.mask as usize + 1;
+        let first = (start & self.mask) as usize;
+        // The run may wrap past the last slot
+        let runs = [(first, count.min(slots - first)), (0, count.saturating_sub(slots - first))];
+        for (index, n) in runs.into_iter().filter(|&(_, n)| n > 0) {
+            let flag = mem::size_of::<AtomicU32>();
+            self.flush_range(layout.flags_offset + index * flag, n * flag);
+            if let Some(offset) = layout.checksums_offset {
+                self.flush_range(offset + index * flag, n * flag);
+            }
+            if let Some(offset) = layout.timestamps_offset {
+                let stamp = mem::size_of::<AtomicU64>();
+                self.flush_range(offset + index * stamp, n * stamp);
+            }
+            let elem_size = header.elem_size();
+            self.flush_range(layout.buffer_offset + index * elem_size, n * elem_size);
+        }
+        self.flush_range(0, mem::size_of::<RingBufferHeader>());
+    }
+
+    // Write back `head` and the consumer table's cursors
+    pub(crate) fn flush_head(&self) {
+        if self.flush == FlushPolicy::None {
+            return;
+        }
+        let table = registry::table_size(self.header().max_consumers());
+        self.flush_range(0, registry::table_offset() + table);
+    }
+
+    fn flush_range(&self, offset: usize, len: usize) {
+        let wait = self.flush == FlushPolicy::FsyncPerBatch;
+        if let Err(_e) = self.segment.flush(offset, len, wait) {
+            event!(warn, ring = self.name(), error = %_e, "failed to write back the ring");
+        }
+    }
+}
+
+// The latency histogram of a typed ring, for tools that don't know `T`
+pub(crate) fn latency_raw(
+    base: *const u8,
+    len: usize,
+    header: &RingBufferHeader,
+) -> Option<LatencyHistogram> {
+    let layout = SegmentLayout::of(header);
+    if len < layout.size {
+        return None;
+    }
+    let histogram = unsafe { &*(base.add(layout.histogram_offset?) as *const SharedHistogram) };
+    Some(histogram.snapshot())
+}
+
+// Pop every committed item as raw bytes, for tools that don't know `T`.
+// Copes with overwriting producers but not with a live consumer.
+pub(crate) fn drain_raw(
+    base: *const u8,
+    header: &RingBufferHeader,
+    f: &mut dyn FnMut(&[u8]),
+) -> usize {
+    let slots = slot_count(header.capacity());
+    let layout = SegmentLayout::of(header);
+    let mask = slots as u64 - 1;
+    let flags = unsafe { base.add(layout.flags_offset) } as *const AtomicU32;
+    let mut item = vec![0; header.elem_size()];
+    let mut drained = 0;
+    let flag = |head: u64| unsafe { &*flags.add((head & mask) as usize) };
+    loop {
+        let copy = |head: u64| {
+            let index = (head & mask) as usize;
+            let slot = unsafe { base.add(layout.buffer_offset + index * header.elem_size()) };
+            unsafe { std::ptr::copy_nonoverlapping(slot, item.as_mut_ptr(), header.elem_size()) };
+        };
+        let Some((_, copied)) = slots::pop(&*header.head, &*header.tail, flag, copy) else {
+            return drained;
+        };
+        header.space_ready.notify();
+        if copied.is_some() {
+            header.consumer_stats.record_pop(1);
+            f(&item);
+            drained += 1;
+        }
+    }
+}
+
+impl<T> Drop for ShmemRingBuffer<T> {
+    fn drop(&mut self) {
+        // The name belongs to the segment that replaced this one
+        if self.header().is_migrated() {
+            self.segment.set_owner(false);
+        }
+        self.header().roles.detach(self.role);
+        if mem::needs_drop::<T>()
+            && self.header().attached.load(Ordering::Acquire) == 1
+            && !self.segment.outlives_handles()
+        {
+            self.drop_leftovers();
+        }
+        self.header().detach();
+        event!(
+            debug,
+            ring = self.name(),
+            role = ?self.role,
+            attached = self.header().attached.load(Ordering::Relaxed),
+            "detached from ring"
+        );
+    }
+}
+
+// --- Segment management ---
+
+// Operations on a ring by name, without attaching a typed handle to it
+pub enum RingBuffer {}
+
+impl RingBuffer {
+    // Remove the named segment from the system, e.g. one left behind by a
+    // crashed creator. Processes that still have it mapped keep working,
+    // but their handles' `ping` fails with `StaleSegment`; nobody new can
+    // open it.
+    pub fn unlink(name: &str) -> Result<(), RbufError> {
+        let mut segment = Backing::Shm.open(name)?;
+        directory::unregister(name);
+        // Dropping an owning mapping unlinks it
+        segment.set_owner(true);
+        let header = RingBufferHeader::validate_any(segment.as_ptr(), segment.len()).ok();
+        // Handles still mapping it find out on their next ping
+        if let Some(header) = header {
+            header.retire();
+        }
+        // The slots of a split broadcast ring go with it
+        let split = header.is_some_and(|header| header.options.has(OPTION_SPLIT_DATA));
+        if split {
+            if let Ok(mut data) = Backing::Shm.open(&broadcast::data_name(name)) {
+                data.set_owner(true);
+            }
+        }
+        Ok(())
+    }
+
+    // Hold every producer on the named typed ring to `limit`, from their
+    // next push on, without attaching to the ring. Fails with
+    // `IncompatibleLayout` for the other kinds.
+    pub fn set_rate_limit(name: &str, limit: RateLimit) -> Result<(), RbufError> {
+        let segment = Backing::Shm.open(name)?;
+        let header = RingBufferHeader::validate_any(segment.as_ptr(), segment.len())?;
+        if header.kind != RingKind::Typed as u32 {
+            return Err(RbufError::IncompatibleLayout(format!(
+                "segment holds ring kind {} but only typed rings have a rate limit",
+                header.kind
+            )));
+        }
+        limit.store(&header.control);
+        event!(debug, ring = name, ?limit, "set rate limit");
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+// rpc.rs
+//
+// Request/reply over byte rings. The server creates the request ring and
+// every client creates a reply ring of its own, `<name>.reply.<client id>`,
+// which the server opens the first time it hears from that client. Frames:
+//
+//     request: [ client id: u64 | call id: u64 | payload ]
+//     reply:   [ call id: u64 | payload ]
+//
+// A client makes one call at a time and drops replies whose call id isn't
+// the one it's waiting for, i.e. replies to calls that already timed out.
+// Payloads are opaque bytes; encode them however both sides agree.
+//
+//     // server
+//     RpcServer::create("prices", 1 << 20)?.serve(|request| quote(request));
+//
+//     // client
+//     let mut client = RpcClient::connect("prices", 1 << 16)?;
+//     let reply = client.call(b"ACME")?;
+use std::collections::HashMap;
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::bytes::{Reader, Writer};
+use crate::error::RbufError;
+
+// How long `call` waits unless told otherwise
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const ID_SIZE: usize = 8;
+
+fn reply_name(name: &str, client: u64) -> String {
+    format!("{}.reply.{:016x}", name, client)
+}
+
+// Unique among the clients of a server while their processes live
+fn client_id() -> u64 {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    (process::id() as u64) << 32 | NEXT.fetch_add(1, Ordering::Relaxed) as u64
+}
+
+fn read_id(frame: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(frame.get(at..at + ID_SIZE)?.try_into().unwrap()))
+}
+
+// --- Server ---
+
+pub struct RpcServer {
+    name: String,
+    requests: Reader,
+    // Reply rings of the clients heard from so far
+    clients: HashMap<u64, Writer>,
+    buf: Vec<u8>,
+}
+
+impl RpcServer {
+    // Create the request ring, with room for `capacity` bytes of requests
+    pub fn create(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        let requests = Reader::create(name, capacity)?;
+        Ok(Self { name: name.to_string(), requests, clients: HashMap::new(), buf: Vec::new() })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Answer requests with `handler`, forever
+    pub fn serve(&mut self, mut handler: impl FnMut(&[u8]) -> Vec<u8>) -> ! {
+        loop {
+            let _ = self.serve_one(None, &mut handler);
+        }
+    }
+
+    // Wait for one request, until `timeout` if there is one, and answer it.
+    // Fails with `Timeout` if none came, with `Decode` if the request wasn't
+    // a valid frame, and with whatever stopped the reply from reaching its
+    // client, e.g. `Full` if the client isn't reading its replies.
+    pub fn serve_one(
+        &mut self,
+        timeout: Option<Duration>,
+        handler: impl FnOnce(&[u8]) -> Vec<u8>,
+    ) -> Result<(), RbufError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.requests.pop_bytes_until(&mut self.buf, deadline)?;
+        let (Some(client), Some(call)) = (read_id(&self.buf, 0), read_id(&self.buf, ID_SIZE))
+        else {
+            return Err(RbufError::Decode("request shorter than its frame".to_string()));
+        };
+
+        let mut reply = call.to_le_bytes().to_vec();
+        reply.extend_from_slice(&handler(&self.buf[2 * ID_SIZE..]));
+        let result = self.reply_ring(client)?.push_bytes(&reply);
+        if result.is_err() {
+            // Maybe it's gone; reopen next time
+            self.clients.remove(&client);
+        }
+        result
+    }
+
+    fn reply_ring(&mut self, client: u64) -> Result<&Writer, RbufError> {
+        if !self.clients.contains_key(&client) {
+            // Let go of clients that have since disconnected
+            self.clients.retain(|_, writer| writer.attached() > 1);
+            let writer = Writer::open(&reply_name(&self.name, client))?;
+            self.clients.insert(client, writer);
+        }
+        Ok(&self.clients[&client])
+    }
+}
+
+// --- Client ---
+
+pub struct RpcClient {
+    requests: Writer,
+    replies: Reader,
+    id: u64,
+    next_call: u64,
+    timeout: Duration,
+    buf: Vec<u8>,
+    request: Vec<u8>,
+}
+
+impl RpcClient {
+    // Open the server's request ring and create a reply ring with room for
+    // `capacity` bytes of replies
+    pub fn connect(name: &str, capacity: usize) -> Result<Self, RbufError> {
+        let requests = Writer::open(name)?;
+        let id = client_id();
+        let replies = Reader::create(&reply_name(name, id), capacity)?;
+        Ok(Self {
+            requests,
+            replies,
+            id,
+            next_call: 0,
+            timeout: DEFAULT_TIMEOUT,
+            buf: Vec::new(),
+            request: Vec::new(),
+        })
+    }
+
+    // How long `call` waits for the request to fit and the reply to come
+    // back. Defaults to five seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn call(&mut self, request: &[u8]) -> Result<Vec<u8>, RbufError> {
+        self.call_timeout(request, self.timeout)
+    }
+
+    // Send `request` and wait for its reply, failing with `Timeout` after
+    // `timeout`. A reply that comes later is thrown away by the next call.
+    pub fn call_timeout(
+        &mut self,
+        request: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, RbufError> {
+        let deadline = Instant::now() + timeout;
+        let call = self.next_call;
+        self.next_call += 1;
+
+        self.request.clear();
+        self.request.extend_from_slice(&self.id.to_le_bytes());
+        self.request.extend_from_slice(&call.to_le_bytes());
+        self.request.extend_from_slice(request);
+        self.requests.push_bytes_until(&self.request, deadline)?;
+
+        loop {
+            self.replies.pop_bytes_until(&mut self.buf, Some(deadline))?;
+            if read_id(&self.buf, 0) == Some(call) {
+                return Ok(self.buf.split_off(ID_SIZE));
+            }
+        }
+    }
+}
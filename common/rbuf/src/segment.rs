@@ -0,0 +1,1033 @@
+// segment.rs
+//
+// The memory a ring lives in. By default that's a POSIX shared memory object
+// named after the ring (a named file mapping on Windows); a regular file
+// mapped with MAP_SHARED works just as well between processes and also
+// survives a reboot. Heap segments never leave the process and exist for
+// tests and single-process users. On Linux a memfd avoids /dev/shm names
+// altogether: the creator hands its descriptor to other processes over a
+// Unix socket (see fd.rs). Anything else can be plugged in through
+// `Backing::Custom`.
+use shared_memory::{Shmem, ShmemConf};
+use std::alloc::{self, Layout};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::io::{AsFd, BorrowedFd, FromRawFd, OwnedFd};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+#[cfg(windows)]
+use windows::Win32::System::Memory::VirtualLock;
+
+use crate::header::CACHE_LINE;
+
+use crate::error::RbufError;
+
+// A mapped region of memory that a ring lives in. The ring logic only ever
+// sees the pointer and the length, so a new kind of memory only needs an
+// implementation of this trait and a `Backend` to create and open it.
+//
+/// # Safety
+///
+/// `as_ptr` must point to `len` bytes that stay mapped, at the same address,
+/// until the segment is dropped, aligned to at least 64 bytes. A freshly
+/// created segment must be zero-filled. Every handle opened on the same
+/// segment must see the same memory.
+pub unsafe trait Segment: Send + Sync {
+    fn as_ptr(&self) -> *mut u8;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Something to identify the segment by in messages: the shm ID, a path
+    fn name(&self) -> &str;
+
+    // Whether dropping this handle removes the segment from the system.
+    // Segments that can't be removed may ignore it.
+    fn set_owner(&mut self, owner: bool);
+
+    // Whether the segment can still be opened once every handle to it is
+    // gone. Items left in one that can't are dropped with the last handle.
+    fn outlives_handles(&self) -> bool {
+        true
+    }
+
+    // Write `len` bytes from `offset` on back to whatever storage is behind
+    // the segment, waiting until they're there if `wait`. Segments that
+    // only live in memory have nothing to do.
+    fn flush(&self, _offset: usize, _len: usize, _wait: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    // The descriptor the segment was mapped from, for segments that keep one
+    // open and can be handed to another process
+    #[cfg(unix)]
+    fn fd(&self) -> Option<BorrowedFd<'_>> {
+        None
+    }
+
+    // Change who may open the segment. Only called on the creator's handle,
+    // before anyone else could have attached.
+    #[cfg(unix)]
+    fn set_permissions(&self, _permissions: &Permissions) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+// Who may open a segment, set by its creator. Fields left at None keep what
+// the backing gives new segments: 0600 and the creating user for shm
+// objects, the umask for files.
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Permissions {
+    // Mode bits, e.g. 0o600 to keep other users out or 0o660 to share with
+    // a group. Not masked by the umask.
+    pub mode: Option<u32>,
+    // Changing the owning user needs CAP_CHOWN
+    pub uid: Option<u32>,
+    // Any group the creator is a member of
+    pub gid: Option<u32>,
+}
+
+#[cfg(unix)]
+impl Permissions {
+    // Apply to whatever `fd` refers to, for `Segment` implementations
+    pub fn apply(&self, fd: BorrowedFd<'_>) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        if self.uid.is_some() || self.gid.is_some() {
+            std::os::unix::fs::fchown(fd, self.uid, self.gid)?;
+        }
+        // After the chown, which may clear setgid bits
+        if let Some(mode) = self.mode {
+            if unsafe { libc::fchmod(fd.as_raw_fd(), mode as libc::mode_t) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+// Creates and opens segments of one kind
+pub trait Backend: fmt::Debug + Send + Sync {
+    // Create a zero-filled segment of `size` bytes. If `name` is taken this
+    // must fail with an `io::ErrorKind::AlreadyExists` wrapped in
+    // `RbufError::Backend` so that open_or_create knows to open instead.
+    fn create(&self, name: &str, size: usize) -> Result<Box<dyn Segment>, RbufError>;
+
+    fn open(&self, name: &str) -> Result<Box<dyn Segment>, RbufError>;
+}
+
+// Where the memory for a ring comes from
+#[derive(Debug, Clone, Default)]
+pub enum Backing {
+    // A shared memory object, named after the ring (/dev/shm on Linux)
+    #[default]
+    Shm,
+    // A regular file mapped into every attached process. Unlike shared
+    // memory the file is left in place when the creator drops its handle
+    // unless `unlink_on_drop(true)` is set.
+    #[cfg(unix)]
+    File(PathBuf),
+    // Plain heap memory, registered under the ring's name so that handles
+    // in the same process can find each other. Nothing outside the process
+    // can see it and nothing is left behind when the process exits.
+    Heap,
+    // An anonymous memfd, sealed against resizing once created. It has no
+    // name anyone else can open: share it by sending `Consumer::fd()` over a
+    // Unix socket with `send_fd`. The memory goes away with the last
+    // descriptor and mapping.
+    #[cfg(target_os = "linux")]
+    Memfd,
+    // The segment behind a descriptor received from the creator, e.g. with
+    // `recv_fd`. Only opens; the segment already exists.
+    #[cfg(unix)]
+    Fd(Arc<OwnedFd>),
+    // Anything else
+    Custom(Arc<dyn Backend>),
+}
+
+impl Backing {
+    pub(crate) fn create(&self, name: &str, size: usize) -> Result<Box<dyn Segment>, RbufError> {
+        match self {
+            Backing::Shm => ShmSegment::create(name, size).map(|s| Box::new(s) as _),
+            #[cfg(unix)]
+            Backing::File(path) => file::FileSegment::create(path, size).map(|s| Box::new(s) as _),
+            Backing::Heap => HeapSegment::create(name, size).map(|s| Box::new(s) as _),
+            #[cfg(target_os = "linux")]
+            Backing::Memfd => fd::FdSegment::create_memfd(name, size, 0).map(|s| Box::new(s) as _),
+            // Reported as already existing so that open_or_create opens it
+            #[cfg(unix)]
+            Backing::Fd(_) => Err(RbufError::FdCreate(io::ErrorKind::AlreadyExists.into())),
+            Backing::Custom(backend) => backend.create(name, size),
+        }
+    }
+
+    pub(crate) fn open(&self, name: &str) -> Result<Box<dyn Segment>, RbufError> {
+        match self {
+            Backing::Shm => ShmSegment::open(name).map(|s| Box::new(s) as _),
+            #[cfg(unix)]
+            Backing::File(path) => file::FileSegment::open(path).map(|s| Box::new(s) as _),
+            Backing::Heap => HeapSegment::open(name).map(|s| Box::new(s) as _),
+            #[cfg(target_os = "linux")]
+            Backing::Memfd => Err(RbufError::FdOpen(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "memfd segments can only be opened through a passed descriptor",
+            ))),
+            #[cfg(unix)]
+            Backing::Fd(fd) => fd::FdSegment::open(fd, name).map(|s| Box::new(s) as _),
+            Backing::Custom(backend) => backend.open(name),
+        }
+    }
+}
+
+// --- Shared memory ---
+
+struct ShmSegment(Shmem);
+
+// `Shmem` is just a mapping plus bookkeeping; all access to the memory goes
+// through the ring's atomics
+unsafe impl Send for ShmSegment {}
+unsafe impl Sync for ShmSegment {}
+
+impl ShmSegment {
+    fn create(name: &str, size: usize) -> Result<Self, RbufError> {
+        ShmemConf::new()
+            .size(size)
+            .os_id(name)
+            .create()
+            .map(Self)
+            .map_err(RbufError::ShmemCreate)
+    }
+
+    fn open(name: &str) -> Result<Self, RbufError> {
+        ShmemConf::new().os_id(name).open().map(Self).map_err(RbufError::ShmemOpen)
+    }
+}
+
+unsafe impl Segment for ShmSegment {
+    fn as_ptr(&self) -> *mut u8 {
+        self.0.as_ptr()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn name(&self) -> &str {
+        self.0.get_os_id()
+    }
+
+    fn set_owner(&mut self, owner: bool) {
+        self.0.set_owner(owner);
+    }
+
+    fn outlives_handles(&self) -> bool {
+        !self.0.is_owner()
+    }
+
+    #[cfg(unix)]
+    fn set_permissions(&self, permissions: &Permissions) -> io::Result<()> {
+        let id = self.0.get_os_id().trim_start_matches('/');
+        let path = std::ffi::CString::new(format!("/{}", id))?;
+        let raw = unsafe { libc::shm_open(path.as_ptr(), libc::O_RDONLY, 0) };
+        if raw < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+        permissions.apply(fd.as_fd())
+    }
+}
+
+// Page sizes rings can be backed by instead of the usual 4 KiB
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HugePageSize {
+    MB2,
+    GB1,
+}
+
+impl HugePageSize {
+    pub fn bytes(self) -> usize {
+        match self {
+            HugePageSize::MB2 => 2 << 20,
+            HugePageSize::GB1 => 1 << 30,
+        }
+    }
+}
+
+impl fmt::Display for HugePageSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HugePageSize::MB2 => write!(f, "2 MiB"),
+            HugePageSize::GB1 => write!(f, "1 GiB"),
+        }
+    }
+}
+
+// Everything about a ring's segment that isn't the ring itself
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SegmentConfig {
+    pub(crate) backing: Backing,
+    pub(crate) huge_pages: Option<HugePageSize>,
+    pub(crate) prefault: bool,
+    pub(crate) lock: bool,
+    #[cfg(unix)]
+    pub(crate) permissions: Option<Permissions>,
+}
+
+impl SegmentConfig {
+    pub(crate) fn create(&self, name: &str, size: usize) -> Result<Box<dyn Segment>, RbufError> {
+        let segment = match self.huge_pages {
+            None => self.backing.create(name, size)?,
+            #[cfg(target_os = "linux")]
+            Some(page) => huge::create(&self.backing, page, name, size)?,
+            #[cfg(not(target_os = "linux"))]
+            Some(page) => {
+                return Err(RbufError::HugePagesUnavailable(format!(
+                    "{} pages are only supported on Linux",
+                    page
+                )))
+            }
+        };
+        #[cfg(unix)]
+        let segment = match &self.permissions {
+            Some(permissions) => restrict(segment, permissions)?,
+            None => segment,
+        };
+        if self.prefault {
+            // Nobody else can see the memory yet, so it's fine to write to it
+            unsafe { touch_pages(&*segment) };
+        }
+        self.lock(segment)
+    }
+
+    pub(crate) fn open(&self, name: &str) -> Result<Box<dyn Segment>, RbufError> {
+        let segment = match self.huge_pages {
+            #[cfg(target_os = "linux")]
+            Some(page) => huge::open(&self.backing, page, name)?,
+            _ => self.backing.open(name)?,
+        };
+        if self.prefault {
+            populate_pages(&*segment);
+        }
+        self.lock(segment)
+    }
+
+    fn lock(&self, segment: Box<dyn Segment>) -> Result<Box<dyn Segment>, RbufError> {
+        if !self.lock {
+            return Ok(segment);
+        }
+        #[cfg(unix)]
+        let locked = match unsafe { libc::mlock(segment.as_ptr() as *const _, segment.len()) } {
+            0 => Ok(segment),
+            _ => Err(io::Error::last_os_error()),
+        };
+        // Limited by the process's minimum working set rather than a rlimit
+        #[cfg(windows)]
+        let locked = match unsafe { VirtualLock(segment.as_ptr() as *const _, segment.len()) } {
+            result if result.as_bool() => Ok(segment),
+            _ => Err(io::Error::last_os_error()),
+        };
+        #[cfg(not(any(unix, windows)))]
+        let locked = Err(io::ErrorKind::Unsupported.into());
+        locked.map_err(RbufError::MemoryLock)
+    }
+}
+
+// Set the permissions of a segment we just created, removing it again if
+// that fails so it isn't left behind more open than asked for
+#[cfg(unix)]
+fn restrict(
+    mut segment: Box<dyn Segment>,
+    permissions: &Permissions,
+) -> Result<Box<dyn Segment>, RbufError> {
+    match segment.set_permissions(permissions) {
+        Ok(()) => Ok(segment),
+        Err(e) => {
+            segment.set_owner(true);
+            Err(RbufError::Permissions(e))
+        }
+    }
+}
+
+pub(crate) fn page_size() -> usize {
+    #[cfg(unix)]
+    return unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    #[cfg(not(unix))]
+    return 4096;
+}
+
+// Fault in every page of a segment nobody else has seen yet by writing to it
+unsafe fn touch_pages(segment: &dyn Segment) {
+    let ptr = segment.as_ptr();
+    for offset in (0..segment.len()).step_by(page_size()) {
+        ptr.add(offset).write_volatile(0);
+    }
+}
+
+// Fault in every page of a live segment without changing its contents
+fn populate_pages(segment: &dyn Segment) {
+    #[cfg(target_os = "linux")]
+    {
+        let populated = unsafe {
+            libc::madvise(
+                segment.as_ptr() as *mut libc::c_void,
+                segment.len(),
+                libc::MADV_POPULATE_WRITE,
+            )
+        };
+        if populated == 0 {
+            return;
+        }
+    }
+    // Older kernels: reading at least maps the pages in
+    let ptr = segment.as_ptr();
+    for offset in (0..segment.len()).step_by(page_size()) {
+        unsafe { ptr.add(offset).read_volatile() };
+    }
+}
+
+// Map the shm segment `name` without write access; see `FdSegment`
+#[cfg(unix)]
+pub(crate) fn open_shm_read_only(name: &str) -> Result<Box<dyn Segment>, RbufError> {
+    fd::FdSegment::open_shm_read_only(name).map(|s| Box::new(s) as _)
+}
+
+// Whether the shm segment `name` may still exist. Only one that's certainly
+// gone counts as missing; one we aren't allowed to open exists all the same.
+#[cfg(unix)]
+pub(crate) fn shm_exists(name: &str) -> bool {
+    let Ok(path) = std::ffi::CString::new(format!("/{}", name.trim_start_matches('/'))) else {
+        return false;
+    };
+    // Just the name, without mapping anything
+    let raw = unsafe { libc::shm_open(path.as_ptr(), libc::O_RDONLY, 0) };
+    if raw >= 0 {
+        unsafe { libc::close(raw) };
+        return true;
+    }
+    io::Error::last_os_error().raw_os_error() != Some(libc::ENOENT)
+}
+
+#[cfg(windows)]
+pub(crate) fn shm_exists(name: &str) -> bool {
+    // ERROR_FILE_NOT_FOUND
+    const NOT_FOUND: u32 = 2;
+    !matches!(
+        ShmSegment::open(name),
+        Err(RbufError::ShmemOpen(shared_memory::ShmemError::MapOpenFailed(NOT_FOUND)))
+    )
+}
+
+// Whether a failed create means somebody else already created the segment
+pub(crate) fn already_exists(error: &RbufError) -> bool {
+    match error {
+        RbufError::ShmemCreate(shared_memory::ShmemError::MappingIdExists) => true,
+        RbufError::FileCreate(e)
+        | RbufError::HeapCreate(e)
+        | RbufError::FdCreate(e)
+        | RbufError::Backend(e) => {
+            e.kind() == io::ErrorKind::AlreadyExists
+        }
+        _ => false,
+    }
+}
+
+// --- Mirrored mappings ---
+
+// Part of a segment mapped twice, back to back, somewhere else in the address
+// space, so that a window running off the end of it carries on at its start.
+// Both copies are the segment's own pages, so writes through either show up
+// everywhere.
+#[cfg(target_os = "linux")]
+pub(crate) struct Mirror {
+    ptr: *mut u8,
+    len: usize,
+}
+
+#[cfg(target_os = "linux")]
+unsafe impl Send for Mirror {}
+#[cfg(target_os = "linux")]
+unsafe impl Sync for Mirror {}
+
+#[cfg(target_os = "linux")]
+impl Mirror {
+    // `offset` and `len` must be multiples of the page size. Fails for
+    // segments that aren't shared mappings, like heap segments.
+    pub(crate) fn new(
+        segment: &dyn Segment,
+        offset: usize,
+        len: usize,
+    ) -> Result<Self, RbufError> {
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE;
+        // Reserve room for both copies so nothing else lands in between
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), 2 * len, libc::PROT_NONE, flags, -1, 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(RbufError::Mirror(io::Error::last_os_error()));
+        }
+        let mirror = Self { ptr: ptr as *mut u8, len };
+        let source = unsafe { segment.as_ptr().add(offset) } as *mut libc::c_void;
+        for copy in 0..2 {
+            // Growing a shared mapping from zero bytes duplicates it instead
+            // of moving it
+            let target = unsafe { mirror.ptr.add(copy * len) };
+            let mapped = unsafe {
+                libc::mremap(source, 0, len, libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED, target)
+            };
+            if mapped == libc::MAP_FAILED {
+                return Err(RbufError::Mirror(io::Error::last_os_error()));
+            }
+        }
+        Ok(mirror)
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Mirror {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, 2 * self.len) };
+    }
+}
+
+// Mirrored rings need mremap; nothing else can create or attach to one
+#[cfg(not(target_os = "linux"))]
+pub(crate) enum Mirror {}
+
+#[cfg(not(target_os = "linux"))]
+impl Mirror {
+    pub(crate) fn new(
+        _segment: &dyn Segment,
+        _offset: usize,
+        _len: usize,
+    ) -> Result<Self, RbufError> {
+        Err(RbufError::Mirror(io::ErrorKind::Unsupported.into()))
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        match *self {}
+    }
+}
+
+// --- Heap segments ---
+
+struct HeapRegion {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+// Only ever accessed through the ring's atomics, like any other segment
+unsafe impl Send for HeapRegion {}
+unsafe impl Sync for HeapRegion {}
+
+impl Drop for HeapRegion {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+// Every live heap segment by name. The memory itself is freed once the last
+// handle drops, even if the name was unregistered earlier.
+fn heap_registry() -> &'static Mutex<HashMap<String, Arc<HeapRegion>>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<String, Arc<HeapRegion>>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+struct HeapSegment {
+    region: Arc<HeapRegion>,
+    name: String,
+    owner: bool,
+}
+
+impl HeapSegment {
+    fn create(name: &str, size: usize) -> Result<Self, RbufError> {
+        let mut registry = heap_registry().lock().unwrap();
+        if registry.contains_key(name) {
+            return Err(RbufError::HeapCreate(io::ErrorKind::AlreadyExists.into()));
+        }
+
+        let layout = Layout::from_size_align(size.max(1), CACHE_LINE)
+            .map_err(|e| RbufError::HeapCreate(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+        // Zeroed, like a fresh shm object
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return Err(RbufError::HeapCreate(io::ErrorKind::OutOfMemory.into()));
+        }
+
+        let region = Arc::new(HeapRegion { ptr, layout });
+        registry.insert(name.to_string(), region.clone());
+        Ok(Self { region, name: name.to_string(), owner: true })
+    }
+
+    fn open(name: &str) -> Result<Self, RbufError> {
+        let registry = heap_registry().lock().unwrap();
+        let region = registry
+            .get(name)
+            .cloned()
+            .ok_or_else(|| RbufError::HeapOpen(io::ErrorKind::NotFound.into()))?;
+        Ok(Self { region, name: name.to_string(), owner: false })
+    }
+}
+
+unsafe impl Segment for HeapSegment {
+    fn as_ptr(&self) -> *mut u8 {
+        self.region.ptr
+    }
+
+    fn len(&self) -> usize {
+        self.region.layout.size()
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn set_owner(&mut self, owner: bool) {
+        self.owner = owner;
+    }
+
+    // Only while the registry holds on to it, which it stops doing once the
+    // owner is gone
+    fn outlives_handles(&self) -> bool {
+        if self.owner {
+            return false;
+        }
+        let registry = heap_registry().lock().unwrap();
+        registry.get(&self.name).is_some_and(|region| Arc::ptr_eq(region, &self.region))
+    }
+
+    // Nothing outside the process can open it anyway
+    #[cfg(unix)]
+    fn set_permissions(&self, _permissions: &Permissions) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for HeapSegment {
+    fn drop(&mut self) {
+        if !self.owner {
+            return;
+        }
+        let mut registry = heap_registry().lock().unwrap();
+        // Only unregister our own region, not a newer one under the same name
+        if registry.get(&self.name).is_some_and(|region| Arc::ptr_eq(region, &self.region)) {
+            registry.remove(&self.name);
+        }
+    }
+}
+
+// --- File mappings ---
+
+#[cfg(unix)]
+pub(super) mod file {
+    use std::fs::{self, File, OpenOptions};
+    use std::io;
+    use std::os::unix::io::{AsFd, AsRawFd};
+    use std::path::{Path, PathBuf};
+    use std::ptr;
+
+    use super::{Permissions, Segment};
+    use crate::error::RbufError;
+
+    pub(super) struct FileSegment {
+        ptr: *mut u8,
+        len: usize,
+        name: String,
+        owner: bool,
+        path: PathBuf,
+    }
+
+    // The mapping is shared memory like any other
+    unsafe impl Send for FileSegment {}
+    unsafe impl Sync for FileSegment {}
+
+    impl FileSegment {
+        pub(super) fn create(path: &Path, size: usize) -> Result<Self, RbufError> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .map_err(RbufError::FileCreate)?;
+            let mapped = file
+                .set_len(size as u64)
+                .and_then(|()| Self::map(&file, path, size));
+            if mapped.is_err() {
+                let _ = fs::remove_file(path);
+            }
+            // A fresh file is all zeroes, just like a fresh shm object
+            mapped.map_err(RbufError::FileCreate)
+        }
+
+        pub(super) fn open(path: &Path) -> Result<Self, RbufError> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .map_err(RbufError::FileOpen)?;
+            let size = file.metadata().map_err(RbufError::FileOpen)?.len() as usize;
+            if size == 0 {
+                // The creator hasn't sized it yet
+                return Err(RbufError::SizeMismatch { expected: 1, actual: 0 });
+            }
+            Self::map(&file, path, size).map_err(RbufError::FileOpen)
+        }
+
+        // The mapping stays valid after `file` is closed
+        fn map(file: &File, path: &Path, size: usize) -> io::Result<Self> {
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    size,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self {
+                ptr: ptr as *mut u8,
+                len: size,
+                name: path.display().to_string(),
+                owner: false,
+                path: path.to_path_buf(),
+            })
+        }
+    }
+
+    unsafe impl Segment for FileSegment {
+        fn as_ptr(&self) -> *mut u8 {
+            self.ptr
+        }
+
+        fn len(&self) -> usize {
+            self.len
+        }
+
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn set_owner(&mut self, owner: bool) {
+            self.owner = owner;
+        }
+
+        fn outlives_handles(&self) -> bool {
+            !self.owner
+        }
+
+        fn flush(&self, offset: usize, len: usize, wait: bool) -> io::Result<()> {
+            // msync wants a page-aligned start
+            let start = offset & !(super::page_size() - 1);
+            let flags = if wait { libc::MS_SYNC } else { libc::MS_ASYNC };
+            let len = (offset + len).min(self.len) - start;
+            let addr = unsafe { self.ptr.add(start) } as *mut libc::c_void;
+            if unsafe { libc::msync(addr, len, flags) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        fn set_permissions(&self, permissions: &Permissions) -> io::Result<()> {
+            permissions.apply(File::open(&self.path)?.as_fd())
+        }
+    }
+
+    impl Drop for FileSegment {
+        fn drop(&mut self) {
+            unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+            if self.owner {
+                let _ = fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+// --- Descriptor-backed segments ---
+
+#[cfg(unix)]
+pub(super) mod fd {
+    use std::io;
+    use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+    use std::ptr;
+
+    use shared_memory::ShmemError;
+
+    use super::{Permissions, Segment};
+    use crate::error::RbufError;
+
+    pub(super) struct FdSegment {
+        ptr: *mut u8,
+        len: usize,
+        name: String,
+        // Kept open so that it can be passed on to more processes
+        fd: OwnedFd,
+    }
+
+    unsafe impl Send for FdSegment {}
+    unsafe impl Sync for FdSegment {}
+
+    impl FdSegment {
+        // A fresh memfd of `size` bytes, sealed so that nobody holding the
+        // descriptor can shrink it under the other processes' mappings
+        #[cfg(target_os = "linux")]
+        // `flags` is added to the defaults, e.g. to ask for huge pages
+        pub(super) fn create_memfd(
+            name: &str,
+            size: usize,
+            flags: libc::c_uint,
+        ) -> Result<Self, RbufError> {
+            use std::ffi::CString;
+            use std::os::unix::io::FromRawFd;
+
+            // The name only shows up in /proc/<pid>/fd; it needn't be unique
+            let label = CString::new(name.trim_start_matches('/'))
+                .map_err(|e| RbufError::FdCreate(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+            let raw = unsafe {
+                libc::memfd_create(
+                    label.as_ptr(),
+                    libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING | flags,
+                )
+            };
+            if raw < 0 {
+                return Err(RbufError::FdCreate(io::Error::last_os_error()));
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+
+            let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_SEAL;
+            if unsafe { libc::ftruncate(raw, size as libc::off_t) } < 0
+                || unsafe { libc::fcntl(raw, libc::F_ADD_SEALS, seals) } < 0
+            {
+                return Err(RbufError::FdCreate(io::Error::last_os_error()));
+            }
+            Self::map(fd, name, size, libc::PROT_WRITE).map_err(RbufError::FdCreate)
+        }
+
+        pub(super) fn open(fd: &OwnedFd, name: &str) -> Result<Self, RbufError> {
+            let fd = fd.try_clone().map_err(RbufError::FdOpen)?;
+            let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+            if unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) } < 0 {
+                return Err(RbufError::FdOpen(io::Error::last_os_error()));
+            }
+            Self::map(fd, name, stat.st_size as usize, libc::PROT_WRITE).map_err(RbufError::FdOpen)
+        }
+
+        // Map an existing shm object without write access, so that nothing
+        // in this process can change it, by mistake or otherwise, unless it
+        // could open the object for writing itself
+        pub(super) fn open_shm_read_only(name: &str) -> Result<Self, RbufError> {
+            use std::ffi::CString;
+            use std::os::unix::io::FromRawFd;
+
+            let failed = |e: io::Error| {
+                let errno = e.raw_os_error().unwrap_or(0) as u32;
+                RbufError::ShmemOpen(ShmemError::MapOpenFailed(errno))
+            };
+            let path = CString::new(format!("/{}", name.trim_start_matches('/')))
+                .map_err(|e| failed(e.into()))?;
+            let raw = unsafe { libc::shm_open(path.as_ptr(), libc::O_RDONLY, 0) };
+            if raw < 0 {
+                return Err(failed(io::Error::last_os_error()));
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+            let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+            if unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) } < 0 {
+                return Err(failed(io::Error::last_os_error()));
+            }
+            Self::map(fd, name, stat.st_size as usize, 0).map_err(failed)
+        }
+
+        // `prot` is added to PROT_READ
+        fn map(fd: OwnedFd, name: &str, size: usize, prot: libc::c_int) -> io::Result<Self> {
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    size,
+                    libc::PROT_READ | prot,
+                    libc::MAP_SHARED,
+                    fd.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { ptr: ptr as *mut u8, len: size, name: name.to_string(), fd })
+        }
+    }
+
+    unsafe impl Segment for FdSegment {
+        fn as_ptr(&self) -> *mut u8 {
+            self.ptr
+        }
+
+        fn len(&self) -> usize {
+            self.len
+        }
+
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        // There's nothing to unlink: the memory is freed with the last
+        // descriptor and mapping
+        fn set_owner(&mut self, _owner: bool) {}
+
+        fn fd(&self) -> Option<BorrowedFd<'_>> {
+            Some(self.fd.as_fd())
+        }
+
+        // Only matters to processes opening it through /proc/<pid>/fd
+        fn set_permissions(&self, permissions: &Permissions) -> io::Result<()> {
+            permissions.apply(self.fd.as_fd())
+        }
+    }
+
+    impl Drop for FdSegment {
+        fn drop(&mut self) {
+            unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+        }
+    }
+}
+
+// --- Huge pages ---
+
+// Shared memory objects can't be given huge pages, so a ring asking for them
+// gets a file on a hugetlbfs mount with the right page size instead, named
+// after the ring and removed by its creator like a shm object would be.
+// Memfds ask the kernel directly.
+#[cfg(target_os = "linux")]
+mod huge {
+    use std::ffi::CString;
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+
+    use super::fd::FdSegment;
+    use super::file::FileSegment;
+    use super::{Backing, HugePageSize, Segment};
+    use crate::error::RbufError;
+
+    pub(super) fn create(
+        backing: &Backing,
+        page: HugePageSize,
+        name: &str,
+        size: usize,
+    ) -> Result<Box<dyn Segment>, RbufError> {
+        // hugetlbfs only hands out whole pages
+        let size = size.next_multiple_of(page.bytes());
+        match backing {
+            Backing::Shm => {
+                let path = mount_for(page)?.join(name.trim_start_matches('/'));
+                let mut segment = FileSegment::create(&path, size).map_err(|e| no_pages(e, page))?;
+                segment.set_owner(true);
+                Ok(Box::new(segment))
+            }
+            Backing::File(path) => {
+                let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+                if !is_hugetlbfs(dir.unwrap_or(Path::new(".")), page) {
+                    return Err(RbufError::HugePagesUnavailable(format!(
+                        "{} is not on a hugetlbfs mount with {} pages",
+                        path.display(),
+                        page
+                    )));
+                }
+                let segment = FileSegment::create(path, size).map_err(|e| no_pages(e, page))?;
+                Ok(Box::new(segment))
+            }
+            Backing::Memfd => {
+                let flags = libc::MFD_HUGETLB
+                    | match page {
+                        HugePageSize::MB2 => libc::MFD_HUGE_2MB,
+                        HugePageSize::GB1 => libc::MFD_HUGE_1GB,
+                    };
+                let segment =
+                    FdSegment::create_memfd(name, size, flags).map_err(|e| no_pages(e, page))?;
+                Ok(Box::new(segment))
+            }
+            // Whatever the descriptor refers to already has its pages
+            Backing::Fd(_) => backing.create(name, size),
+            Backing::Heap | Backing::Custom(_) => Err(RbufError::HugePagesUnavailable(
+                "huge pages need a shm, file or memfd backing".to_string(),
+            )),
+        }
+    }
+
+    pub(super) fn open(
+        backing: &Backing,
+        page: HugePageSize,
+        name: &str,
+    ) -> Result<Box<dyn Segment>, RbufError> {
+        match backing {
+            Backing::Shm => {
+                let path = mount_for(page)?.join(name.trim_start_matches('/'));
+                Ok(Box::new(FileSegment::open(&path)?))
+            }
+            _ => backing.open(name),
+        }
+    }
+
+    // The kernel reports an empty huge page pool as ENOMEM or ENOSPC, which
+    // on its own tells nobody what to do about it
+    fn no_pages(error: RbufError, page: HugePageSize) -> RbufError {
+        let errno = match &error {
+            RbufError::FileCreate(e) | RbufError::FdCreate(e) => e.raw_os_error(),
+            _ => None,
+        };
+        match errno {
+            Some(libc::ENOMEM) | Some(libc::ENOSPC) => RbufError::HugePagesUnavailable(format!(
+                "not enough free {} pages; reserve more with /proc/sys/vm/nr_hugepages",
+                page
+            )),
+            Some(libc::EINVAL) => RbufError::HugePagesUnavailable(format!(
+                "the kernel doesn't support {} pages",
+                page
+            )),
+            _ => error,
+        }
+    }
+
+    fn mount_for(page: HugePageSize) -> Result<PathBuf, RbufError> {
+        let mounts = fs::read_to_string("/proc/mounts").map_err(|e| {
+            RbufError::HugePagesUnavailable(format!("can't read /proc/mounts: {}", e))
+        })?;
+        mounts
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let mount_point = fields.nth(1)?;
+                (fields.next()? == "hugetlbfs").then(|| PathBuf::from(mount_point))
+            })
+            .find(|mount_point| is_hugetlbfs(mount_point, page))
+            .ok_or_else(|| {
+                RbufError::HugePagesUnavailable(format!(
+                    "no hugetlbfs mount with {} pages",
+                    page
+                ))
+            })
+    }
+
+    fn is_hugetlbfs(path: &Path, page: HugePageSize) -> bool {
+        let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+            return false;
+        };
+        let mut stat = unsafe { std::mem::zeroed::<libc::statfs>() };
+        if unsafe { libc::statfs(path.as_ptr(), &mut stat) } < 0 {
+            return false;
+        }
+        stat.f_type == libc::HUGETLBFS_MAGIC && stat.f_bsize as usize == page.bytes()
+    }
+}
@@ -0,0 +1,203 @@
+// select.rs
+//
+// Waiting on several typed rings at once, for processes that route between
+// them. On Linux every ring's `readable_fd` goes into one epoll set; other
+// platforms poll the rings, sleeping a little between rounds.
+//
+//     let mut selector = Selector::new();
+//     for name in ["orders.0", "orders.1"] {
+//         selector.add(Consumer::<Order>::create(name, 1024)?)?;
+//     }
+//     loop {
+//         let index = selector.select();
+//         while let Ok(order) = selector.get_mut(index).pop() {
+//             route(index, order);
+//         }
+//     }
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::consumer::Consumer;
+use crate::error::RbufError;
+use crate::shm_safe::ShmSafe;
+
+// A set of consumers to wait on together. Owns them, since popping needs
+// them mutably between selects.
+pub struct Selector<T> {
+    consumers: Vec<Consumer<T>>,
+    // Where the next scan starts, so a busy ring can't starve the others
+    next: usize,
+    // Created by the first `add`
+    #[cfg(target_os = "linux")]
+    epoll: Option<sys::Epoll>,
+}
+
+impl<T: ShmSafe> Selector<T> {
+    pub fn new() -> Self {
+        Self {
+            consumers: Vec::new(),
+            next: 0,
+            #[cfg(target_os = "linux")]
+            epoll: None,
+        }
+    }
+
+    // Add a consumer, returning the index `select` reports it by. On Linux
+    // this starts the consumer's `readable_fd` relay, which can fail.
+    #[cfg(target_os = "linux")]
+    pub fn add(&mut self, consumer: Consumer<T>) -> io::Result<usize> {
+        let index = self.consumers.len();
+        let epoll = match &mut self.epoll {
+            Some(epoll) => epoll,
+            epoll => epoll.insert(sys::Epoll::new()?),
+        };
+        epoll.add(consumer.readable_fd()?, index)?;
+        self.consumers.push(consumer);
+        Ok(index)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn add(&mut self, consumer: Consumer<T>) -> io::Result<usize> {
+        self.consumers.push(consumer);
+        Ok(self.consumers.len() - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.consumers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.consumers.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> &Consumer<T> {
+        &self.consumers[index]
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> &mut Consumer<T> {
+        &mut self.consumers[index]
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Consumer<T>> {
+        self.consumers.iter_mut()
+    }
+
+    pub fn into_inner(self) -> Vec<Consumer<T>> {
+        self.consumers
+    }
+
+    // The index of a ring with items waiting, if any, without blocking.
+    // Scans start one past the ring reported last time.
+    pub fn try_select(&mut self) -> Option<usize> {
+        let count = self.consumers.len();
+        let found = (0..count)
+            .map(|offset| (self.next + offset) % count)
+            .find(|&index| !self.consumers[index].is_empty())?;
+        self.next = (found + 1) % count;
+        Some(found)
+    }
+
+    // Wait until one of the rings has items and return its index. A pop
+    // right after can still come up `Empty` while a producer finishes its
+    // push. Panics if no consumer was added.
+    pub fn select(&mut self) -> usize {
+        match self.wait(None) {
+            Ok(index) => index,
+            Err(_) => unreachable!("waiting without a deadline never times out"),
+        }
+    }
+
+    // Like `select`, but gives up with `Timeout` after `timeout`
+    pub fn select_timeout(&mut self, timeout: Duration) -> Result<usize, RbufError> {
+        self.wait(Some(Instant::now() + timeout))
+    }
+
+    fn wait(&mut self, deadline: Option<Instant>) -> Result<usize, RbufError> {
+        assert!(!self.consumers.is_empty(), "select over no consumers");
+        loop {
+            if let Some(index) = self.try_select() {
+                return Ok(index);
+            }
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) if !left.is_zero() => Some(left),
+                    _ => return Err(RbufError::Timeout),
+                },
+                None => None,
+            };
+            #[cfg(target_os = "linux")]
+            if let Some(epoll) = &self.epoll {
+                epoll.wait(&self.consumers, timeout);
+            }
+            #[cfg(not(target_os = "linux"))]
+            std::thread::sleep(timeout.map_or(POLL_INTERVAL, |t| t.min(POLL_INTERVAL)));
+        }
+    }
+}
+
+impl<T: ShmSafe> Default for Selector<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+const POLL_INTERVAL: Duration = Duration::from_micros(500);
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+    use std::os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+    use std::time::Duration;
+
+    use crate::consumer::Consumer;
+    use crate::shm_safe::ShmSafe;
+
+    pub(super) struct Epoll {
+        fd: OwnedFd,
+    }
+
+    impl Epoll {
+        pub(super) fn new() -> io::Result<Self> {
+            let raw = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+            if raw < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { fd: unsafe { OwnedFd::from_raw_fd(raw) } })
+        }
+
+        pub(super) fn add(&self, fd: BorrowedFd<'_>, index: usize) -> io::Result<()> {
+            let mut event = libc::epoll_event { events: libc::EPOLLIN as u32, u64: index as u64 };
+            let (epoll, fd) = (self.fd.as_raw_fd(), fd.as_raw_fd());
+            if unsafe { libc::epoll_ctl(epoll, libc::EPOLL_CTL_ADD, fd, &mut event) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        // Sleep until a ring's eventfd fires, then reset the ones that did.
+        // Callers re-check the rings themselves, so interruptions and
+        // spurious wakeups just return early.
+        pub(super) fn wait<T: ShmSafe>(
+            &self,
+            consumers: &[Consumer<T>],
+            timeout: Option<Duration>,
+        ) {
+            let timeout = timeout
+                .map_or(-1, |t| t.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32);
+            let mut events = [libc::epoll_event { events: 0, u64: 0 }; 16];
+            let ready = unsafe {
+                libc::epoll_wait(self.fd.as_raw_fd(), events.as_mut_ptr(), 16, timeout)
+            };
+            for event in events.iter().take(ready.max(0) as usize) {
+                let Some(consumer) = consumers.get(event.u64 as usize) else {
+                    continue;
+                };
+                if let Ok(fd) = consumer.readable_fd() {
+                    let mut count = [0u8; 8];
+                    unsafe { libc::read(fd.as_raw_fd(), count.as_mut_ptr() as *mut _, 8) };
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+// sharded.rs
+//
+// A ring partitioned by key: one typed ring per shard, named `<name>.0` to
+// `<name>.<shards - 1>`, and a producer that pushes each item into the shard
+// its key hashes to. Items with the same key always land in the same shard
+// and keep their order there, while different shards can be drained by
+// different consumer processes, each taking a share of them.
+//
+// Keys are hashed the same way in every process, but producers have to
+// agree on how many shards there are: one that thinks there are more or
+// fewer sends keys to other shards, and their order is lost.
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::consumer::Consumer;
+use crate::error::{PushError, RbufError};
+use crate::hash_map;
+use crate::peer::HEARTBEAT_INTERVAL;
+use crate::producer::Producer;
+use crate::select::Selector;
+use crate::shm_safe::ShmSafe;
+use crate::wait::WaitStrategy;
+
+// The ring holding one shard's items
+pub fn shard_name(name: &str, shard: usize) -> String {
+    format!("{}.{}", name, shard)
+}
+
+// --- Producer ---
+
+pub struct ShardedProducer<T> {
+    // Indexed by shard
+    shards: Vec<Producer<T>>,
+}
+
+impl<T: ShmSafe> ShardedProducer<T> {
+    // Attach to every shard of a ring split `shards` ways. Fails unless the
+    // consumers have created all of them. Panics if `shards` is 0.
+    pub fn open(name: &str, shards: usize) -> Result<Self, RbufError> {
+        assert!(shards > 0, "a sharded ring needs at least one shard");
+        let shards = (0..shards)
+            .map(|shard| Producer::open(&shard_name(name, shard)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { shards })
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    // The shard every item pushed with `key` goes to
+    pub fn shard_of<K: Hash + ?Sized>(&self, key: &K) -> usize {
+        (hash_map::hash(key) % self.shards.len() as u64) as usize
+    }
+
+    // The producer for one shard. Panics if there's no such shard.
+    pub fn shard(&self, shard: usize) -> &Producer<T> {
+        &self.shards[shard]
+    }
+
+    // Push into `key`'s shard. Fails with `Full` (handing the item back) when
+    // that shard is full, even if others have room, since items of the same
+    // key can only go in order through the one shard.
+    pub fn push_keyed<K: Hash + ?Sized>(&self, key: &K, item: T) -> Result<(), PushError<T>> {
+        self.shards[self.shard_of(key)].push(item)
+    }
+
+    // Push, waiting as the wait strategy says until `key`'s shard has room
+    pub fn push_keyed_blocking<K: Hash + ?Sized>(
+        &self,
+        key: &K,
+        item: T,
+    ) -> Result<(), PushError<T>> {
+        self.shards[self.shard_of(key)].push_blocking(item)
+    }
+
+    // How `push_keyed_blocking` waits for space
+    pub fn set_wait_strategy(&mut self, strategy: Arc<dyn WaitStrategy>) {
+        for shard in &mut self.shards {
+            shard.set_wait_strategy(strategy.clone());
+        }
+    }
+
+    // Items waiting in every shard together
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(Producer::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(Producer::is_empty)
+    }
+
+    pub fn heartbeat(&self) {
+        self.shards.iter().for_each(Producer::heartbeat);
+    }
+
+    // Close every shard, like `Producer::close`. Each consumer sees
+    // `Disconnected` once its shards are drained.
+    pub fn close(&self) {
+        self.shards.iter().for_each(Producer::close);
+    }
+}
+
+// --- Consumer ---
+
+// Drains some of a sharded ring's shards, in one process. Spreading the
+// shards over several of these, in as many processes, spreads the load.
+pub struct ShardedConsumer<T> {
+    name: String,
+    // The shard each of the selector's consumers drains, by index
+    shards: Vec<usize>,
+    selector: Selector<T>,
+    // Where the next pop starts looking, so a busy shard can't starve the
+    // others
+    next: usize,
+}
+
+impl<T: ShmSafe> ShardedConsumer<T> {
+    // Create the given shards of a ring, with room for `capacity` items
+    // each. Other consumers create the rest. Fails if `shards` is empty.
+    pub fn create(
+        name: &str,
+        shards: impl IntoIterator<Item = usize>,
+        capacity: usize,
+    ) -> Result<Self, RbufError> {
+        Self::attach(name, shards, |name| Consumer::create(name, capacity))
+    }
+
+    // Attach to shards someone else created, e.g. to take over those of a
+    // consumer that went away
+    pub fn open(name: &str, shards: impl IntoIterator<Item = usize>) -> Result<Self, RbufError> {
+        Self::attach(name, shards, Consumer::open)
+    }
+
+    fn attach(
+        name: &str,
+        shards: impl IntoIterator<Item = usize>,
+        attach: impl Fn(&str) -> Result<Consumer<T>, RbufError>,
+    ) -> Result<Self, RbufError> {
+        let shards: Vec<usize> = shards.into_iter().collect();
+        if shards.is_empty() {
+            return Err(RbufError::IncompatibleLayout("no shards to consume".to_string()));
+        }
+        // Shards attached before a failure detach (and unlink, if we created
+        // them) on drop
+        let mut selector = Selector::new();
+        for &shard in &shards {
+            let consumer = attach(&shard_name(name, shard))?;
+            // Only the eventfd relay behind each shard can fail here
+            selector.add(consumer).map_err(RbufError::Backend)?;
+        }
+        event!(debug, ring = name, ?shards, "attached to shards");
+        Ok(Self { name: name.to_string(), shards, selector, next: 0 })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // The shards this consumer drains
+    pub fn shards(&self) -> &[usize] {
+        &self.shards
+    }
+
+    fn index_of(&self, shard: usize) -> Option<usize> {
+        self.shards.iter().position(|&s| s == shard)
+    }
+
+    // The consumer for one of our shards, if we drain it
+    pub fn shard(&self, shard: usize) -> Option<&Consumer<T>> {
+        self.index_of(shard).map(|index| self.selector.get(index))
+    }
+
+    pub fn shard_mut(&mut self, shard: usize) -> Option<&mut Consumer<T>> {
+        self.index_of(shard).map(|index| self.selector.get_mut(index))
+    }
+
+    // Whether dropping this handle removes its shards from the system.
+    // Defaults to true for the shards it created.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        for consumer in self.selector.iter_mut() {
+            consumer.set_unlink_on_drop(unlink);
+        }
+    }
+
+    // Items waiting in our shards together
+    pub fn len(&self) -> usize {
+        (0..self.shards.len()).map(|index| self.selector.get(index).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (0..self.shards.len()).all(|index| self.selector.get(index).is_empty())
+    }
+
+    pub fn heartbeat(&self) {
+        (0..self.shards.len()).for_each(|index| self.selector.get(index).heartbeat());
+    }
+
+    // Pop from the next of our shards that has anything, taking turns.
+    // Fails with `Disconnected` once every one of them is closed and
+    // drained, and with `CorruptMessage` like `Consumer::pop`.
+    pub fn pop(&mut self) -> Result<T, RbufError> {
+        self.pop_with_shard().map(|(item, _)| item)
+    }
+
+    // `pop`, also saying which shard the item came from
+    pub fn pop_with_shard(&mut self) -> Result<(T, usize), RbufError> {
+        let count = self.shards.len();
+        let mut closed = 0;
+        for offset in 0..count {
+            let index = (self.next + offset) % count;
+            match self.selector.get_mut(index).pop() {
+                Err(RbufError::Empty) => {}
+                // The other shards may still hold items
+                Err(RbufError::Disconnected) => closed += 1,
+                result => {
+                    self.next = (index + 1) % count;
+                    return result.map(|item| (item, self.shards[index]));
+                }
+            }
+        }
+        if closed == count {
+            Err(RbufError::Disconnected)
+        } else {
+            Err(RbufError::Empty)
+        }
+    }
+
+    // Pop, waiting until a producer pushes to any of our shards. Corrupt
+    // items are skipped; they only show up in the stats. Fails with
+    // `Disconnected` once every shard is closed and drained.
+    pub fn pop_blocking(&mut self) -> Result<T, RbufError> {
+        loop {
+            match self.wait_for_item(None) {
+                Some(Err(RbufError::CorruptMessage { .. })) => {}
+                Some(result) => return result,
+                None => unreachable!("waiting without a deadline never times out"),
+            }
+        }
+    }
+
+    // Like `pop_blocking`, but gives up after `timeout`, and reports a
+    // corrupt item as `CorruptMessage` like `pop`
+    pub fn pop_timeout(&mut self, timeout: Duration) -> Result<T, RbufError> {
+        self.wait_for_item(Some(Instant::now() + timeout)).unwrap_or(Err(RbufError::Timeout))
+    }
+
+    // Like `Consumer`, wake up every `HEARTBEAT_INTERVAL` to beat
+    fn wait_for_item(&mut self, deadline: Option<Instant>) -> Option<Result<T, RbufError>> {
+        loop {
+            match self.pop() {
+                Err(RbufError::Empty) => {}
+                result => return Some(result),
+            }
+            let slice = Instant::now() + HEARTBEAT_INTERVAL;
+            let until = deadline.map_or(slice, |deadline| deadline.min(slice));
+            let timeout = until.saturating_duration_since(Instant::now());
+            if self.selector.select_timeout(timeout).is_ok() {
+                continue;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return None;
+            }
+            self.heartbeat();
+        }
+    }
+}
@@ -0,0 +1,42 @@
+// shm_safe.rs
+//
+// Only types whose bytes mean the same thing in every process may live in a
+// ring. A `String` or `Box` would carry a pointer into the address space of
+// the process that pushed it, which is garbage (or worse) to the process that
+// pops it.
+//
+// Nor may types with bytes that aren't a valid value: a slot that was never
+// written is all zeroes, and any process attached to the segment can write
+// whatever it likes, so `bool`, `char` and the `NonZero` integers are out.
+
+// Marker for types that can be copied byte-for-byte into shared memory and
+// read back in another process.
+//
+// This is the escape hatch for your own message types:
+//
+//     #[derive(Clone, Copy)]
+//     #[repr(C)]
+//     struct Quote { bid: f64, ask: f64, ts: u64 }
+//
+//     unsafe impl rbuf::ShmSafe for Quote {}
+//
+/// # Safety
+///
+/// Every bit pattern of the type's size must be a valid value of it: no
+/// `bool`, `char`, `NonZero*` or enums anywhere inside. Nor may it contain
+/// pointers, references or anything else that is only meaningful inside one
+/// address space (heap allocations, file descriptors, `Rc`, ...), and its
+/// layout must be the same in every process attaching to the ring, which in
+/// practice means `#[repr(C)]` and the same definition compiled into every
+/// binary.
+pub unsafe trait ShmSafe: 'static {}
+
+macro_rules! impl_shm_safe {
+    ($($t:ty),* $(,)?) => {
+        $(unsafe impl ShmSafe for $t {})*
+    };
+}
+
+impl_shm_safe!((), u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: ShmSafe, const N: usize> ShmSafe for [T; N] {}
@@ -0,0 +1,270 @@
+// slots.rs
+//
+// How the rings hand slots between producers and consumers, written against
+// the `Atomic` trait rather than std's atomics. The rings run it on the
+// atomics in their segments; under `--cfg loom`, tests/loom.rs runs the very
+// same code on loom's atomics, through every interleaving loom can find.
+// The headers can't simply hold loom's atomics instead: those are bigger
+// than the layout allows, and keep state outside the segment.
+//
+// Only public under loom, for the models.
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::error::RbufError;
+#[cfg(loom)]
+pub use crate::ring::{SLOT_ABORTED, SLOT_COMMITTED, SLOT_EMPTY};
+#[cfg(not(loom))]
+use crate::ring::{SLOT_ABORTED, SLOT_COMMITTED, SLOT_EMPTY};
+
+// What the protocols need of an atomic holding a `V`
+pub trait Atomic<V> {
+    fn load(&self, order: Ordering) -> V;
+    fn store(&self, value: V, order: Ordering);
+    fn compare_exchange(
+        &self,
+        current: V,
+        new: V,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<V, V>;
+    fn compare_exchange_weak(
+        &self,
+        current: V,
+        new: V,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<V, V>;
+    // Give way to whoever we're spinning on
+    fn spin();
+}
+
+macro_rules! impl_atomic {
+    ($($atomic:ty => $value:ty, $spin:path;)*) => {
+        $(
+            impl Atomic<$value> for $atomic {
+                #[inline]
+                fn load(&self, order: Ordering) -> $value {
+                    <$atomic>::load(self, order)
+                }
+
+                #[inline]
+                fn store(&self, value: $value, order: Ordering) {
+                    <$atomic>::store(self, value, order)
+                }
+
+                #[inline]
+                fn compare_exchange(
+                    &self,
+                    current: $value,
+                    new: $value,
+                    success: Ordering,
+                    failure: Ordering,
+                ) -> Result<$value, $value> {
+                    <$atomic>::compare_exchange(self, current, new, success, failure)
+                }
+
+                #[inline]
+                fn compare_exchange_weak(
+                    &self,
+                    current: $value,
+                    new: $value,
+                    success: Ordering,
+                    failure: Ordering,
+                ) -> Result<$value, $value> {
+                    <$atomic>::compare_exchange_weak(self, current, new, success, failure)
+                }
+
+                fn spin() {
+                    $spin()
+                }
+            }
+        )*
+    };
+}
+
+impl_atomic! {
+    AtomicU32 => u32, std::thread::yield_now;
+    AtomicU64 => u64, std::thread::yield_now;
+}
+
+#[cfg(loom)]
+impl_atomic! {
+    loom::sync::atomic::AtomicU32 => u32, loom::thread::yield_now;
+    loom::sync::atomic::AtomicU64 => u64, loom::thread::yield_now;
+}
+
+// --- Typed ring ---
+//
+// `head` and `tail` are free-running and every slot has a flag; see ring.rs.
+
+// Claim up to `wanted` consecutive slots from `tail` on for a producer,
+// returning the first position and how many were claimed. `limit` says how
+// many may be claimed from a given tail besides what's free, or refuses
+// outright. With no room, `make_room` is asked to drop the oldest item, at
+// the given head, and says whether to look again.
+pub fn claim<A: Atomic<u64>>(
+    head: &A,
+    tail: &A,
+    capacity: u64,
+    wanted: usize,
+    mut limit: impl FnMut(u64) -> Result<u64, RbufError>,
+    mut make_room: impl FnMut(u64) -> bool,
+) -> Result<(u64, usize), RbufError> {
+    let mut start = tail.load(Ordering::Acquire);
+    loop {
+        let oldest = head.load(Ordering::Acquire);
+        // Our tail is stale if the consumer has already passed it
+        let Some(used) = start.checked_sub(oldest) else {
+            start = tail.load(Ordering::Acquire);
+            continue;
+        };
+        let limit = limit(start)?;
+        let count = wanted.min(capacity.saturating_sub(used).min(limit) as usize);
+        if count == 0 {
+            if make_room(oldest) {
+                start = tail.load(Ordering::Acquire);
+                continue;
+            }
+            return Err(RbufError::Full);
+        }
+
+        // SeqCst for `grow`, like its notice
+        match tail.compare_exchange_weak(
+            start,
+            start + count as u64,
+            Ordering::SeqCst,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => return Ok((start, count)),
+            Err(current) => start = current,
+        }
+    }
+}
+
+// Mark a claimed slot, now written or given up, as COMMITTED or ABORTED
+pub fn publish<A: Atomic<u32>>(flag: &A, state: u32) {
+    flag.store(state, Ordering::Release);
+}
+
+// Take the slot at `pos`, whose flag was `observed`, from whoever else may
+// race for it: the consumer and producers overwriting the oldest item. The
+// flag is cleared first, so only one of them can get as far as `head`, and
+// put back if `head` has moved on, because then the flag we cleared was
+// that of a later item that has since been written into the same slot.
+// Returns whether we took it.
+pub fn take<A: Atomic<u32>, B: Atomic<u64>>(head: &B, flag: &A, pos: u64, observed: u32) -> bool {
+    if flag.compare_exchange(observed, SLOT_EMPTY, Ordering::AcqRel, Ordering::Relaxed).is_err() {
+        return false;
+    }
+    let taken = head.compare_exchange(pos, pos + 1, Ordering::AcqRel, Ordering::Relaxed);
+    if taken.is_err() {
+        flag.store(observed, Ordering::Release);
+        return false;
+    }
+    true
+}
+
+// Take the oldest item, at `pos`, to make room for a producer. An empty flag
+// means another producer is still writing the slot or the consumer is in
+// the middle of taking it; both resolve quickly, but after `attempts` spins
+// this gives up and returns None. Otherwise returns whether it was us who
+// took the slot; if not, someone else did, which made room just the same.
+pub fn drop_oldest<A: Atomic<u32>, B: Atomic<u64>>(
+    head: &B,
+    flag: &A,
+    pos: u64,
+    attempts: usize,
+) -> Option<bool> {
+    let mut spins = 0;
+    let observed = loop {
+        let state = flag.load(Ordering::Acquire);
+        if state != SLOT_EMPTY {
+            break state;
+        }
+        if head.load(Ordering::Acquire) != pos {
+            return Some(false);
+        }
+        if spins == attempts {
+            return None;
+        }
+        spins += 1;
+        A::spin();
+    };
+    Some(take(head, flag, pos, observed))
+}
+
+// Take the item at `head` for a consumer. `read` copies it out of its slot
+// before the race for it is decided, so it may see the slot being
+// overwritten: its copy only counts if we win, and is dropped otherwise.
+// Returns None if there's nothing to take yet, else the position taken and
+// the copy, or no copy for an aborted reservation.
+pub fn pop<'a, A: Atomic<u32> + 'a, B: Atomic<u64>, R>(
+    head: &B,
+    tail: &B,
+    flag: impl Fn(u64) -> &'a A,
+    mut read: impl FnMut(u64) -> R,
+) -> Option<(u64, Option<R>)> {
+    loop {
+        let pos = head.load(Ordering::Acquire);
+        if pos == tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let flag = flag(pos);
+        let state = flag.load(Ordering::Acquire);
+        let copy = match state {
+            SLOT_COMMITTED => Some(read(pos)),
+            SLOT_ABORTED => None,
+            // Still being written, or a producer is dropping it right now
+            _ => return None,
+        };
+        if take(head, flag, pos, state) {
+            return Some((pos, copy));
+        }
+    }
+}
+
+// --- Work queue ---
+//
+// Every slot has a sequence number saying whose turn it is; see mpmc.rs.
+
+// Claim the position at `cursor` once its slot's sequence number says it's
+// our turn, which is `lag` behind the number: 0 for producers at `tail`, 1
+// for consumers at `head`. `on_turn` runs each time it is, before racing
+// for it. Returns None if the slot isn't ready, i.e. the queue is full for
+// producers and empty for consumers.
+pub fn claim_turn<'a, A: Atomic<u64> + 'a>(
+    cursor: &A,
+    seq: impl Fn(u64) -> &'a A,
+    lag: u64,
+    mut on_turn: impl FnMut(u64),
+) -> Option<u64> {
+    let mut pos = cursor.load(Ordering::Relaxed);
+    loop {
+        let turn = seq(pos).load(Ordering::Acquire);
+        match (turn.wrapping_sub(pos.wrapping_add(lag)) as i64).signum() {
+            // Ours; race the others on this side for it
+            0 => {
+                on_turn(pos);
+                match cursor.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Some(pos),
+                    Err(current) => pos = current,
+                }
+            }
+            // Not handed over from the other side yet
+            -1 => return None,
+            // Someone on our side got here first; catch up
+            _ => pos = cursor.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Hand a claimed slot over to the other side, giving it sequence number
+// `next`: its position + 1 once written, + capacity once emptied
+pub fn hand_over<A: Atomic<u64>>(seq: &A, next: u64) {
+    seq.store(next, Ordering::Release);
+}
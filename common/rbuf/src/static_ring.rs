@@ -0,0 +1,278 @@
+// static_ring.rs
+//
+// A typed ring whose capacity is a power of two fixed at build time. The
+// segment is laid out like any other typed ring, so inspection, the
+// directory and `ping` all work on it, but the handles index slots with a
+// constant mask and leave out everything that costs on the hot path:
+// checksums, timestamps, stats and the consumer table.
+//
+// Each handle assumes it is the only one of its side: a `StaticProducer`
+// moves `tail` without a CAS and a `StaticConsumer` moves `head` without
+// one. A `StaticConsumer` can still be fed by ordinary producers, as long
+// as none of them overwrites.
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::config::RingConfig;
+use crate::error::{PushError, RbufError};
+use crate::header::{RingBufferHeader, Role, OPTION_CHECKSUMS, OPTION_TIMESTAMPS};
+use crate::ring::{ShmemRingBuffer, SLOT_ABORTED, SLOT_COMMITTED, SLOT_EMPTY};
+use crate::shm_safe::ShmSafe;
+use crate::wait::{self, Blocking, WaitStrategy};
+
+// Shorthands for the handles of a ring of `N` items:
+//
+//     let consumer = StaticRing::<Quote, 4096>::consumer("quotes")?;
+//     let mut producer = StaticRing::<Quote, 4096>::producer("quotes")?;
+pub struct StaticRing<T, const N: usize> {
+    _phantom: PhantomData<T>,
+}
+
+impl<T, const N: usize> StaticRing<T, N> {
+    // Fails the build for any `N` that handles are made with that isn't a
+    // power of two
+    const MASK: u64 = {
+        assert!(N.is_power_of_two(), "a static ring's capacity must be a power of two");
+        N as u64 - 1
+    };
+}
+
+impl<T: ShmSafe, const N: usize> StaticRing<T, N> {
+    // Shorthand for `RingConfig::new(name).static_consumer()`, which creates
+    // the ring
+    pub fn consumer(name: &str) -> Result<StaticConsumer<T, N>, RbufError> {
+        RingConfig::new(name).static_consumer()
+    }
+
+    // Shorthand for `RingConfig::new(name).static_producer()`, which attaches
+    // to an existing ring
+    pub fn producer(name: &str) -> Result<StaticProducer<T, N>, RbufError> {
+        RingConfig::new(name).static_producer()
+    }
+}
+
+// Check that a ring someone else may have created is one the handles can
+// work with
+fn check<T, const N: usize>(rb: &ShmemRingBuffer<T>) -> Result<(), RbufError> {
+    let header = rb.header();
+    if header.capacity() as u64 != StaticRing::<T, N>::MASK + 1 {
+        return Err(RbufError::IncompatibleLayout(format!(
+            "the ring holds {} items, not the {} it was built for",
+            header.capacity(),
+            N
+        )));
+    }
+    if header.options.has(OPTION_CHECKSUMS) || header.options.has(OPTION_TIMESTAMPS) {
+        return Err(RbufError::IncompatibleLayout(
+            "static rings keep neither checksums nor timestamps".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// --- Producer ---
+
+pub struct StaticProducer<T, const N: usize> {
+    rb: ShmemRingBuffer<T>,
+    flags: *const AtomicU32,
+    slots: *mut T,
+    wait: Arc<dyn WaitStrategy>,
+}
+
+// The pointers are into `rb`'s mapping, which lives as long as the handle
+unsafe impl<T: Send, const N: usize> Send for StaticProducer<T, N> {}
+
+impl<T: ShmSafe, const N: usize> StaticProducer<T, N> {
+    pub(crate) fn from_ring(rb: ShmemRingBuffer<T>) -> Result<Self, RbufError> {
+        check::<T, N>(&rb)?;
+        let (flags, slots) = rb.slot_bases();
+        Ok(Self { rb, flags, slots, wait: Arc::new(Blocking) })
+    }
+
+    fn header(&self) -> &RingBufferHeader {
+        self.rb.header()
+    }
+
+    pub fn name(&self) -> &str {
+        self.rb.name()
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    // How `push_blocking` waits for space
+    pub fn set_wait_strategy(&mut self, strategy: Arc<dyn WaitStrategy>) {
+        self.wait = strategy;
+    }
+
+    // Whether dropping this handle removes the segment from the system
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.rb.set_owner(unlink);
+    }
+
+    // See `Producer::ping`
+    pub fn ping(&self) -> Result<(), RbufError> {
+        self.rb.ping()
+    }
+
+    // See `Producer::close`
+    pub fn close(&self) {
+        self.header().roles.close(Role::Producer);
+        self.header().data_ready.notify();
+    }
+
+    // See `Producer::is_closed`
+    pub fn is_closed(&self) -> bool {
+        let roles = &self.header().roles;
+        roles.is_closed(Role::Producer) || roles.is_closed(Role::Consumer)
+    }
+
+    pub fn push(&mut self, item: T) -> Result<(), PushError<T>> {
+        self.try_push(item)
+    }
+
+    fn try_push(&self, item: T) -> Result<(), PushError<T>> {
+        let header = self.header();
+        // Nobody else moves `tail`
+        let tail = header.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(header.head.load(Ordering::Acquire)) >= N as u64 {
+            return Err(PushError::new(RbufError::Full, item));
+        }
+        if header.roles.is_closed(Role::Producer) {
+            return Err(PushError::new(RbufError::Disconnected, item));
+        }
+
+        let index = (tail & StaticRing::<T, N>::MASK) as usize;
+        unsafe {
+            self.slots.add(index).write(item);
+            (*self.flags.add(index)).store(SLOT_COMMITTED, Ordering::Release);
+        }
+        header.tail.store(tail.wrapping_add(1), Ordering::Release);
+        header.data_ready.notify();
+        Ok(())
+    }
+
+    // Push, waiting as the wait strategy says until the consumer frees a
+    // slot. Fails with anything but `Full` straight away.
+    pub fn push_blocking(&mut self, item: T) -> Result<(), RbufError> {
+        let mut item = Some(item);
+        let space_ready = &self.header().space_ready;
+        wait::wait_until(&*self.wait, space_ready, None, || match self.try_push(item.take()?) {
+            Ok(()) => Some(Ok(())),
+            Err(e) if matches!(e.error(), RbufError::Full) => {
+                item = Some(e.into_inner());
+                None
+            }
+            Err(e) => Some(Err(e.into_error())),
+        })
+        .expect("waiting without a deadline never times out")
+    }
+}
+
+// --- Consumer ---
+
+pub struct StaticConsumer<T, const N: usize> {
+    rb: ShmemRingBuffer<T>,
+    flags: *const AtomicU32,
+    slots: *mut T,
+    wait: Arc<dyn WaitStrategy>,
+}
+
+unsafe impl<T: Send, const N: usize> Send for StaticConsumer<T, N> {}
+
+impl<T: ShmSafe, const N: usize> StaticConsumer<T, N> {
+    pub(crate) fn from_ring(rb: ShmemRingBuffer<T>) -> Result<Self, RbufError> {
+        check::<T, N>(&rb)?;
+        let (flags, slots) = rb.slot_bases();
+        Ok(Self { rb, flags, slots, wait: Arc::new(Blocking) })
+    }
+
+    fn header(&self) -> &RingBufferHeader {
+        self.rb.header()
+    }
+
+    pub fn name(&self) -> &str {
+        self.rb.name()
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        self.rb.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // How `pop_blocking` waits for items
+    pub fn set_wait_strategy(&mut self, strategy: Arc<dyn WaitStrategy>) {
+        self.wait = strategy;
+    }
+
+    // Whether dropping this handle removes the segment from the system.
+    // Defaults to true for the side that created it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.rb.set_owner(unlink);
+    }
+
+    // See `Consumer::ping`
+    pub fn ping(&self) -> Result<(), RbufError> {
+        self.rb.ping()
+    }
+
+    // Fails with `Empty`, or with `Disconnected` once a producer has closed
+    // the ring and everything pushed before has been popped
+    pub fn pop(&mut self) -> Result<T, RbufError> {
+        self.try_pop()
+    }
+
+    fn try_pop(&self) -> Result<T, RbufError> {
+        let header = self.header();
+        loop {
+            // Nobody else moves `head`
+            let head = header.head.load(Ordering::Relaxed);
+            let index = (head & StaticRing::<T, N>::MASK) as usize;
+            let flag = unsafe { &*self.flags.add(index) };
+            let item = match flag.load(Ordering::Acquire) {
+                SLOT_COMMITTED => Some(unsafe { self.slots.add(index).read() }),
+                // Given up by an ordinary producer; nothing to read
+                SLOT_ABORTED => None,
+                _ => return Err(self.nothing_left()),
+            };
+            flag.store(SLOT_EMPTY, Ordering::Relaxed);
+            header.head.store(head.wrapping_add(1), Ordering::Release);
+            header.space_ready.notify();
+            if let Some(item) = item {
+                return Ok(item);
+            }
+        }
+    }
+
+    fn nothing_left(&self) -> RbufError {
+        let header = self.header();
+        let closed = header.roles.is_closed(Role::Producer);
+        let empty = header.tail.load(Ordering::Acquire) == header.head.load(Ordering::Relaxed);
+        if closed && empty {
+            RbufError::Disconnected
+        } else {
+            RbufError::Empty
+        }
+    }
+
+    // Pop, waiting as the wait strategy says until a producer publishes.
+    // Fails with `Disconnected` once a producer has closed the ring and it's
+    // drained.
+    pub fn pop_blocking(&mut self) -> Result<T, RbufError> {
+        let data_ready = &self.header().data_ready;
+        wait::wait_until(&*self.wait, data_ready, None, || match self.try_pop() {
+            Err(RbufError::Empty) => None,
+            result => Some(result),
+        })
+        .expect("waiting without a deadline never times out")
+    }
+}
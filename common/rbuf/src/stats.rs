@@ -0,0 +1,136 @@
+// stats.rs
+//
+// Counters kept in the header so that any process attached to a ring, not
+// just the ones doing the work, can see how it's doing. Producer-side and
+// consumer-side counters live on separate cache lines like `head` and `tail`.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::latency::LatencyHistogram;
+
+// Written by producers
+#[repr(C)]
+pub(crate) struct ProducerCounters {
+    pushes: AtomicU64,
+    // Pushes rejected because the ring was full
+    full: AtomicU64,
+    // Unread items dropped to make room under `FullPolicy::Overwrite`
+    overwritten: AtomicU64,
+    high_watermark: AtomicU64,
+    // Nanoseconds since the Unix epoch, 0 if never
+    last_push: AtomicU64,
+}
+
+// Written by the consumer
+#[repr(C)]
+pub(crate) struct ConsumerCounters {
+    pops: AtomicU64,
+    last_pop: AtomicU64,
+    // Items discarded because they failed their checksum
+    corrupt: AtomicU64,
+}
+
+// Timestamps in shared memory are nanoseconds since the Unix epoch, so that
+// every process reads them the same way
+pub(crate) fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+}
+
+pub(crate) fn from_nanos(nanos: u64) -> Option<SystemTime> {
+    (nanos != 0).then(|| UNIX_EPOCH + Duration::from_nanos(nanos))
+}
+
+impl ProducerCounters {
+    pub(crate) const fn new() -> Self {
+        Self {
+            pushes: AtomicU64::new(0),
+            full: AtomicU64::new(0),
+            overwritten: AtomicU64::new(0),
+            high_watermark: AtomicU64::new(0),
+            last_push: AtomicU64::new(0),
+        }
+    }
+
+    // `occupancy` is the number of claimed slots right after the push
+    pub(crate) fn record_push(&self, count: usize, occupancy: usize) {
+        self.pushes.fetch_add(count as u64, Ordering::Relaxed);
+        self.high_watermark.fetch_max(occupancy as u64, Ordering::Relaxed);
+        self.last_push.store(now_nanos(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_full(&self) {
+        self.full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_overwrite(&self) {
+        self.overwritten.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reset(&self) {
+        for counter in [&self.pushes, &self.full, &self.overwritten, &self.high_watermark] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.last_push.store(0, Ordering::Relaxed);
+    }
+}
+
+impl ConsumerCounters {
+    pub(crate) const fn new() -> Self {
+        Self { pops: AtomicU64::new(0), last_pop: AtomicU64::new(0), corrupt: AtomicU64::new(0) }
+    }
+
+    pub(crate) fn record_pop(&self, count: usize) {
+        self.pops.fetch_add(count as u64, Ordering::Relaxed);
+        self.last_pop.store(now_nanos(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_corrupt(&self) {
+        self.corrupt.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reset(&self) {
+        for counter in [&self.pops, &self.last_pop, &self.corrupt] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+// A snapshot of a ring's counters since it was created, or since an admin
+// sent `Command::ResetStats`. The counters are updated independently, so a
+// snapshot taken under load may be slightly inconsistent (e.g. `pops`
+// briefly ahead of `pushes`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub pushes: u64,
+    pub pops: u64,
+    // Pushes rejected with `Full`; blocking pushes that eventually succeed
+    // don't count
+    pub full: u64,
+    // Unread items dropped by overwriting producers
+    pub overwritten: u64,
+    // Items discarded by the consumer because they failed their checksum
+    pub corrupt: u64,
+    // Most items ever in the ring at once
+    pub high_watermark: usize,
+    pub last_push: Option<SystemTime>,
+    pub last_pop: Option<SystemTime>,
+    // How long popped items waited in the ring, for typed rings created
+    // with `RingConfig::timestamps`
+    pub latency: Option<LatencyHistogram>,
+}
+
+impl Stats {
+    pub(crate) fn read(producers: &ProducerCounters, consumer: &ConsumerCounters) -> Self {
+        Self {
+            pushes: producers.pushes.load(Ordering::Relaxed),
+            pops: consumer.pops.load(Ordering::Relaxed),
+            full: producers.full.load(Ordering::Relaxed),
+            overwritten: producers.overwritten.load(Ordering::Relaxed),
+            corrupt: consumer.corrupt.load(Ordering::Relaxed),
+            high_watermark: producers.high_watermark.load(Ordering::Relaxed) as usize,
+            last_push: from_nanos(producers.last_push.load(Ordering::Relaxed)),
+            last_pop: from_nanos(consumer.last_pop.load(Ordering::Relaxed)),
+            latency: None,
+        }
+    }
+}
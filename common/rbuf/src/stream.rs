@@ -0,0 +1,144 @@
+// stream.rs
+//
+// `std::io` adapters over a byte ring, for code that streams bytes rather
+// than sending messages: serializers, codecs, compressors. The writer cuts
+// the stream into messages of at most `STREAM_CHUNK` bytes, and the reader
+// glues them back together. An empty message marks the end of the stream,
+// which the reader reports as end of file.
+//
+// Chunks from two writers would interleave, so a ring carries one stream
+// writer at a time.
+//
+//     let mut out = RingWriter::new(Writer::open("logs")?);
+//     io::copy(&mut File::open("app.log")?, &mut out)?;
+//     out.finish()?;
+use std::io::{self, BufRead, Read, Write};
+
+use crate::bytes::{Reader, Writer};
+use crate::error::RbufError;
+
+// Largest message a `RingWriter` sends
+const STREAM_CHUNK: usize = 64 * 1024;
+
+fn io_error(error: RbufError) -> io::Error {
+    match error {
+        RbufError::Timeout => io::Error::new(io::ErrorKind::TimedOut, error),
+        error => io::Error::other(error),
+    }
+}
+
+// --- Writer side ---
+
+// Buffers what is written and pushes it a chunk at a time, waiting for room
+// like `push_bytes_blocking`. Dropping it flushes and ends the stream,
+// ignoring errors; call `finish` to see them.
+pub struct RingWriter {
+    writer: Writer,
+    buf: Vec<u8>,
+    chunk: usize,
+    finished: bool,
+}
+
+impl RingWriter {
+    pub fn new(writer: Writer) -> Self {
+        let chunk = writer.max_message_size().clamp(1, STREAM_CHUNK);
+        Self { writer, buf: Vec::with_capacity(chunk), chunk, finished: false }
+    }
+
+    pub fn get_ref(&self) -> &Writer {
+        &self.writer
+    }
+
+    // Flush what's buffered and tell the reader the stream is over
+    pub fn finish(mut self) -> io::Result<()> {
+        self.end()
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.finished = true;
+        self.writer.push_bytes_blocking(&[]).map_err(io_error)
+    }
+}
+
+impl Write for RingWriter {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if self.buf.len() == self.chunk {
+            self.flush()?;
+        }
+        let n = bytes.len().min(self.chunk - self.buf.len());
+        self.buf.extend_from_slice(&bytes[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.writer.push_bytes_blocking(&self.buf).map_err(io_error)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RingWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.end();
+        }
+    }
+}
+
+// --- Reader side ---
+
+// Reads the stream back, sleeping while the ring is empty. Returns end of
+// file once for every stream a writer finishes, and carries on with the
+// next one after that.
+pub struct RingReader {
+    reader: Reader,
+    buf: Vec<u8>,
+    // How much of `buf` has been read
+    pos: usize,
+}
+
+impl RingReader {
+    pub fn new(reader: Reader) -> Self {
+        Self { reader, buf: Vec::new(), pos: 0 }
+    }
+
+    pub fn get_ref(&self) -> &Reader {
+        &self.reader
+    }
+
+    pub fn into_inner(self) -> Reader {
+        self.reader
+    }
+}
+
+impl Read for RingReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        let available = self.fill_buf()?;
+        let n = available.len().min(out.len());
+        out[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for RingReader {
+    // Empty only at the end of a stream
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() {
+            self.pos = 0;
+            self.buf.clear();
+            self.reader.pop_bytes_until(&mut self.buf, None).map_err(io_error)?;
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos = (self.pos + n).min(self.buf.len());
+    }
+}
@@ -0,0 +1,585 @@
+// sync.rs
+//
+// Locks for data that several processes share: put them in an arena (or
+// anywhere else in a segment every process maps) and lock them from any of
+// them. They sleep on futexes without the private flag, so a waiter in one
+// process is woken by an unlock in another.
+//
+// A process can die holding a lock. Waiters notice once the holder's pid is
+// gone and take the lock over, and the guard they get says so, since the
+// dead holder may have left the data half updated. Readers of an
+// `ShmRwLock` aren't tracked, so one that dies holding a read lock keeps
+// writers out for good.
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::process;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::error::RbufError;
+use crate::notify::{self, WaitQueue};
+use crate::peer;
+use crate::shm_safe::ShmSafe;
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+// Locked, and someone may be asleep waiting for it
+const CONTENDED: u32 = 2;
+
+// How long a waiter sleeps before checking whether the holder still exists
+const OWNER_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// --- ShmMutex ---
+
+#[repr(C)]
+pub struct ShmMutex<T> {
+    state: AtomicU32,
+    // Pid of the holder, 0 while unlocked or just after locking
+    owner: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ShmSafe> ShmSafe for ShmMutex<T> {}
+unsafe impl<T: Send> Send for ShmMutex<T> {}
+unsafe impl<T: Send> Sync for ShmMutex<T> {}
+
+impl<T> ShmMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            owner: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    // Block until the lock is ours
+    pub fn lock(&self) -> ShmMutexGuard<'_, T> {
+        self.lock_until(None).expect("no deadline to miss")
+    }
+
+    // Fails with `Timeout` if the lock is still held after `timeout`
+    pub fn lock_timeout(&self, timeout: Duration) -> Result<ShmMutexGuard<'_, T>, RbufError> {
+        self.lock_until(Some(Instant::now() + timeout))
+    }
+
+    // Fails with `Timeout` straight away if the lock is held
+    pub fn try_lock(&self) -> Result<ShmMutexGuard<'_, T>, RbufError> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .map_err(|_| RbufError::Timeout)?;
+        Ok(self.locked(None))
+    }
+
+    // No other handle can be using it, so no locking
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    fn lock_until(&self, deadline: Option<Instant>) -> Result<ShmMutexGuard<'_, T>, RbufError> {
+        if let Ok(guard) = self.try_lock() {
+            return Ok(guard);
+        }
+
+        loop {
+            // Whoever unlocks next has to wake someone, since we may sleep
+            if self.state.swap(CONTENDED, Ordering::Acquire) == UNLOCKED {
+                return Ok(self.locked(None));
+            }
+            if let Some(dead) = self.take_from_dead() {
+                return Ok(self.locked(Some(dead)));
+            }
+
+            let mut sleep = OWNER_CHECK_INTERVAL;
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(RbufError::Timeout);
+                }
+                sleep = sleep.min(remaining);
+            }
+            notify::wait_word(&self.state, CONTENDED, Some(sleep));
+        }
+    }
+
+    // If the holder's process is gone, make the lock ours and return its pid
+    fn take_from_dead(&self) -> Option<u32> {
+        let owner = self.owner.load(Ordering::Acquire);
+        if owner == 0 || owner == process::id() || peer::process_alive(owner) {
+            return None;
+        }
+        // Only one waiter gets to take it over
+        self.owner
+            .compare_exchange(owner, process::id(), Ordering::AcqRel, Ordering::Relaxed)
+            .ok()?;
+        event!(warn, pid = owner, "took over a lock from a dead process");
+        Some(owner)
+    }
+
+    fn locked(&self, abandoned_by: Option<u32>) -> ShmMutexGuard<'_, T> {
+        self.owner.store(process::id(), Ordering::Release);
+        ShmMutexGuard { mutex: self, abandoned_by }
+    }
+
+    fn unlock(&self) {
+        self.owner.store(0, Ordering::Release);
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            notify::wake_word(&self.state, 1);
+        }
+    }
+}
+
+impl<T: Default> Default for ShmMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct ShmMutexGuard<'a, T> {
+    mutex: &'a ShmMutex<T>,
+    abandoned_by: Option<u32>,
+}
+
+impl<T> ShmMutexGuard<'_, T> {
+    // The pid of a holder that died with the lock, if this guard took it
+    // over from one. What it guards may be half updated.
+    pub fn abandoned_by(&self) -> Option<u32> {
+        self.abandoned_by
+    }
+}
+
+impl<T> Deref for ShmMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for ShmMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for ShmMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+// --- ShmCondvar ---
+
+// Waits for a change to data behind a `ShmMutex`, in any process
+#[repr(C)]
+pub struct ShmCondvar {
+    queue: WaitQueue,
+}
+
+unsafe impl ShmSafe for ShmCondvar {}
+
+impl ShmCondvar {
+    pub const fn new() -> Self {
+        Self { queue: WaitQueue::new() }
+    }
+
+    // Unlock, sleep until notified and lock again. Wakeups can be spurious,
+    // so check the condition in a loop, or use `wait_while`.
+    pub fn wait<'a, T>(&self, guard: ShmMutexGuard<'a, T>) -> ShmMutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let seq = self.queue.prepare_wait();
+        drop(guard);
+        self.queue.wait(seq);
+        mutex.lock()
+    }
+
+    // Like `wait`, but also wakes after `timeout`; the flag says whether it
+    // ran out
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: ShmMutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (ShmMutexGuard<'a, T>, bool) {
+        let mutex = guard.mutex;
+        let start = Instant::now();
+        let seq = self.queue.prepare_wait();
+        drop(guard);
+        self.queue.wait_timeout(seq, timeout);
+        let guard = mutex.lock();
+        (guard, start.elapsed() >= timeout)
+    }
+
+    // Wait until `condition` is false, which it's checked under the lock
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: ShmMutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> ShmMutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.queue.notify_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.queue.notify();
+    }
+}
+
+impl Default for ShmCondvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// --- ShmRwLock ---
+
+// Any number of readers or one writer. Readers aren't held back by a waiting
+// writer, so a steady stream of them can keep writers out.
+#[repr(C)]
+pub struct ShmRwLock<T> {
+    // Number of readers, or WRITER
+    state: AtomicU32,
+    // Pid of the writer, 0 while there is none or just after locking
+    writer: AtomicU32,
+    // Waiters asleep on `state`, so unlocking knows whether to wake anyone
+    sleepers: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+const WRITER: u32 = u32::MAX;
+
+unsafe impl<T: ShmSafe> ShmSafe for ShmRwLock<T> {}
+unsafe impl<T: Send> Send for ShmRwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for ShmRwLock<T> {}
+
+impl<T> ShmRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            writer: AtomicU32::new(0),
+            sleepers: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> ShmReadGuard<'_, T> {
+        self.read_until(None).expect("no deadline to miss")
+    }
+
+    // Fails with `Timeout` if a writer still holds the lock after `timeout`
+    pub fn read_timeout(&self, timeout: Duration) -> Result<ShmReadGuard<'_, T>, RbufError> {
+        self.read_until(Some(Instant::now() + timeout))
+    }
+
+    // Fails with `Timeout` straight away if a writer holds the lock
+    pub fn try_read(&self) -> Result<ShmReadGuard<'_, T>, RbufError> {
+        let state = self.state.load(Ordering::Relaxed);
+        let abandoned_by = self.try_read_from(state).ok_or(RbufError::Timeout)?;
+        Ok(ShmReadGuard { lock: self, abandoned_by })
+    }
+
+    pub fn write(&self) -> ShmWriteGuard<'_, T> {
+        self.write_until(None).expect("no deadline to miss")
+    }
+
+    // Fails with `Timeout` if the lock is still held after `timeout`
+    pub fn write_timeout(&self, timeout: Duration) -> Result<ShmWriteGuard<'_, T>, RbufError> {
+        self.write_until(Some(Instant::now() + timeout))
+    }
+
+    // Fails with `Timeout` straight away if the lock is held
+    pub fn try_write(&self) -> Result<ShmWriteGuard<'_, T>, RbufError> {
+        let state = self.state.load(Ordering::Relaxed);
+        let abandoned_by = self.try_write_from(state).ok_or(RbufError::Timeout)?;
+        Ok(ShmWriteGuard { lock: self, abandoned_by })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    fn read_until(&self, deadline: Option<Instant>) -> Result<ShmReadGuard<'_, T>, RbufError> {
+        let abandoned_by = self.wait_for(deadline, |state| self.try_read_from(state))?;
+        Ok(ShmReadGuard { lock: self, abandoned_by })
+    }
+
+    fn write_until(&self, deadline: Option<Instant>) -> Result<ShmWriteGuard<'_, T>, RbufError> {
+        let abandoned_by = self.wait_for(deadline, |state| self.try_write_from(state))?;
+        Ok(ShmWriteGuard { lock: self, abandoned_by })
+    }
+
+    // One attempt at a read lock, given the state last seen. The inner
+    // option is the pid of a dead writer whose lock this took over.
+    fn try_read_from(&self, state: u32) -> Option<Option<u32>> {
+        if state == WRITER {
+            let dead = self.take_from_dead()?;
+            // Nobody else touches the lock while the dead writer still has it
+            self.writer.store(0, Ordering::Release);
+            self.state.store(1, Ordering::SeqCst);
+            self.wake_sleepers();
+            return Some(Some(dead));
+        }
+        // One below WRITER is as many readers as there can be
+        if state + 1 == WRITER {
+            return None;
+        }
+        self.state
+            .compare_exchange(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| None)
+    }
+
+    fn try_write_from(&self, state: u32) -> Option<Option<u32>> {
+        if state == WRITER {
+            // `take_from_dead` already made the writer us
+            return self.take_from_dead().map(Some);
+        }
+        self.state.compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        self.writer.store(process::id(), Ordering::Release);
+        Some(None)
+    }
+
+    // If the writer's process is gone, make its pid ours and return the old
+    fn take_from_dead(&self) -> Option<u32> {
+        let writer = self.writer.load(Ordering::Acquire);
+        if writer == 0 || writer == process::id() || peer::process_alive(writer) {
+            return None;
+        }
+        self.writer
+            .compare_exchange(writer, process::id(), Ordering::AcqRel, Ordering::Relaxed)
+            .ok()?;
+        event!(warn, pid = writer, "took over a write lock from a dead process");
+        Some(writer)
+    }
+
+    // Call `attempt` with each new state until it succeeds
+    fn wait_for<R>(
+        &self,
+        deadline: Option<Instant>,
+        mut attempt: impl FnMut(u32) -> Option<R>,
+    ) -> Result<R, RbufError> {
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if let Some(result) = attempt(state) {
+                return Ok(result);
+            }
+
+            let mut sleep = OWNER_CHECK_INTERVAL;
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(RbufError::Timeout);
+                }
+                sleep = sleep.min(remaining);
+            }
+            // Only sleeps if `state` hasn't moved since, and unlocking sees
+            // the registration before it decides whether to wake
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            notify::wait_word(&self.state, state, Some(sleep));
+            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn wake_sleepers(&self) {
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            notify::wake_word(&self.state, i32::MAX);
+        }
+    }
+
+    fn unlock_read(&self) {
+        if self.state.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.wake_sleepers();
+        }
+    }
+
+    fn unlock_write(&self) {
+        self.writer.store(0, Ordering::Release);
+        self.state.store(0, Ordering::SeqCst);
+        self.wake_sleepers();
+    }
+}
+
+impl<T: Default> Default for ShmRwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct ShmReadGuard<'a, T> {
+    lock: &'a ShmRwLock<T>,
+    abandoned_by: Option<u32>,
+}
+
+impl<T> ShmReadGuard<'_, T> {
+    // The pid of a writer that died with the lock, if this guard took it
+    // over from one; see `ShmMutexGuard::abandoned_by`
+    pub fn abandoned_by(&self) -> Option<u32> {
+        self.abandoned_by
+    }
+}
+
+impl<T> Deref for ShmReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for ShmReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_read();
+    }
+}
+
+pub struct ShmWriteGuard<'a, T> {
+    lock: &'a ShmRwLock<T>,
+    abandoned_by: Option<u32>,
+}
+
+impl<T> ShmWriteGuard<'_, T> {
+    // See `ShmMutexGuard::abandoned_by`
+    pub fn abandoned_by(&self) -> Option<u32> {
+        self.abandoned_by
+    }
+}
+
+impl<T> Deref for ShmWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for ShmWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for ShmWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_write();
+    }
+}
+
+// --- ShmBarrier ---
+
+// Holds every caller of `wait` until `parties` of them, from any processes,
+// have arrived, e.g. so streaming only starts once every producer has
+// attached. Reusable: the next `parties` callers form the next phase.
+#[repr(C)]
+pub struct ShmBarrier {
+    parties: u32,
+    // Futex word, set to the phase number after `arrivals` moves on to it
+    phase: AtomicU32,
+    // Phase number in the upper half, arrivals in it in the lower, so a
+    // waiter giving up can't miscount a phase that has just opened
+    arrivals: AtomicU64,
+}
+
+unsafe impl ShmSafe for ShmBarrier {}
+
+impl ShmBarrier {
+    pub const fn new(parties: u32) -> Self {
+        Self { parties, phase: AtomicU32::new(0), arrivals: AtomicU64::new(0) }
+    }
+
+    pub fn parties(&self) -> u32 {
+        self.parties
+    }
+
+    // Block until the phase is complete. Returns true in exactly one of the
+    // callers per phase, for whatever needs doing once.
+    pub fn wait(&self) -> bool {
+        self.wait_until(None).expect("no deadline to miss")
+    }
+
+    // Fails with `Timeout`, without counting this caller towards the phase,
+    // if not everyone arrived within `timeout`; a party that died before
+    // arriving would otherwise hold the rest forever
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool, RbufError> {
+        self.wait_until(Some(Instant::now() + timeout))
+    }
+
+    fn wait_until(&self, deadline: Option<Instant>) -> Result<bool, RbufError> {
+        let Some(phase) = self.arrive() else {
+            return Ok(true);
+        };
+        loop {
+            // `phase` can lag `arrivals`, which is the one to go by
+            let current = self.phase.load(Ordering::SeqCst);
+            if (self.arrivals.load(Ordering::Acquire) >> 32) as u32 != phase {
+                return Ok(false);
+            }
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return self.leave(phase);
+                    }
+                    Some(remaining)
+                }
+                None => None,
+            };
+            notify::wait_word(&self.phase, current, timeout);
+        }
+    }
+
+    // Count this caller in and return the phase it waits on, or None if it
+    // was the last to arrive and opened the next phase
+    fn arrive(&self) -> Option<u32> {
+        let mut arrivals = self.arrivals.load(Ordering::Acquire);
+        loop {
+            let phase = (arrivals >> 32) as u32;
+            let last = arrivals as u32 + 1 >= self.parties;
+            // The last arrival clears the count in the same step as it moves
+            // to the next phase, so nobody can join the one that's done
+            let next = if last { (phase.wrapping_add(1) as u64) << 32 } else { arrivals + 1 };
+            match self.arrivals.compare_exchange_weak(
+                arrivals,
+                next,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) if last => {
+                    self.phase.store(phase.wrapping_add(1), Ordering::SeqCst);
+                    notify::wake_word(&self.phase, i32::MAX);
+                    return None;
+                }
+                Ok(_) => return Some(phase),
+                Err(current) => arrivals = current,
+            }
+        }
+    }
+
+    // Take back this caller's arrival, unless the phase opened meanwhile
+    fn leave(&self, phase: u32) -> Result<bool, RbufError> {
+        let mut arrivals = self.arrivals.load(Ordering::Acquire);
+        while (arrivals >> 32) as u32 == phase {
+            match self.arrivals.compare_exchange_weak(
+                arrivals,
+                arrivals - 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Err(RbufError::Timeout),
+                Err(current) => arrivals = current,
+            }
+        }
+        Ok(false)
+    }
+}
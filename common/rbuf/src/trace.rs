@@ -0,0 +1,17 @@
+// trace.rs
+//
+// `tracing` spans and events behind the `tracing` feature. Call sites use
+// `event!` instead of the `tracing` macros so they need no cfg of their own;
+// without the feature it expands to nothing, arguments included, so only
+// pass expressions that are fine not to evaluate.
+//
+// Creating, opening and detaching, and producers blocked on a full ring, are
+// logged at DEBUG; every push and pop at TRACE; dead peers and lost items at
+// WARN.
+
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::$level!($($arg)+);
+    };
+}
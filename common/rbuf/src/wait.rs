@@ -0,0 +1,139 @@
+// wait.rs
+//
+// What a blocking call does between failed attempts. Spinning keeps latency
+// down at the cost of a core; parking on the wait queue costs a syscall on
+// each side but leaves the CPU free.
+use std::fmt;
+use std::hint;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::notify::WaitQueue;
+
+// One step of waiting, chosen by a `WaitStrategy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Idle {
+    // Burn a few cycles and try again
+    Spin,
+    // Give the rest of our time slice to another thread
+    Yield,
+    // Sleep for a fixed time (cut short by a call's deadline)
+    Sleep(Duration),
+    // Sleep until the other side signals the ring
+    Park,
+}
+
+// Decides how blocking calls wait. `attempt` counts the failed attempts so
+// far in the current call, starting at 0, so strategies can escalate.
+pub trait WaitStrategy: fmt::Debug + Send + Sync {
+    fn idle(&self, attempt: u32) -> Idle;
+}
+
+// Never gives up the CPU. Lowest latency, one core per waiting handle.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BusySpin;
+
+impl WaitStrategy for BusySpin {
+    fn idle(&self, _attempt: u32) -> Idle {
+        Idle::Spin
+    }
+}
+
+// Spins for a while, then yields on every further attempt
+#[derive(Debug, Clone, Copy)]
+pub struct SpinThenYield {
+    pub spins: u32,
+}
+
+impl Default for SpinThenYield {
+    fn default() -> Self {
+        Self { spins: 100 }
+    }
+}
+
+impl WaitStrategy for SpinThenYield {
+    fn idle(&self, attempt: u32) -> Idle {
+        if attempt < self.spins {
+            Idle::Spin
+        } else {
+            Idle::Yield
+        }
+    }
+}
+
+// Sleeps, doubling the sleep after every failed attempt up to `max`
+#[derive(Debug, Clone, Copy)]
+pub struct Sleep {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Sleep {
+    fn default() -> Self {
+        Self { initial: Duration::from_micros(10), max: Duration::from_millis(1) }
+    }
+}
+
+impl WaitStrategy for Sleep {
+    fn idle(&self, attempt: u32) -> Idle {
+        let sleep = self.initial.saturating_mul(1 << attempt.min(31));
+        Idle::Sleep(sleep.min(self.max))
+    }
+}
+
+// Parks on the ring's wait queue straight away. The default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blocking;
+
+impl WaitStrategy for Blocking {
+    fn idle(&self, _attempt: u32) -> Idle {
+        Idle::Park
+    }
+}
+
+// Call `attempt` until it returns something, idling as `strategy` says in
+// between. Returns None if `deadline` passes first. `queue` is the one the
+// other side signals when `attempt` may succeed.
+pub(crate) fn wait_until<R>(
+    strategy: &dyn WaitStrategy,
+    queue: &WaitQueue,
+    deadline: Option<Instant>,
+    mut attempt: impl FnMut() -> Option<R>,
+) -> Option<R> {
+    let mut attempts = 0;
+    loop {
+        if let Some(result) = attempt() {
+            return Some(result);
+        }
+
+        let remaining = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return None;
+                }
+                Some(deadline - now)
+            }
+            None => None,
+        };
+
+        match strategy.idle(attempts) {
+            Idle::Spin => hint::spin_loop(),
+            Idle::Yield => thread::yield_now(),
+            Idle::Sleep(sleep) => thread::sleep(remaining.map_or(sleep, |r| sleep.min(r))),
+            Idle::Park => {
+                // Register before the re-check so a signal in between isn't lost
+                let seq = queue.prepare_wait();
+                if let Some(result) = attempt() {
+                    queue.cancel_wait();
+                    return Some(result);
+                }
+                match remaining {
+                    Some(remaining) => queue.wait_timeout(seq, remaining),
+                    None => queue.wait(seq),
+                }
+            }
+        }
+        attempts = attempts.saturating_add(1);
+    }
+}
@@ -0,0 +1,226 @@
+// watermark.rs
+//
+// High and low occupancy thresholds on a typed ring, so that a producer
+// hears the ring is filling up while its pushes still succeed and can
+// throttle whatever feeds it, rather than finding out from a failed push.
+// Filling to the high mark raises the pressure, and it stays raised until
+// the ring drains to the low mark, so a ring hovering around one threshold
+// doesn't flap.
+//
+// Only pushes can cross the high mark and only pops the low one, and pops
+// happen in the consumer's process. A thread here sleeps on whichever of
+// the ring's wait queues can change the pressure next, and reports each
+// crossing to the callback and, on Linux, to an eventfd. The producer also
+// checks after its own pushes, so `Producer::pressure` is never behind them.
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::header::RingBufferHeader;
+
+// Which side of the watermarks a ring is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pressure {
+    // Below the high mark since it last drained to the low one
+    #[default]
+    Low,
+    // Reached the high mark and hasn't drained to the low one since
+    High,
+}
+
+type Callback = Arc<dyn Fn(Pressure) + Send + Sync>;
+
+// Thresholds for `Producer::set_watermarks`, as fractions of the ring's
+// capacity
+#[derive(Clone)]
+pub struct Watermarks {
+    high: f64,
+    low: f64,
+    callback: Option<Callback>,
+}
+
+impl Watermarks {
+    // Raise the pressure once the ring is `high` full and lower it once it's
+    // down to `low`, e.g. 0.8 and 0.2. Panics unless 0 <= low < high <= 1.
+    pub fn new(high: f64, low: f64) -> Self {
+        assert!(0.0 <= low && low < high && high <= 1.0, "watermarks must be 0 <= low < high <= 1");
+        Self { high, low, callback: None }
+    }
+
+    // Call `f` with the new pressure on every crossing. It runs on the
+    // pushing thread when a push raises the pressure and on the watching
+    // thread otherwise, so it should be quick.
+    pub fn on_change(mut self, f: impl Fn(Pressure) + Send + Sync + 'static) -> Self {
+        self.callback = Some(Arc::new(f));
+        self
+    }
+}
+
+// The thresholds, and the pressure they last put the ring under
+struct State {
+    high: f64,
+    low: f64,
+    // The ring's header; a different one once the producer follows the
+    // ring into a bigger segment
+    header: AtomicPtr<RingBufferHeader>,
+    raised: AtomicBool,
+    callback: Option<Callback>,
+    #[cfg(target_os = "linux")]
+    fd: OwnedFd,
+    stopped: AtomicBool,
+}
+
+impl State {
+    // Move to whatever pressure `len` items put a ring of `capacity` under.
+    // Returns whether that was a crossing; only one caller reports each.
+    fn update(&self, len: usize, capacity: usize) -> bool {
+        let high = ((self.high * capacity as f64).ceil() as usize).clamp(1, capacity);
+        let low = ((self.low * capacity as f64) as usize).min(high - 1);
+        let (from, to) = if self.raised.load(Ordering::Acquire) {
+            if len > low {
+                return false;
+            }
+            (true, false)
+        } else {
+            if len < high {
+                return false;
+            }
+            (false, true)
+        };
+        if self.raised.compare_exchange(from, to, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            return false;
+        }
+        let pressure = if to { Pressure::High } else { Pressure::Low };
+        if let Some(callback) = &self.callback {
+            callback(pressure);
+        }
+        #[cfg(target_os = "linux")]
+        signal(&self.fd);
+        true
+    }
+
+    fn pressure(&self) -> Pressure {
+        if self.raised.load(Ordering::Acquire) {
+            Pressure::High
+        } else {
+            Pressure::Low
+        }
+    }
+
+    // The header lives in shared memory that outlives the watching thread
+    fn header(&self) -> &RingBufferHeader {
+        unsafe { &*self.header.load(Ordering::Acquire) }
+    }
+}
+
+// Must be dropped before the mappings holding any header it watched
+pub(crate) struct Watcher {
+    state: Arc<State>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watcher {
+    pub(crate) fn new(header: &RingBufferHeader, watermarks: Watermarks) -> io::Result<Self> {
+        let state = Arc::new(State {
+            high: watermarks.high,
+            low: watermarks.low,
+            header: AtomicPtr::new(header as *const _ as *mut _),
+            raised: AtomicBool::new(false),
+            callback: watermarks.callback,
+            #[cfg(target_os = "linux")]
+            fd: eventfd()?,
+            stopped: AtomicBool::new(false),
+        });
+        let thread = {
+            let state = state.clone();
+            thread::Builder::new()
+                .name("rbuf-watermark".into())
+                .spawn(move || Self::run(&state))?
+        };
+        Ok(Self { state, thread: Some(thread) })
+    }
+
+    fn run(state: &State) {
+        loop {
+            let header = state.header();
+            // Pushes are all that can raise the pressure, and pops all that
+            // can lower it
+            let queue = match state.pressure() {
+                Pressure::Low => &header.data_ready,
+                Pressure::High => &header.space_ready,
+            };
+            let seq = queue.prepare_wait();
+            if state.stopped.load(Ordering::Acquire) {
+                queue.cancel_wait();
+                return;
+            }
+            if state.update(occupancy(header), header.capacity()) {
+                queue.cancel_wait();
+                continue;
+            }
+            queue.wait(seq);
+        }
+    }
+
+    // The pressure the ring is under, brought up to date with its occupancy
+    pub(crate) fn pressure(&self, len: usize, capacity: usize) -> Pressure {
+        self.state.update(len, capacity);
+        self.state.pressure()
+    }
+
+    // Watch `header` from now on, for a producer that followed the ring into
+    // a bigger segment. The old one has to stay mapped until we drop.
+    pub(crate) fn retarget(&self, header: &RingBufferHeader) {
+        let old = self.state.header.swap(header as *const _ as *mut _, Ordering::AcqRel);
+        // Kick the thread over to the new header's queues
+        let old = unsafe { &*old };
+        old.data_ready.notify();
+        old.space_ready.notify();
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) fn fd(&self) -> BorrowedFd<'_> {
+        self.state.fd.as_fd()
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.state.stopped.store(true, Ordering::Release);
+        // Kick the thread out of whichever queue it sleeps on. Anyone else
+        // waiting on them sees a spurious wakeup and re-checks.
+        let header = self.state.header();
+        header.data_ready.notify();
+        header.space_ready.notify();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Slots between head and tail, as `ShmemRingBuffer::len` counts them
+fn occupancy(header: &RingBufferHeader) -> usize {
+    let head = header.head.load(Ordering::Acquire);
+    let tail = header.tail.load(Ordering::Acquire);
+    (tail.saturating_sub(head) as usize).min(header.capacity())
+}
+
+#[cfg(target_os = "linux")]
+fn eventfd() -> io::Result<OwnedFd> {
+    let raw = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+    if raw < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(raw) })
+}
+
+// Add one to the counter. A full counter is readable already, so EAGAIN
+// loses nothing.
+#[cfg(target_os = "linux")]
+fn signal(fd: &OwnedFd) {
+    let one = 1u64.to_ne_bytes();
+    unsafe { libc::write(fd.as_raw_fd(), one.as_ptr() as *const _, one.len()) };
+}
@@ -0,0 +1,245 @@
+// loom.rs
+//
+// How the typed ring and the work queue hand slots over, run under loom on
+// the same `rbuf::slots` code the rings run on their segments. Each model
+// fails on any interleaving that loses an item, delivers one twice or lets
+// a slot be written while it's read. Only built with `--cfg loom`; see
+// Cargo.toml.
+#![cfg(loom)]
+
+use loom::cell::UnsafeCell;
+use loom::model::Builder;
+use loom::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use loom::sync::Arc;
+use loom::thread;
+use rbuf::slots::{self, SLOT_ABORTED, SLOT_COMMITTED, SLOT_EMPTY};
+use rbuf::RbufError;
+
+fn model(f: impl Fn() + Sync + Send + 'static) {
+    let mut builder = Builder::new();
+    // Enough to reorder every step of a claim against every step of a pop
+    builder.preemption_bound = Some(3);
+    builder.check(f);
+}
+
+// --- Typed ring ---
+
+// `head`, `tail` and the slot flags as a typed ring's header has them.
+// Values are atomics rather than cells because a pop reads its slot before
+// it knows it has won it, as the real pop does, and an overwriting producer
+// may be writing the slot meanwhile; what's checked is that such a copy is
+// never kept.
+struct Ring {
+    head: AtomicU64,
+    tail: AtomicU64,
+    flags: [AtomicU32; 2],
+    values: [AtomicU64; 2],
+    mask: u64,
+}
+
+impl Ring {
+    fn new(capacity: usize) -> Self {
+        assert!(capacity.is_power_of_two() && capacity <= 2);
+        Self {
+            head: AtomicU64::new(0),
+            tail: AtomicU64::new(0),
+            flags: [AtomicU32::new(SLOT_EMPTY), AtomicU32::new(SLOT_EMPTY)],
+            values: [AtomicU64::new(0), AtomicU64::new(0)],
+            mask: capacity as u64 - 1,
+        }
+    }
+
+    fn flag(&self, pos: u64) -> &AtomicU32 {
+        &self.flags[(pos & self.mask) as usize]
+    }
+
+    fn value(&self, pos: u64) -> &AtomicU64 {
+        &self.values[(pos & self.mask) as usize]
+    }
+
+    // One claim attempt, dropping the oldest item if `overwrite` and full.
+    // Returns the position claimed and the one dropped, if any.
+    fn try_push(&self, overwrite: bool) -> Result<(u64, Option<u64>), RbufError> {
+        let mut dropped = None;
+        let make_room = |head| {
+            if !overwrite {
+                return false;
+            }
+            let taken = slots::drop_oldest(&self.head, self.flag(head), head, 1);
+            if taken == Some(true) {
+                dropped = Some(head);
+            }
+            taken.is_some()
+        };
+        let capacity = self.mask + 1;
+        let (pos, _) = slots::claim(&self.head, &self.tail, capacity, 1, |_| Ok(2), make_room)?;
+        // Every item is its position + 1, so a copy of the wrong one shows
+        self.value(pos).store(pos + 1, Ordering::Relaxed);
+        slots::publish(self.flag(pos), SLOT_COMMITTED);
+        Ok((pos, dropped))
+    }
+
+    fn push(&self, overwrite: bool) -> (u64, Option<u64>) {
+        loop {
+            match self.try_push(overwrite) {
+                Ok(pushed) => return pushed,
+                Err(RbufError::Full) => thread::yield_now(),
+                Err(e) => panic!("claim failed: {}", e),
+            }
+        }
+    }
+
+    // Returns the position popped; None if there was nothing to pop
+    fn try_pop(&self) -> Option<u64> {
+        loop {
+            let read = |pos| self.value(pos).load(Ordering::Relaxed);
+            let (pos, copy) = slots::pop(&self.head, &self.tail, |pos| self.flag(pos), read)?;
+            // No copy means an aborted claim was skipped
+            if let Some(copy) = copy {
+                assert_eq!(copy, pos + 1, "kept a copy of the wrong item");
+                return Some(pos);
+            }
+        }
+    }
+
+    // Pop whatever is left once every thread is done. Loom can't wait out
+    // spinning for long, so threads pop what they find and the models drain
+    // the rest after joining them.
+    fn drain(&self) -> Vec<u64> {
+        std::iter::from_fn(|| self.try_pop()).collect()
+    }
+}
+
+#[test]
+fn two_producers_claim_and_commit_against_one_consumer() {
+    model(|| {
+        let ring = Arc::new(Ring::new(2));
+        let producers: Vec<_> = (0..2)
+            .map(|_| {
+                let ring = ring.clone();
+                thread::spawn(move || ring.push(false).0)
+            })
+            .collect();
+
+        let mut popped: Vec<_> = ring.try_pop().into_iter().chain(ring.try_pop()).collect();
+        let mut pushed: Vec<_> = producers.into_iter().map(|p| p.join().unwrap()).collect();
+        popped.extend(ring.drain());
+        popped.sort();
+        pushed.sort();
+        assert_eq!(popped, [0, 1]);
+        assert_eq!(pushed, [0, 1]);
+    });
+}
+
+#[test]
+fn aborted_claim_is_skipped() {
+    model(|| {
+        let ring = Arc::new(Ring::new(2));
+        let producer = {
+            let ring = ring.clone();
+            thread::spawn(move || {
+                let (pos, _) = slots::claim(&ring.head, &ring.tail, 2, 1, |_| Ok(2), |_| false)
+                    .expect("an empty ring has room");
+                slots::publish(ring.flag(pos), SLOT_ABORTED);
+                ring.push(false).0
+            })
+        };
+
+        let mut popped: Vec<_> = ring.try_pop().into_iter().collect();
+        assert_eq!(producer.join().unwrap(), 1);
+        popped.extend(ring.drain());
+        assert_eq!(popped, [1]);
+    });
+}
+
+#[test]
+fn drop_oldest_races_a_pop() {
+    model(|| {
+        let ring = Arc::new(Ring::new(1));
+        ring.push(false);
+
+        // Finds the ring full and drops the item at 0 unless the pop got it
+        let producer = {
+            let ring = ring.clone();
+            thread::spawn(move || ring.push(true))
+        };
+        let first = ring.try_pop();
+        let (pushed, dropped) = producer.join().unwrap();
+        assert_eq!(pushed, 1);
+
+        // Each item is popped or dropped, and only once
+        let mut seen: Vec<_> = first.into_iter().chain(ring.drain()).chain(dropped).collect();
+        seen.sort();
+        assert_eq!(seen, [0, 1]);
+    });
+}
+
+// --- Work queue ---
+
+// `head`, `tail` and the slots as the work queue's segment has them. Only
+// the slot's owner ever touches its value, so here cells catch a read or a
+// write that isn't ordered after the handover.
+struct Queue {
+    head: AtomicU64,
+    tail: AtomicU64,
+    seqs: [AtomicU64; 2],
+    values: [UnsafeCell<u64>; 2],
+}
+
+unsafe impl Sync for Queue {}
+
+impl Queue {
+    fn new() -> Self {
+        Self {
+            head: AtomicU64::new(0),
+            tail: AtomicU64::new(0),
+            seqs: [AtomicU64::new(0), AtomicU64::new(1)],
+            values: [UnsafeCell::new(0), UnsafeCell::new(0)],
+        }
+    }
+
+    fn seq(&self, pos: u64) -> &AtomicU64 {
+        &self.seqs[(pos & 1) as usize]
+    }
+
+    // Never full here: the models push no more than the queue holds
+    fn push(&self, value: u64) {
+        let pos = slots::claim_turn(&self.tail, |pos| self.seq(pos), 0, |_| {}).unwrap();
+        self.values[(pos & 1) as usize].with_mut(|v| unsafe { *v = value });
+        slots::hand_over(self.seq(pos), pos + 1);
+    }
+
+    fn try_pop(&self) -> Option<u64> {
+        let pos = slots::claim_turn(&self.head, |pos| self.seq(pos), 1, |_| {})?;
+        let value = self.values[(pos & 1) as usize].with(|v| unsafe { *v });
+        slots::hand_over(self.seq(pos), pos + 2);
+        Some(value)
+    }
+}
+
+#[test]
+fn work_queue_delivers_each_item_to_one_consumer() {
+    model(|| {
+        let queue = Arc::new(Queue::new());
+        let producers: Vec<_> = [1, 2]
+            .into_iter()
+            .map(|value| {
+                let queue = queue.clone();
+                thread::spawn(move || queue.push(value))
+            })
+            .collect();
+        let consumer = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.try_pop())
+        };
+
+        let mut popped: Vec<_> = queue.try_pop().into_iter().collect();
+        popped.extend(consumer.join().unwrap());
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        popped.extend(std::iter::from_fn(|| queue.try_pop()));
+        popped.sort();
+        assert_eq!(popped, [1, 2]);
+    });
+}
@@ -0,0 +1,255 @@
+// ring_props.rs
+//
+// Random sequences of pushes and pops, single items and batches, checked
+// against a `VecDeque` model of the ring: everything pushed comes out once,
+// in order, and nothing else does. Then the same across threads, with only
+// the order per producer to go by. Rings are heap-backed, so these need no
+// shared memory.
+use std::collections::VecDeque;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use proptest::prelude::*;
+use rbuf::{Backing, Consumer, Producer, RbufError, RingConfig};
+
+fn unique_name() -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    format!("ring-props-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+fn ring(capacity: usize) -> (Producer<u64>, Consumer<u64>) {
+    let config = RingConfig::new(&unique_name()).capacity(capacity).backing(Backing::Heap);
+    let consumer = config.consumer().unwrap();
+    let producer = config.producer().unwrap();
+    (producer, consumer)
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Push,
+    PushSlice(usize),
+    // Claim up to `n` slots and commit them
+    Reserve(usize),
+    // Claim up to `n` slots and drop them uncommitted
+    Abort(usize),
+    Pop,
+    PopInto(usize),
+    PopSlice(usize),
+    PopSliceRef(usize),
+    Drain,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => Just(Op::Push),
+        2 => (1..8usize).prop_map(Op::PushSlice),
+        2 => (1..8usize).prop_map(Op::Reserve),
+        1 => (1..4usize).prop_map(Op::Abort),
+        4 => Just(Op::Pop),
+        2 => (0..8usize).prop_map(Op::PopInto),
+        2 => (1..8usize).prop_map(Op::PopSlice),
+        2 => (1..8usize).prop_map(Op::PopSliceRef),
+        1 => Just(Op::Drain),
+    ]
+}
+
+// What the ring should hold: one entry per claimed slot, `None` for the
+// aborted ones the consumer skips
+struct Model {
+    slots: VecDeque<Option<u64>>,
+    capacity: usize,
+    next: u64,
+}
+
+impl Model {
+    fn free(&self) -> usize {
+        self.capacity - self.slots.len()
+    }
+
+    // Up to `max` values from the front, and the aborted slots around them
+    // while there is room for more, like a batch pop goes
+    fn take_values(&mut self, max: usize) -> Vec<u64> {
+        let mut taken = Vec::new();
+        while taken.len() < max {
+            match self.slots.pop_front() {
+                Some(Some(value)) => taken.push(value),
+                Some(None) => {}
+                None => break,
+            }
+        }
+        taken
+    }
+
+    fn skip_aborted(&mut self) {
+        while self.slots.front() == Some(&None) {
+            self.slots.pop_front();
+        }
+    }
+
+    // Values at the front, up to the first aborted slot
+    fn run(&self) -> usize {
+        self.slots.iter().take_while(|slot| slot.is_some()).count()
+    }
+}
+
+fn apply(op: &Op, producer: &Producer<u64>, consumer: &mut Consumer<u64>, model: &mut Model) {
+    match *op {
+        Op::Push => match producer.push(model.next) {
+            Ok(()) => {
+                assert!(model.free() > 0);
+                model.slots.push_back(Some(model.next));
+                model.next += 1;
+            }
+            Err(e) => {
+                assert!(matches!(e.error(), RbufError::Full));
+                assert_eq!(model.free(), 0);
+                assert_eq!(e.into_inner(), model.next);
+            }
+        },
+        Op::PushSlice(n) => {
+            let items: Vec<u64> = (model.next..model.next + n as u64).collect();
+            let pushed = producer.push_slice(&items);
+            assert_eq!(pushed, n.min(model.free()));
+            model.slots.extend(items[..pushed].iter().copied().map(Some));
+            model.next += pushed as u64;
+        }
+        Op::Reserve(n) => match producer.reserve_slice(n) {
+            Ok(mut guard) => {
+                assert!(!guard.is_empty() && guard.len() <= n.min(model.free()));
+                for slot in guard.iter_mut() {
+                    slot.write(model.next);
+                    model.slots.push_back(Some(model.next));
+                    model.next += 1;
+                }
+                unsafe { guard.commit() };
+            }
+            Err(e) => {
+                assert!(matches!(e, RbufError::Full));
+                assert_eq!(model.free(), 0);
+            }
+        },
+        Op::Abort(n) => match producer.reserve_slice(n) {
+            Ok(guard) => {
+                assert!(!guard.is_empty() && guard.len() <= n.min(model.free()));
+                model.slots.extend(std::iter::repeat_n(None, guard.len()));
+            }
+            Err(e) => {
+                assert!(matches!(e, RbufError::Full));
+                assert_eq!(model.free(), 0);
+            }
+        },
+        Op::Pop => {
+            let expected = model.take_values(1);
+            match consumer.pop() {
+                Ok(value) => assert_eq!(vec![value], expected),
+                Err(e) => {
+                    assert!(matches!(e, RbufError::Empty));
+                    assert!(expected.is_empty());
+                }
+            }
+        }
+        Op::PopInto(max) => {
+            let mut out = Vec::new();
+            let popped = consumer.pop_into(&mut out, max);
+            assert_eq!(popped, out.len());
+            assert_eq!(out, model.take_values(max));
+        }
+        Op::PopSlice(max) => {
+            let mut out = vec![MaybeUninit::uninit(); max];
+            let popped = consumer.pop_slice(&mut out);
+            let out: Vec<u64> = out[..popped].iter().map(|v| unsafe { v.assume_init() }).collect();
+            assert_eq!(out, model.take_values(max));
+        }
+        Op::PopSliceRef(max) => {
+            model.skip_aborted();
+            let run = model.run();
+            match consumer.pop_slice_ref(max) {
+                Some(slice) => {
+                    assert!(!slice.is_empty() && slice.len() <= max.min(run));
+                    let expected = model.take_values(slice.len());
+                    assert_eq!(&*slice, &expected[..]);
+                }
+                None => assert_eq!(run, 0),
+            }
+        }
+        Op::Drain => {
+            let drained: Vec<u64> = consumer.drain().collect();
+            let expected = model.take_values(usize::MAX);
+            assert_eq!(drained, expected);
+        }
+    }
+    assert_eq!(consumer.len(), model.slots.len());
+}
+
+proptest! {
+    #[test]
+    fn single_thread_matches_model(
+        capacity in 1..32usize,
+        ops in proptest::collection::vec(op(), 1..200),
+    ) {
+        let (producer, mut consumer) = ring(capacity);
+        let capacity = consumer.capacity();
+        let mut model = Model { slots: VecDeque::new(), capacity, next: 0 };
+        for op in &ops {
+            apply(op, &producer, &mut consumer, &mut model);
+        }
+        // Whatever is left comes out too
+        let rest: Vec<u64> = consumer.drain().collect();
+        prop_assert_eq!(rest, model.take_values(usize::MAX));
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn threads_lose_and_duplicate_nothing(
+        capacity in 1..16usize,
+        producers in 1..4u64,
+        per_producer in 1..500u64,
+        batch in 1..8usize,
+    ) {
+        let (producer, mut consumer) = ring(capacity);
+        let name = producer.name().to_string();
+        drop(producer);
+
+        // Each value is its producer in the top half and its index below
+        let handles: Vec<_> = (0..producers)
+            .map(|id| {
+                let producer: Producer<u64> =
+                    RingConfig::new(&name).backing(Backing::Heap).producer().unwrap();
+                thread::spawn(move || {
+                    let mut next = 0;
+                    while next < per_producer {
+                        let end = (next + batch as u64).min(per_producer);
+                        let items: Vec<u64> = (next..end).map(|i| id << 32 | i).collect();
+                        next += producer.push_slice(&items) as u64;
+                        thread::yield_now();
+                    }
+                })
+            })
+            .collect();
+
+        let mut seen = vec![0u64; producers as usize];
+        let mut received = 0;
+        while received < producers * per_producer {
+            match consumer.pop() {
+                Ok(value) => {
+                    let (id, index) = ((value >> 32) as usize, value & 0xffff_ffff);
+                    prop_assert!(id < seen.len());
+                    // In order per producer, so no gaps and no repeats
+                    prop_assert_eq!(index, seen[id]);
+                    seen[id] += 1;
+                    received += 1;
+                }
+                Err(RbufError::Empty) => thread::yield_now(),
+                Err(e) => panic!("pop failed: {}", e),
+            }
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        prop_assert!(consumer.pop().is_err());
+    }
+}